@@ -0,0 +1,63 @@
+//! Host-side cost of LPL framing, and end-to-end update throughput against
+//! the simulated device across block sizes, ACK policies and framing.
+//!
+//! `cargo bench --bench framing`
+
+use std::time::Duration;
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fwupd_lib_rs::{encode_frame, measure_transfer, Framing, LinkConditions, SimModel, TransferCase};
+use tokio::runtime::Runtime;
+
+const BLOCK_SIZES: [usize; 5] = [64, 128, 256, 512, 1024];
+const IMAGE_SIZE: usize = 16 * 1024;
+
+/// Pseudo-random payload, so COBS sees zeros at realistic spacing
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i.wrapping_mul(2_654_435_761) >> 7) as u8).collect()
+}
+
+fn framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_frame");
+    for block_size in BLOCK_SIZES {
+        let packet = payload(block_size);
+        group.throughput(Throughput::Bytes(block_size as u64));
+        let options = [
+            ("point-to-point", Framing::DEFAULT, None),
+            ("rs485", Framing::DEFAULT, Some(0x12)),
+            ("0x7e-delimited", Framing { syn: 0x7E, delimiter: 0x7E }, None),
+        ];
+        for (name, framing, netid) in options {
+            group.bench_with_input(BenchmarkId::new(name, block_size), &packet, |b, packet| {
+                let mut out = BytesMut::with_capacity(block_size * 2);
+                b.iter(|| {
+                    out.clear();
+                    encode_frame(framing, netid, packet, &mut out);
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Full updates of the simulated device over a 2 ms link, through the
+/// same requests, data frames and ACK handling as against hardware
+fn transfer(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let model = SimModel::default();
+    let conditions = LinkConditions { latency: Duration::from_millis(2), ..LinkConditions::clean() };
+    let image = payload(IMAGE_SIZE);
+
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(IMAGE_SIZE as u64));
+    for case in TransferCase::sweep() {
+        group.bench_function(case.to_string(), |b| {
+            b.iter(|| runtime.block_on(measure_transfer(&model, conditions, case, &image)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, framing, transfer);
+criterion_main!(benches);
@@ -0,0 +1,174 @@
+//! Simulated bootloader for testing hosts and firmware without hardware.
+//!
+//! ```text
+//! fwupd-sim [--tcp ADDR | --pty] [--model FILE] [--image FILE] [--fault SPEC]...
+//! fwupd-sim --bench [--link CONDITIONS] [--size BYTES] [--model FILE]
+//! ```
+//!
+//! Listens on `127.0.0.1:5555` unless told otherwise; with `--pty` it opens
+//! a pseudo-terminal and prints its `serial://` URI instead. The model file
+//! is TOML (see `SimModel`); faults given on the command line are added to
+//! it, e.g. `--fault disconnect:40 --fault wrong-crc`.
+//!
+//! `--bench` serves no one: it updates an in-process device over a link with
+//! the given conditions (`clean` by default) once per block size, ACK policy
+//! and framing option, and prints the throughput of each.
+
+use std::path::Path;
+use std::process::ExitCode;
+use fwupd_lib_rs::{
+    measure_transfer, Error, FirmwareFormat, FirmwareImage, LinkConditions, Result, SimFault, SimModel,
+    SimulatedDevice, TransferCase,
+};
+use log::{error, info};
+use tokio::net::TcpListener;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:5555";
+const USAGE: &str = "usage: fwupd-sim [--tcp ADDR | --pty] [--model FILE] [--image FILE] [--fault SPEC]...\n       \
+                     fwupd-sim --bench [--link CONDITIONS] [--size BYTES] [--model FILE]";
+const DEFAULT_BENCH_SIZE: usize = 64 * 1024;
+
+enum Listen {
+    Tcp(String),
+    Pty,
+}
+
+struct Options {
+    listen: Listen,
+    model: Option<String>,
+    image: Option<String>,
+    faults: Vec<SimFault>,
+    /// Measure transfers in-process instead of serving a host
+    bench: bool,
+    link: LinkConditions,
+    bench_size: usize,
+}
+
+fn parse_args() -> Result<Options> {
+    let mut options = Options {
+        listen: Listen::Tcp(DEFAULT_ADDR.into()),
+        model: None,
+        image: None,
+        faults: Vec::new(),
+        bench: false,
+        link: LinkConditions::clean(),
+        bench_size: DEFAULT_BENCH_SIZE,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| Error::Configuration(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--tcp" => options.listen = Listen::Tcp(value()?),
+            "--pty" => options.listen = Listen::Pty,
+            "--model" => options.model = Some(value()?),
+            "--image" => options.image = Some(value()?),
+            "--fault" => options.faults.push(value()?.parse()?),
+            "--bench" => options.bench = true,
+            "--link" => options.link = value()?.parse()?,
+            "--size" => {
+                options.bench_size = value()?
+                    .parse()
+                    .map_err(|_| Error::Configuration("--size needs a byte count".into()))?;
+            }
+            _ => return Err(Error::Configuration(USAGE.into())),
+        }
+    }
+    Ok(options)
+}
+
+fn load_model(options: &Options) -> Result<SimModel> {
+    let mut model = match &options.model {
+        Some(path) => SimModel::from_file(path)?,
+        None => SimModel::default(),
+    };
+    model.faults.extend(&options.faults);
+    Ok(model)
+}
+
+fn build_device(options: &Options) -> Result<SimulatedDevice> {
+    let device = SimulatedDevice::new(load_model(options)?);
+    match &options.image {
+        Some(path) => {
+            let format = FirmwareFormat::from_path(Path::new(path));
+            device.with_image(&FirmwareImage::load(path, format, 0xFF)?)
+        }
+        None => Ok(device),
+    }
+}
+
+/// Updates a fresh device for every [`TransferCase`] and prints a line each;
+/// a failing case is reported and the sweep goes on
+async fn bench(options: &Options) -> Result<()> {
+    let model = load_model(options)?;
+    let image: Vec<u8> = (0..options.bench_size).map(|i| (i.wrapping_mul(2_654_435_761) >> 7) as u8).collect();
+    println!("{} byte image, {:?}", image.len(), options.link);
+    for case in TransferCase::sweep() {
+        match measure_transfer(&model, options.link, case, &image).await {
+            Ok(sample) => println!(
+                "{:>9.1} KiB/s {:>8.2?}  {}",
+                sample.bytes_per_sec() / 1024.0, sample.elapsed, case
+            ),
+            Err(e) => println!("   failed: {}  {}", e, case),
+        }
+    }
+    Ok(())
+}
+
+async fn run(options: Options) -> Result<()> {
+    if options.bench {
+        return bench(&options).await;
+    }
+    let mut device = build_device(&options)?;
+    let model = device.model();
+    info!(
+        "Simulating device {:#06x} rev {} with {} KiB of flash at {:#010x}",
+        model.device_id, model.device_rev, model.memory.flash_size / 1024, model.memory.flash_address
+    );
+
+    match options.listen {
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(&addr).await?;
+            println!("tcp://{}", listener.local_addr()?);
+            // Like the real thing, the device talks to one host at a time
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                info!("Host connected from {}", peer);
+                if let Err(e) = device.serve(stream).await {
+                    error!("Session with {} failed: {}", peer, e);
+                }
+            }
+        }
+        Listen::Pty => {
+            let (mut device_end, host_end) = tokio_serial::SerialStream::pair()
+                .map_err(|e| Error::Connection(format!("Cannot open a PTY: {}", e)))?;
+            let path = tokio_serial::SerialPort::name(&host_end)
+                .ok_or_else(|| Error::Connection("PTY has no name".into()))?;
+            println!("serial://{}", path);
+            // Holding the host end open keeps the PTY alive between sessions
+            let _host_end = host_end;
+            loop {
+                device.serve(&mut device_end).await?;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(options).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Updates a device from the command line.
+//!
+//! ```text
+//! fwupd [--profile NAME] [--uri URI] [--firmware FILE] [--update] [--verify]
+//! fwupd completions bash|zsh|fish
+//! ```
+//!
+//! A profile is read from `$FWUPD_CONFIG` or `./fwupd.toml`; the other
+//! options override it. Without `--update` or `--verify` the device info is
+//! printed. `completions` prints a script for the shell to source, e.g.
+//! `source <(fwupd completions bash)`; it completes `--uri` by calling back
+//! with `--complete-uri`, which lists the serial ports plugged in right now.
+
+use std::process::ExitCode;
+use fwupd_lib_rs::{
+    completion_script, connect, update_firmware, uri_completions, DfuConfig, Error, Result, Shell,
+    COMPLETE_URI_FLAG,
+};
+use log::error;
+
+const BIN: &str = "fwupd";
+const USAGE: &str = "usage: fwupd [--profile NAME] [--uri URI] [--firmware FILE] [--update] [--verify]\n       \
+                     fwupd completions bash|zsh|fish";
+
+#[derive(Default)]
+struct Options {
+    profile: Option<String>,
+    uri: Option<String>,
+    firmware: Option<String>,
+    update: bool,
+    verify: bool,
+}
+
+enum Action {
+    Run(Options),
+    /// Print the completion script for a shell
+    Completions(Shell),
+    /// Answer a completion script asking for `--uri` values
+    CompleteUri(Shell, String),
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Action> {
+    let mut args = args.into_iter();
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| Error::Configuration(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "completions" => return Ok(Action::Completions(value()?.parse()?)),
+            COMPLETE_URI_FLAG => {
+                let shell = value()?.parse()?;
+                // An empty word may not be passed at all
+                return Ok(Action::CompleteUri(shell, args.next().unwrap_or_default()));
+            }
+            "--profile" => options.profile = Some(value()?),
+            "--uri" => options.uri = Some(value()?),
+            "--firmware" => options.firmware = Some(value()?),
+            "--update" => options.update = true,
+            "--verify" => options.verify = true,
+            _ => return Err(Error::Configuration(USAGE.into())),
+        }
+    }
+    Ok(Action::Run(options))
+}
+
+fn build_config(options: Options) -> Result<DfuConfig> {
+    let mut config = match &options.profile {
+        Some(name) => DfuConfig::profile(name)?,
+        None => DfuConfig::new(),
+    };
+    if let Some(uri) = options.uri {
+        config = config.with_uri(uri);
+    }
+    if let Some(firmware) = options.firmware {
+        config = config.with_firmware(firmware);
+    }
+    if options.update {
+        config = config.update();
+    }
+    if options.verify {
+        config = config.verify();
+    }
+    if !config.update && !config.verify {
+        config = config.get_info();
+    }
+    Ok(config)
+}
+
+async fn run(options: Options) -> Result<()> {
+    let config = build_config(options)?;
+    let stream = connect(&config).await?;
+    let report = update_firmware(stream, config).await?;
+
+    if let Some(device) = &report.device {
+        println!(
+            "Device {:#06x} rev {}, bootloader version {:#04x}",
+            device.device_id, device.device_rev, device.bootloader_version
+        );
+    }
+    for region in &report.regions {
+        let outcome = if region.skipped { "up to date" } else if region.verified { "verified" } else { "written" };
+        println!("{:?} region at {:#010x}, {} bytes: {}", region.kind, region.address, region.size, outcome);
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let action = match parse_args(std::env::args().skip(1)) {
+        Ok(action) => action,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match action {
+        Action::Completions(shell) => print!("{}", completion_script(shell, BIN)),
+        Action::CompleteUri(shell, prefix) => print!("{}", uri_completions(shell, &prefix)),
+        Action::Run(options) => {
+            if let Err(e) = run(options).await {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
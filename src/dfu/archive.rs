@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::image::FirmwareImage;
+use super::info::DeviceInfo;
+use super::report::{Phase, UpdateReport};
+use super::session::unix_now;
+use super::signing::to_hex;
+use super::types::InfoBlockV2;
+use super::DfuStream;
+
+const INDEX_FILE: &str = "index.toml";
+
+/// Firmware a device held before it was last updated
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedImage {
+    pub device_id: u16,
+    pub device_rev: u16,
+    /// Version from the entry banner, when one was parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub address: u32,
+    pub size: u32,
+    /// CRC32 the device reported for its firmware region
+    pub crc: u32,
+    /// Seconds since the Unix epoch
+    pub archived: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ArchiveIndex {
+    #[serde(default)]
+    devices: BTreeMap<String, ArchivedImage>,
+}
+
+/// Last-known-good images keyed by hex UID: one Intel HEX dump per device
+/// plus a TOML index, so an update can later be rolled back exactly
+#[derive(Debug)]
+pub struct FirmwareArchive {
+    dir: PathBuf,
+    index: ArchiveIndex,
+}
+
+impl FirmwareArchive {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let index = if index_path.exists() {
+            let content = std::fs::read_to_string(&index_path)?;
+            toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))?
+        } else {
+            ArchiveIndex::default()
+        };
+        Ok(Self { dir, index })
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &ArchivedImage)> {
+        self.index.devices.iter().map(|(uid, image)| (uid.as_str(), image))
+    }
+
+    pub fn get(&self, uid: &[u8]) -> Option<&ArchivedImage> {
+        self.index.devices.get(&to_hex(uid))
+    }
+
+    /// Path of the device's dump, whether or not one was archived
+    pub fn image_path(&self, uid: &[u8]) -> PathBuf {
+        self.dir.join(format!("{}.hex", to_hex(uid)))
+    }
+
+    /// Replaces the device's archived image
+    pub fn store(&mut self, device: &DeviceInfo, image: &FirmwareImage, crc: u32) -> Result<()> {
+        image.save(self.image_path(&device.uid))?;
+        self.index.devices.insert(to_hex(&device.uid), ArchivedImage {
+            device_id: device.device_id,
+            device_rev: device.device_rev,
+            version: device.banner.as_ref().and_then(|banner| banner.version.clone()),
+            address: image.base(),
+            size: image.len() as u32,
+            crc,
+            archived: unix_now(),
+        });
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = toml::to_string(&self.index).map_err(|e| Error::Configuration(e.to_string()))?;
+        std::fs::write(self.dir.join(INDEX_FILE), content)?;
+        Ok(())
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Archives the firmware being replaced, unless the device already holds
+    /// the new image (a retry), which would overwrite the good copy
+    pub(super) async fn archive_firmware(
+        &mut self,
+        dir: &str,
+        info: &InfoBlockV2,
+        firmware: &FirmwareImage,
+        report: &mut UpdateReport,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let device = report.device.clone().expect("device info was read before archiving");
+        let fill = self.config.gap_filling as u8;
+        let current = self.read_firmware_region(info).await?;
+        let crc = current.device_crc(info, fill);
+        if crc == firmware.device_crc(info, fill) {
+            info!("Device already holds the new image, keeping its archived one");
+            return Ok(());
+        }
+
+        let mut archive = FirmwareArchive::open(dir)?;
+        archive.store(&device, &current, crc)?;
+        info!("Archived {} bytes of firmware for device {}", current.len(), to_hex(&device.uid));
+        report.timings.add(Phase::Backup, started.elapsed());
+        Ok(())
+    }
+
+    /// Writes back the image archived for `uid` before its last update; the
+    /// device answering must be that device
+    pub async fn rollback(&mut self, uid: &[u8]) -> Result<UpdateReport> {
+        let dir = self.config.archive_dir.clone()
+            .ok_or_else(|| Error::Configuration("Rollback needs an archive directory".into()))?;
+        let archive = FirmwareArchive::open(&dir)?;
+        let archived = archive.get(uid)
+            .ok_or_else(|| Error::Configuration(format!("No archived image for device {}", to_hex(uid))))?;
+        let expected: [u8; 16] = uid.try_into()
+            .map_err(|_| Error::Configuration(format!("Device UID must be 16 bytes, got {}", uid.len())))?;
+        info!("Rolling back device {} to its image archived at {}", to_hex(uid), archived.archived);
+
+        self.config.filename = Some(archive.image_path(uid).display().to_string());
+        self.config.firmware_bytes = None;
+        self.config.firmware_set = None;
+        self.config.bundle = None;
+        // The image being rolled back is not worth keeping
+        self.config.archive_dir = None;
+        self.config.expected_uid = Some(expected);
+        self.config.update = true;
+        self.config.verify = true;
+        self.config.overwrite = true;
+        self.update().await
+    }
+}
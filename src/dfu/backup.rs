@@ -0,0 +1,138 @@
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use log::info;
+use tokio::time::Instant;
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::image::FirmwareImage;
+use super::region::RegionKind;
+use super::report::{Phase, UpdateReport};
+use super::types::{Command, InfoBlockV2};
+use super::DfuStream;
+
+/// Device memory to dump: a region of the memory map or an address range.
+///
+/// Parses from `firmware`, `metadata`, `0x08000000..0x08004000` or
+/// `0x08000000+0x4000`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpRange {
+    Region(RegionKind),
+    Addresses(Range<u32>),
+}
+
+impl From<RegionKind> for DumpRange {
+    fn from(kind: RegionKind) -> Self {
+        Self::Region(kind)
+    }
+}
+
+impl From<Range<u32>> for DumpRange {
+    fn from(range: Range<u32>) -> Self {
+        Self::Addresses(range)
+    }
+}
+
+impl FromStr for DumpRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Configuration(format!("Invalid dump range: {}", s));
+        match s {
+            "firmware" => return Ok(Self::Region(RegionKind::Firmware)),
+            "metadata" => return Ok(Self::Region(RegionKind::Metadata)),
+            _ => {}
+        }
+        let range = if let Some((start, end)) = s.split_once("..") {
+            parse_address(start).ok_or_else(invalid)?..parse_address(end).ok_or_else(invalid)?
+        } else if let Some((start, len)) = s.split_once('+') {
+            let start = parse_address(start).ok_or_else(invalid)?;
+            start..start.checked_add(parse_address(len).ok_or_else(invalid)?).ok_or_else(invalid)?
+        } else {
+            return Err(invalid());
+        };
+        Ok(Self::Addresses(range))
+    }
+}
+
+impl DumpRange {
+    fn resolve(&self, info: &InfoBlockV2) -> Result<Range<u32>> {
+        let range = match self {
+            Self::Region(kind) => info.memmap
+                .memory_regions()
+                .into_iter()
+                .find(|region| region.kind == *kind)
+                .map(|region| region.address..region.end())
+                .ok_or_else(|| Error::Configuration(format!("Device has no {:?} region", kind)))?,
+            Self::Addresses(range) => range.clone(),
+        };
+        if range.is_empty() {
+            return Err(Error::Configuration(format!("Empty dump range {:#010x}..{:#010x}", range.start, range.end)));
+        }
+        Ok(range)
+    }
+}
+
+/// `0x1F00` or `7936`
+fn parse_address(value: &str) -> Option<u32> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Reads a region or address range from the device as Intel HEX, with
+    /// extended linear address records wherever it crosses a 64 KiB boundary,
+    /// so the dump can be flashed back as is
+    pub async fn dump_region(&mut self, range: impl Into<DumpRange>) -> Result<String> {
+        let info = self.read_bootloader_info().await?;
+        let range = range.into().resolve(&info)?;
+        info!("Dumping {:#010x}..{:#010x}", range.start, range.end);
+        let image = self.read_range(&info, range.start, range.end - range.start).await?;
+        image.to_hex()
+    }
+
+    /// Reads the whole firmware region back from the device
+    pub(super) async fn read_firmware_region(&mut self, info: &InfoBlockV2) -> Result<FirmwareImage> {
+        let (address, size) = (info.memmap.firmware_address, info.memmap.firmware_size);
+        self.read_range(info, address, size).await
+    }
+
+    async fn read_range(&mut self, info: &InfoBlockV2, address: u32, size: u32) -> Result<FirmwareImage> {
+        if !self.commands.contains(Command::ReadProgramMemory) {
+            return Err(Error::CommandUnsupported(Command::ReadProgramMemory));
+        }
+
+        let block_size = self.max_block_size(info);
+        let mut data = Vec::with_capacity(size as usize);
+        while data.len() < size as usize {
+            let offset = data.len() as u32;
+            let len = block_size.min(size as usize - data.len());
+            let block = self.read_memory(address + offset, len)
+                .await
+                .map_err(|e| e.at_block(Phase::Backup, offset as usize / block_size, address + offset))?;
+            data.extend_from_slice(&block);
+        }
+        Ok(FirmwareImage::new(address, data))
+    }
+
+    /// Saves the firmware currently on the device to `path`, as Intel HEX or
+    /// (for `.bin`) a raw binary
+    pub(super) async fn backup_firmware(
+        &mut self,
+        info: &InfoBlockV2,
+        path: &str,
+        report: &mut UpdateReport,
+    ) -> Result<()> {
+        let started = Instant::now();
+        info!("Backing up firmware region to {}", path);
+        let image = self.read_firmware_region(info).await?;
+        image.save(Path::new(path))?;
+        info!("Backed up {} bytes, CRC {:#010x}", image.len(), image.crc32());
+        report.timings.add(Phase::Backup, started.elapsed());
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use log::{debug, info};
+use regex::Regex;
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tokio::time::{timeout, Instant};
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::DfuStream;
+
+const DEFAULT_BANNER_WINDOW_MS: u64 = 500;
+/// The banner is over once the line stays quiet this long
+const BANNER_IDLE: Duration = Duration::from_millis(50);
+const MAX_BANNER_LEN: usize = 4096;
+
+/// Text the bootloader printed on entry and the fields extracted from it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootloaderBanner {
+    pub text: String,
+    pub version: Option<String>,
+    pub build: Option<String>,
+    /// Every named group that matched, including `version` and `build`
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BannerSpec {
+    regex: Option<String>,
+    format: Option<String>,
+    window_ms: Option<u64>,
+}
+
+/// Extracts fields from the ASCII banner some bootloaders print before
+/// answering the binary protocol. Named groups become fields; `version`
+/// and `build` are also surfaced on their own.
+///
+/// ```toml
+/// [factory.banner]
+/// format = "BL v{version} ({build})"
+/// window_ms = 800
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "BannerSpec")]
+pub struct BannerParser {
+    pattern: Regex,
+    window: Duration,
+}
+
+impl TryFrom<BannerSpec> for BannerParser {
+    type Error = Error;
+
+    fn try_from(spec: BannerSpec) -> Result<Self> {
+        let parser = match (spec.regex, spec.format) {
+            (Some(regex), None) => Self::new(&regex)?,
+            (None, Some(format)) => Self::from_format(&format)?,
+            _ => return Err(Error::Configuration("Banner needs exactly one of regex or format".into())),
+        };
+        Ok(match spec.window_ms {
+            Some(ms) => parser.with_window(Duration::from_millis(ms)),
+            None => parser,
+        })
+    }
+}
+
+impl BannerParser {
+    /// Regular expression with named groups, e.g. `v(?P<version>[\d.]+)`
+    pub fn new(pattern: &str) -> Result<Self> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| Error::Configuration(format!("Invalid banner pattern: {}", e)))?;
+        Ok(Self { pattern, window: Duration::from_millis(DEFAULT_BANNER_WINDOW_MS) })
+    }
+
+    /// Literal text with `{name}` placeholders, each matching one word
+    pub fn from_format(format: &str) -> Result<Self> {
+        let mut pattern = String::new();
+        let mut rest = format;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..]
+                .find('}')
+                .map(|close| open + close)
+                .ok_or_else(|| Error::Configuration(format!("Unclosed placeholder in banner format: {}", format)))?;
+            pattern.push_str(&regex::escape(&rest[..open]));
+            pattern.push_str(&format!(r"(?P<{}>\S+)", &rest[open + 1..close]));
+            rest = &rest[close + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        Self::new(&pattern)
+    }
+
+    /// How long to listen for the banner after entry
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn parse(&self, text: &str) -> BootloaderBanner {
+        let mut banner = BootloaderBanner { text: text.to_string(), ..Default::default() };
+        if let Some(captures) = self.pattern.captures(text) {
+            for name in self.pattern.capture_names().flatten() {
+                if let Some(value) = captures.name(name) {
+                    banner.fields.insert(name.to_string(), value.as_str().to_string());
+                }
+            }
+        }
+        banner.version = banner.fields.get("version").cloned();
+        banner.build = banner.fields.get("build").cloned();
+        banner
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Reads the banner printed after entry so it doesn't disturb protocol sync
+    pub(super) async fn capture_banner(&mut self) -> Result<()> {
+        let Some(parser) = self.config.banner.clone() else {
+            return Ok(());
+        };
+
+        let deadline = Instant::now() + parser.window;
+        let mut data = Vec::new();
+        let mut buf = [0u8; 256];
+        while data.len() < MAX_BANNER_LEN {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            // Stop at the first pause once something has arrived
+            let wait = if data.is_empty() { remaining } else { remaining.min(BANNER_IDLE) };
+            match timeout(wait, self.stream.read(&mut buf)).await {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(n)) => data.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+
+        if data.is_empty() {
+            debug!("No bootloader banner within {:?}", parser.window);
+            return Ok(());
+        }
+        let banner = parser.parse(String::from_utf8_lossy(&data).trim());
+        info!("Bootloader banner: {}", banner.text);
+        self.banner = Some(banner);
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use crate::error::{Error, Result};
+use crate::protocols::channel::{ChannelConfig, DEFAULT_CHANNEL_CAPACITY};
+
+/// Largest frame the protocol layers buffer per queued message
+const FRAME_SIZE: usize = 1024;
+const DEFAULT_CONSOLE_LIMIT: usize = 64 * 1024;
+
+/// Splits a per-session memory cap between the notification queue, console
+/// capture and the firmware image. The queue is bounded; notifications
+/// beyond it are dropped rather than grow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    channel_capacity: Option<usize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { limit, channel_capacity: None }
+    }
+
+    /// Fixes the queue depth instead of deriving it from the limit
+    pub fn with_channel_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.channel_capacity = capacity.map(|c| c.max(1));
+        self
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Messages the notification queue may hold, from a quarter of the budget
+    pub fn channel_capacity(&self) -> usize {
+        if let Some(capacity) = self.channel_capacity {
+            return capacity;
+        }
+        match self.limit {
+            Some(limit) => (limit / 4 / FRAME_SIZE).clamp(1, DEFAULT_CHANNEL_CAPACITY),
+            None => DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+
+    pub fn channel_config(&self) -> ChannelConfig {
+        ChannelConfig::new(self.channel_capacity())
+    }
+
+    /// Initial size of the frame assembly buffer
+    pub fn frame_buffer(&self) -> usize {
+        FRAME_SIZE
+    }
+
+    /// Bytes of device console output kept, at most an eighth of the budget
+    pub fn console_limit(&self) -> usize {
+        match self.limit {
+            Some(limit) => (limit / 8).min(DEFAULT_CONSOLE_LIMIT),
+            None => DEFAULT_CONSOLE_LIMIT,
+        }
+    }
+
+    /// Rejects images that don't fit in what is left after the queue and console
+    pub fn check_image(&self, len: usize) -> Result<()> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let reserved = self.channel_capacity() * FRAME_SIZE + self.console_limit();
+        let available = limit.saturating_sub(reserved);
+        if len > available {
+            return Err(Error::MemoryLimit { needed: len, available });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+use log::info;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use super::image::{FirmwareFormat, FirmwareImage};
+
+/// One image of a bundle and where it goes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BundleImage {
+    /// Label used in logs, e.g. "application" or "config"
+    pub name: String,
+    /// Image path, relative to the bundle file
+    pub file: String,
+    /// Where the image starts; optional for formats that carry addresses
+    pub address: Option<u32>,
+    pub format: Option<FirmwareFormat>,
+}
+
+#[derive(Deserialize)]
+struct BundleFile {
+    #[serde(default)]
+    image: Vec<BundleImage>,
+}
+
+/// Several images flashed in one bootloader session, each at its own address
+///
+/// ```toml
+/// [[image]]
+/// name = "application"
+/// file = "app.hex"
+///
+/// [[image]]
+/// name = "config"
+/// file = "config.bin"
+/// address = 0x080e0000
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+    images: Vec<BundleImage>,
+    base_dir: PathBuf,
+}
+
+impl Bundle {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let file: BundleFile = toml::from_str(&content)
+            .map_err(|e| Error::Configuration(e.to_string()))?;
+
+        Ok(Self {
+            images: file.image,
+            base_dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        })
+    }
+
+    pub fn images(&self) -> &[BundleImage] {
+        &self.images
+    }
+
+    pub fn path(&self, image: &BundleImage) -> PathBuf {
+        self.base_dir.join(&image.file)
+    }
+
+    /// Loads every image and combines them into one image with a segment
+    /// per part; `format` applies to images that don't name their own
+    pub fn load(&self, format: FirmwareFormat, fill: u8) -> Result<FirmwareImage> {
+        let mut parts: Vec<(&str, FirmwareImage)> = Vec::with_capacity(self.images.len());
+        for image in &self.images {
+            let path = self.path(image);
+            let loaded = FirmwareImage::load(&path, image.format.unwrap_or(format), fill)?;
+            let loaded = match image.address {
+                Some(address) => loaded.with_base(address),
+                None if loaded.is_placed() => loaded,
+                None => {
+                    return Err(Error::Configuration(format!(
+                        "Bundle image {} carries no addresses and needs one", image.name
+                    )));
+                }
+            };
+
+            for (name, other) in &parts {
+                let overlaps = loaded.segments().iter().any(|a| {
+                    other.segments().iter().any(|b| a.address < b.end() && b.address < a.end())
+                });
+                if overlaps {
+                    return Err(Error::Configuration(format!(
+                        "Bundle images {} and {} overlap", name, image.name
+                    )));
+                }
+            }
+
+            info!(
+                "Bundle image {}: {} bytes at {:#010x}",
+                image.name,
+                loaded.data_len(),
+                loaded.base()
+            );
+            parts.push((&image.name, loaded));
+        }
+
+        let records = parts
+            .into_iter()
+            .flat_map(|(_, image)| image.segments().to_vec())
+            .map(|segment| (segment.address, segment.data))
+            .collect();
+        Ok(FirmwareImage::from_records(records, fill))
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use super::session::unix_now;
+use super::signing::to_hex;
+
+const INDEX_FILE: &str = "index.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub last_used: u64,
+    /// Pinned artifacts are never evicted
+    #[serde(default)]
+    pub pinned: bool,
+    /// URLs the artifact was downloaded from
+    #[serde(default)]
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    #[serde(default)]
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+/// Firmware artifacts stored by SHA-256, so a gateway downloads each image
+/// once however many devices it updates. Contents are re-hashed on every
+/// read; a corrupted file is dropped rather than flashed.
+#[derive(Debug)]
+pub struct ArtifactCache {
+    dir: PathBuf,
+    index: CacheIndex,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl ArtifactCache {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let index = if index_path.exists() {
+            let content = std::fs::read_to_string(&index_path)?;
+            toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))?
+        } else {
+            CacheIndex::default()
+        };
+        Ok(Self { dir, index, max_size: None, max_age: None })
+    }
+
+    /// Evicts least recently used artifacts beyond this many bytes
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Evicts artifacts unused for longer than this
+    pub fn with_max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &CacheEntry)> {
+        self.index.entries.iter().map(|(digest, entry)| (digest.as_str(), entry))
+    }
+
+    /// Artifact with the given SHA-256 (hex), if cached and intact
+    pub fn get(&mut self, sha256: &str) -> Result<Option<Vec<u8>>> {
+        let sha256 = sha256.to_ascii_lowercase();
+        if !self.index.entries.contains_key(&sha256) {
+            return Ok(None);
+        }
+
+        let path = self.artifact_path(&sha256);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Cached artifact {} unreadable: {}", sha256, e);
+                self.remove(&sha256)?;
+                return Ok(None);
+            }
+        };
+        if digest(&data) != sha256 {
+            warn!("Cached artifact {} is corrupted, dropping it", sha256);
+            self.remove(&sha256)?;
+            return Ok(None);
+        }
+
+        if let Some(entry) = self.index.entries.get_mut(&sha256) {
+            entry.last_used = unix_now();
+        }
+        self.save_index()?;
+        Ok(Some(data))
+    }
+
+    /// Stores `data` and returns its SHA-256; `url` is remembered as its source
+    pub fn insert(&mut self, data: &[u8], url: Option<&str>) -> Result<String> {
+        let sha256 = digest(data);
+        write_atomic(&self.artifact_path(&sha256), data)?;
+
+        let entry = self.index.entries.entry(sha256.clone()).or_default();
+        entry.size = data.len() as u64;
+        entry.last_used = unix_now();
+        if let Some(url) = url {
+            if !entry.urls.iter().any(|u| u == url) {
+                entry.urls.push(url.to_string());
+            }
+        }
+        self.evict()?;
+        self.save_index()?;
+        Ok(sha256)
+    }
+
+    /// Protects an artifact from eviction
+    pub fn pin(&mut self, sha256: &str) -> Result<()> {
+        self.set_pinned(sha256, true)
+    }
+
+    pub fn unpin(&mut self, sha256: &str) -> Result<()> {
+        self.set_pinned(sha256, false)
+    }
+
+    /// Applies the age and size limits to unpinned artifacts, least recently
+    /// used first; returns the bytes freed
+    pub fn evict(&mut self) -> Result<u64> {
+        let now = unix_now();
+        let mut candidates: Vec<(String, CacheEntry)> = self.index.entries
+            .iter()
+            .filter(|(_, entry)| !entry.pinned)
+            .map(|(digest, entry)| (digest.clone(), entry.clone()))
+            .collect();
+        candidates.sort_by_key(|(_, entry)| entry.last_used);
+
+        let mut total: u64 = self.index.entries.values().map(|entry| entry.size).sum();
+        let mut freed = 0;
+        for (digest, entry) in candidates {
+            let expired = self.max_age
+                .is_some_and(|age| now.saturating_sub(entry.last_used) > age.as_secs());
+            let oversize = self.max_size.is_some_and(|max| total > max);
+            if !expired && !oversize {
+                continue;
+            }
+            self.remove(&digest)?;
+            total -= entry.size;
+            freed += entry.size;
+        }
+
+        if freed > 0 {
+            info!("Evicted {} bytes from the firmware cache", freed);
+            self.save_index()?;
+        }
+        Ok(freed)
+    }
+
+    fn set_pinned(&mut self, sha256: &str, pinned: bool) -> Result<()> {
+        let entry = self.index.entries
+            .get_mut(&sha256.to_ascii_lowercase())
+            .ok_or_else(|| Error::Configuration(format!("{} is not in the cache", sha256)))?;
+        entry.pinned = pinned;
+        self.save_index()
+    }
+
+    fn remove(&mut self, sha256: &str) -> Result<()> {
+        self.index.entries.remove(sha256);
+        if let Err(e) = std::fs::remove_file(self.artifact_path(sha256)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        self.save_index()
+    }
+
+    fn artifact_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(sha256)
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let content = toml::to_string(&self.index).map_err(|e| Error::Configuration(e.to_string()))?;
+        write_atomic(&self.dir.join(INDEX_FILE), content.as_bytes())
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Writes under a temporary name first, so a crash can't leave a partial file
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let partial = path.with_extension("part");
+    std::fs::write(&partial, data)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
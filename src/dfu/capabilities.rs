@@ -0,0 +1,167 @@
+use std::time::Duration;
+use log::{info, warn};
+
+use crate::error::{Error, Result};
+use crate::protocols::apl;
+use crate::transport::DfuTransport;
+use super::DfuStream;
+use super::support::CommandSet;
+use super::types::{Command, InfoBlockV2};
+
+/// Size of the block returned by `ReadCapabilities`
+pub const CAPABILITIES_BLOCK_SIZE: usize = 32;
+const MAX_ERASE_GRANULARITIES: usize = 6;
+
+const HASH_FLAG_CRC32: u8 = 0x01;
+const HASH_FLAG_SHA256: u8 = 0x02;
+
+/// Digest algorithms a bootloader can compute over flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Crc32,
+    Sha256,
+}
+
+/// What the bootloader can do, as reported by the device or inferred from its info block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub commands: CommandSet,
+    /// Blocks the device can buffer before it must acknowledge
+    pub max_concurrent_blocks: u16,
+    pub hashes: Vec<HashAlgorithm>,
+    /// Distinct erase sizes, smallest first
+    pub erase_granularities: Vec<u32>,
+    /// False when the values were inferred rather than reported by the device
+    pub reported: bool,
+}
+
+impl Capabilities {
+    /// Parses the capability block.
+    ///
+    /// Layout: u32 command bitmap, u16 max concurrent blocks, u8 hash flags,
+    /// u8 erase granularity count, then up to six u32 erase sizes.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < CAPABILITIES_BLOCK_SIZE {
+            return Err(Error::TruncatedResponse {
+                expected: CAPABILITIES_BLOCK_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let bitmap = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let commands = Command::ALL
+            .into_iter()
+            .filter(|command| bitmap & (1 << *command as u8) != 0)
+            .fold(CommandSet::mandatory(), CommandSet::with);
+
+        let hash_flags = data[6];
+        let mut hashes = Vec::new();
+        if hash_flags & HASH_FLAG_CRC32 != 0 {
+            hashes.push(HashAlgorithm::Crc32);
+        }
+        if hash_flags & HASH_FLAG_SHA256 != 0 {
+            hashes.push(HashAlgorithm::Sha256);
+        }
+
+        let count = (data[7] as usize).min(MAX_ERASE_GRANULARITIES);
+        let mut erase_granularities: Vec<u32> = data[8..8 + count * 4]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .filter(|size| *size > 0)
+            .collect();
+        erase_granularities.sort_unstable();
+        erase_granularities.dedup();
+
+        Ok(Self {
+            commands,
+            max_concurrent_blocks: u16::from_le_bytes([data[4], data[5]]).max(1),
+            hashes,
+            erase_granularities,
+            reported: true,
+        })
+    }
+
+    /// Best guess for bootloaders without `ReadCapabilities`
+    pub fn infer(info: &InfoBlockV2) -> Self {
+        let commands = CommandSet::infer(info.version);
+
+        let mut hashes = vec![HashAlgorithm::Crc32];
+        if commands.contains(Command::ReadProgramSha256) {
+            hashes.push(HashAlgorithm::Sha256);
+        }
+
+        let mut erase_granularities: Vec<u32> = info.memmap.regions
+            .iter()
+            .filter(|region| region.count > 0 && region.size > 0)
+            .map(|region| region.size)
+            .collect();
+        erase_granularities.sort_unstable();
+        erase_granularities.dedup();
+
+        Self {
+            commands,
+            max_concurrent_blocks: 1,
+            hashes,
+            erase_granularities,
+            reported: false,
+        }
+    }
+
+    pub fn supports_hash(&self, hash: HashAlgorithm) -> bool {
+        self.hashes.contains(&hash)
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Reads the bootloader info and its capabilities, inferring them on older devices
+    pub async fn capabilities(&mut self) -> Result<Capabilities> {
+        let info = self.read_bootloader_info().await?;
+        self.read_capabilities(&info).await
+    }
+
+    pub(super) async fn read_capabilities(&mut self, info: &InfoBlockV2) -> Result<Capabilities> {
+        if !info.supports_capabilities() {
+            return Ok(Capabilities::infer(info));
+        }
+        Capabilities::from_bytes(&self.read_capability_block().await?)
+    }
+
+    pub(super) async fn read_capability_block(&mut self) -> Result<[u8; CAPABILITIES_BLOCK_SIZE]> {
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::ReadRequest,
+            CAPABILITIES_BLOCK_SIZE,
+            Duration::ZERO,
+            Command::ReadCapabilities as usize,
+            0,
+            CAPABILITIES_BLOCK_SIZE,
+        ).await?;
+
+        let mut block = [0u8; CAPABILITIES_BLOCK_SIZE];
+        self.read_response(&mut block).await?;
+        Ok(block)
+    }
+
+    /// Adopts the device's command set and keeps the ACK window within its buffering
+    pub(super) fn apply_capabilities(&mut self, capabilities: &Capabilities) {
+        self.commands = capabilities.commands;
+
+        // An inferred limit says nothing about the device, so trust the configured policy
+        let window = self.config.ack_policy.window();
+        if capabilities.reported && window > capabilities.max_concurrent_blocks {
+            warn!(
+                "ACK window of {} blocks exceeds device limit of {}, reducing",
+                window, capabilities.max_concurrent_blocks
+            );
+            self.apl.set_ack_policy(self.config.ack_policy.limit_window(capabilities.max_concurrent_blocks));
+        }
+
+        info!(
+            "Capabilities ({}): {} concurrent block(s), hashes {:?}, erase sizes {:?}",
+            if capabilities.reported { "reported" } else { "inferred" },
+            capabilities.max_concurrent_blocks,
+            capabilities.hashes,
+            capabilities.erase_granularities,
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use super::discovery::{complete_uri, UriCandidate};
+
+/// Option a completion script calls back with the shell and the word being
+/// completed, e.g. `fwupd --complete-uri bash serial:///dev/ttyU`
+pub const COMPLETE_URI_FLAG: &str = "--complete-uri";
+
+/// Shell a completion script is generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = Error;
+
+    /// `bash`, `zsh` or `fish`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => Err(Error::Configuration(format!("Unknown shell: {}", s))),
+        }
+    }
+}
+
+/// Completion script for the CLI named `bin`, to be sourced by `shell`.
+/// `--uri` values are completed live: the script runs
+/// `bin --complete-uri SHELL PREFIX`, which the CLI answers with
+/// [`uri_completions`], so ports plugged in later show up too.
+pub fn completion_script(shell: Shell, bin: &str) -> String {
+    let function: String = bin
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match shell {
+        // `serial://` holds a colon, which bash splits words at
+        Shell::Bash => format!(
+            r#"_{function}() {{
+    local cur prev
+    _get_comp_words_by_ref -n : cur prev
+    if [[ "$prev" == "--uri" ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($({bin} {flag} bash "$cur" 2>/dev/null))
+        __ltrim_colon_completions "$cur"
+    fi
+}}
+complete -o default -F _{function} {bin}
+"#,
+            flag = COMPLETE_URI_FLAG,
+        ),
+        Shell::Zsh => format!(
+            r#"#compdef {bin}
+_{function}_uris() {{
+    local -a uris
+    uris=("${{(@f)$({bin} {flag} zsh "$PREFIX" 2>/dev/null)}}")
+    _describe 'device URI' uris
+}}
+_arguments '*--uri[device URI]:uri:_{function}_uris' '*:file:_files'
+"#,
+            flag = COMPLETE_URI_FLAG,
+        ),
+        Shell::Fish => format!(
+            "complete -c {bin} -l uri -x -d 'Device URI' -a '({bin} {flag} fish (commandline -ct) 2>/dev/null)'\n",
+            flag = COMPLETE_URI_FLAG,
+        ),
+    }
+}
+
+/// Live serial ports starting with `prefix`, one per line as `shell`
+/// expects them: bare URIs for bash, `uri:description` for zsh and
+/// tab-separated for fish
+pub fn uri_completions(shell: Shell, prefix: &str) -> String {
+    format_candidates(shell, &complete_uri(prefix))
+}
+
+fn format_candidates(shell: Shell, candidates: &[UriCandidate]) -> String {
+    candidates
+        .iter()
+        .map(|candidate| match shell {
+            Shell::Bash => format!("{}\n", candidate.uri),
+            // _describe splits at the first unescaped colon
+            Shell::Zsh => format!("{}:{}\n", candidate.uri.replace(':', "\\:"), candidate.description),
+            Shell::Fish => format!("{}\t{}\n", candidate.uri, candidate.description),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_are_formatted_per_shell() {
+        let candidates = [UriCandidate {
+            uri: "serial:///dev/ttyUSB0".into(),
+            description: "USB 0403:6001 FT232R".into(),
+            vid: Some(0x0403),
+            pid: Some(0x6001),
+            serial_number: None,
+        }];
+        assert_eq!(format_candidates(Shell::Bash, &candidates), "serial:///dev/ttyUSB0\n");
+        assert_eq!(
+            format_candidates(Shell::Zsh, &candidates),
+            "serial\\:///dev/ttyUSB0:USB 0403:6001 FT232R\n"
+        );
+        assert_eq!(
+            format_candidates(Shell::Fish, &candidates),
+            "serial:///dev/ttyUSB0\tUSB 0403:6001 FT232R\n"
+        );
+    }
+
+    #[test]
+    fn scripts_call_back_for_uris() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = completion_script(shell, "fwupd-cli");
+            assert!(script.contains("fwupd-cli --complete-uri"), "{}", script);
+        }
+        assert!(completion_script(Shell::Bash, "fwupd-cli").contains("_fwupd_cli()"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// Compressed wrapper around a firmware file, recognised by extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zip,
+}
+
+impl Compression {
+    fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gz" => Some(Compression::Gzip),
+            "xz" => Some(Compression::Xz),
+            "zip" => Some(Compression::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a firmware file, transparently decompressing `.gz`, `.xz` and
+/// single-file `.zip` inputs
+pub(super) fn read_firmware(path: &Path) -> Result<Vec<u8>> {
+    unpack(std::fs::read(path)?, path)
+}
+
+/// Decompresses `data` if `path` has a compressed extension
+pub(super) fn unpack(data: Vec<u8>, path: &Path) -> Result<Vec<u8>> {
+    match Compression::from_path(path) {
+        None => Ok(data),
+        Some(compression) => decompress(compression, data, path),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn decompress(compression: Compression, data: Vec<u8>, path: &Path) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut contents = Vec::new();
+    match compression {
+        Compression::Gzip => {
+            flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut contents)?;
+        }
+        Compression::Xz => {
+            xz2::read::XzDecoder::new(data.as_slice()).read_to_end(&mut contents)?;
+        }
+        Compression::Zip => {
+            let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))
+                .map_err(|e| Error::Decompression(e.to_string()))?;
+            let files: Vec<usize> = (0..archive.len())
+                .filter(|&i| archive.by_index(i).map(|f| f.is_file()).unwrap_or(false))
+                .collect();
+            let [index] = files[..] else {
+                return Err(Error::Decompression(format!(
+                    "expected exactly one file in {}, found {}",
+                    path.display(),
+                    files.len()
+                )));
+            };
+            let mut file = archive
+                .by_index(index)
+                .map_err(|e| Error::Decompression(e.to_string()))?;
+            file.read_to_end(&mut contents)?;
+        }
+    }
+    Ok(contents)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(compression: Compression, _data: Vec<u8>, path: &Path) -> Result<Vec<u8>> {
+    Err(Error::Configuration(format!(
+        "{} is {:?} compressed; rebuild with the `compression` feature",
+        path.display(),
+        compression
+    )))
+}
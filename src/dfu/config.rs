@@ -1,22 +1,89 @@
-use super::types::{DfuConfig, UpdateMode};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio_util::sync::CancellationToken;
+
+use crate::protocols::apl::AckPolicy;
+use crate::protocols::lpl::{Framing, MAX_NETID};
+use super::banner::BannerParser;
+#[cfg(feature = "fault-injection")]
+use super::fault::AbortPoint;
+use super::entry::{EntryMethod, EntryTiming, SyncPreamble};
+use super::image::FirmwareFormat;
+use super::info::DiagnosticLimits;
+use super::quirks::Quirks;
+use super::region::UpdateOrdering;
+use super::registry::DEFAULT_WEAR_LIMIT;
+use super::resume::ResumeToken;
+use super::types::{Baud, DfuConfig, UpdateMode};
+use super::verify::VerifyMethod;
+use super::MAX_RECONNECTION_ATTEMPTS;
 
 impl Default for DfuConfig {
     fn default() -> Self {
         Self {
             uri: String::new(),
             filename: None,
+            firmware_bytes: None,
+            firmware_sha256: None,
+            firmware_set: None,
+            cache_dir: None,
+            bundle: None,
+            base_address: None,
+            firmware_format: FirmwareFormat::Auto,
+            dfu_target: 0,
             block_size: 1024,
+            max_firmware_size: None,
+            memory_limit: None,
+            channel_capacity: None,
             get_info: false,
             update: false,
             overwrite: false,
             verify: false,
+            verifier: VerifyMethod::DeviceCrc,
+            manifest_key: None,
+            pipelined_verify: false,
+            update_ordering: UpdateOrdering::default(),
             quit: false,
+            commit: false,
+            strict: false,
+            diagnostics: false,
+            diagnostic_limits: DiagnosticLimits::default(),
             dev_netid: 0,
-            dev_speed: 9600,
-            upd_speed: 115200,
-            lnk_speed: 9600,
+            bus_addressing: false,
+            framing: Framing::default(),
+            dev_speed: Baud(9600),
+            upd_speed: Baud(115200),
+            lnk_speed: Baud(9600),
+            baud_sweep: Vec::new(),
+            reconnect_attempts: MAX_RECONNECTION_ATTEMPTS,
+            reconnect_backoff: Duration::from_millis(500),
             upd_mode: UpdateMode::None,
+            entry: EntryMethod::default(),
+            entry_timing: EntryTiming::default(),
+            sync_preamble: None,
+            console_port: None,
+            banner: None,
+            quirk_database: None,
+            quirks: Quirks::default(),
+            ack_policy: AckPolicy::default(),
+            session_file: None,
+            keep_open: false,
+            resume_token: None,
+            idempotency_key: None,
+            request_journal: None,
+            registry_file: None,
+            backup_file: None,
+            archive_dir: None,
+            expected_uid: None,
+            require_uid: false,
+            conformance_scratch: None,
+            cancellation: None,
+            wear_limit: DEFAULT_WEAR_LIMIT,
             gap_filling: 0xFF,
+            trim_fill: false,
+            pad_to_crc_window: false,
+            #[cfg(feature = "fault-injection")]
+            abort_at: None,
         }
     }
 }
@@ -36,31 +103,229 @@ impl DfuConfig {
         self
     }
 
+    /// Flashes an image already in memory (embedded or downloaded) instead of a file
+    pub fn with_firmware_bytes(mut self, data: impl Into<Bytes>) -> Self {
+        self.firmware_bytes = Some(data.into());
+        self
+    }
+
+    /// Digest a firmware URL must match; without it `<url>.sha256` is fetched
+    pub fn with_firmware_sha256(mut self, digest: impl Into<String>) -> Self {
+        self.firmware_sha256 = Some(digest.into());
+        self
+    }
+
+    /// Overrides detecting the firmware file format from its contents
+    pub fn with_firmware_format(mut self, format: FirmwareFormat) -> Self {
+        self.firmware_format = format;
+        self
+    }
+
+    /// Image to flash from a multi-target DfuSe file
+    pub fn with_dfu_target(mut self, index: usize) -> Self {
+        self.dfu_target = index;
+        self
+    }
+
+    /// Address the image is loaded at; defaults to the device's firmware address.
+    /// Raw binaries carry no addresses, so this is where they start.
+    pub fn with_base_address(mut self, address: u32) -> Self {
+        self.base_address = Some(address);
+        self
+    }
+
+    /// Keeps downloaded firmware in `dir`; with a SHA-256 set, a cached copy
+    /// is used without contacting the server
+    pub fn with_cache_dir(mut self, dir: impl Into<String>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Mapping file or directory of images; the one matching the device is flashed
+    pub fn with_firmware_set(mut self, path: impl Into<String>) -> Self {
+        self.firmware_set = Some(path.into());
+        self
+    }
+
+    /// Bundle file listing several images, all flashed in one session.
+    /// Each image has its own address, so `base_address` would move them all.
+    pub fn with_bundle(mut self, path: impl Into<String>) -> Self {
+        self.bundle = Some(path.into());
+        self
+    }
+
     pub fn with_block_size(mut self, size: usize) -> Self {
         self.block_size = size;
         self
     }
 
+    pub fn with_max_firmware_size(mut self, size: usize) -> Self {
+        self.max_firmware_size = Some(size);
+        self
+    }
+
+    /// Caps the memory a session may use for notifications, console capture and the image
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Device notifications queued before further ones are dropped
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = Some(capacity);
+        self
+    }
+
     pub fn with_update_mode(mut self, mode: UpdateMode) -> Self {
         self.upd_mode = mode;
         self
     }
 
-    pub fn with_device_speed(mut self, speed: usize) -> Self {
+    pub fn with_entry(mut self, method: EntryMethod) -> Self {
+        self.entry = method;
+        self
+    }
+
+    pub fn with_entry_timing(mut self, timing: EntryTiming) -> Self {
+        self.entry_timing = timing;
+        self
+    }
+
+    /// Sends a preamble after entry and waits for a clean response before the update
+    pub fn with_sync_preamble(mut self, preamble: SyncPreamble) -> Self {
+        self.sync_preamble = Some(preamble);
+        self
+    }
+
+    pub fn with_console_port(mut self, path: impl Into<String>) -> Self {
+        self.console_port = Some(path.into());
+        self
+    }
+
+    /// Reads and parses the banner the bootloader prints on entry
+    pub fn with_banner(mut self, parser: BannerParser) -> Self {
+        self.banner = Some(parser);
+        self
+    }
+
+    pub fn with_quirk_database(mut self, path: impl Into<String>) -> Self {
+        self.quirk_database = Some(path.into());
+        self
+    }
+
+    /// Forces quirks on top of whatever the device database reports
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn with_ack_policy(mut self, policy: AckPolicy) -> Self {
+        self.ack_policy = policy;
+        self
+    }
+
+    /// File used to hand an open bootloader session to the next tool
+    pub fn with_session_file(mut self, path: impl Into<String>) -> Self {
+        self.session_file = Some(path.into());
+        self
+    }
+
+    /// Leave the device in the bootloader and record the session for the next tool
+    pub fn keep_open(mut self) -> Self {
+        self.keep_open = true;
+        self
+    }
+
+    /// Continue a transfer suspended through `UpdateHandle::suspend`
+    pub fn with_resume_token(mut self, token: ResumeToken) -> Self {
+        self.resume_token = Some(token);
+        self
+    }
+
+    /// Retrying an update under the same key skips, resumes or restarts it
+    /// depending on what the earlier attempt got done; needs a request journal
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    /// File recording the outcome of every keyed update
+    pub fn with_request_journal(mut self, path: impl Into<String>) -> Self {
+        self.request_journal = Some(path.into());
+        self
+    }
+
+    /// Persistent device registry used to track flash wear across sessions
+    pub fn with_registry(mut self, path: impl Into<String>) -> Self {
+        self.registry_file = Some(path.into());
+        self
+    }
+
+    /// Erases per region after which a wear warning is reported
+    pub fn with_wear_limit(mut self, erases: u64) -> Self {
+        self.wear_limit = erases;
+        self
+    }
+
+    /// Don't transfer fill bytes at the end of the image; erased flash already holds them
+    pub fn trim_fill(mut self) -> Self {
+        self.trim_fill = true;
+        self
+    }
+
+    /// Write fill bytes up to the end of the device's CRC window, so the flash
+    /// matches the padded image release tooling checksums; wins over trimming
+    pub fn pad_to_crc_window(mut self) -> Self {
+        self.pad_to_crc_window = true;
+        self
+    }
+
+    /// Talks to the device at `netid` on a multi-drop bus, ignoring frames
+    /// from every other node
+    pub fn with_network_id(mut self, netid: usize) -> Self {
+        self.dev_netid = netid;
+        self.bus_addressing = true;
+        self
+    }
+
+    /// Frames with a bootloader's own SYN byte and delimiter, e.g. 0x7E
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn with_device_speed(mut self, speed: Baud) -> Self {
         self.dev_speed = speed;
         self
     }
 
-    pub fn with_update_speed(mut self, speed: usize) -> Self {
+    /// Speed the transfer runs at, switched to once the bootloader answers
+    pub fn with_update_speed(mut self, speed: Baud) -> Self {
         self.upd_speed = speed;
         self
     }
 
-    pub fn with_link_speed(mut self, speed: usize) -> Self {
+    /// Speed the bootloader is entered and detected at, restored on exit
+    pub fn with_link_speed(mut self, speed: Baud) -> Self {
         self.lnk_speed = speed;
         self
     }
 
+    /// Speeds to probe when the device doesn't answer at the link speed,
+    /// e.g. `Baud::COMMON`; the one that works becomes the link speed
+    pub fn with_baud_sweep(mut self, speeds: impl Into<Vec<Baud>>) -> Self {
+        self.baud_sweep = speeds.into();
+        self
+    }
+
+    /// How often `update_with_reconnect` re-opens a lost link, and how long
+    /// it waits before the first try
+    pub fn with_reconnect(mut self, attempts: usize, backoff: Duration) -> Self {
+        self.reconnect_attempts = attempts;
+        self.reconnect_backoff = backoff;
+        self
+    }
+
     pub fn get_info(mut self) -> Self {
         self.get_info = true;
         self
@@ -76,20 +341,132 @@ impl DfuConfig {
         self
     }
 
+    pub fn with_verifier(mut self, method: VerifyMethod) -> Self {
+        self.verifier = method;
+        self
+    }
+
+    /// Check each region's device CRC while the next region is written; needs a
+    /// bootloader that answers requests strictly in order
+    pub fn pipeline_verify(mut self) -> Self {
+        self.pipelined_verify = true;
+        self
+    }
+
+    /// Use `UpdateOrdering::MetadataLast` so an interrupted update fails safe;
+    /// needs [`verify`](Self::verify), since the metadata is only written
+    /// once the other regions are known good
+    pub fn with_update_ordering(mut self, ordering: UpdateOrdering) -> Self {
+        self.update_ordering = ordering;
+        self
+    }
+
+    /// Require manifests to be signed by the Ed25519 public key in this file
+    pub fn with_manifest_key(mut self, path: impl Into<String>) -> Self {
+        self.manifest_key = Some(path.into());
+        self
+    }
+
     pub fn overwrite(mut self) -> Self {
         self.overwrite = true;
         self
     }
 
+    /// Send the commit command even if the bootloader can't report supporting it
+    pub fn commit(mut self) -> Self {
+        self.commit = true;
+        self
+    }
+
+    /// Fail instead of warning when the device reports info the host doesn't understand
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Reads the firmware region out to `path` (Intel HEX, or raw binary for
+    /// `.bin`) before anything is written
+    pub fn backup_to(mut self, path: impl Into<String>) -> Self {
+        self.backup_file = Some(path.into());
+        self
+    }
+
+    /// Keeps the firmware each update replaces in `dir`, keyed by device UID,
+    /// so it can be restored with `rollback`
+    pub fn with_archive(mut self, dir: impl Into<String>) -> Self {
+        self.archive_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_expected_uid(mut self, uid: [u8; 16]) -> Self {
+        self.expected_uid = Some(uid);
+        self
+    }
+
+    /// Let the conformance checker erase and write at `address` to check
+    /// block acknowledgement; whatever was there is lost
+    pub fn with_conformance_scratch(mut self, address: u32) -> Self {
+        self.conformance_scratch = Some(address);
+        self
+    }
+
+    /// Only accept images built for this very device, i.e. embedding its
+    /// UID; bootloaders older than 0x30 don't report one
+    pub fn require_uid(mut self) -> Self {
+        self.require_uid = true;
+        self
+    }
+
+    /// Lets another task cancel the update; it stops between blocks and
+    /// fails with `Error::Cancelled`, leaving the device in its bootloader
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    pub fn diagnostics(mut self) -> Self {
+        self.diagnostics = true;
+        self
+    }
+
+    pub fn with_diagnostic_limits(mut self, limits: DiagnosticLimits) -> Self {
+        self.diagnostic_limits = limits;
+        self
+    }
+
+    /// Aborts the session at `point`, for testing that interrupted updates
+    /// leave the device recoverable
+    #[cfg(feature = "fault-injection")]
+    pub fn with_abort_at(mut self, point: AbortPoint) -> Self {
+        self.abort_at = Some(point);
+        self
+    }
+
     pub fn validate(&self) -> Result<(), &'static str> {
         if self.uri.is_empty() {
             return Err("URI must be specified");
         }
 
-        if self.update && self.filename.is_none() {
+        let has_firmware = self.filename.is_some()
+            || self.firmware_bytes.is_some()
+            || self.firmware_set.is_some()
+            || self.bundle.is_some();
+        if self.update && !has_firmware {
             return Err("Firmware file must be specified for update");
         }
 
+        if self.bus_addressing && self.dev_netid > MAX_NETID {
+            return Err("Network id must be below 128");
+        }
+
+        if self.update && self.update_ordering == UpdateOrdering::MetadataLast && !self.verify {
+            return Err("Metadata-last ordering needs verification");
+        }
+
+        if self.idempotency_key.is_some() && self.request_journal.is_none() {
+            return Err("Idempotency keys need a request journal");
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,419 @@
+use std::fmt;
+use std::time::Duration;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::info::{DeviceInfo, Diagnostics, ResetCause};
+use super::region::RegionKind;
+use super::report::UpdateReport;
+use super::types::{Command, InfoBlockV2, UpdateMode};
+use super::{calculate_crc32, DfuStream};
+
+/// Bytes read back from the start of the firmware region to cross-check digests
+const PROBE_LEN: u32 = 256;
+/// Anything arriving this long after a complete response is not part of it
+const STRAY_WINDOW: Duration = Duration::from_millis(100);
+/// Blocks written to the scratch area: a full ACK window and a partial one
+/// under the usual policies
+const SCRATCH_BLOCKS: usize = 3;
+const NO_SCRATCH: &str = "needs a scratch area to write (`with_conformance_scratch`)";
+
+/// How the device fared on one check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Passed,
+    /// Behaviour differs from the documented protocol
+    Deviation(String),
+    /// An optional command the device does not implement
+    Unsupported,
+    /// Not exercised, with the reason
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConformanceCheck {
+    pub name: &'static str,
+    pub command: Option<Command>,
+    pub outcome: CheckOutcome,
+}
+
+/// Findings of [`DfuStream::check_conformance`], printable as a report for
+/// bootloader developers
+#[derive(Debug, Clone)]
+pub struct ConformanceReport {
+    pub device: DeviceInfo,
+    pub checks: Vec<ConformanceCheck>,
+}
+
+impl ConformanceReport {
+    pub fn deviations(&self) -> impl Iterator<Item = &ConformanceCheck> {
+        self.checks.iter().filter(|check| matches!(check.outcome, CheckOutcome::Deviation(_)))
+    }
+
+    pub fn is_conformant(&self) -> bool {
+        self.deviations().next().is_none()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Device {:#06x} rev {}, bootloader {:#04x}",
+            self.device.device_id, self.device.device_rev, self.device.bootloader_version
+        )?;
+        for check in &self.checks {
+            let command = check.command.map(|command| format!(" ({:?})", command)).unwrap_or_default();
+            match &check.outcome {
+                CheckOutcome::Passed => writeln!(f, "  PASS  {}{}", check.name, command)?,
+                CheckOutcome::Deviation(reason) => writeln!(f, "  FAIL  {}{}: {}", check.name, command, reason)?,
+                CheckOutcome::Unsupported => writeln!(f, "  n/a   {}{}: not implemented", check.name, command)?,
+                CheckOutcome::Skipped(reason) => writeln!(f, "  skip  {}{}: {}", check.name, command, reason)?,
+            }
+        }
+        let deviations = self.deviations().count();
+        if deviations == 0 {
+            write!(f, "Conformant")
+        } else {
+            write!(f, "{} deviation(s)", deviations)
+        }
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Exercises every read-only command and flags deviations from the
+    /// documented protocol: malformed info blocks, missing mandatory
+    /// commands, digests disagreeing with the memory they cover, short or
+    /// overlong responses, and quirks known for the device. Erasing and
+    /// writing are only exercised at the configured scratch address, which
+    /// also checks block acknowledgement; without one they are skipped.
+    pub async fn check_conformance(&mut self) -> Result<ConformanceReport> {
+        let mut session = UpdateReport::new();
+        if self.config.upd_mode != UpdateMode::None {
+            self.enter_bootloader(&mut session).await?;
+        }
+
+        let info = self.read_bootloader_info().await?;
+        let device = DeviceInfo::from(&info);
+        let mut checks = Vec::new();
+
+        let outcome = match info.unsupported_fields() {
+            fields if fields.is_empty() => CheckOutcome::Passed,
+            fields => CheckOutcome::Deviation(fields.join(", ")),
+        };
+        let outcome = self.settle(Ok(outcome)).await?;
+        checks.push(check("info block", Command::ReadBootloaderInfo, outcome));
+
+        let again = self.read_bootloader_info().await.map(|info| {
+            if DeviceInfo::from(&info) == device {
+                CheckOutcome::Passed
+            } else {
+                CheckOutcome::Deviation("Info block changed between two reads".into())
+            }
+        });
+        let outcome = self.settle(again).await?;
+        checks.push(check("info block is stable", Command::ReadBootloaderInfo, outcome));
+
+        let outcome = if info.supports_capabilities() {
+            let block = self.read_capability_block().await.map(|block| capability_outcome(&block));
+            self.settle(block).await?
+        } else {
+            CheckOutcome::Unsupported
+        };
+        checks.push(check("capability block", Command::ReadCapabilities, outcome));
+        let capabilities = self.read_capabilities(&info).await?;
+        self.commands = capabilities.commands;
+
+        self.check_digests(&info, &mut checks).await?;
+
+        let outcome = if self.commands.contains(Command::ReadDiagnostics) {
+            let diagnostics = self.read_diagnostics().await.map(|d| diagnostics_outcome(&d));
+            self.settle(diagnostics).await?
+        } else {
+            CheckOutcome::Unsupported
+        };
+        checks.push(check("diagnostics", Command::ReadDiagnostics, outcome));
+
+        self.apply_quirks(&info)?;
+        let scratch = self.check_scratch_write(&info, &mut checks).await?;
+        checks.push(ConformanceCheck {
+            name: "block acknowledgement",
+            command: None,
+            outcome: match &scratch {
+                _ if self.quirks.off_by_one_ack => {
+                    CheckOutcome::Deviation("Known to acknowledge block N as N + 1".into())
+                }
+                Some((acks, _)) => acks.clone(),
+                None => CheckOutcome::Skipped(NO_SCRATCH.into()),
+            },
+        });
+        let max_block_size = info.max_block_size as usize;
+        checks.push(ConformanceCheck {
+            name: "frame size",
+            command: None,
+            outcome: match (self.quirks.max_frame_size, &scratch) {
+                (Some(size), _) if size < max_block_size => CheckOutcome::Deviation(format!(
+                    "Known to accept {} byte frames, though the info block announces {}", size, max_block_size
+                )),
+                (_, Some((CheckOutcome::Passed, size))) if *size >= max_block_size => CheckOutcome::Passed,
+                (_, Some((CheckOutcome::Passed, size))) => {
+                    CheckOutcome::Skipped(format!("scratch blocks were only {} bytes", size))
+                }
+                (_, Some(_)) => CheckOutcome::Skipped("scratch blocks were not acknowledged".into()),
+                (_, None) => CheckOutcome::Skipped(NO_SCRATCH.into()),
+            },
+        });
+        if self.quirks.erase_delay_ms > 0 {
+            checks.push(ConformanceCheck {
+                name: "erase completion",
+                command: Some(Command::EraseMemory),
+                outcome: CheckOutcome::Deviation(format!(
+                    "Known to answer {} ms before an erase completes", self.quirks.erase_delay_ms
+                )),
+            });
+        }
+
+        let outcome = if self.commands.contains(Command::CommitImage) {
+            CheckOutcome::Skipped("changes flash".into())
+        } else {
+            CheckOutcome::Unsupported
+        };
+        checks.push(check("flash programming", Command::CommitImage, outcome));
+
+        self.leave_bootloader(&mut session, false).await?;
+        let report = ConformanceReport { device, checks };
+        match report.deviations().count() {
+            0 => info!("Device conforms to the protocol"),
+            n => warn!("Device deviates from the protocol in {} check(s)", n),
+        }
+        Ok(report)
+    }
+
+    /// Erases the configured scratch area and writes a few blocks to it
+    /// under the ACK policy in use, then checks the device CRC of what
+    /// landed. ACKs are taken at face value here, so a device known to
+    /// number them off by one fails rather than being compensated for.
+    /// Returns the acknowledgement outcome and the block size written, or
+    /// `None` when nothing was written.
+    async fn check_scratch_write(
+        &mut self,
+        info: &InfoBlockV2,
+        checks: &mut Vec<ConformanceCheck>,
+    ) -> Result<Option<(CheckOutcome, usize)>> {
+        let Some(address) = self.config.conformance_scratch else {
+            for command in [Command::EraseMemory, Command::WriteProgramMemory] {
+                let outcome = if self.commands.contains(command) {
+                    CheckOutcome::Skipped(NO_SCRATCH.into())
+                } else {
+                    CheckOutcome::Unsupported
+                };
+                checks.push(check("flash programming", command, outcome));
+            }
+            return Ok(None);
+        };
+        let region = match info.memmap.memory_regions().into_iter().find(|region| region.contains(address)) {
+            Some(region) if region.kind == RegionKind::Metadata => {
+                return Err(Error::Configuration("The conformance scratch area can't be the metadata".into()));
+            }
+            Some(region) => region,
+            None => return Err(Error::OutsideMemoryMap(address)),
+        };
+        let block_size = region.block_size(self.max_block_size(info));
+        let len = ((SCRATCH_BLOCKS * block_size) as u32).min(region.end() - address);
+        let data: Vec<u8> = (0..len).map(|i| (i * 31 + 7) as u8).collect();
+
+        let outcome = if self.commands.contains(Command::EraseMemory) {
+            let (erase_address, erase_size) = region.erase_range(address, len);
+            let erased = self.erase_memory(erase_address, erase_size).await.map(|_| CheckOutcome::Passed);
+            self.settle(erased).await?
+        } else {
+            CheckOutcome::Unsupported
+        };
+        checks.push(check("scratch erase", Command::EraseMemory, outcome));
+
+        if !self.commands.contains(Command::WriteProgramMemory) {
+            checks.push(check("scratch write", Command::WriteProgramMemory, CheckOutcome::Unsupported));
+            return Ok(None);
+        }
+        self.apl.set_ack_offset(0);
+        let written = self.write_scratch(address, &data, block_size).await;
+        self.apl.set_ack_offset(self.quirks.off_by_one_ack as u16);
+        let acks = self.settle(written.map(|_| CheckOutcome::Passed)).await?;
+
+        let outcome = if acks == CheckOutcome::Passed {
+            let expected = calculate_crc32(&data);
+            let crc = self.read_firmware_crc(address, len).await.map(|actual| {
+                if actual == expected {
+                    CheckOutcome::Passed
+                } else {
+                    CheckOutcome::Deviation(format!(
+                        "Device CRC of the written blocks is {:#010x}, expected {:#010x}", actual, expected
+                    ))
+                }
+            });
+            self.settle(crc).await?
+        } else {
+            CheckOutcome::Skipped("blocks were not acknowledged".into())
+        };
+        checks.push(check("scratch write", Command::WriteProgramMemory, outcome));
+        Ok(Some((acks, block_size)))
+    }
+
+    async fn write_scratch(&mut self, address: u32, data: &[u8], block_size: usize) -> Result<()> {
+        self.begin_write(address, data.len(), block_size).await?;
+        for block in data.chunks(block_size) {
+            self.write_block(block).await?;
+        }
+        self.end_write().await
+    }
+
+    /// Reads the start of the firmware region and checks the CRC and
+    /// SHA-256 the device computes over it
+    async fn check_digests(&mut self, info: &InfoBlockV2, checks: &mut Vec<ConformanceCheck>) -> Result<()> {
+        let memmap = &info.memmap;
+        let (fw_address, fw_size) = (memmap.firmware_address, memmap.firmware_size);
+        let md_end = memmap.metadata_address + memmap.metadata_size;
+        // The device leaves its metadata out of CRCs, so probe past it
+        let address = if (memmap.metadata_address..md_end).contains(&fw_address) { md_end } else { fw_address };
+        let len = PROBE_LEN
+            .min(info.max_block_size as u32)
+            .min((fw_address + fw_size).saturating_sub(address));
+        if len == 0 {
+            let outcome = CheckOutcome::Skipped("no firmware region outside the metadata".into());
+            checks.push(check("memory read", Command::ReadProgramMemory, outcome));
+            return Ok(());
+        }
+
+        let data = if self.commands.contains(Command::ReadProgramMemory) {
+            let read = self.read_memory(address, len as usize).await;
+            let data = read.as_ref().ok().cloned();
+            let outcome = self.settle(read.map(|_| CheckOutcome::Passed)).await?;
+            checks.push(check("memory read", Command::ReadProgramMemory, outcome));
+            data
+        } else {
+            checks.push(check("memory read", Command::ReadProgramMemory, CheckOutcome::Unsupported));
+            None
+        };
+
+        let outcome = match self.read_firmware_crc(address, len).await {
+            Ok(crc) => match &data {
+                Some(data) => Ok(crc_outcome(crc, calculate_crc32(data))),
+                // Without memory reads, a CRC can only be checked for consistency
+                None => self.read_firmware_crc(address, len).await.map(|again| {
+                    if again == crc {
+                        CheckOutcome::Passed
+                    } else {
+                        CheckOutcome::Deviation(format!(
+                            "CRC of unchanged memory went from {:#010x} to {:#010x}", crc, again
+                        ))
+                    }
+                }),
+            },
+            Err(e) => Err(e),
+        };
+        let outcome = self.settle(outcome).await?;
+        checks.push(check("CRC", Command::ReadProgramCrc, outcome));
+
+        let outcome = match (self.commands.contains(Command::ReadProgramSha256), &data) {
+            (false, _) => CheckOutcome::Unsupported,
+            (true, None) => CheckOutcome::Skipped("needs ReadProgramMemory to compare against".into()),
+            (true, Some(data)) => {
+                let expected: [u8; 32] = Sha256::digest(data).into();
+                let digest = self.read_firmware_sha256(address, len).await.map(|actual| {
+                    if actual == expected {
+                        CheckOutcome::Passed
+                    } else {
+                        CheckOutcome::Deviation("SHA-256 differs from the digest of the memory read".into())
+                    }
+                });
+                self.settle(digest).await?
+            }
+        };
+        checks.push(check("SHA-256", Command::ReadProgramSha256, outcome));
+        Ok(())
+    }
+
+    /// Turns a check's result into its outcome: answers arriving late,
+    /// incomplete or with trailing bytes are deviations, while a link that
+    /// is gone ends the run
+    async fn settle(&mut self, result: Result<CheckOutcome>) -> Result<CheckOutcome> {
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(Error::Timeout) => CheckOutcome::Deviation("No complete response in time".into()),
+            Err(e) if e.is_link_loss() => return Err(e),
+            Err(e) => CheckOutcome::Deviation(e.to_string()),
+        };
+        let stray = self.stray_bytes().await?;
+        Ok(match outcome {
+            CheckOutcome::Passed if stray > 0 => {
+                CheckOutcome::Deviation(format!("{} byte(s) beyond the expected response", stray))
+            }
+            outcome => outcome,
+        })
+    }
+
+    /// Drains whatever the device sent after its response
+    async fn stray_bytes(&mut self) -> Result<usize> {
+        let mut buf = [0u8; 256];
+        let mut stray = 0;
+        while let Ok(read) = timeout(STRAY_WINDOW, self.stream.read(&mut buf)).await {
+            match read? {
+                0 => break,
+                n => stray += n,
+            }
+        }
+        Ok(stray)
+    }
+}
+
+fn check(name: &'static str, command: Command, outcome: CheckOutcome) -> ConformanceCheck {
+    ConformanceCheck { name, command: Some(command), outcome }
+}
+
+fn capability_outcome(block: &[u8]) -> CheckOutcome {
+    let bitmap = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+    let missing: Vec<String> = Command::ALL
+        .into_iter()
+        .filter(|command| command.is_mandatory() && bitmap & (1 << *command as u8) == 0)
+        .map(|command| format!("{:?}", command))
+        .collect();
+    if !missing.is_empty() {
+        return CheckOutcome::Deviation(format!("Bitmap omits mandatory {}", missing.join(", ")));
+    }
+    let unknown = bitmap >> Command::ALL.len();
+    if unknown != 0 {
+        return CheckOutcome::Deviation(format!("Bitmap sets undefined command bits {:#x}", unknown << Command::ALL.len()));
+    }
+    CheckOutcome::Passed
+}
+
+/// Names the usual CRC32 mistakes when the device's CRC is wrong
+fn crc_outcome(actual: u32, expected: u32) -> CheckOutcome {
+    if actual == expected {
+        return CheckOutcome::Passed;
+    }
+    let hint = if actual == !expected {
+        " (final XOR missing)"
+    } else if actual == expected.swap_bytes() {
+        " (sent big-endian)"
+    } else {
+        ""
+    };
+    CheckOutcome::Deviation(format!(
+        "Device reported {:#010x}, CRC32 of the memory read is {:#010x}{}", actual, expected, hint
+    ))
+}
+
+fn diagnostics_outcome(diagnostics: &Diagnostics) -> CheckOutcome {
+    if let ResetCause::Other(cause) = diagnostics.reset_cause {
+        return CheckOutcome::Deviation(format!("Undefined reset cause {}", cause));
+    }
+    if diagnostics.supply_voltage_mv == 0 {
+        return CheckOutcome::Deviation("Supply voltage reported as 0 mV".into());
+    }
+    CheckOutcome::Passed
+}
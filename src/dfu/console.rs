@@ -0,0 +1,157 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinHandle;
+#[cfg(feature = "serial")]
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::error::{Error, Result};
+
+const DEFAULT_CAPTURE_LIMIT: usize = 64 * 1024;
+
+struct CaptureState {
+    data: Vec<u8>,
+    enabled: bool,
+    limit: usize,
+}
+
+/// Shared buffer collecting whatever the device prints outside the protocol
+#[derive(Clone)]
+pub struct ConsoleCapture {
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl Default for ConsoleCapture {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_CAPTURE_LIMIT)
+    }
+}
+
+impl ConsoleCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(CaptureState {
+                data: Vec::new(),
+                enabled: true,
+                limit,
+            })),
+        }
+    }
+
+    /// Wraps the update transport so bytes read while capture is enabled are recorded
+    pub fn tap<S>(&self, stream: S) -> ConsoleTap<S> {
+        ConsoleTap {
+            inner: stream,
+            capture: self.clone(),
+        }
+    }
+
+    /// Starts capturing from a second, read-only serial port
+    #[cfg(feature = "serial")]
+    pub fn open_port(path: &str, baud: u32) -> Result<(Self, JoinHandle<()>)> {
+        use tokio::io::AsyncReadExt;
+
+        let mut port = tokio_serial::new(path, baud)
+            .open_native_async()
+            .map_err(|e| Error::Connection(format!("Console port {}: {}", path, e)))?;
+
+        let capture = Self::new();
+        let sink = capture.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 256];
+            while let Ok(n) = port.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                sink.record(&buf[..n]);
+            }
+        });
+
+        Ok((capture, task))
+    }
+
+    #[cfg(not(feature = "serial"))]
+    pub fn open_port(path: &str, _baud: u32) -> Result<(Self, JoinHandle<()>)> {
+        Err(Error::Configuration(format!("Console port {} needs the `serial` feature", path)))
+    }
+
+    /// Caps the captured bytes, dropping anything already beyond the new limit
+    pub fn set_limit(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        state.data.truncate(limit);
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.state.lock().unwrap().enabled = enabled;
+    }
+
+    pub fn record(&self, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if !state.enabled {
+            return;
+        }
+        let room = state.limit.saturating_sub(state.data.len());
+        let take = bytes.len().min(room);
+        state.data.extend_from_slice(&bytes[..take]);
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.contents()).into_owned()
+    }
+}
+
+/// Transport wrapper feeding received bytes into a [`ConsoleCapture`]
+pub struct ConsoleTap<S> {
+    inner: S,
+    capture: ConsoleCapture,
+}
+
+impl<S> ConsoleTap<S> {
+    pub fn capture(&self) -> &ConsoleCapture {
+        &self.capture
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ConsoleTap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.capture.record(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ConsoleTap<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
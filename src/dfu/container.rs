@@ -0,0 +1,115 @@
+use std::path::Path;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use super::image::FirmwareFormat;
+use super::types::InfoBlockV2;
+
+const CONTAINER_MAGIC: &[u8] = b"FWUPD-CONTAINER\n";
+const HEADER_END: &[u8] = b"\n---\n";
+
+/// Whether `data` starts with the container magic line
+pub(super) fn is_container(data: &[u8]) -> bool {
+    data.starts_with(CONTAINER_MAGIC)
+}
+
+/// Release metadata a container carries ahead of its image
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ContainerHeader {
+    /// Device id the image was built for
+    pub device_id: u16,
+    /// Oldest bootloader version able to take the image
+    #[serde(default)]
+    pub min_bootloader: u8,
+    /// CRC32 of the payload bytes as stored in the container
+    pub image_crc: u32,
+    pub version: String,
+    /// Format of the payload; detected from its contents by default
+    #[serde(default)]
+    pub format: FirmwareFormat,
+}
+
+impl ContainerHeader {
+    /// Rejects a device the image wasn't built for
+    pub fn check(&self, info: &InfoBlockV2) -> Result<()> {
+        let (device_id, version) = (info.device.id, info.version);
+        if self.device_id != device_id {
+            return Err(Error::ContainerMismatch(format!(
+                "built for device {:#06x}, device is {:#06x}",
+                self.device_id, device_id
+            )));
+        }
+        if version < self.min_bootloader {
+            return Err(Error::ContainerMismatch(format!(
+                "needs bootloader {:#04x} or newer, device has {:#04x}",
+                self.min_bootloader, version
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Firmware image wrapped with a TOML header naming the device it is for
+///
+/// ```text
+/// FWUPD-CONTAINER
+/// device_id = 0x1234
+/// min_bootloader = 0x30
+/// image_crc = 0x1c291ca3
+/// version = "2.1.0"
+/// ---
+/// <image bytes in any supported format>
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareContainer {
+    header: ContainerHeader,
+    payload: Vec<u8>,
+}
+
+impl FirmwareContainer {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !is_container(data) {
+            return Err(Error::ContainerError("missing container magic".into()));
+        }
+        let rest = &data[CONTAINER_MAGIC.len()..];
+        let end = rest
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+            .ok_or_else(|| Error::ContainerError("header not terminated by ---".into()))?;
+
+        let header = std::str::from_utf8(&rest[..end])
+            .map_err(|_| Error::ContainerError("header is not UTF-8".into()))?;
+        let header: ContainerHeader = toml::from_str(header)
+            .map_err(|e| Error::ContainerError(e.to_string()))?;
+        if header.format == FirmwareFormat::Container {
+            return Err(Error::ContainerError("containers can't be nested".into()));
+        }
+
+        let payload = rest[end + HEADER_END.len()..].to_vec();
+        let crc = crc32fast::hash(&payload);
+        if crc != header.image_crc {
+            return Err(Error::ContainerError(format!(
+                "payload CRC {:#010x} doesn't match header {:#010x}",
+                crc, header.image_crc
+            )));
+        }
+
+        Ok(Self { header, payload })
+    }
+
+    pub fn header(&self) -> &ContainerHeader {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn into_parts(self) -> (ContainerHeader, Vec<u8>) {
+        (self.header, self.payload)
+    }
+}
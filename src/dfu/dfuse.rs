@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+const SUFFIX_LEN: usize = 16;
+const SUFFIX_SIGNATURE: &[u8; 3] = b"UFD";
+const DFUSE_SIGNATURE: &[u8; 5] = b"DfuSe";
+const DFUSE_PREFIX_LEN: usize = 11;
+const TARGET_SIGNATURE: &[u8; 6] = b"Target";
+const TARGET_PREFIX_LEN: usize = 274;
+const TARGET_NAME_LEN: usize = 255;
+const ELEMENT_HEADER_LEN: usize = 8;
+
+/// Whether `data` ends with a DFU suffix signature
+pub(super) fn has_suffix(data: &[u8]) -> bool {
+    data.len() >= SUFFIX_LEN && {
+        let suffix = &data[data.len() - SUFFIX_LEN..];
+        &suffix[8..11] == SUFFIX_SIGNATURE && suffix[11] as usize == SUFFIX_LEN
+    }
+}
+
+/// Trailer every DFU file ends with (DFU 1.1, appendix B)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    pub device: u16,
+    pub product: u16,
+    pub vendor: u16,
+    pub dfu_version: u16,
+}
+
+/// One alternate setting's worth of data in a DfuSe container
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuTarget {
+    pub alt_setting: u8,
+    pub name: Option<String>,
+    /// `(address, data)` of every element; plain DFU files have a single
+    /// element at address 0 that still needs placing
+    pub elements: Vec<(u32, Vec<u8>)>,
+}
+
+/// A parsed `.dfu` file: plain DFU payload or DfuSe container
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuFile {
+    suffix: DfuSuffix,
+    targets: Vec<DfuTarget>,
+    dfuse: bool,
+}
+
+impl DfuFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !has_suffix(data) {
+            return Err(Error::DfuFileError("missing DFU suffix"));
+        }
+        let (payload, suffix) = data.split_at(data.len() - SUFFIX_LEN);
+
+        // The suffix CRC is a CRC32 without the final inversion
+        let stored = u32::from_le_bytes(suffix[12..16].try_into().unwrap());
+        if !crc32fast::hash(&data[..data.len() - 4]) != stored {
+            return Err(Error::DfuFileError("suffix CRC mismatch"));
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([suffix[offset], suffix[offset + 1]]);
+        let suffix = DfuSuffix {
+            device: u16_at(0),
+            product: u16_at(2),
+            vendor: u16_at(4),
+            dfu_version: u16_at(6),
+        };
+
+        if payload.starts_with(DFUSE_SIGNATURE) {
+            Ok(Self { suffix, targets: parse_dfuse(payload)?, dfuse: true })
+        } else {
+            let target = DfuTarget {
+                alt_setting: 0,
+                name: None,
+                elements: vec![(0, payload.to_vec())],
+            };
+            Ok(Self { suffix, targets: vec![target], dfuse: false })
+        }
+    }
+
+    pub fn suffix(&self) -> &DfuSuffix {
+        &self.suffix
+    }
+
+    pub fn targets(&self) -> &[DfuTarget] {
+        &self.targets
+    }
+
+    /// Whether elements carry their own addresses
+    pub fn is_dfuse(&self) -> bool {
+        self.dfuse
+    }
+
+    pub fn target(&self, index: usize) -> Result<&DfuTarget> {
+        self.targets.get(index).ok_or(Error::NoDfuTarget {
+            index,
+            available: self.targets.len(),
+        })
+    }
+}
+
+/// Reads the targets following the DfuSe prefix (UM0391)
+fn parse_dfuse(payload: &[u8]) -> Result<Vec<DfuTarget>> {
+    if payload.len() < DFUSE_PREFIX_LEN {
+        return Err(Error::DfuFileError("DfuSe container truncated"));
+    }
+    if payload[5] != 0x01 {
+        return Err(Error::DfuFileError("unsupported DfuSe version"));
+    }
+    let target_count = payload[10];
+
+    let u32_at = |offset: usize| -> Result<u32> {
+        payload
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or(Error::DfuFileError("DfuSe container truncated"))
+    };
+
+    let mut targets = Vec::with_capacity(target_count as usize);
+    let mut offset = DFUSE_PREFIX_LEN;
+    for _ in 0..target_count {
+        let prefix = payload
+            .get(offset..offset + TARGET_PREFIX_LEN)
+            .ok_or(Error::DfuFileError("DfuSe container truncated"))?;
+        if !prefix.starts_with(TARGET_SIGNATURE) {
+            return Err(Error::DfuFileError("missing DfuSe target signature"));
+        }
+
+        let alt_setting = prefix[6];
+        let named = u32_at(offset + 7)? != 0;
+        let name = named.then(|| {
+            let raw = &prefix[11..11 + TARGET_NAME_LEN];
+            let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+            String::from_utf8_lossy(&raw[..end]).into_owned()
+        });
+        let element_count = u32_at(offset + 270)?;
+        offset += TARGET_PREFIX_LEN;
+
+        let mut elements = Vec::new();
+        for _ in 0..element_count {
+            let address = u32_at(offset)?;
+            let size = u32_at(offset + 4)? as usize;
+            let start = offset + ELEMENT_HEADER_LEN;
+            let data = payload
+                .get(start..start + size)
+                .ok_or(Error::DfuFileError("DfuSe element extends past end of file"))?;
+            elements.push((address, data.to_vec()));
+            offset = start + size;
+        }
+
+        targets.push(DfuTarget { alt_setting, name, elements });
+    }
+
+    Ok(targets)
+}
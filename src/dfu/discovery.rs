@@ -0,0 +1,217 @@
+#[cfg(feature = "serial")]
+use serialport::SerialPortType;
+
+use std::time::Duration;
+use log::debug;
+use tokio::time::timeout;
+
+use crate::error::{Error, Result};
+use crate::transport::{connect, DfuTransport};
+use super::info::DeviceInfo;
+use super::report::UpdateReport;
+use super::types::{DfuConfig, UpdateMode};
+use super::DfuStream;
+
+/// How long a probed port gets to answer, bootloader entry included
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A connectable device URI with a human-readable description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UriCandidate {
+    pub uri: String,
+    pub description: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+/// A discovered port or network device and, if it was probed, the
+/// bootloader answering on it
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub candidate: UriCandidate,
+    /// None when not probed or nothing answered
+    pub device: Option<DeviceInfo>,
+}
+
+/// Narrows discovered ports down to the intended device; unset fields match anything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl PortFilter {
+    pub fn usb(vid: u16, pid: u16) -> Self {
+        Self { vid: Some(vid), pid: Some(pid), serial_number: None }
+    }
+
+    pub fn with_serial_number(mut self, serial: impl Into<String>) -> Self {
+        self.serial_number = Some(serial.into());
+        self
+    }
+
+    pub fn matches(&self, candidate: &UriCandidate) -> bool {
+        (self.vid.is_none() || self.vid == candidate.vid)
+            && (self.pid.is_none() || self.pid == candidate.pid)
+            && (self.serial_number.is_none() || self.serial_number == candidate.serial_number)
+    }
+}
+
+/// Lists live serial ports as `serial://` URIs, describing USB adapters by VID/PID
+#[cfg(feature = "serial")]
+pub fn serial_uri_candidates() -> Result<Vec<UriCandidate>> {
+    let ports = serialport::available_ports()
+        .map_err(|e| Error::Connection(e.to_string()))?;
+
+    Ok(ports
+        .into_iter()
+        .map(|port| {
+            let usb = match &port.port_type {
+                SerialPortType::UsbPort(usb) => Some(usb),
+                _ => None,
+            };
+            UriCandidate {
+                uri: format!("serial://{}", port.port_name),
+                description: describe_port(&port.port_type),
+                vid: usb.map(|usb| usb.vid),
+                pid: usb.map(|usb| usb.pid),
+                serial_number: usb.and_then(|usb| usb.serial_number.clone()),
+            }
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "serial"))]
+pub fn serial_uri_candidates() -> Result<Vec<UriCandidate>> {
+    Err(Error::Configuration("Listing serial ports needs the `serial` feature".into()))
+}
+
+/// Lists serial ports for pickers and autodetection. With `probe`, each port
+/// is opened with that config's speeds and entry method and asked for its
+/// bootloader info; ports that stay silent are still listed.
+pub async fn discover_serial(probe: Option<&DfuConfig>) -> Result<Vec<DiscoveredDevice>> {
+    Ok(probe_candidates(serial_uri_candidates()?, probe).await)
+}
+
+/// Finds devices advertising the DFU service on the LAN within `window`, by
+/// mDNS and SSDP as enabled, and with `probe` reads each one's bootloader info
+#[cfg_attr(not(any(feature = "mdns", feature = "ssdp")), allow(unused_variables))]
+pub async fn discover_network(window: Duration, probe: Option<&DfuConfig>) -> Result<Vec<DiscoveredDevice>> {
+    #[cfg(not(any(feature = "mdns", feature = "ssdp")))]
+    return Err(Error::Configuration("Network discovery needs the `mdns` or `ssdp` feature".into()));
+
+    #[cfg(any(feature = "mdns", feature = "ssdp"))]
+    {
+        let mut candidates: Vec<UriCandidate> = Vec::new();
+        #[cfg(feature = "mdns")]
+        candidates.extend(super::mdns::network_uri_candidates(window).await?);
+        #[cfg(feature = "ssdp")]
+        for candidate in super::ssdp::ssdp_uri_candidates(window).await? {
+            // Devices announcing themselves both ways are listed once
+            if !candidates.iter().any(|known| known.uri == candidate.uri) {
+                candidates.push(candidate);
+            }
+        }
+        Ok(probe_candidates(candidates, probe).await)
+    }
+}
+
+async fn probe_candidates(candidates: Vec<UriCandidate>, probe: Option<&DfuConfig>) -> Vec<DiscoveredDevice> {
+    let mut devices = Vec::new();
+    for candidate in candidates {
+        let device = match probe {
+            Some(template) => {
+                let config = DfuConfig { uri: candidate.uri.clone(), ..template.clone() };
+                match timeout(PROBE_TIMEOUT, probe_port(config)).await {
+                    Ok(Ok(device)) => Some(device),
+                    Ok(Err(e)) => {
+                        debug!("{}: no bootloader ({})", candidate.uri, e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("{}: no answer within {:?}", candidate.uri, PROBE_TIMEOUT);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        devices.push(DiscoveredDevice { candidate, device });
+    }
+    devices
+}
+
+async fn probe_port(config: DfuConfig) -> Result<DeviceInfo> {
+    let stream = connect(&config).await?;
+    DfuStream::new(stream, config)?.identify().await
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Reads the bootloader info and leaves the device as it was found
+    async fn identify(&mut self) -> Result<DeviceInfo> {
+        let mut report = UpdateReport::new();
+        if self.config.upd_mode != UpdateMode::None {
+            self.enter_bootloader(&mut report).await?;
+        }
+        let info = self.prepare_session(&mut report).await?;
+        self.leave_bootloader(&mut report, false).await?;
+        Ok(DeviceInfo::from(&info))
+    }
+}
+
+/// Every device reachable from here: serial ports and, with the `mdns` or
+/// `ssdp` feature, network devices advertising themselves within `window`
+#[cfg_attr(not(any(feature = "mdns", feature = "ssdp")), allow(unused_variables))]
+#[cfg_attr(not(any(feature = "serial", feature = "mdns", feature = "ssdp")), allow(unused_mut))]
+pub async fn discover(window: Duration) -> Result<Vec<UriCandidate>> {
+    let mut candidates = Vec::new();
+    #[cfg(feature = "serial")]
+    candidates.extend(serial_uri_candidates()?);
+    #[cfg(any(feature = "mdns", feature = "ssdp"))]
+    candidates.extend(discover_network(window, None).await?.into_iter().map(|found| found.candidate));
+    Ok(candidates)
+}
+
+/// Resolves "the device" matching `filter`, refusing to guess between several
+pub fn find_device(filter: &PortFilter) -> Result<UriCandidate> {
+    let mut candidates: Vec<UriCandidate> = serial_uri_candidates()?
+        .into_iter()
+        .filter(|c| filter.matches(c))
+        .collect();
+
+    match candidates.len() {
+        0 => Err(Error::DeviceNotFound),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(Error::AmbiguousDevice { candidates }),
+    }
+}
+
+/// Candidates whose URI starts with `prefix`, for shell `--uri` completion
+pub fn complete_uri(prefix: &str) -> Vec<UriCandidate> {
+    serial_uri_candidates()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| c.uri.starts_with(prefix))
+        .collect()
+}
+
+#[cfg(feature = "serial")]
+fn describe_port(port_type: &SerialPortType) -> String {
+    match port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut description = format!("USB {:04x}:{:04x}", usb.vid, usb.pid);
+            if let Some(product) = usb.product.as_ref().or(usb.manufacturer.as_ref()) {
+                description.push_str(&format!(" {}", product));
+            }
+            if let Some(serial) = &usb.serial_number {
+                description.push_str(&format!(" ({})", serial));
+            }
+            description
+        }
+        SerialPortType::PciPort => "PCI serial port".to_string(),
+        SerialPortType::BluetoothPort => "Bluetooth serial port".to_string(),
+        SerialPortType::Unknown => "Serial port".to_string(),
+    }
+}
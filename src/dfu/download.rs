@@ -0,0 +1,66 @@
+use crate::error::{Error, Result};
+
+/// Whether a firmware name refers to a remote artifact rather than a local file
+pub(super) fn is_url(name: &str) -> bool {
+    name.starts_with("http://") || name.starts_with("https://")
+}
+
+/// Downloads `url` and checks its SHA-256 before anything is parsed or flashed.
+///
+/// The digest is `expected` when given, otherwise the `<url>.sha256` file
+/// published next to the artifact (`sha256sum` output is accepted).
+#[cfg(feature = "http")]
+pub(super) async fn download(url: &str, expected: Option<&str>) -> Result<Vec<u8>> {
+    use log::info;
+    use sha2::{Digest, Sha256};
+    use crate::error::Checksum;
+    use super::signing::{from_hex, to_hex};
+
+    info!("Downloading firmware from {}", url);
+    let data = fetch(url).await?;
+
+    let expected = match expected {
+        Some(digest) => digest.to_string(),
+        None => {
+            let sidecar = fetch(&format!("{}.sha256", url)).await?;
+            String::from_utf8_lossy(&sidecar)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+    };
+    let expected: [u8; 32] = from_hex(&expected)?
+        .try_into()
+        .map_err(|_| Error::Configuration(format!("Invalid SHA-256 digest: {}", expected)))?;
+
+    let actual: [u8; 32] = Sha256::digest(&data).into();
+    if actual != expected {
+        return Err(Error::DownloadMismatch {
+            url: url.to_string(),
+            expected: Checksum::Sha256(expected),
+            actual: Checksum::Sha256(actual),
+        });
+    }
+
+    info!("Downloaded {} bytes, SHA-256 {}", data.len(), to_hex(&actual));
+    Ok(data)
+}
+
+#[cfg(feature = "http")]
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let response = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Download(e.to_string()))?;
+    let body = response.bytes().await.map_err(|e| Error::Download(e.to_string()))?;
+    Ok(body.to_vec())
+}
+
+#[cfg(not(feature = "http"))]
+pub(super) async fn download(url: &str, _expected: Option<&str>) -> Result<Vec<u8>> {
+    Err(Error::Configuration(format!(
+        "Downloading {} needs the `http` feature",
+        url
+    )))
+}
@@ -0,0 +1,102 @@
+use crate::error::{Error, Result};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const PT_LOAD: u32 = 1;
+
+pub(super) fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(&ELF_MAGIC)
+}
+
+/// Byte-order and width aware reader over the raw file
+struct ElfReader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl ElfReader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+        let mut bytes: [u8; N] = self.data
+            .get(offset..offset + N)
+            .and_then(|slice| slice.try_into().ok())
+            .ok_or(Error::ElfError("file truncated"))?;
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        self.bytes(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        self.bytes(offset).map(u32::from_le_bytes)
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64> {
+        self.bytes(offset).map(u64::from_le_bytes)
+    }
+}
+
+/// Returns `(load address, contents)` of every non-empty PT_LOAD segment,
+/// placed at its physical (load) address rather than its run address
+pub(super) fn load_segments(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    if !is_elf(data) || data.len() < 16 {
+        return Err(Error::ElfError("not an ELF file"));
+    }
+    let wide = match data[4] {
+        ELFCLASS32 => false,
+        ELFCLASS64 => true,
+        _ => return Err(Error::ElfError("unknown ELF class")),
+    };
+    let big_endian = match data[5] {
+        ELFDATA2LSB => false,
+        ELFDATA2MSB => true,
+        _ => return Err(Error::ElfError("unknown byte order")),
+    };
+    let elf = ElfReader { data, big_endian };
+
+    let (phoff, phentsize, phnum) = if wide {
+        (elf.u64(0x20)? as usize, elf.u16(0x36)?, elf.u16(0x38)?)
+    } else {
+        (elf.u32(0x1C)? as usize, elf.u16(0x2A)?, elf.u16(0x2C)?)
+    };
+
+    let mut segments = Vec::new();
+    for index in 0..phnum as usize {
+        let header = phoff + index * phentsize as usize;
+        if elf.u32(header)? != PT_LOAD {
+            continue;
+        }
+
+        let (offset, paddr, filesz) = if wide {
+            (elf.u64(header + 0x08)?, elf.u64(header + 0x18)?, elf.u64(header + 0x20)?)
+        } else {
+            (
+                elf.u32(header + 0x04)? as u64,
+                elf.u32(header + 0x0C)? as u64,
+                elf.u32(header + 0x10)? as u64,
+            )
+        };
+        // .bss and similar segments occupy RAM only
+        if filesz == 0 {
+            continue;
+        }
+
+        let address = u32::try_from(paddr)
+            .map_err(|_| Error::ElfError("segment load address beyond 32 bits"))?;
+        let contents = data
+            .get(offset as usize..(offset + filesz) as usize)
+            .ok_or(Error::ElfError("segment extends past end of file"))?;
+        segments.push((address, contents.to_vec()));
+    }
+
+    if segments.is_empty() {
+        return Err(Error::ElfError("no loadable segments"));
+    }
+    Ok(segments)
+}
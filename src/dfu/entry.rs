@@ -0,0 +1,527 @@
+use std::future::Future;
+use std::time::Duration;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::DfuStream;
+#[cfg(feature = "power-switch")]
+use super::power::PowerCycleEntry;
+
+const DEFAULT_STARTUP_MS: u64 = 1000;
+const DEFAULT_RESET_PULSE_MS: u64 = 100;
+const DEFAULT_DETECT_INTERVAL_MS: u64 = 200;
+const DEFAULT_SYNC_REPEAT: usize = 16;
+const DEFAULT_SYNC_SETTLE_MS: u64 = 20;
+const DEFAULT_SYNC_ATTEMPTS: usize = 3;
+/// Input quiet for this long counts as drained
+const DRAIN_IDLE: Duration = Duration::from_millis(10);
+
+/// Delays around bootloader entry and exit; devices range from ~100 ms to
+/// several seconds between reset and a responsive bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct EntryTiming {
+    /// Wait after a reboot, reset or power cycle before talking to the bootloader
+    pub startup_ms: u64,
+    /// Width of reset line pulses
+    pub reset_pulse_ms: u64,
+    /// Times to probe for the bootloader after each entry method
+    pub detect_attempts: usize,
+    /// Spacing between those probes
+    pub detect_interval_ms: u64,
+    /// Wait after a baud rate change before the next request
+    pub settle_ms: u64,
+}
+
+impl Default for EntryTiming {
+    fn default() -> Self {
+        Self {
+            startup_ms: DEFAULT_STARTUP_MS,
+            reset_pulse_ms: DEFAULT_RESET_PULSE_MS,
+            detect_attempts: 1,
+            detect_interval_ms: DEFAULT_DETECT_INTERVAL_MS,
+            settle_ms: 0,
+        }
+    }
+}
+
+impl EntryTiming {
+    pub fn startup(&self) -> Duration {
+        Duration::from_millis(self.startup_ms)
+    }
+
+    pub fn reset_pulse(&self) -> Duration {
+        Duration::from_millis(self.reset_pulse_ms)
+    }
+
+    pub fn detect_interval(&self) -> Duration {
+        Duration::from_millis(self.detect_interval_ms)
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_millis(self.settle_ms)
+    }
+}
+
+/// Bytes sent after entry and before the first request, so a receiver that
+/// glitched while the device rebooted resynchronises; 0x55 also alternates
+/// every bit for bootloaders that measure the baud rate
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncPreamble {
+    pub pattern: Vec<u8>,
+    pub repeat: usize,
+    /// Quiet time after the preamble; whatever arrives meanwhile is discarded
+    pub settle_ms: u64,
+    /// Preamble and probe rounds before giving up
+    pub attempts: usize,
+}
+
+impl Default for SyncPreamble {
+    fn default() -> Self {
+        Self {
+            pattern: vec![0x55],
+            repeat: DEFAULT_SYNC_REPEAT,
+            settle_ms: DEFAULT_SYNC_SETTLE_MS,
+            attempts: DEFAULT_SYNC_ATTEMPTS,
+        }
+    }
+}
+
+impl SyncPreamble {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.pattern.repeat(self.repeat)
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_millis(self.settle_ms)
+    }
+}
+
+/// Line levels to set and how long to hold them; `true` asserts a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineStep {
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    #[serde(default)]
+    pub hold_ms: u64,
+}
+
+impl LineStep {
+    pub const fn new(dtr: Option<bool>, rts: Option<bool>, hold_ms: u64) -> Self {
+        Self { dtr, rts, hold_ms }
+    }
+}
+
+/// DTR/RTS patterns that reset a board into its bootloader and back into
+/// the application
+///
+/// ```toml
+/// [factory.entry.reset_sequence]
+/// enter = [
+///     { dtr = false, rts = true, hold_ms = 100 },
+///     { dtr = true, rts = false, hold_ms = 50 },
+///     { dtr = false },
+/// ]
+/// exit = [{ rts = true, hold_ms = 100 }, { rts = false }]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResetSequence {
+    pub enter: Vec<LineStep>,
+    #[serde(default)]
+    pub exit: Vec<LineStep>,
+}
+
+impl ResetSequence {
+    /// Arduino-style boards: DTR (and RTS) asserted after a quiet period,
+    /// which the reset capacitor turns into a reset pulse
+    pub fn arduino() -> Self {
+        Self {
+            enter: vec![
+                LineStep::new(Some(false), Some(false), 250),
+                LineStep::new(Some(true), Some(true), 50),
+            ],
+            exit: Vec::new(),
+        }
+    }
+
+    /// ESP32/ESP8266 two-transistor auto-reset: EN pulled low, then IO0
+    /// held low while EN rises
+    pub fn esp32() -> Self {
+        Self {
+            enter: vec![
+                LineStep::new(Some(false), Some(true), 100),
+                LineStep::new(Some(true), Some(false), 50),
+                LineStep::new(Some(false), None, 0),
+            ],
+            exit: vec![
+                LineStep::new(None, Some(true), 100),
+                LineStep::new(None, Some(false), 0),
+            ],
+        }
+    }
+
+    async fn run<T: DfuTransport>(steps: &[LineStep], dfu: &mut DfuStream<T>) -> Result<()> {
+        for step in steps {
+            debug!("DTR {:?}, RTS {:?} for {} ms", step.dtr, step.rts, step.hold_ms);
+            dfu.stream
+                .reset_lines(step.dtr, step.rts)
+                .await
+                .map_err(|e| Error::EntryFailed(format!("Reset sequence: {}", e)))?;
+            sleep(Duration::from_millis(step.hold_ms)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Bootloader entry/exit strategy selected on `DfuConfig`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryMethod {
+    /// Device is expected to already run the bootloader
+    AlreadyInBootloader,
+    /// Ask the application firmware to reboot into the bootloader
+    RebootCommand,
+    /// Pulse DTR on the serial port to reset the device
+    LineReset,
+    /// Toggle DTR and RTS in the pattern the board's auto-reset circuit expects
+    ResetSequence(ResetSequence),
+    /// Drive boot-select/reset GPIOs through sysfs
+    Gpio(GpioEntry),
+    /// Run external commands, e.g. to power-cycle a relay
+    Hook(HookEntry),
+    /// Cold-boot through a USB relay or networked PDU
+    #[cfg(feature = "power-switch")]
+    PowerCycle(PowerCycleEntry),
+    /// Try each method in turn until the bootloader answers
+    Chain(Vec<EntryMethod>),
+}
+
+impl Default for EntryMethod {
+    fn default() -> Self {
+        Self::Chain(vec![Self::AlreadyInBootloader, Self::RebootCommand])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GpioEntry {
+    /// sysfs `value` file of the boot-select line
+    pub boot: Option<String>,
+    /// sysfs `value` file of the reset line
+    pub reset: Option<String>,
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HookEntry {
+    pub enter: String,
+    pub exit: Option<String>,
+}
+
+pub trait EntryStrategy {
+    fn name(&self) -> &'static str;
+
+    fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> impl Future<Output = Result<()>> + Send;
+
+    fn exit<T: DfuTransport>(&self, _dfu: &mut DfuStream<T>) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+pub struct AlreadyInBootloader;
+
+impl EntryStrategy for AlreadyInBootloader {
+    fn name(&self) -> &'static str {
+        "already in bootloader"
+    }
+
+    async fn enter<T: DfuTransport>(&self, _dfu: &mut DfuStream<T>) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct RebootCommand;
+
+impl EntryStrategy for RebootCommand {
+    fn name(&self) -> &'static str {
+        "reboot command"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        dfu.send_reboot_command().await?;
+        sleep(dfu.config.entry_timing.startup()).await;
+        Ok(())
+    }
+}
+
+pub struct LineReset;
+
+impl EntryStrategy for LineReset {
+    fn name(&self) -> &'static str {
+        "DTR reset"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        let timing = dfu.config.entry_timing;
+        dfu.stream
+            .reset_lines(Some(true), None)
+            .await
+            .map_err(|e| Error::EntryFailed(format!("DTR reset: {}", e)))?;
+        sleep(timing.reset_pulse()).await;
+        dfu.stream
+            .reset_lines(Some(false), None)
+            .await
+            .map_err(|e| Error::EntryFailed(format!("DTR reset: {}", e)))?;
+
+        sleep(timing.startup()).await;
+        Ok(())
+    }
+}
+
+impl EntryStrategy for ResetSequence {
+    fn name(&self) -> &'static str {
+        "DTR/RTS sequence"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        Self::run(&self.enter, dfu).await?;
+        sleep(dfu.config.entry_timing.startup()).await;
+        Ok(())
+    }
+
+    async fn exit<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        Self::run(&self.exit, dfu).await
+    }
+}
+
+impl GpioEntry {
+    fn set(&self, path: &str, active: bool) -> Result<()> {
+        let level = if active != self.active_low { "1" } else { "0" };
+        std::fs::write(path, level)
+            .map_err(|e| Error::EntryFailed(format!("{}: {}", path, e)))
+    }
+
+    async fn pulse_reset(&self, timing: &EntryTiming) -> Result<()> {
+        if let Some(reset) = &self.reset {
+            self.set(reset, true)?;
+            sleep(timing.reset_pulse()).await;
+            self.set(reset, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl EntryStrategy for GpioEntry {
+    fn name(&self) -> &'static str {
+        "GPIO"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        let timing = dfu.config.entry_timing;
+        if let Some(boot) = &self.boot {
+            self.set(boot, true)?;
+        }
+        self.pulse_reset(&timing).await?;
+        sleep(timing.startup()).await;
+        Ok(())
+    }
+
+    async fn exit<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        if let Some(boot) = &self.boot {
+            self.set(boot, false)?;
+        }
+        self.pulse_reset(&dfu.config.entry_timing).await
+    }
+}
+
+impl HookEntry {
+    async fn run(command: &str) -> Result<()> {
+        info!("Running hook: {}", command);
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()
+            .await?;
+
+        if !status.success() {
+            return Err(Error::EntryFailed(format!("Hook '{}' failed: {}", command, status)));
+        }
+        Ok(())
+    }
+}
+
+impl EntryStrategy for HookEntry {
+    fn name(&self) -> &'static str {
+        "hook"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        Self::run(&self.enter).await?;
+        sleep(dfu.config.entry_timing.startup()).await;
+        Ok(())
+    }
+
+    async fn exit<T: DfuTransport>(&self, _dfu: &mut DfuStream<T>) -> Result<()> {
+        match &self.exit {
+            Some(command) => Self::run(command).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Extracts the device path from a `serial://` URI
+#[cfg(feature = "serial")]
+pub fn serial_path(uri: &str) -> Option<&str> {
+    uri.strip_prefix("serial://")
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    pub(super) async fn run_entry(&mut self, method: &EntryMethod) -> Result<()> {
+        let methods = match method {
+            EntryMethod::Chain(methods) => methods.as_slice(),
+            single => std::slice::from_ref(single),
+        };
+
+        let mut last_error = Error::BootloaderNotDetected;
+        for method in methods {
+            if let Err(e) = self.enter_with(method).await {
+                warn!("Entry via {:?} failed: {}", method, e);
+                last_error = e;
+                continue;
+            }
+
+            match self.detect_after_entry().await {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Repeats entry at each sweep speed until the device answers, and keeps
+    /// that speed as the link speed to return to on exit
+    pub(super) async fn sweep_speeds(&mut self, method: &EntryMethod, error: Error) -> Result<()> {
+        let mut last_error = error;
+        for speed in self.config.baud_sweep.clone() {
+            if speed == self.config.lnk_speed {
+                continue;
+            }
+            info!("No answer, trying {}", speed);
+            self.set_speed(speed).await?;
+            match self.run_entry(method).await {
+                Ok(()) => {
+                    info!("Device answers at {}", speed);
+                    self.config.lnk_speed = speed;
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Probes for the bootloader until it answers or the attempts run out
+    async fn detect_after_entry(&mut self) -> Result<()> {
+        self.capture_banner().await?;
+        if let Some(preamble) = self.config.sync_preamble.clone() {
+            return self.sync_link(&preamble).await;
+        }
+        let timing = self.config.entry_timing;
+        let mut attempt = 1;
+        loop {
+            match self.detect_bootloader().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= timing.detect_attempts => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    sleep(timing.detect_interval()).await;
+                }
+            }
+        }
+    }
+
+    /// Sends the preamble and probes until the bootloader answers without
+    /// a single framing or checksum error
+    async fn sync_link(&mut self, preamble: &SyncPreamble) -> Result<()> {
+        let attempts = preamble.attempts.max(1);
+        for attempt in 1..=attempts {
+            self.stream.write_all(&preamble.bytes()).await?;
+            DfuTransport::flush(&mut self.stream).await?;
+            sleep(preamble.settle()).await;
+            self.drain_input().await?;
+
+            let errors = self.lpl.errors().total() + self.apl.errors().total();
+            match self.detect_bootloader().await {
+                Ok(()) if self.lpl.errors().total() + self.apl.errors().total() == errors => {
+                    info!("Link synchronised after {} attempt(s)", attempt);
+                    return Ok(());
+                }
+                Ok(()) => warn!("Sync attempt {}: response arrived with protocol errors", attempt),
+                Err(e) => warn!("Sync attempt {}: {}", attempt, e),
+            }
+        }
+        Err(Error::LinkNotSynchronized { attempts })
+    }
+
+    /// Discards whatever the device sent so far, e.g. noise from its reboot
+    async fn drain_input(&mut self) -> Result<()> {
+        let mut buf = [0u8; 256];
+        let mut discarded = 0;
+        while let Ok(read) = timeout(DRAIN_IDLE, self.stream.read(&mut buf)).await {
+            match read? {
+                0 => break,
+                n => discarded += n,
+            }
+        }
+        if discarded > 0 {
+            debug!("Discarded {} byte(s) of line noise", discarded);
+        }
+        Ok(())
+    }
+
+    async fn enter_with(&mut self, method: &EntryMethod) -> Result<()> {
+        match method {
+            EntryMethod::AlreadyInBootloader => self.enter_using(&AlreadyInBootloader).await,
+            EntryMethod::RebootCommand => self.enter_using(&RebootCommand).await,
+            EntryMethod::LineReset => self.enter_using(&LineReset).await,
+            EntryMethod::ResetSequence(sequence) => self.enter_using(sequence).await,
+            EntryMethod::Gpio(gpio) => self.enter_using(gpio).await,
+            EntryMethod::Hook(hook) => self.enter_using(hook).await,
+            #[cfg(feature = "power-switch")]
+            EntryMethod::PowerCycle(power) => self.enter_using(power).await,
+            EntryMethod::Chain(_) => Box::pin(self.run_entry(method)).await,
+        }
+    }
+
+    async fn enter_using<S: EntryStrategy>(&mut self, strategy: &S) -> Result<()> {
+        info!("Entering bootloader via {}", strategy.name());
+        strategy.enter(self).await
+    }
+
+    pub(super) async fn run_exit(&mut self, method: &EntryMethod) -> Result<()> {
+        match method {
+            EntryMethod::AlreadyInBootloader => AlreadyInBootloader.exit(self).await,
+            EntryMethod::RebootCommand => RebootCommand.exit(self).await,
+            EntryMethod::LineReset => LineReset.exit(self).await,
+            EntryMethod::ResetSequence(sequence) => sequence.exit(self).await,
+            EntryMethod::Gpio(gpio) => gpio.exit(self).await,
+            EntryMethod::Hook(hook) => hook.exit(self).await,
+            #[cfg(feature = "power-switch")]
+            EntryMethod::PowerCycle(power) => power.exit(self).await,
+            EntryMethod::Chain(methods) => {
+                for method in methods {
+                    Box::pin(self.run_exit(method)).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
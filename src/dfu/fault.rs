@@ -0,0 +1,47 @@
+#[cfg(feature = "fault-injection")]
+use log::warn;
+
+#[cfg(feature = "fault-injection")]
+use crate::error::Error;
+use crate::error::Result;
+use crate::transport::DfuTransport;
+use super::DfuStream;
+
+/// Place in an update where a test session is deliberately cut off, to
+/// check on real hardware that the device stays recoverable there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortPoint {
+    /// Once this share of the image bytes has been written
+    Writing { percent: u8 },
+    /// After every region is written and verified, before the commit
+    BeforeCommit,
+    /// With a verification request sent but not answered
+    Verifying,
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Fails with [`Error::Aborted`] if the session is set to stop at `point`;
+    /// the device is left as is, without leaving the bootloader
+    pub(super) fn abort_at(&self, point: AbortPoint) -> Result<()> {
+        #[cfg(feature = "fault-injection")]
+        if self.config.abort_at == Some(point) {
+            warn!("Aborting the session at {:?} (fault injection)", point);
+            return Err(Error::Aborted(point));
+        }
+        let _ = point;
+        Ok(())
+    }
+
+    /// Like [`Self::abort_at`] for a `Writing` point, once `written` of
+    /// `total` bytes are out
+    pub(super) fn abort_writing(&self, written: usize, total: usize) -> Result<()> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(AbortPoint::Writing { percent }) = self.config.abort_at {
+            if written * 100 >= percent as usize * total.max(1) {
+                return self.abort_at(AbortPoint::Writing { percent });
+            }
+        }
+        let _ = (written, total);
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use futures::future::join_all;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{watch, Semaphore};
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::report::UpdateReport;
+use super::resume::ResumeToken;
+use super::rollout::{bus_of, PlannedUpdate, RolloutPlan};
+use super::DfuStream;
+
+/// Caps how many updates run at once, over every transport or one URI scheme
+///
+/// ```toml
+/// [[limit]]
+/// scheme = "tcp"
+/// max = 4
+///
+/// [[limit]]
+/// scheme = "serial"
+/// per_bus = true
+/// max = 1
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyLimit {
+    /// URI scheme the limit applies to ("tcp", "serial", ...); all when unset
+    #[serde(default)]
+    pub scheme: Option<String>,
+    /// Count each bus (serial adapter, CAN interface, gateway) separately
+    #[serde(default)]
+    pub per_bus: bool,
+    pub max: usize,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize) -> Self {
+        Self { scheme: None, per_bus: false, max }
+    }
+
+    pub fn for_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = Some(scheme.into());
+        self
+    }
+
+    pub fn per_bus(mut self) -> Self {
+        self.per_bus = true;
+        self
+    }
+
+    /// Semaphore key for `uri`, or None if the limit doesn't cover it
+    fn key(&self, uri: &str) -> Option<String> {
+        let scheme = uri.split_once("://").map_or("", |(scheme, _)| scheme);
+        if self.scheme.as_deref().is_some_and(|s| s != scheme) {
+            return None;
+        }
+        Some(if self.per_bus { bus_of(uri).to_string() } else { String::new() })
+    }
+}
+
+fn default_limits() -> Vec<ConcurrencyLimit> {
+    vec![ConcurrencyLimit::new(1).per_bus()]
+}
+
+/// Limits a job must satisfy all of; by default one update per bus at a time
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulerLimits {
+    #[serde(default = "default_limits", rename = "limit")]
+    pub limits: Vec<ConcurrencyLimit>,
+}
+
+impl Default for SchedulerLimits {
+    fn default() -> Self {
+        Self { limits: default_limits() }
+    }
+}
+
+impl SchedulerLimits {
+    /// No limits at all; add them with [`Self::with_limit`]
+    pub fn none() -> Self {
+        Self { limits: Vec::new() }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    pub fn with_limit(mut self, limit: ConcurrencyLimit) -> Self {
+        self.limits.push(limit);
+        self
+    }
+}
+
+/// How far a rollout goes before the rest of the fleet is touched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RolloutPolicy {
+    #[default]
+    AllAtOnce,
+    /// Update the first `count` devices of the plan, and continue only if
+    /// every one of them updates and passes its health check
+    Canary { count: usize },
+}
+
+/// Outcome of every job a scheduler ran
+#[derive(Debug, Default)]
+pub struct FleetReport {
+    pub completed: Vec<(String, UpdateReport)>,
+    pub failed: Vec<(String, Error)>,
+    /// Why the rollout stopped early, if it did
+    pub aborted: Option<String>,
+    /// Devices left untouched after an abort
+    pub not_started: Vec<String>,
+    /// Devices stopped mid-transfer by an abort, left in the bootloader;
+    /// pass the token to `DfuConfig::with_resume_token` to finish them
+    pub suspended: Vec<(String, ResumeToken)>,
+}
+
+impl FleetReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.aborted.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FleetState {
+    Running,
+    Paused,
+    Aborted,
+}
+
+/// Pauses, resumes or aborts a running [`Scheduler`] from another task.
+///
+/// Running transfers are only ever stopped between blocks, leaving the
+/// device in the bootloader with a resumable, half-written image.
+#[derive(Debug, Clone)]
+pub struct FleetControl {
+    state: Arc<watch::Sender<FleetState>>,
+}
+
+impl FleetControl {
+    /// Holds back jobs that haven't started and suspends running ones once
+    /// their in-flight block is written
+    pub fn pause(&self) {
+        self.transition(FleetState::Running, FleetState::Paused);
+    }
+
+    /// Continues paused transfers where they stopped
+    pub fn resume(&self) {
+        self.transition(FleetState::Paused, FleetState::Running);
+    }
+
+    /// Stops the rollout: jobs not yet started are skipped and running ones
+    /// are suspended and reported with their resume tokens
+    pub fn abort(&self) {
+        self.state.send_replace(FleetState::Aborted);
+    }
+
+    pub fn state(&self) -> FleetState {
+        *self.state.borrow()
+    }
+
+    fn transition(&self, from: FleetState, to: FleetState) {
+        self.state.send_if_modified(|state| {
+            let changed = *state == from;
+            if changed {
+                *state = to;
+            }
+            changed
+        });
+    }
+}
+
+enum JobOutcome {
+    // Boxed: a report dwarfs the other variants
+    Done(Box<Result<UpdateReport>>),
+    NotStarted,
+    Suspended(ResumeToken),
+}
+
+/// Health check used by [`Scheduler::run`]: the bootloader reported no
+/// marginal hardware
+pub fn default_health_check(report: &UpdateReport) -> Result<()> {
+    if report.is_marginal() {
+        return Err(Error::HealthCheckFailed(report.hardware_warnings.join("; ")));
+    }
+    Ok(())
+}
+
+/// Runs the updates of a rollout plan concurrently, within the configured limits
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    limits: SchedulerLimits,
+    policy: RolloutPolicy,
+    control: FleetControl,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(SchedulerLimits::default())
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: SchedulerLimits) -> Self {
+        Self {
+            limits,
+            policy: RolloutPolicy::default(),
+            control: FleetControl { state: Arc::new(watch::channel(FleetState::Running).0) },
+        }
+    }
+
+    /// Handle for pausing, resuming or aborting runs of this scheduler
+    pub fn control(&self) -> FleetControl {
+        self.control.clone()
+    }
+
+    pub fn with_policy(mut self, policy: RolloutPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn limits(&self) -> &SchedulerLimits {
+        &self.limits
+    }
+
+    /// Opens a session for each planned update with `open` (which sets up the
+    /// config, e.g. the firmware file) and runs it once every limit covering
+    /// its URI has room. Jobs start in plan order.
+    pub async fn run<T, F, Fut>(&self, plan: &RolloutPlan, open: F) -> FleetReport
+    where
+        T: DfuTransport,
+        F: Fn(&PlannedUpdate) -> Fut,
+        Fut: Future<Output = Result<DfuStream<T>>>,
+    {
+        self.run_checked(plan, open, |_, report| std::future::ready(default_health_check(report)))
+            .await
+    }
+
+    /// Like [`Self::run`], with `check` run against each device after its
+    /// update (e.g. probing the application). Under a canary policy, a failed
+    /// canary aborts the rollout before any other device is touched.
+    pub async fn run_checked<T, F, Fut, C, CFut>(&self, plan: &RolloutPlan, open: F, check: C) -> FleetReport
+    where
+        T: DfuTransport,
+        F: Fn(&PlannedUpdate) -> Fut,
+        Fut: Future<Output = Result<DfuStream<T>>>,
+        C: Fn(&PlannedUpdate, &UpdateReport) -> CFut,
+        CFut: Future<Output = Result<()>>,
+    {
+        // A new run starts over after an abort; a pause carries over
+        self.control.transition(FleetState::Aborted, FleetState::Running);
+        let updates: Vec<&PlannedUpdate> = plan.updates().collect();
+        let canaries = match self.policy {
+            RolloutPolicy::AllAtOnce => 0,
+            RolloutPolicy::Canary { count } => count.min(updates.len()),
+        };
+        let (canaries, rest) = updates.split_at(canaries);
+
+        let mut report = FleetReport::default();
+        if !canaries.is_empty() {
+            info!("Updating {} canary device(s)", canaries.len());
+            self.run_batch(canaries, &open, &check, &mut report).await;
+            if !report.failed.is_empty() {
+                let reason = format!("{} of {} canaries failed", report.failed.len(), canaries.len());
+                warn!("Rollout aborted: {}", reason);
+                report.aborted = Some(reason);
+                report.not_started = rest.iter().map(|update| update.uri.clone()).collect();
+                return report;
+            }
+            info!("All canaries healthy, continuing with {} device(s)", rest.len());
+        }
+
+        self.run_batch(rest, &open, &check, &mut report).await;
+        if self.control.state() == FleetState::Aborted {
+            report.aborted = Some("aborted by operator".into());
+        }
+        info!("Fleet run finished: {} updated, {} failed", report.completed.len(), report.failed.len());
+        report
+    }
+
+    async fn run_batch<T, F, Fut, C, CFut>(
+        &self,
+        updates: &[&PlannedUpdate],
+        open: &F,
+        check: &C,
+        report: &mut FleetReport,
+    ) where
+        T: DfuTransport,
+        F: Fn(&PlannedUpdate) -> Fut,
+        Fut: Future<Output = Result<DfuStream<T>>>,
+        C: Fn(&PlannedUpdate, &UpdateReport) -> CFut,
+        CFut: Future<Output = Result<()>>,
+    {
+        let mut semaphores: HashMap<(usize, String), Arc<Semaphore>> = HashMap::new();
+        let jobs = updates.iter().map(|&update| {
+            let gates: Vec<Arc<Semaphore>> = self.limits.limits
+                .iter()
+                .enumerate()
+                .filter_map(|(index, limit)| {
+                    let key = limit.key(&update.uri)?;
+                    let semaphore = semaphores
+                        .entry((index, key))
+                        .or_insert_with(|| Arc::new(Semaphore::new(limit.max.max(1))));
+                    Some(semaphore.clone())
+                })
+                .collect();
+            self.run_job(update, gates, open, check)
+        });
+        let results = join_all(jobs.collect::<Vec<_>>()).await;
+
+        for (uri, outcome) in results {
+            match outcome {
+                JobOutcome::Done(result) => match *result {
+                    Ok(update) => report.completed.push((uri, update)),
+                    Err(e) => {
+                        warn!("{}: {}", uri, e);
+                        report.failed.push((uri, e));
+                    }
+                },
+                JobOutcome::NotStarted => report.not_started.push(uri),
+                JobOutcome::Suspended(token) => {
+                    warn!("{}: suspended before {:#010x}", uri, token.next_address());
+                    report.suspended.push((uri, token));
+                }
+            }
+        }
+    }
+
+    async fn run_job<T, F, Fut, C, CFut>(
+        &self,
+        update: &PlannedUpdate,
+        gates: Vec<Arc<Semaphore>>,
+        open: &F,
+        check: &C,
+    ) -> (String, JobOutcome)
+    where
+        T: DfuTransport,
+        F: Fn(&PlannedUpdate) -> Fut,
+        Fut: Future<Output = Result<DfuStream<T>>>,
+        C: Fn(&PlannedUpdate, &UpdateReport) -> CFut,
+        CFut: Future<Output = Result<()>>,
+    {
+        // Always taken in limit order, so two jobs can't wait on each other
+        let mut permits = Vec::with_capacity(gates.len());
+        for gate in &gates {
+            permits.push(gate.acquire().await.expect("scheduler semaphores are never closed"));
+        }
+
+        let mut state = self.control.state.subscribe();
+        if wait_unpaused(&mut state).await == FleetState::Aborted {
+            return (update.uri.clone(), JobOutcome::NotStarted);
+        }
+
+        info!("{}: updating to {}", update.uri, update.file.display());
+        let outcome = match open(update).await {
+            Ok(mut dfu) => run_controlled(&mut dfu, &mut state).await,
+            Err(e) => JobOutcome::Done(Box::new(Err(e))),
+        };
+        drop(permits);
+
+        let outcome = match outcome {
+            JobOutcome::Done(result) => match *result {
+                Ok(report) => JobOutcome::Done(Box::new(check(update, &report).await.map(|()| report))),
+                Err(e) => JobOutcome::Done(Box::new(Err(e))),
+            },
+            outcome => outcome,
+        };
+        (update.uri.clone(), outcome)
+    }
+}
+
+/// Waits out a pause and returns the state it ended in
+async fn wait_unpaused(state: &mut watch::Receiver<FleetState>) -> FleetState {
+    // The scheduler owns the sender, so it outlives every job
+    state.wait_for(|state| *state != FleetState::Paused)
+        .await
+        .map_or(FleetState::Aborted, |state| *state)
+}
+
+/// Resolves once the fleet leaves the running state
+async fn stop_requested(state: &mut watch::Receiver<FleetState>) {
+    // Drop the borrow right away; holding it would block the controller
+    let _ = state.wait_for(|state| *state != FleetState::Running).await;
+}
+
+/// Runs the update, suspending it at a block boundary on pause or abort and
+/// continuing from the resume token once the pause ends
+async fn run_controlled<T: DfuTransport>(
+    dfu: &mut DfuStream<T>,
+    state: &mut watch::Receiver<FleetState>,
+) -> JobOutcome {
+    let handle = dfu.handle();
+    loop {
+        let result = {
+            let update = dfu.update();
+            tokio::pin!(update);
+            tokio::select! {
+                result = &mut update => result,
+                () = stop_requested(state) => {
+                    // Let the in-flight block finish; the update stops before the next one
+                    handle.request_suspend();
+                    update.await
+                }
+            }
+        };
+
+        let token = match result {
+            Err(Error::Suspended(token)) => *token,
+            result => return JobOutcome::Done(Box::new(result)),
+        };
+        if wait_unpaused(state).await == FleetState::Aborted {
+            return JobOutcome::Suspended(token);
+        }
+        info!("Resuming transfer at {:#010x}", token.next_address());
+        dfu.config.resume_token = Some(token);
+    }
+}
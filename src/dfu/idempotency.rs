@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::image::FirmwareImage;
+use super::region::RegionKind;
+use super::report::UpdateReport;
+use super::resume::ResumeToken;
+use super::session::unix_now;
+use super::DfuStream;
+
+/// How an update retried under an idempotency key was carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    /// The key was not seen before
+    Fresh,
+    /// An earlier attempt completed and the device still holds its image
+    Skipped,
+    /// An earlier attempt was suspended and continued from its token
+    Resumed,
+    /// An earlier attempt was interrupted, or the device changed since it completed
+    Restarted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RequestState {
+    Started,
+    Suspended { token: String },
+    /// Firmware region as written, checked against the device on a retry
+    Completed { address: u32, size: u32, crc: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RequestRecord {
+    uri: String,
+    image_crc: u32,
+    image_len: usize,
+    /// Seconds since the Unix epoch of the last state change
+    timestamp: u64,
+    state: RequestState,
+}
+
+/// Update requests by idempotency key, stored as TOML
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RequestJournal {
+    #[serde(default)]
+    requests: BTreeMap<String, RequestRecord>,
+}
+
+impl RequestJournal {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self).map_err(|e| Error::Configuration(e.to_string()))?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// The running update's key and what the journal knew about it beforehand
+pub(super) struct KeyedRequest {
+    key: String,
+    previous: Option<RequestRecord>,
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Looks the idempotency key up before the device is touched; a suspended
+    /// attempt is continued from its resume token
+    pub(super) fn recall_request(&mut self) -> Result<Option<KeyedRequest>> {
+        let (Some(key), Some(path)) = (&self.config.idempotency_key, &self.config.request_journal) else {
+            return Ok(None);
+        };
+
+        let previous = RequestJournal::load(Path::new(path))?.requests.remove(key);
+        if let Some(RequestRecord { state: RequestState::Suspended { token }, .. }) = &previous {
+            if self.config.resume_token.is_none() {
+                info!("Request {} was suspended, resuming it", key);
+                self.config.resume_token = Some(ResumeToken::decode(token)?);
+            }
+        }
+        Ok(Some(KeyedRequest { key: key.clone(), previous }))
+    }
+
+    /// Decides from the journal and the device whether the image must be written
+    pub(super) async fn replay(&mut self, request: &KeyedRequest, firmware: &FirmwareImage) -> Result<Replay> {
+        let Some(previous) = &request.previous else {
+            return Ok(Replay::Fresh);
+        };
+        if previous.uri != self.config.uri
+            || previous.image_crc != firmware.crc32()
+            || previous.image_len != firmware.len()
+        {
+            return Err(Error::Configuration(format!(
+                "Idempotency key {} was used for a different update",
+                request.key
+            )));
+        }
+
+        let replay = match previous.state {
+            RequestState::Started => Replay::Restarted,
+            RequestState::Suspended { .. } => Replay::Resumed,
+            RequestState::Completed { address, size, crc } => {
+                if self.read_firmware_crc(address, size).await? == crc {
+                    Replay::Skipped
+                } else {
+                    warn!("Device no longer holds the image written for request {}", request.key);
+                    Replay::Restarted
+                }
+            }
+        };
+        info!("Request {} seen before: {:?}", request.key, replay);
+        Ok(replay)
+    }
+
+    pub(super) fn journal_started(&self, request: &KeyedRequest, firmware: &FirmwareImage) -> Result<()> {
+        self.journal(request, firmware, RequestState::Started)
+    }
+
+    pub(super) fn journal_suspended(
+        &self,
+        request: &KeyedRequest,
+        firmware: &FirmwareImage,
+        token: &ResumeToken,
+    ) -> Result<()> {
+        self.journal(request, firmware, RequestState::Suspended { token: token.encode() })
+    }
+
+    pub(super) fn journal_completed(
+        &self,
+        request: &KeyedRequest,
+        firmware: &FirmwareImage,
+        report: &UpdateReport,
+    ) -> Result<()> {
+        let region = report.regions
+            .iter()
+            .find(|region| region.kind == RegionKind::Firmware)
+            .or_else(|| report.regions.first());
+        let Some(region) = region else {
+            return Ok(());
+        };
+        let state = RequestState::Completed { address: region.address, size: region.size, crc: region.crc };
+        self.journal(request, firmware, state)
+    }
+
+    fn journal(&self, request: &KeyedRequest, firmware: &FirmwareImage, state: RequestState) -> Result<()> {
+        let Some(path) = &self.config.request_journal else {
+            return Ok(());
+        };
+        let path = Path::new(path);
+        let mut journal = RequestJournal::load(path)?;
+        journal.requests.insert(request.key.clone(), RequestRecord {
+            uri: self.config.uri.clone(),
+            image_crc: firmware.crc32(),
+            image_len: firmware.len(),
+            timestamp: unix_now(),
+            state,
+        });
+        journal.save(path)
+    }
+}
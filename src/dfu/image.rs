@@ -0,0 +1,625 @@
+use std::ops::Range;
+use std::path::Path;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use super::calculate_crc32;
+use super::compression::read_firmware;
+use super::container::{self, ContainerHeader, FirmwareContainer};
+use super::dfuse::{self, DfuFile};
+use super::elf;
+use super::types::InfoBlockV2;
+
+/// Gap value for images created without one
+pub const DEFAULT_FILL: u8 = 0xFF;
+/// Data bytes per record when writing Intel HEX
+#[cfg(feature = "ihex")]
+const HEX_RECORD_LEN: usize = 16;
+
+/// On-disk firmware file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareFormat {
+    /// Detected from the file contents
+    #[default]
+    Auto,
+    #[serde(rename = "hex")]
+    IntelHex,
+    Srec,
+    Elf,
+    #[serde(rename = "bin")]
+    Binary,
+    /// DFU suffix file, optionally a DfuSe container
+    Dfu,
+    /// Image wrapped with a header naming its target device
+    #[serde(rename = "fwc")]
+    Container,
+}
+
+impl FirmwareFormat {
+    /// Format recognised from magic bytes or record structure; anything else
+    /// is taken to be a raw binary
+    pub fn detect(data: &[u8]) -> Self {
+        if container::is_container(data) {
+            return FirmwareFormat::Container;
+        }
+        if elf::is_elf(data) {
+            return FirmwareFormat::Elf;
+        }
+        if dfuse::has_suffix(data) {
+            return FirmwareFormat::Dfu;
+        }
+
+        let Ok(text) = std::str::from_utf8(data) else {
+            return FirmwareFormat::Binary;
+        };
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+        let Some(first) = lines.peek().copied() else {
+            return FirmwareFormat::Binary;
+        };
+        let is_record = |line: &str, marker: char, digits: usize| {
+            line.starts_with(marker)
+                && line.len() > digits
+                && line[1..].bytes().all(|b| b.is_ascii_hexdigit())
+        };
+
+        if first.starts_with(':') && lines.all(|line| is_record(line, ':', 10)) {
+            FirmwareFormat::IntelHex
+        } else if first.starts_with('S') && lines.all(|line| is_record(line, 'S', 7)) {
+            FirmwareFormat::Srec
+        } else {
+            FirmwareFormat::Binary
+        }
+    }
+
+    /// Format implied by the extension; unknown extensions are read as Intel HEX
+    pub fn from_path(path: &Path) -> Self {
+        // Look through a compression suffix: `app.srec.gz` is an S-record file
+        let path = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz" | "xz") => Path::new(path.file_stem().unwrap_or_default()),
+            _ => path,
+        };
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match ext.as_deref() {
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => FirmwareFormat::Srec,
+            Some("elf" | "axf" | "out") => FirmwareFormat::Elf,
+            Some("bin") => FirmwareFormat::Binary,
+            Some("dfu") => FirmwareFormat::Dfu,
+            Some("fwc") => FirmwareFormat::Container,
+            _ => FirmwareFormat::IntelHex,
+        }
+    }
+}
+
+/// Contiguous run of image bytes at a device address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+
+    pub fn range(&self) -> Range<u32> {
+        self.address..self.end()
+    }
+}
+
+/// Layout and checksums of an image, gathered without a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInspection {
+    pub placed: bool,
+    /// Address range of every segment
+    pub ranges: Vec<Range<u32>>,
+    /// Unoccupied ranges between segments
+    pub gaps: Vec<Range<u32>>,
+    /// Bytes held in segments
+    pub size: usize,
+    /// Bytes from the first to the last address, gaps included
+    pub span: usize,
+    pub entry_point: Option<u32>,
+    /// CRC32 of the contiguous image, gaps read as `fill`
+    pub crc32: u32,
+    pub fill: u8,
+    pub container: Option<ContainerHeader>,
+}
+
+/// Firmware image as discrete segments in device address space.
+///
+/// Gaps between segments are never transferred; where a contiguous view is
+/// needed (CRCs, digests) they read as `fill`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareImage {
+    /// Address that offset 0 of the file maps to
+    base: u32,
+    /// Sorted, non-overlapping and non-adjacent
+    segments: Vec<Segment>,
+    fill: u8,
+    /// Whether `base` came from the file or a caller rather than defaulting to 0
+    placed: bool,
+    /// Start address recorded in the file, if any
+    entry_point: Option<u32>,
+    /// Header of the container the image was unwrapped from
+    container: Option<ContainerHeader>,
+}
+
+impl FirmwareImage {
+    pub fn new(base: u32, data: Vec<u8>) -> Self {
+        Self::unplaced(vec![(0, data)], DEFAULT_FILL).with_base(base)
+    }
+
+    /// Loads `path` in the given format, detecting it from the contents for `Auto`
+    pub fn load(path: impl AsRef<Path>, format: FirmwareFormat, fill: u8) -> Result<Self> {
+        Self::load_target(path, format, 0, fill)
+    }
+
+    /// Like [`Self::load`], taking image `target` from multi-target (DfuSe) files
+    pub fn load_target(
+        path: impl AsRef<Path>,
+        format: FirmwareFormat,
+        target: usize,
+        fill: u8,
+    ) -> Result<Self> {
+        Self::from_bytes(read_firmware(path.as_ref())?, format, target, fill)
+    }
+
+    /// Parses an image already in memory, e.g. embedded or downloaded at runtime
+    pub fn from_bytes(data: Vec<u8>, format: FirmwareFormat, target: usize, fill: u8) -> Result<Self> {
+        let format = match format {
+            FirmwareFormat::Auto => FirmwareFormat::detect(&data),
+            format => format,
+        };
+        match format {
+            FirmwareFormat::Auto | FirmwareFormat::IntelHex => Self::from_hex(&text(data)?, fill),
+            FirmwareFormat::Srec => Ok(Self::from_records(parse_srec(&text(data)?)?, fill)),
+            FirmwareFormat::Elf => Ok(Self::from_records(elf::load_segments(&data)?, fill)),
+            FirmwareFormat::Binary => Ok(Self::unplaced(vec![(0, data)], fill)),
+            FirmwareFormat::Dfu => Self::from_dfu(&DfuFile::parse(&data)?, target, fill),
+            FirmwareFormat::Container => {
+                let (header, payload) = FirmwareContainer::parse(&data)?.into_parts();
+                let image = Self::from_bytes(payload, header.format, target, fill)?;
+                Ok(Self { container: Some(header), ..image })
+            }
+        }
+    }
+
+    /// Parses an Intel HEX file into one segment per run of contiguous records.
+    ///
+    /// Files with extended linear/segment address records carry absolute
+    /// addresses and are placed; without them, record offsets are relative and
+    /// the image starts at address 0 until it is placed with [`Self::with_base`].
+    pub fn from_hex_file(path: impl AsRef<Path>, fill: u8) -> Result<Self> {
+        let data = read_firmware(path.as_ref())?;
+        Self::from_hex(&text(data)?, fill)
+    }
+
+    #[cfg(feature = "ihex")]
+    fn from_hex(content: &str, fill: u8) -> Result<Self> {
+        let mut records = Vec::new();
+        let mut upper: Option<u32> = None;
+        let mut entry_point = None;
+        for record in ihex::Reader::new(content) {
+            match record.map_err(Error::HexFileError)? {
+                ihex::Record::Data { offset, value } => {
+                    records.push((upper.unwrap_or(0) + offset as u32, value));
+                }
+                ihex::Record::ExtendedLinearAddress(high) => upper = Some((high as u32) << 16),
+                ihex::Record::ExtendedSegmentAddress(segment) => upper = Some((segment as u32) << 4),
+                ihex::Record::StartLinearAddress(address) => entry_point = Some(address),
+                ihex::Record::StartSegmentAddress { cs, ip } => {
+                    entry_point = Some(((cs as u32) << 4) + ip as u32);
+                }
+                ihex::Record::EndOfFile => break,
+            }
+        }
+
+        let image = match upper {
+            Some(_) => Self::from_records(records, fill),
+            None => Self::unplaced(records, fill),
+        };
+        Ok(image.with_entry_point(entry_point))
+    }
+
+    #[cfg(not(feature = "ihex"))]
+    fn from_hex(_content: &str, _fill: u8) -> Result<Self> {
+        Err(Error::Configuration("Intel HEX support needs the `ihex` feature".into()))
+    }
+
+    /// Parses a Motorola S-record file (S1/S2/S3 data records).
+    ///
+    /// Records carry absolute addresses, so the image starts at the lowest one.
+    pub fn from_srec_file(path: impl AsRef<Path>, fill: u8) -> Result<Self> {
+        let data = read_firmware(path.as_ref())?;
+        Ok(Self::from_records(parse_srec(&text(data)?)?, fill))
+    }
+
+    /// Builds the flash image from the loadable segments of an ELF linker output,
+    /// placed at their load addresses (LMA)
+    pub fn from_elf_file(path: impl AsRef<Path>, fill: u8) -> Result<Self> {
+        let data = read_firmware(path.as_ref())?;
+        Ok(Self::from_records(elf::load_segments(&data)?, fill))
+    }
+
+    /// Extracts `target` from a `.dfu` file. DfuSe elements are placed at their
+    /// addresses; a plain DFU payload is unplaced like a raw binary.
+    pub fn from_dfu_file(path: impl AsRef<Path>, target: usize, fill: u8) -> Result<Self> {
+        let data = read_firmware(path.as_ref())?;
+        Self::from_dfu(&DfuFile::parse(&data)?, target, fill)
+    }
+
+    fn from_dfu(file: &DfuFile, target: usize, fill: u8) -> Result<Self> {
+        let elements = file.target(target)?.elements.clone();
+        if file.is_dfuse() {
+            Ok(Self::from_records(elements, fill))
+        } else {
+            Ok(Self::unplaced(elements, fill))
+        }
+    }
+
+    /// Records with absolute addresses; the image starts at the lowest one
+    pub(super) fn from_records(records: Vec<(u32, Vec<u8>)>, fill: u8) -> Self {
+        let segments = merge_records(records);
+        let base = segments.first().map_or(0, |segment| segment.address);
+        Self {
+            base,
+            segments,
+            fill,
+            placed: true,
+            entry_point: None,
+            container: None,
+        }
+    }
+
+    /// Records with offsets relative to a base that is decided later
+    fn unplaced(records: Vec<(u32, Vec<u8>)>, fill: u8) -> Self {
+        Self {
+            base: 0,
+            segments: merge_records(records),
+            fill,
+            placed: false,
+            entry_point: None,
+            container: None,
+        }
+    }
+
+    /// Loads a raw binary (e.g. `objcopy -O binary` output) as-is
+    pub fn from_bin_file(path: impl AsRef<Path>) -> Result<Self> {
+        let data = read_firmware(path.as_ref())?;
+        Ok(Self::unplaced(vec![(0, data)], DEFAULT_FILL))
+    }
+
+    /// Moves the image so that file offset 0 (or the lowest file address) is at `base`
+    pub fn with_base(mut self, base: u32) -> Self {
+        for segment in &mut self.segments {
+            segment.address = base.wrapping_add(segment.address.wrapping_sub(self.base));
+        }
+        self.base = base;
+        self.placed = true;
+        self
+    }
+
+    #[cfg(feature = "ihex")]
+    fn with_entry_point(mut self, entry_point: Option<u32>) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    /// Drops trailing `fill` bytes
+    pub fn trim_end(mut self, fill: u8) -> Self {
+        while let Some(last) = self.segments.last_mut() {
+            let len = last.data.iter().rposition(|b| *b != fill).map_or(0, |i| i + 1);
+            last.data.truncate(len);
+            if !last.data.is_empty() {
+                break;
+            }
+            self.segments.pop();
+        }
+        self
+    }
+
+    /// Extends the image with `fill` so it ends at `end`; never shortens it
+    pub fn pad_to(mut self, end: u32, fill: u8) -> Self {
+        match self.segments.last_mut() {
+            Some(last) if end > last.end() => {
+                let len = (end - last.address) as usize;
+                last.data.resize(len, fill);
+            }
+            Some(_) => {}
+            None if end > self.base => {
+                self.segments.push(Segment {
+                    address: self.base,
+                    data: vec![fill; (end - self.base) as usize],
+                });
+            }
+            None => {}
+        }
+        self
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// True when the image's address came from the file (e.g. SREC) or was set explicitly
+    pub fn is_placed(&self) -> bool {
+        self.placed
+    }
+
+    /// Start address from the file (Intel HEX start address records)
+    pub fn entry_point(&self) -> Option<u32> {
+        self.entry_point
+    }
+
+    /// Release metadata, for images loaded from a container
+    pub fn container(&self) -> Option<&ContainerHeader> {
+        self.container.as_ref()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// First address past the last segment
+    pub fn end(&self) -> u32 {
+        self.segments.last().map_or(self.base, Segment::end)
+    }
+
+    /// Span from `base` to the end of the last segment, gaps included
+    pub fn len(&self) -> usize {
+        self.end().saturating_sub(self.base) as usize
+    }
+
+    /// Bytes actually held in segments, i.e. what gets transferred
+    pub fn data_len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn fill(&self) -> u8 {
+        self.fill
+    }
+
+    /// Feeds the image from `base` to its end to `sink`, gaps as `fill`,
+    /// without materialising the whole span
+    pub fn for_each_chunk(&self, mut sink: impl FnMut(&[u8])) {
+        const GAP_CHUNK: usize = 4096;
+        let gap = [self.fill; GAP_CHUNK];
+        let mut position = self.base;
+        for segment in &self.segments {
+            let mut remaining = segment.address.saturating_sub(position) as usize;
+            while remaining > 0 {
+                let len = remaining.min(GAP_CHUNK);
+                sink(&gap[..len]);
+                remaining -= len;
+            }
+            sink(&segment.data);
+            position = segment.end();
+        }
+    }
+
+    /// Contiguous bytes from `base` to the end, gaps filled
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        self.for_each_chunk(|chunk| bytes.extend_from_slice(chunk));
+        bytes
+    }
+
+    /// Intel HEX text with absolute addresses, starting an extended linear
+    /// address record wherever the upper 16 address bits change
+    #[cfg(feature = "ihex")]
+    pub fn to_hex(&self) -> Result<String> {
+        let mut records = Vec::new();
+        let mut upper = None;
+        for segment in &self.segments {
+            let mut address = segment.address;
+            let mut rest = segment.data.as_slice();
+            while !rest.is_empty() {
+                let high = (address >> 16) as u16;
+                if upper != Some(high) {
+                    records.push(ihex::Record::ExtendedLinearAddress(high));
+                    upper = Some(high);
+                }
+                // Data records can't cross a 64 KiB boundary
+                let to_boundary = 0x1_0000 - (address & 0xFFFF) as usize;
+                let (chunk, tail) = rest.split_at(rest.len().min(HEX_RECORD_LEN).min(to_boundary));
+                records.push(ihex::Record::Data { offset: address as u16, value: chunk.to_vec() });
+                address += chunk.len() as u32;
+                rest = tail;
+            }
+        }
+        if let Some(entry_point) = self.entry_point {
+            records.push(ihex::Record::StartLinearAddress(entry_point));
+        }
+        records.push(ihex::Record::EndOfFile);
+
+        ihex::create_object_file_representation(&records)
+            .map_err(|e| Error::Configuration(format!("Cannot write Intel HEX: {}", e)))
+    }
+
+    #[cfg(not(feature = "ihex"))]
+    pub fn to_hex(&self) -> Result<String> {
+        Err(Error::Configuration("Intel HEX support needs the `ihex` feature".into()))
+    }
+
+    /// Writes the image as a raw binary for `.bin` paths, Intel HEX otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        match FirmwareFormat::from_path(path) {
+            FirmwareFormat::Binary => std::fs::write(path, self.to_bytes())?,
+            _ => std::fs::write(path, self.to_hex()?)?,
+        }
+        Ok(())
+    }
+
+    /// Image truncated or padded with `fill` to exactly `len` bytes from `base`
+    pub fn normalized(&self, len: usize, fill: u8) -> Vec<u8> {
+        let mut bytes = self.bytes_in(self.base..self.base.saturating_add(len as u32), fill);
+        bytes.resize(len, fill);
+        bytes
+    }
+
+    /// Byte sequence the device CRCs for its firmware region.
+    ///
+    /// The window is bounded by the firmware region of the memory map and any
+    /// part of the metadata region that overlaps it is left out, since the
+    /// bootloader skips its own metadata when computing `ReadProgramCrc`.
+    pub fn normalized_for_device(&self, info: &InfoBlockV2, fill: u8) -> Vec<u8> {
+        let memmap = &info.memmap;
+        let (fw_address, fw_size) = (memmap.firmware_address, memmap.firmware_size);
+        let (md_address, md_size) = (memmap.metadata_address, memmap.metadata_size);
+        self.window_bytes(fw_address..fw_address + fw_size, md_address..md_address + md_size, fill)
+    }
+
+    /// CRC32 the device is expected to report for this image
+    pub fn device_crc(&self, info: &InfoBlockV2, fill: u8) -> u32 {
+        calculate_crc32(&self.normalized_for_device(info, fill))
+    }
+
+    /// Like [`Self::device_crc`], for a firmware and metadata region known
+    /// without a live session (e.g. from an inventory scan)
+    pub fn window_crc(&self, firmware: Range<u32>, metadata: Range<u32>, fill: u8) -> u32 {
+        calculate_crc32(&self.window_bytes(firmware, metadata, fill))
+    }
+
+    fn window_bytes(&self, firmware: Range<u32>, metadata: Range<u32>, fill: u8) -> Vec<u8> {
+        let window = self.bytes_in(firmware.clone(), fill);
+
+        let md_start = metadata.start.max(firmware.start);
+        let md_end = metadata.end.min(firmware.end);
+        if md_start >= md_end {
+            return window;
+        }
+
+        let (start, end) = ((md_start - firmware.start) as usize, (md_end - firmware.start) as usize);
+        let mut bytes = window[..start].to_vec();
+        bytes.extend_from_slice(&window[end..]);
+        bytes
+    }
+
+    /// CRC32 of the contiguous image, gaps included
+    pub fn crc32(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        self.for_each_chunk(|chunk| hasher.update(chunk));
+        hasher.finalize()
+    }
+
+    pub fn inspect(&self) -> ImageInspection {
+        let ranges: Vec<Range<u32>> = self.segments.iter().map(Segment::range).collect();
+        let gaps = ranges
+            .windows(2)
+            .map(|pair| pair[0].end..pair[1].start)
+            .collect();
+        ImageInspection {
+            placed: self.placed,
+            ranges,
+            gaps,
+            size: self.data_len(),
+            span: self.len(),
+            entry_point: self.entry_point,
+            crc32: self.crc32(),
+            fill: self.fill,
+            container: self.container.clone(),
+        }
+    }
+
+    /// Exactly the bytes of `range`, `fill` wherever no segment covers it
+    fn bytes_in(&self, range: Range<u32>, fill: u8) -> Vec<u8> {
+        let mut bytes = vec![fill; range.len()];
+        for segment in &self.segments {
+            let start = segment.address.max(range.start);
+            let end = segment.end().min(range.end);
+            if start >= end {
+                continue;
+            }
+            let (from, to) = ((start - range.start) as usize, (end - range.start) as usize);
+            let offset = (start - segment.address) as usize;
+            bytes[from..to].copy_from_slice(&segment.data[offset..offset + (to - from)]);
+        }
+        bytes
+    }
+}
+
+/// Sorts records into segments, joining ones that touch; later records win
+/// where they overlap
+fn merge_records(mut records: Vec<(u32, Vec<u8>)>) -> Vec<Segment> {
+    records.sort_by_key(|(address, _)| *address);
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for (address, data) in records {
+        if data.is_empty() {
+            continue;
+        }
+        match segments.last_mut() {
+            Some(last) if address <= last.end() => {
+                let offset = (address - last.address) as usize;
+                let end = offset + data.len();
+                if end > last.data.len() {
+                    last.data.resize(end, 0);
+                }
+                last.data[offset..end].copy_from_slice(&data);
+            }
+            _ => segments.push(Segment { address, data }),
+        }
+    }
+    segments
+}
+
+/// Text record formats must be valid UTF-8
+fn text(data: Vec<u8>) -> Result<String> {
+    String::from_utf8(data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into())
+}
+
+/// Extracts `(address, data)` from every S1/S2/S3 record, checking checksums
+fn parse_srec(content: &str) -> Result<Vec<(u32, Vec<u8>)>> {
+    let mut records = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason| Error::SrecError { line: index + 1, reason };
+
+        let record_type = line
+            .strip_prefix('S')
+            .and_then(|rest| rest.chars().next())
+            .ok_or_else(|| error("missing 'S' record marker"))?;
+        let address_len = match record_type {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            // Header, count and start address records carry no image data
+            '0' | '5' | '6' | '7' | '8' | '9' => continue,
+            _ => return Err(error("unknown record type")),
+        };
+
+        let bytes = super::signing::from_hex(&line[2..])
+            .map_err(|_| error("invalid hex digits"))?;
+        let (count, body) = bytes.split_first().ok_or_else(|| error("missing byte count"))?;
+        if body.len() != *count as usize || body.len() < address_len + 1 {
+            return Err(error("byte count does not match record length"));
+        }
+
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(error("checksum mismatch"));
+        }
+
+        let address = body[..address_len]
+            .iter()
+            .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        records.push((address, body[address_len..body.len() - 1].to_vec()));
+    }
+
+    Ok(records)
+}
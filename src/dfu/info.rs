@@ -0,0 +1,264 @@
+use crate::error::{Error, Result};
+use super::banner::BootloaderBanner;
+use super::region::MemoryRegion;
+use super::types::{DeviceId, DeviceMemoryMap, InfoBlockV2, Region};
+
+pub const DIAGNOSTICS_BLOCK_SIZE: usize = 16;
+pub const INFO_BLOCK_SIZE: usize = size_of::<InfoBlockV2>();
+
+/// Bit in the first reserved info byte announcing 64-bit request packets
+pub const PROTOCOL_FLAG_ADDR64: u8 = 0x01;
+pub const PROTOCOL_FLAG_CAPABILITIES: u8 = 0x02;
+const KNOWN_PROTOCOL_FLAGS: u8 = PROTOCOL_FLAG_ADDR64 | PROTOCOL_FLAG_CAPABILITIES;
+
+/// Info block versions this host understands
+pub const SUPPORTED_INFO_VERSIONS: std::ops::RangeInclusive<u8> = 0x20..=0x3F;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetCause {
+    Unknown,
+    PowerOn,
+    Brownout,
+    Watchdog,
+    Software,
+    ExternalPin,
+    Lockup,
+    Other(u8),
+}
+
+impl From<u8> for ResetCause {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Unknown,
+            1 => Self::PowerOn,
+            2 => Self::Brownout,
+            3 => Self::Watchdog,
+            4 => Self::Software,
+            5 => Self::ExternalPin,
+            6 => Self::Lockup,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Health data reported by the bootloader's `ReadDiagnostics` command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics {
+    pub supply_voltage_mv: u16,
+    pub temperature_c: f32,
+    pub reset_cause: ResetCause,
+    pub flash_erase_count: u32,
+    pub flash_write_count: u32,
+}
+
+impl Diagnostics {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < DIAGNOSTICS_BLOCK_SIZE {
+            return Err(Error::TruncatedResponse {
+                expected: DIAGNOSTICS_BLOCK_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        // Temperature is reported in tenths of a degree
+        let temperature = i16::from_le_bytes([data[2], data[3]]);
+
+        Ok(Self {
+            supply_voltage_mv: u16::from_le_bytes([data[0], data[1]]),
+            temperature_c: temperature as f32 / 10.0,
+            reset_cause: ResetCause::from(data[4]),
+            flash_erase_count: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+            flash_write_count: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+        })
+    }
+
+    /// Returns a description of every reading outside the given limits
+    pub fn marginal_reasons(&self, limits: &DiagnosticLimits) -> Vec<String> {
+        let mut reasons = Vec::new();
+
+        if self.supply_voltage_mv < limits.min_supply_voltage_mv {
+            reasons.push(format!(
+                "Supply voltage {} mV below {} mV",
+                self.supply_voltage_mv, limits.min_supply_voltage_mv
+            ));
+        }
+
+        if self.temperature_c > limits.max_temperature_c {
+            reasons.push(format!(
+                "Temperature {:.1} C above {:.1} C",
+                self.temperature_c, limits.max_temperature_c
+            ));
+        }
+
+        if self.flash_erase_count > limits.max_flash_erase_count {
+            reasons.push(format!(
+                "Flash erase count {} above {}",
+                self.flash_erase_count, limits.max_flash_erase_count
+            ));
+        }
+
+        if matches!(self.reset_cause, ResetCause::Brownout | ResetCause::Lockup) {
+            reasons.push(format!("Last reset caused by {:?}", self.reset_cause));
+        }
+
+        reasons
+    }
+
+    pub fn is_marginal(&self, limits: &DiagnosticLimits) -> bool {
+        !self.marginal_reasons(limits).is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiagnosticLimits {
+    pub min_supply_voltage_mv: u16,
+    pub max_temperature_c: f32,
+    pub max_flash_erase_count: u32,
+}
+
+impl Default for DiagnosticLimits {
+    fn default() -> Self {
+        Self {
+            min_supply_voltage_mv: 3000,
+            max_temperature_c: 85.0,
+            max_flash_erase_count: 10_000,
+        }
+    }
+}
+
+/// Device information reported by the bootloader
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub bootloader_version: u8,
+    pub max_block_size: u16,
+    pub device_id: u16,
+    pub device_rev: u16,
+    pub uid: [u8; 16],
+    pub flash_address: u32,
+    pub flash_size: u32,
+    /// Firmware region, then the metadata region if the device has one
+    pub memory_map: Vec<MemoryRegion>,
+    pub diagnostics: Option<Diagnostics>,
+    /// Parsed entry banner, when a banner parser is configured
+    pub banner: Option<BootloaderBanner>,
+}
+
+impl From<&InfoBlockV2> for DeviceInfo {
+    fn from(info: &InfoBlockV2) -> Self {
+        Self {
+            bootloader_version: info.version,
+            max_block_size: info.max_block_size,
+            device_id: info.device.id,
+            device_rev: info.device.rev,
+            uid: info.device.uid,
+            flash_address: info.memmap.flash_address,
+            flash_size: info.memmap.flash_size,
+            memory_map: info.memmap.memory_regions(),
+            diagnostics: None,
+            banner: None,
+        }
+    }
+}
+
+impl InfoBlockV2 {
+    /// Parses the block as the bootloader sends it, little-endian and unpadded
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < INFO_BLOCK_SIZE {
+            return Err(Error::TruncatedResponse {
+                expected: INFO_BLOCK_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let mut cursor = data;
+        let mut take = |n: usize| {
+            let (head, rest) = cursor.split_at(n);
+            cursor = rest;
+            head
+        };
+        let u16_at = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]);
+        let u32_at = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        let version = take(1)[0];
+        let max_block_size = u16_at(take(2));
+        let device = DeviceId {
+            id: u16_at(take(2)),
+            rev: u16_at(take(2)),
+            uid: take(16).try_into().expect("16 byte slice"),
+        };
+        let unused = take(18).try_into().expect("18 byte slice");
+        let memmap = DeviceMemoryMap {
+            metadata_address: u32_at(take(4)),
+            metadata_size: u32_at(take(4)),
+            firmware_address: u32_at(take(4)),
+            firmware_size: u32_at(take(4)),
+            flash_address: u32_at(take(4)),
+            flash_size: u32_at(take(4)),
+            flash_write_blocksize: u16_at(take(2)),
+            regions: std::array::from_fn(|_| Region {
+                count: u32_at(take(4)),
+                size: u32_at(take(4)),
+            }),
+        };
+
+        memmap.check_ranges()?;
+        Ok(Self { version, max_block_size, device, unused, memmap })
+    }
+
+    pub fn protocol_flags(&self) -> u8 {
+        self.unused[0]
+    }
+
+    pub fn supports_addr64(&self) -> bool {
+        self.protocol_flags() & PROTOCOL_FLAG_ADDR64 != 0
+    }
+
+    pub fn supports_capabilities(&self) -> bool {
+        self.protocol_flags() & PROTOCOL_FLAG_CAPABILITIES != 0
+    }
+
+    /// Lists everything in the info block the host does not know how to interpret
+    pub fn unsupported_fields(&self) -> Vec<String> {
+        let mut unsupported = Vec::new();
+
+        let version = self.version;
+        if !SUPPORTED_INFO_VERSIONS.contains(&version) {
+            unsupported.push(format!("info block version {:#04x}", version));
+        }
+
+        let unknown_flags = self.protocol_flags() & !KNOWN_PROTOCOL_FLAGS;
+        if unknown_flags != 0 {
+            unsupported.push(format!("protocol flags {:#04x}", unknown_flags));
+        }
+
+        // Remaining reserved bytes are zero on every bootloader we know of
+        if let Some(pos) = self.unused[1..].iter().position(|b| *b != 0) {
+            unsupported.push(format!("reserved info byte {} is {:#04x}", pos + 1, self.unused[pos + 1]));
+        }
+
+        let memmap = &self.memmap;
+        let mut seen_empty = false;
+        for (i, region) in memmap.regions.iter().enumerate() {
+            let (count, size) = (region.count, region.size);
+            if count == 0 {
+                seen_empty = true;
+                continue;
+            }
+            if seen_empty {
+                unsupported.push(format!("flash region {} follows an empty region", i));
+            }
+            if !size.is_power_of_two() {
+                unsupported.push(format!("flash region {} sector size {:#x}", i, size));
+            }
+        }
+
+        let firmware_address = memmap.firmware_address;
+        if memmap.sector_size_at(firmware_address) == 0 {
+            unsupported.push(format!(
+                "firmware address {:#010x} outside the flash region table", firmware_address
+            ));
+        }
+
+        unsupported
+    }
+}
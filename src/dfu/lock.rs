@@ -0,0 +1,52 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+
+/// Advisory lock held for the lifetime of an update session, so two processes
+/// can't interleave frames on the same device. Released on drop, or by the OS
+/// if the process dies.
+#[derive(Debug)]
+pub struct SessionLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl SessionLock {
+    /// Locks `uri`, failing immediately if another session holds it
+    pub fn acquire(uri: &str) -> Result<Option<Self>> {
+        // Placeholder URIs (e.g. "stream") don't identify a device
+        if !uri.contains("://") {
+            return Ok(None);
+        }
+        Self::acquire_device(uri).map(Some)
+    }
+
+    fn acquire_device(uri: &str) -> Result<Self> {
+        let path = lock_path(uri);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file, path }),
+            Err(TryLockError::WouldBlock) => Err(Error::DeviceBusyLocked { uri: uri.to_string() }),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// One lock file per URI in the system temp directory
+fn lock_path(uri: &str) -> PathBuf {
+    let name: String = uri
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    std::env::temp_dir().join(format!("fwupd-{}.lock", name))
+}
@@ -0,0 +1,56 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use log::{debug, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use tokio::time::{timeout_at, Instant};
+
+use crate::error::{Error, Result};
+use super::discovery::UriCandidate;
+
+/// DNS-SD service type networked bootloaders and gateways advertise
+pub const DFU_SERVICE_TYPE: &str = "_dfu._tcp.local.";
+/// TXT key carrying the device serial number
+const SERIAL_PROPERTY: &str = "serial";
+
+/// Browses for `_dfu._tcp` services for `window` and lists each as a
+/// `tcp://` URI; IPv4 addresses are preferred
+pub async fn network_uri_candidates(window: Duration) -> Result<Vec<UriCandidate>> {
+    let daemon = ServiceDaemon::new().map_err(|e| Error::Connection(format!("mDNS: {}", e)))?;
+    let events = daemon
+        .browse(DFU_SERVICE_TYPE)
+        .map_err(|e| Error::Connection(format!("mDNS: {}", e)))?;
+
+    let deadline = Instant::now() + window;
+    let mut candidates: Vec<UriCandidate> = Vec::new();
+    while let Ok(Ok(event)) = timeout_at(deadline, events.recv_async()).await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        let addresses = service.get_addresses();
+        let Some(address) = addresses.iter().find(|a| a.is_ipv4()).or_else(|| addresses.iter().next()) else {
+            continue;
+        };
+        let uri = match address {
+            IpAddr::V4(v4) => format!("tcp://{}:{}", v4, service.get_port()),
+            IpAddr::V6(v6) => format!("tcp://[{}]:{}", v6, service.get_port()),
+        };
+        if candidates.iter().any(|candidate| candidate.uri == uri) {
+            continue;
+        }
+
+        let name = service.get_fullname().trim_end_matches(DFU_SERVICE_TYPE).trim_end_matches('.');
+        debug!("mDNS: {} at {}", name, uri);
+        candidates.push(UriCandidate {
+            uri,
+            description: format!("Network device {}", name),
+            vid: None,
+            pid: None,
+            serial_number: service.get_property_val_str(SERIAL_PROPERTY).map(str::to_string),
+        });
+    }
+
+    // The daemon's reply only confirms the shutdown; nothing to act on
+    let _ = daemon.shutdown();
+    info!("mDNS: {} device(s) advertising {}", candidates.len(), DFU_SERVICE_TYPE);
+    Ok(candidates)
+}
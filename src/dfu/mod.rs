@@ -1,109 +1,777 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::sleep;
-use tokio_stream::StreamExt;
-use bytes::BytesMut;
-use log::{info, error, warn};
+use tokio::io::AsyncReadExt;
+use log::{debug, info, error, warn};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+// tokio's clock, so tests running with paused time skip delays deterministically
+use tokio::time::{sleep, timeout, Instant};
 
 use crate::protocols::{apl, lpl};
-use crate::error::Result;
+use crate::protocols::channel::ChannelError;
+use crate::protocols::stats::{ErrorStats, ProtocolErrorKind};
+use crate::error::{Checksum, Error, Result};
+use crate::transport::DfuTransport;
 
+mod archive;
+mod backup;
+mod banner;
+mod budget;
+mod bundle;
+mod cache;
+mod capabilities;
+mod completion;
+mod compression;
 mod config;
+mod conformance;
+mod console;
+mod container;
+mod dfuse;
+mod discovery;
+mod download;
+mod elf;
+mod entry;
+mod fault;
+mod fleet;
+mod idempotency;
+mod image;
+mod info;
+mod lock;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "power-switch")]
+mod power;
+mod profile;
+mod quirks;
+mod reconnect;
+mod region;
+mod registry;
+mod report;
+mod resume;
+mod rollout;
+mod scan;
+#[cfg(feature = "tcp")]
+mod serve;
+mod selection;
+mod session;
+mod signing;
+mod simulator;
+#[cfg(feature = "ssdp")]
+mod ssdp;
+mod state;
+mod streaming;
+mod support;
+mod tags;
+mod throughput;
 mod types;
+mod verify;
 
-pub use config::*;
+pub use archive::*;
+pub use backup::*;
+pub use banner::*;
+pub use budget::*;
+pub use bundle::*;
+pub use cache::*;
+pub use capabilities::*;
+pub use completion::*;
+pub use conformance::*;
+pub use console::*;
+pub use container::*;
+pub use dfuse::*;
+pub use discovery::*;
+pub use entry::*;
+pub use fault::*;
+pub use fleet::*;
+pub use idempotency::*;
+pub use image::*;
+pub use info::*;
+pub use lock::*;
+#[cfg(feature = "mdns")]
+pub use mdns::*;
+#[cfg(feature = "power-switch")]
+pub use power::*;
+pub use profile::*;
+pub use quirks::*;
+pub use reconnect::*;
+pub use region::*;
+pub use registry::*;
+pub use report::*;
+pub use resume::*;
+pub use rollout::*;
+pub use scan::*;
+#[cfg(feature = "tcp")]
+pub use serve::*;
+pub use selection::*;
+pub use session::*;
+pub use signing::*;
+pub use simulator::*;
+#[cfg(feature = "ssdp")]
+pub use ssdp::*;
+pub use state::*;
+pub use support::*;
+pub use tags::*;
+pub use throughput::*;
 pub use types::*;
+pub use verify::*;
 
 const MAX_RECONNECTION_ATTEMPTS: usize = 3;
+/// How long the bootloader may take to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct DfuStream<T> {
     stream: T,
+    _lock: Option<SessionLock>,
     config: DfuConfig,
     lpl: lpl::LplStream,
     apl: apl::AplStream,
-    buffer: BytesMut,
+    notifications: Option<mpsc::Receiver<apl::AplMessage>>,
+    /// Timeouts and short reads, which happen outside any frame
+    errors: ErrorStats,
+    /// A pipelined CRC request whose raw answer hasn't been read yet
+    crc_requested: bool,
+    /// A pipelined CRC answer read early, waiting to be checked
+    crc_received: Option<u32>,
+    console: Option<ConsoleCapture>,
+    console_task: Option<JoinHandle<()>>,
+    quirks: Quirks,
+    commands: CommandSet,
+    suspend: Arc<AtomicBool>,
+    transfer: watch::Sender<TransferState>,
+    state: watch::Sender<UpdateState>,
+    progress: watch::Sender<UpdateProgress>,
+    banner: Option<BootloaderBanner>,
+    budget: MemoryBudget,
+    /// Line speed last set, which a saved session resumes at
+    speed: Baud,
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
+impl<T: DfuTransport> DfuStream<T> {
     pub fn new(stream: T, config: DfuConfig) -> Result<Self> {
-        config.validate()?;
-        
-        let (apl, apl_tx) = apl::AplStream::new(1024);
-        let (lpl, _) = lpl::LplStream::new(1024, apl_tx);
+        config.validate().map_err(|e| Error::Configuration(e.into()))?;
+        let lock = SessionLock::acquire(&config.uri)?;
+
+        let budget = MemoryBudget::new(config.memory_limit)
+            .with_channel_capacity(config.channel_capacity);
+        let mut apl = apl::AplStream::new();
+        apl.set_ack_policy(config.ack_policy);
+        let (mut lpl, notifications) = lpl::LplStream::new(budget.channel_config());
+        if config.bus_addressing {
+            lpl.set_netid(Some(config.dev_netid as u8));
+        }
+        lpl.set_framing(config.framing);
+        let speed = config.lnk_speed;
 
         Ok(Self {
             stream,
+            _lock: lock,
             config,
             lpl,
             apl,
-            buffer: BytesMut::with_capacity(1024),
+            notifications: Some(notifications),
+            errors: ErrorStats::new(),
+            crc_requested: false,
+            crc_received: None,
+            console: None,
+            console_task: None,
+            quirks: Quirks::default(),
+            commands: CommandSet::default(),
+            suspend: Arc::new(AtomicBool::new(false)),
+            transfer: watch::channel(TransferState::Idle).0,
+            state: watch::channel(UpdateState::Idle).0,
+            progress: watch::channel(UpdateProgress::default()).0,
+            banner: None,
+            budget,
+            speed,
         })
     }
 
-    pub async fn update(&mut self) -> Result<()> {
-        info!("Starting firmware update process");
+    /// Handle for suspending the update from another task
+    pub fn handle(&self) -> UpdateHandle {
+        UpdateHandle::new(self.suspend.clone(), self.transfer.subscribe())
+    }
+
+    /// Follows the update through its phases, down to the block being written
+    pub fn state(&self) -> watch::Receiver<UpdateState> {
+        self.state.subscribe()
+    }
+
+    fn set_state(&self, state: UpdateState) {
+        self.state.send_replace(state);
+    }
+
+    /// Log and notification messages the device sends while blocks are
+    /// being acknowledged; only the first call gets the receiver. A receiver
+    /// that falls behind fails the update with a channel overflow.
+    pub fn notifications(&mut self) -> Option<mpsc::Receiver<apl::AplMessage>> {
+        self.notifications.take()
+    }
+
+    /// Logs device messages nobody took the receiver for, so an unwatched
+    /// queue never fills up
+    fn drain_notifications(&mut self) {
+        let Some(notifications) = self.notifications.as_mut() else {
+            return;
+        };
+        while let Ok(message) = notifications.try_recv() {
+            debug!("Device {:?}: {}", message.packet_type, String::from_utf8_lossy(&message.data));
+        }
+    }
+
+    /// Protocol errors counted so far, also after an update that failed
+    pub fn protocol_errors(&self) -> BTreeMap<ProtocolErrorKind, u64> {
+        let mut errors = BTreeMap::new();
+        for stats in [self.lpl.errors(), self.apl.errors(), &self.errors] {
+            for (kind, count) in stats.counts() {
+                *errors.entry(*kind).or_insert(0) += count;
+            }
+        }
+        errors
+    }
+
+    /// Follows the bytes written, for progress bars
+    pub fn progress(&self) -> watch::Receiver<UpdateProgress> {
+        self.progress.subscribe()
+    }
 
-        if self.config.upd_mode != UpdateMode::None {
-            self.auto_enter().await?;
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.config.cancellation {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
         }
+    }
+
+    fn set_progress_phase(&self, phase: Phase) {
+        self.progress.send_modify(|progress| progress.phase = Some(phase));
+    }
+
+    /// Attaches a capture whose tap wraps this stream's transport; it records
+    /// only while the device is being brought into the bootloader
+    pub fn attach_console(&mut self, capture: ConsoleCapture) {
+        capture.set_enabled(false);
+        if self.budget.limit().is_some() {
+            capture.set_limit(self.budget.console_limit());
+        }
+        self.console = Some(capture);
+    }
 
-        if self.config.get_info || self.config.update || self.config.verify {
-            let info = self.read_bootloader_info().await?;
-            self.log_device_info(&info);
+    pub async fn update(&mut self) -> Result<UpdateReport> {
+        self.transfer.send_replace(TransferState::Running);
+        let result = self.run_update().await;
+        self.suspend.store(false, Ordering::SeqCst);
+        // Stop the console reader however the update ended
+        if let Some(task) = self.console_task.take() {
+            task.abort();
+        }
 
-            if self.config.update || self.config.verify {
-                self.process_firmware(&info).await?;
+        let state = match &result {
+            Err(Error::Suspended(token) | Error::LinkLost { token, .. }) => {
+                TransferState::Suspended((**token).clone())
+            }
+            Err(Error::Cancelled) => {
+                // The image is incomplete and uncommitted, so the device
+                // stays in its bootloader for the next attempt
+                warn!("Update cancelled, device left in the bootloader");
+                TransferState::Finished
             }
+            _ => TransferState::Finished,
+        };
+        self.transfer.send_replace(state);
+        self.set_state(UpdateState::Idle);
+        result
+    }
+
+    async fn run_update(&mut self) -> Result<UpdateReport> {
+        info!("Starting firmware update process");
+        let mut report = UpdateReport::new();
+
+        // Parse the image before rebooting the device so a bad file can't
+        // leave the product stuck in the bootloader
+        let firmware = if self.config.update || self.config.verify {
+            Some(self.load_firmware().await?)
+        } else {
+            None
+        };
+
+        if let Some(path) = self.config.console_port.clone() {
+            let (capture, task) = ConsoleCapture::open_port(&path, self.config.dev_speed.bps())?;
+            capture.set_limit(self.budget.console_limit());
+            self.console = Some(capture);
+            self.console_task = Some(task);
+        }
+
+        let request = self.recall_request()?;
+        let resumed = self.resume_session().await?;
+
+        if self.config.upd_mode != UpdateMode::None && resumed.is_none() {
+            self.enter_bootloader(&mut report).await?;
         }
 
-        if self.config.quit {
-            self.quit_bootloader().await?;
+        let backup = self.config.backup_file.is_some();
+        if self.config.get_info || self.config.update || self.config.verify || backup {
+            let started = Instant::now();
+            let info = self.prepare_session(&mut report).await?;
+
+            let device = DeviceInfo::from(&info);
+            if self.config.expected_uid.is_some_and(|uid| uid != device.uid) {
+                return Err(Error::UnexpectedDevice { uid: device.uid.to_vec() });
+            }
+            let expected = self.config.resume_token.as_ref().map(ResumeToken::session).or(resumed.as_ref());
+            if let Some(session) = expected {
+                if !session.matches(&device) {
+                    return Err(Error::SessionMismatch);
+                }
+            }
+
+            let mut device = device;
+            device.banner = self.banner.clone();
+            if self.config.diagnostics {
+                if self.commands.contains(Command::ReadDiagnostics) {
+                    let diagnostics = self.read_diagnostics().await?;
+                    self.log_diagnostics(&diagnostics, &mut report);
+                    device.diagnostics = Some(diagnostics);
+                } else {
+                    self.note_unsupported(&mut report, Command::ReadDiagnostics, Fallback::DiagnosticsSkipped);
+                }
+            }
+            report.device = Some(device);
+            report.timings.add(Phase::Info, started.elapsed());
+
+            if let Some(path) = self.config.backup_file.clone() {
+                self.backup_firmware(&info, &path, &mut report).await?;
+            }
+
+            if let Some(firmware) = firmware {
+                let device = report.device.as_ref().expect("device info was just read");
+                // An explicit base wins, then addresses from the file itself
+                let firmware = firmware.select(device)?;
+                let firmware = match self.config.base_address {
+                    Some(base) => firmware.with_base(base),
+                    None if firmware.is_placed() => firmware,
+                    None => firmware.with_base(info.memmap.firmware_address),
+                };
+                let firmware = self.shape_firmware(firmware, &info);
+                self.validate_firmware(&firmware, &info)?;
+
+                if let Some(request) = &request {
+                    report.replay = Some(self.replay(request, &firmware).await?);
+                }
+                if report.replay == Some(Replay::Skipped) {
+                    info!("Update already applied, nothing to write");
+                } else {
+                    if let Some(dir) = self.config.archive_dir.clone().filter(|_| self.config.update) {
+                        self.archive_firmware(&dir, &info, &firmware, &mut report).await?;
+                    }
+                    self.write_firmware(&firmware, &info, request.as_ref(), &mut report).await?;
+                }
+            }
         }
 
-        if self.config.upd_mode != UpdateMode::None {
-            self.auto_exit().await?;
+        self.leave_bootloader(&mut report, resumed.is_some()).await?;
+
+        if let Some(task) = self.console_task.take() {
+            task.abort();
         }
+        if let Some(console) = &self.console {
+            report.console = Some(console.text());
+        }
+        self.collect_protocol_errors(&mut report);
 
+        info!("Phase timings:");
+        for (phase, elapsed) in report.timings.iter() {
+            info!("  {:?}: {:.3} s", phase, elapsed.as_secs_f64());
+        }
         info!("Firmware update completed successfully");
+        Ok(report)
+    }
+
+    /// Writes and verifies the image, recording progress under the request's idempotency key
+    async fn write_firmware(
+        &mut self,
+        firmware: &FirmwareImage,
+        info: &InfoBlockV2,
+        request: Option<&KeyedRequest>,
+        report: &mut UpdateReport,
+    ) -> Result<()> {
+        let resume_from = match &self.config.resume_token {
+            Some(token) if !token.matches_image(firmware) => {
+                return Err(Error::Configuration(
+                    "Resume token was issued for a different firmware image".into()
+                ));
+            }
+            Some(token) => token.next_address(),
+            None => 0,
+        };
+
+        if let Some(request) = request {
+            self.journal_started(request, firmware)?;
+        }
+        let suspended = match self.process_firmware(firmware, info, resume_from, report).await {
+            Ok(suspended) => suspended,
+            // The block that failed is written again once the link is back
+            Err(Error::BlockFailed { phase: Phase::Write, address, source, .. }) if source.is_link_loss() => {
+                warn!("Link lost writing {:#010x}: {}", address, source);
+                let token = self.resume_point(firmware, address, request, report)?;
+                return Err(Error::LinkLost { token: Box::new(token), source });
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(next_address) = suspended {
+            // Leave the device in the bootloader for whoever resumes
+            info!("Transfer suspended before {:#010x}", next_address);
+            let token = self.resume_point(firmware, next_address, request, report)?;
+            return Err(Error::Suspended(Box::new(token)));
+        }
+        if let Some(request) = request {
+            self.journal_completed(request, firmware, report)?;
+        }
+        if self.config.update {
+            self.track_wear(report)?;
+        }
+        Ok(())
+    }
+
+    /// Token continuing the transfer at `next_address`, journaled under the
+    /// request's idempotency key
+    fn resume_point(
+        &self,
+        firmware: &FirmwareImage,
+        next_address: u32,
+        request: Option<&KeyedRequest>,
+        report: &UpdateReport,
+    ) -> Result<ResumeToken> {
+        let device = report.device.as_ref().expect("device info is read before writing");
+        let session = SessionState::new(&self.config.uri, self.speed, device);
+        let token = ResumeToken::new(session, firmware, next_address);
+        if let Some(request) = request {
+            self.journal_suspended(request, firmware, &token)?;
+        }
+        Ok(token)
+    }
+
+    /// Enters the bootloader, dumping captured console output if that fails
+    async fn enter_bootloader(&mut self, report: &mut UpdateReport) -> Result<()> {
+        let started = Instant::now();
+        self.set_state(UpdateState::EnteringBootloader);
+        self.capture_entry_console(true);
+        let entered = self.auto_enter().await;
+        self.capture_entry_console(false);
+
+        if let Err(e) = entered {
+            if let Some(console) = &self.console {
+                error!("Console output during bootloader entry:\n{}", console.text());
+            }
+            return Err(e);
+        }
+        let elapsed = started.elapsed();
+        info!("Bootloader answered {} ms after entry started", elapsed.as_millis());
+        report.entry_time = Some(elapsed);
+        report.timings.add(Phase::Entry, elapsed);
+
+        // The bootloader is found at the link speed and updated at its own
+        if self.config.upd_speed != self.config.lnk_speed {
+            info!("Switching to {} for the update", self.config.upd_speed);
+            self.set_speed(self.config.upd_speed).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the info block and sets up quirks, capabilities and addressing from it
+    async fn prepare_session(&mut self, report: &mut UpdateReport) -> Result<InfoBlockV2> {
+        self.set_state(UpdateState::Negotiating);
+        let info = self.read_bootloader_info().await?;
+        self.log_device_info(&info);
+        self.check_info_support(&info, report)?;
+        self.apply_quirks(&info)?;
+        if !self.quirks.is_empty() {
+            report.warn(Warning::QuirksApplied(self.quirks));
+        }
+        // The banner is only printed on entry, not to a resumed session
+        let version = self.banner.as_ref().and_then(|banner| banner.version.as_ref());
+        if self.config.banner.is_some() && report.entry_time.is_some() && version.is_none() {
+            report.warn(Warning::VersionMissing);
+        }
+        report.quirks = self.quirks;
+        let capabilities = self.read_capabilities(&info).await?;
+        self.apply_capabilities(&capabilities);
+        report.capabilities = Some(capabilities);
+        self.negotiate_address_width(&info);
+        Ok(info)
+    }
+
+    /// Returns the device to its application, or keeps the session open for a later run
+    async fn leave_bootloader(&mut self, report: &mut UpdateReport, resumed: bool) -> Result<()> {
+        let started = Instant::now();
+        self.set_state(UpdateState::Exiting);
+        if self.config.keep_open {
+            self.save_session(report)?;
+        } else {
+            if self.config.quit {
+                self.quit_bootloader().await?;
+            }
+
+            if self.config.upd_mode != UpdateMode::None {
+                self.auto_exit().await?;
+            }
+
+            if let (Some(path), true) = (&self.config.session_file, resumed) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        report.timings.add(Phase::Exit, started.elapsed());
+        Ok(())
+    }
+
+    /// Picks up a bootloader session left open by another process
+    async fn resume_session(&mut self) -> Result<Option<SessionState>> {
+        let session = if let Some(token) = &self.config.resume_token {
+            token.session().clone()
+        } else {
+            let Some(path) = &self.config.session_file else {
+                return Ok(None);
+            };
+            if !Path::new(path).exists() {
+                return Ok(None);
+            }
+            SessionState::load(path)?
+        };
+
+        // A stale bootloader has timed out, so the device must be entered again
+        if session.uri != self.config.uri || session.is_stale() {
+            warn!("Ignoring saved session (stale or different URI)");
+            return Ok(None);
+        }
+
+        info!("Resuming bootloader session opened {} s ago", session.age().as_secs());
+        self.set_speed(session.speed).await?;
+        self.detect_bootloader().await?;
+        Ok(Some(session))
+    }
+
+    fn save_session(&self, report: &UpdateReport) -> Result<()> {
+        let (Some(path), Some(device)) = (&self.config.session_file, &report.device) else {
+            return Err(Error::Configuration(
+                "Keeping the session open needs a session file and device info".into()
+            ));
+        };
+
+        SessionState::new(&self.config.uri, self.speed, device).save(path)?;
+        info!("Leaving bootloader session open, state saved to {}", path);
         Ok(())
     }
 
+    fn capture_entry_console(&self, enabled: bool) {
+        // A secondary port captures the whole session; a tap only the entry
+        if self.console_task.is_none() {
+            if let Some(console) = &self.console {
+                console.set_enabled(enabled);
+            }
+        }
+    }
+
     async fn auto_enter(&mut self) -> Result<()> {
         info!("Entering bootloader mode");
         
         // Set initial speed
         self.set_speed(self.config.lnk_speed).await?;
 
-        // Try to detect bootloader
-        if self.detect_bootloader().await.is_ok() {
-            info!("Bootloader detected");
-            return Ok(());
+        let method = self.config.entry.clone();
+        match self.run_entry(&method).await {
+            Err(e) if !self.config.baud_sweep.is_empty() => self.sweep_speeds(&method, e).await?,
+            result => result?,
         }
-
-        // Send reboot command
-        self.send_reboot_command().await?;
-        
-        // Wait for bootloader
-        sleep(Duration::from_millis(1000)).await;
-        
-        // Verify bootloader is active
-        self.detect_bootloader().await?;
         
         info!("Successfully entered bootloader mode");
         Ok(())
     }
 
-    async fn process_firmware(&mut self, info: &InfoBlockV2) -> Result<()> {
-        if self.config.update {
-            info!("Starting firmware update");
-            self.write_firmware(info).await?;
+    async fn process_firmware(
+        &mut self,
+        firmware: &FirmwareImage,
+        info: &InfoBlockV2,
+        resume_from: u32,
+        report: &mut UpdateReport,
+    ) -> Result<Option<u32>> {
+        let mut parts = split_image(firmware, info, self.config.gap_filling as u8)?;
+        parts.sort_by_key(|part| part.address);
+        let metadata_last = self.config.update_ordering == UpdateOrdering::MetadataLast
+            && parts.iter().any(|part| part.region.kind == RegionKind::Metadata);
+        if metadata_last {
+            parts.sort_by_key(|part| part.region.kind == RegionKind::Metadata);
+        }
+        let dropped = bytes_outside_regions(firmware, info);
+        if dropped > 0 {
+            report.warn(Warning::DataDropped { bytes: dropped });
+        }
+
+        let max_block_size = self.max_block_size(info);
+
+        if self.config.update && !self.commands.contains(Command::EraseMemory) {
+            self.note_unsupported(report, Command::EraseMemory, Fallback::EraseSkipped);
+        }
+
+        let mut verifier = self.config.verifier.clone();
+        if let Some(command) = verifier.required_command() {
+            if self.config.verify && !self.commands.contains(command) {
+                verifier = VerifyMethod::DeviceCrc;
+                self.note_unsupported(report, command, Fallback::VerifyWith(verifier.clone()));
+            }
+        }
+
+        let mut entries: Vec<RegionReport> = parts
+            .iter()
+            .map(|part| RegionReport {
+                kind: part.region.kind,
+                address: part.address,
+                size: part.data.len() as u32,
+                block_size: part.region.block_size(max_block_size),
+                erased: 0,
+                crc: calculate_crc32(&part.data),
+                skipped: false,
+                verified: false,
+            })
+            .collect();
+        // Regions written before a suspend are not touched again. Written
+        // last, metadata is only partly done if the suspend hit it, and then
+        // everything else is complete.
+        let holds_resume = |entry: &RegionReport| (entry.address..entry.address + entry.size).contains(&resume_from);
+        let resuming_metadata = metadata_last
+            && entries.iter().any(|entry| entry.kind == RegionKind::Metadata && holds_resume(entry));
+        let starts: Vec<u32> = entries
+            .iter()
+            .map(|entry| match entry.kind {
+                RegionKind::Metadata if metadata_last && !holds_resume(entry) => 0,
+                RegionKind::Firmware if resuming_metadata => entry.size,
+                _ => resume_from.saturating_sub(entry.address).min(entry.size),
+            })
+            .collect();
+        // Only metadata written last is worth erasing up front
+        let mut invalidated = !metadata_last || resuming_metadata;
+
+        // While a CRC response is outstanding no other response may be read,
+        // so every up-to-date check happens before the first write
+        let pipelined = self.config.pipelined_verify
+            && self.config.update
+            && self.config.verify
+            && verifier == VerifyMethod::DeviceCrc;
+        // A bad manifest or signature fails before anything is written
+        let verifier = if self.config.verify && !pipelined {
+            Some(verifier.prepare(self.config.manifest_key.as_deref())?)
+        } else {
+            None
+        };
+        if pipelined {
+            for (index, part) in parts.iter().enumerate() {
+                if starts[index] == 0 {
+                    self.check_installed(part, &mut entries[index]).await?;
+                }
+            }
+        }
+        let mut pending: Option<usize> = None;
+        let total = entries.iter().zip(&starts).map(|(entry, start)| (entry.size - start) as usize).sum();
+        let mut progress = Progress { written: 0, total };
+        self.progress.send_replace(UpdateProgress { total, ..Default::default() });
+
+        for (index, part) in parts.iter().enumerate() {
+            let start = starts[index];
+            // Everything before the metadata must have verified
+            if metadata_last && part.region.kind == RegionKind::Metadata {
+                if let Some(prev) = pending.take() {
+                    self.collect_pipelined_crc(&parts[prev], &mut entries[prev], &mut report.timings).await?;
+                }
+            }
+            if self.config.update {
+                if start == entries[index].size {
+                    info!("{:?} region already written before suspend", part.region.kind);
+                } else {
+                    if !pipelined && start == 0 {
+                        self.check_installed(part, &mut entries[index]).await?;
+                    }
+                    if !entries[index].skipped && !invalidated && part.region.kind != RegionKind::Metadata {
+                        self.invalidate_metadata(&parts, &mut entries).await?;
+                        invalidated = true;
+                    }
+                    if !entries[index].skipped {
+                        info!("Starting {:?} region update at {:#010x}", part.region.kind, part.address + start);
+                        let suspended = self
+                            .write_region(part, start, &mut entries[index], &mut progress, &mut report.timings)
+                            .await?;
+                        if let Some(next_address) = suspended {
+                            if let Some(prev) = pending {
+                                self.collect_pipelined_crc(&parts[prev], &mut entries[prev], &mut report.timings).await?;
+                            }
+                            report.regions.extend(entries.drain(..index));
+                            return Ok(Some(next_address));
+                        }
+                    } else {
+                        progress.skip(entries[index].size as usize);
+                        self.progress.send_modify(|update| update.total = progress.total);
+                    }
+                }
+            }
+
+            if pipelined {
+                // The device computes this CRC while the next region is transferred
+                if let Some(prev) = pending.take() {
+                    self.collect_pipelined_crc(&parts[prev], &mut entries[prev], &mut report.timings).await?;
+                }
+                self.request_firmware_crc(part.address, entries[index].size).await?;
+                self.crc_requested = true;
+                pending = Some(index);
+            } else if let Some(verifier) = &verifier {
+                info!("Verifying {:?} region", part.region.kind);
+                let started = Instant::now();
+                self.verify_region(verifier, part, &mut entries[index]).await?;
+                report.timings.add(Phase::Verify, started.elapsed());
+            }
         }
+        if let Some(prev) = pending {
+            self.collect_pipelined_crc(&parts[prev], &mut entries[prev], &mut report.timings).await?;
+        }
+        report.regions.extend(entries);
 
-        if self.config.verify {
-            info!("Verifying firmware");
-            self.verify_firmware(info).await?;
+        let written = report.regions.iter().any(|region| !region.skipped);
+        if self.config.update && written
+            && (self.config.commit || self.commands.contains(Command::CommitImage))
+        {
+            self.abort_at(AbortPoint::BeforeCommit)?;
+            self.check_cancelled()?;
+            let started = Instant::now();
+            self.commit_image(report).await?;
+            report.timings.add(Phase::Commit, started.elapsed());
         }
 
+        Ok(None)
+    }
+
+    /// Asks the bootloader to check the written image against its metadata and
+    /// mark it bootable
+    async fn commit_image(&mut self, report: &UpdateReport) -> Result<()> {
+        let firmware = report.regions
+            .iter()
+            .find(|region| region.kind == RegionKind::Firmware);
+        let (address, size, crc) = firmware
+            .map(|region| (region.address, region.size, region.crc))
+            .unwrap_or_default();
+
+        info!("Committing firmware image ({} bytes, CRC {:#010x})", size, crc);
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::WriteRequest,
+            1,
+            Duration::ZERO,
+            Command::CommitImage as usize,
+            address as usize,
+            size as usize,
+        ).await?;
+
+        let mut status = [0u8; 1];
+        self.read_response(&mut status).await?;
+        if status[0] != 0 {
+            return Err(Error::CommitFailed { status: status[0], size, crc });
+        }
         Ok(())
     }
 
@@ -113,7 +781,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
             &mut self.stream,
             apl::AplRequestType::WriteRequest,
             0,
-            0,
+            Duration::ZERO,
             Command::BootloaderQuit as usize,
             0,
             0,
@@ -123,105 +791,523 @@ impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
 
     async fn auto_exit(&mut self) -> Result<()> {
         info!("Restoring normal operation mode");
+        let method = self.config.entry.clone();
+        self.run_exit(&method).await?;
         self.set_speed(self.config.lnk_speed).await?;
         Ok(())
     }
 
-    async fn set_speed(&mut self, speed: usize) -> Result<()> {
-        // Implementation depends on stream type
+    async fn set_speed(&mut self, speed: Baud) -> Result<()> {
+        // Whatever is queued still goes out at the old speed
+        DfuTransport::flush(&mut self.stream).await?;
+        self.stream.set_baud(speed).await?;
+        self.speed = speed;
+        // Some adapters drop the first bytes after a baud rate change
+        sleep(self.config.entry_timing.settle()).await;
         Ok(())
     }
 
     fn log_device_info(&self, info: &InfoBlockV2) {
         info!("Device Information:");
         info!("  Version: {:#04x}", info.version);
-        info!("  Device ID: {:#06x}", info.device.id);
-        info!("  Revision: {:#06x}", info.device.rev);
+        info!("  Device ID: {:#06x}", { info.device.id });
+        info!("  Revision: {:#06x}", { info.device.rev });
     }
-}
 
-impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
-    async fn write_firmware(&mut self, info: &InfoBlockV2) -> Result<()> {
-        let firmware = self.load_firmware()?;
-        
-        // Check if firmware is already installed
-        let current_crc = self.read_firmware_crc(
-            info.memmap.firmware_address,
-            info.memmap.firmware_size
-        ).await?;
-        
-        let new_crc = calculate_crc32(&firmware);
-        if current_crc == new_crc && !self.config.overwrite {
-            info!("Firmware already up to date (CRC: {:#010x})", new_crc);
+    fn apply_quirks(&mut self, info: &InfoBlockV2) -> Result<()> {
+        let mut database = QuirkDatabase::builtin();
+        if let Some(path) = &self.config.quirk_database {
+            database.extend(QuirkDatabase::from_file(path)?);
+        }
+
+        self.quirks = database
+            .lookup(info.device.id, info.version)
+            .merge(self.config.quirks);
+
+        self.apl.set_ack_offset(self.quirks.off_by_one_ack as u16);
+        Ok(())
+    }
+
+    fn negotiate_address_width(&mut self, info: &InfoBlockV2) {
+        // Legacy bootloaders only understand 32-bit request packets
+        let width = if info.supports_addr64() {
+            apl::AddressWidth::Bits64
+        } else {
+            apl::AddressWidth::Bits32
+        };
+        info!("Using {:?} request addressing", width);
+        self.lpl.set_address_width(width);
+    }
+
+    /// Adds this session's erases/writes to the registry and warns about worn regions
+    fn track_wear(&self, report: &mut UpdateReport) -> Result<()> {
+        let (Some(path), Some(device)) = (&self.config.registry_file, &report.device) else {
+            return Ok(());
+        };
+
+        let mut registry = DeviceRegistry::load(path)?;
+        let record = registry.device_mut(device);
+        for region in &report.regions {
+            let wear = record.record(region);
+            if region.erased > 0 && wear.erases >= self.config.wear_limit {
+                let warning = format!(
+                    "{:?} region at {:#010x} erased {} times (limit {})",
+                    region.kind, region.address, wear.erases, self.config.wear_limit
+                );
+                warn!("Flash wear: {}", warning);
+                report.hardware_warnings.push(warning);
+            }
+        }
+        registry.save(path)
+    }
+
+    fn collect_protocol_errors(&self, report: &mut UpdateReport) {
+        report.protocol_errors = self.protocol_errors();
+        for (kind, count) in &report.protocol_errors {
+            warn!("{} {:?} error(s) during session", count, kind);
+        }
+    }
+
+    fn note_unsupported(&self, report: &mut UpdateReport, command: Command, fallback: Fallback) {
+        warn!("Bootloader does not support {:?}, degrading to {:?}", command, fallback);
+        report.unsupported.push(UnsupportedCommand { command, fallback });
+    }
+
+    fn check_info_support(&self, info: &InfoBlockV2, report: &mut UpdateReport) -> Result<()> {
+        let unsupported = info.unsupported_fields();
+        if unsupported.is_empty() {
             return Ok(());
         }
 
-        // Write firmware in blocks
-        let block_size = self.config.block_size.min(info.max_block_size as usize);
-        let total_blocks = (firmware.len() + block_size - 1) / block_size;
+        if self.config.strict {
+            return Err(Error::UnsupportedInfo { fields: unsupported });
+        }
+
+        report.warn(Warning::UnsupportedInfo(unsupported));
+        Ok(())
+    }
 
-        for (i, chunk) in firmware.chunks(block_size).enumerate() {
-            let offset = i * block_size;
-            self.write_block(
-                chunk,
-                info.memmap.firmware_address + offset as u32
-            ).await?;
+    fn log_diagnostics(&self, diagnostics: &Diagnostics, report: &mut UpdateReport) {
+        info!("Device Diagnostics:");
+        info!("  Supply voltage: {} mV", diagnostics.supply_voltage_mv);
+        info!("  Temperature: {:.1} C", diagnostics.temperature_c);
+        info!("  Reset cause: {:?}", diagnostics.reset_cause);
+        info!("  Flash erase/write count: {}/{}",
+            diagnostics.flash_erase_count, diagnostics.flash_write_count);
 
-            let progress = ((i + 1) * 100) / total_blocks;
-            info!("Progress: {}%", progress);
+        for reason in diagnostics.marginal_reasons(&self.config.diagnostic_limits) {
+            warn!("Marginal hardware: {}", reason);
+            report.hardware_warnings.push(reason);
         }
+    }
+
+    pub async fn read_bootloader_info(&mut self) -> Result<InfoBlockV2> {
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::ReadRequest,
+            INFO_BLOCK_SIZE,
+            Duration::ZERO,
+            Command::ReadBootloaderInfo as usize,
+            0,
+            INFO_BLOCK_SIZE,
+        ).await?;
+
+        let mut block = [0u8; INFO_BLOCK_SIZE];
+        self.read_response(&mut block).await?;
+        InfoBlockV2::from_bytes(&block)
+    }
 
+    /// Succeeds once the bootloader answers an info request
+    async fn detect_bootloader(&mut self) -> Result<()> {
+        self.read_bootloader_info().await?;
+        info!("Bootloader detected");
         Ok(())
     }
 
-    async fn verify_firmware(&mut self, info: &InfoBlockV2) -> Result<()> {
-        let firmware = self.load_firmware()?;
-        let firmware_crc = calculate_crc32(&firmware);
+    /// Asks the running application to reboot into its bootloader. DFU-aware
+    /// applications listen for the bootloader's own framing and take a quit
+    /// request, meaningless to them otherwise, as that signal.
+    async fn send_reboot_command(&mut self) -> Result<()> {
+        info!("Sending reboot command");
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::WriteRequest,
+            0,
+            Duration::ZERO,
+            Command::BootloaderQuit as usize,
+            0,
+            0,
+        ).await?;
+        DfuTransport::flush(&mut self.stream).await
+    }
 
-        let device_crc = self.read_firmware_crc(
-            info.memmap.firmware_address,
-            info.memmap.firmware_size
+    pub async fn read_diagnostics(&mut self) -> Result<Diagnostics> {
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::ReadRequest,
+            DIAGNOSTICS_BLOCK_SIZE,
+            Duration::ZERO,
+            Command::ReadDiagnostics as usize,
+            0,
+            DIAGNOSTICS_BLOCK_SIZE,
         ).await?;
 
-        if firmware_crc != device_crc {
-            error!("Verification failed: CRC mismatch");
-            error!("Expected: {:#010x}, Got: {:#010x}", firmware_crc, device_crc);
-            return Err(Error::VerificationFailed);
+        let mut block = [0u8; DIAGNOSTICS_BLOCK_SIZE];
+        self.read_response(&mut block).await?;
+        Diagnostics::from_bytes(&block)
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Erases the metadata regions before the first write so the device
+    /// holds no valid image until they are rewritten
+    async fn invalidate_metadata(&mut self, parts: &[RegionImage], entries: &mut [RegionReport]) -> Result<()> {
+        if !self.commands.contains(Command::EraseMemory) {
+            warn!("Cannot invalidate metadata without an erase command");
+            return Ok(());
+        }
+        for (part, entry) in parts.iter().zip(entries.iter_mut()) {
+            if part.region.kind != RegionKind::Metadata {
+                continue;
+            }
+            info!("Invalidating metadata at {:#010x} until the image is verified", part.address);
+            self.set_state(UpdateState::Erasing);
+            let (address, size) = part.region.erase_range(part.address, entry.size);
+            self.erase_memory(address, size)
+                .await
+                .map_err(|e| e.at_block(Phase::Erase, 0, address))?;
+            // Found up to date before, but erased now
+            entry.skipped = false;
+        }
+        Ok(())
+    }
+
+    /// Marks the region skipped if the device already holds its content
+    async fn check_installed(&mut self, part: &RegionImage, entry: &mut RegionReport) -> Result<()> {
+        let current_crc = self.read_firmware_crc(part.address, entry.size).await?;
+        if current_crc == entry.crc && !self.config.overwrite {
+            info!("{:?} region already up to date (CRC: {:#010x})", entry.kind, entry.crc);
+            entry.skipped = true;
+        }
+        Ok(())
+    }
+
+    async fn collect_pipelined_crc(
+        &mut self,
+        part: &RegionImage,
+        entry: &mut RegionReport,
+        timings: &mut PhaseTimings,
+    ) -> Result<()> {
+        let started = Instant::now();
+        self.set_state(UpdateState::Verifying);
+        self.set_progress_phase(Phase::Verify);
+        self.abort_at(AbortPoint::Verifying)?;
+        self.receive_pipelined_crc().await?;
+        let actual = self.crc_received
+            .take()
+            .ok_or_else(|| Error::Protocol("No pipelined CRC was requested".into()))?;
+        timings.add(Phase::Verify, started.elapsed());
+
+        if actual != entry.crc {
+            return Err(Error::VerificationFailed {
+                range: part.range(),
+                expected: Checksum::Crc32(entry.crc),
+                actual: Checksum::Crc32(actual),
+            });
+        }
+        entry.verified = true;
+        info!("{:?} region verification successful", entry.kind);
+        Ok(())
+    }
+
+    /// Writes `part` from byte `start` onwards; returns the next address to
+    /// write if the transfer was suspended
+    async fn write_region(
+        &mut self,
+        part: &RegionImage,
+        start: u32,
+        entry: &mut RegionReport,
+        progress: &mut Progress,
+        timings: &mut PhaseTimings,
+    ) -> Result<Option<u32>> {
+        // A resumed region is partially written and already erased
+        let resuming = start > 0;
+
+        // Bootloaders without an erase command erase implicitly on write
+        if !resuming && self.commands.contains(Command::EraseMemory) {
+            self.check_cancelled()?;
+            let started = Instant::now();
+            self.set_state(UpdateState::Erasing);
+            self.set_progress_phase(Phase::Erase);
+            let (erase_address, erase_size) = part.region.erase_range(part.address, entry.size);
+            self.erase_memory(erase_address, erase_size)
+                .await
+                .map_err(|e| e.at_block(Phase::Erase, 0, erase_address))?;
+            if self.quirks.erase_delay_ms > 0 {
+                sleep(Duration::from_millis(self.quirks.erase_delay_ms as u64)).await;
+            }
+            entry.erased = erase_size;
+            timings.add(Phase::Erase, started.elapsed());
+        }
+
+        // One write request covers the rest of the region, its blocks
+        // following as numbered data packets
+        let started = Instant::now();
+        let block_size = entry.block_size;
+        let first_block = start as usize / block_size;
+        let offset = first_block * block_size;
+        let first_address = part.address + offset as u32;
+        self.begin_write(first_address, part.data.len() - offset, block_size)
+            .await
+            .map_err(|e| e.at_block(Phase::Write, first_block, first_address))?;
+
+        // Blocks in flight are only known written once acknowledged, so a
+        // failure or suspend points at the oldest block without an ACK
+        let unacked_block = |acknowledged: u16| {
+            let block = first_block + acknowledged as usize;
+            (block, part.address + (block * block_size) as u32)
+        };
+
+        for (i, chunk) in part.data.chunks(block_size).enumerate().skip(first_block) {
+            let address = part.address + (i * block_size) as u32;
+            if self.suspend.load(Ordering::SeqCst) {
+                self.end_write()
+                    .await
+                    .map_err(|e| e.at_block(Phase::Write, i, address))?;
+                timings.add(Phase::Write, started.elapsed());
+                return Ok(Some(unacked_block(self.apl.acknowledged()).1));
+            }
+            self.check_cancelled()?;
+
+            self.set_state(UpdateState::Writing { block: i });
+            if let Err(e) = self.write_block(chunk).await {
+                let (block, address) = unacked_block(self.apl.acknowledged());
+                return Err(e.at_block(Phase::Write, block, address));
+            }
+
+            progress.advance(chunk.len());
+            self.progress.send_replace(UpdateProgress {
+                phase: Some(Phase::Write),
+                block: i,
+                written: progress.written,
+                total: progress.total,
+            });
+            self.abort_writing(progress.written, progress.total)?;
+        }
+        if let Err(e) = self.end_write().await {
+            let (block, address) = unacked_block(self.apl.acknowledged());
+            return Err(e.at_block(Phase::Write, block, address));
         }
+        timings.add(Phase::Write, started.elapsed());
+
+        Ok(None)
+    }
 
-        info!("Firmware verification successful");
+    async fn verify_region(
+        &mut self,
+        verifier: &PreparedVerifier,
+        part: &RegionImage,
+        entry: &mut RegionReport,
+    ) -> Result<()> {
+        self.set_state(UpdateState::Verifying);
+        self.set_progress_phase(Phase::Verify);
+        self.abort_at(AbortPoint::Verifying)?;
+        self.check_cancelled()?;
+        self.run_verifier(verifier, part).await?;
+
+        entry.verified = true;
+        info!("{:?} region verification successful", entry.kind);
         Ok(())
     }
 
-    async fn write_block(&mut self, data: &[u8], address: u32) -> Result<()> {
+    async fn erase_memory(&mut self, address: u32, size: u32) -> Result<()> {
         self.lpl.send_request(
             &mut self.stream,
             apl::AplRequestType::WriteRequest,
-            data.len(),
             0,
+            Duration::ZERO,
+            Command::EraseMemory as usize,
+            address as usize,
+            size as usize,
+        ).await?;
+
+        Ok(())
+    }
+
+    /// Reads the raw answer to an outstanding pipelined CRC request. It
+    /// must come off the link before any framed ACK is waited for, as
+    /// frame reads discard everything up to the next SYN.
+    async fn receive_pipelined_crc(&mut self) -> Result<()> {
+        if self.crc_requested {
+            let mut crc = [0u8; 4];
+            self.read_response(&mut crc).await?;
+            self.crc_requested = false;
+            self.crc_received = Some(u32::from_le_bytes(crc));
+        }
+        Ok(())
+    }
+
+    async fn begin_write(&mut self, address: u32, len: usize, block_size: usize) -> Result<()> {
+        // The CRC is computed while the region is erased; writes need ACKs
+        self.receive_pipelined_crc().await?;
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::WriteRequest,
+            block_size,
+            Duration::ZERO,
             Command::WriteProgramMemory as usize,
             address as usize,
-            data.len(),
+            len,
         ).await?;
+        self.apl.begin_transfer();
+
+        Ok(())
+    }
+
+    /// Sends the next block of the current write request, waiting for the
+    /// device's ACK once the ACK policy's window is full
+    async fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        let packet = self.apl.create_data(data);
+        self.lpl.send_packet(&mut self.stream, &packet).await?;
+        if self.apl.block_sent() {
+            self.await_acks().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the ACK of a final partial window, if the policy has the
+    /// device send one
+    async fn end_write(&mut self) -> Result<()> {
+        if self.apl.final_ack_due() {
+            self.await_acks().await?;
+        }
+        Ok(())
+    }
 
+    /// Reads ACKs until every block sent is acknowledged; an error packet
+    /// fails the block
+    async fn await_acks(&mut self) -> Result<()> {
+        while self.apl.unacked() > 0 {
+            self.drain_notifications();
+            let Ok(message) = timeout(RESPONSE_TIMEOUT, self.lpl.read_message(&mut self.stream)).await else {
+                self.errors.record(
+                    ProtocolErrorKind::Timeout,
+                    format!("{} block(s) unacknowledged after {:?}", self.apl.unacked(), RESPONSE_TIMEOUT),
+                );
+                return Err(Error::Timeout);
+            };
+            let message = message.map_err(|e| match e.get_ref().and_then(|e| e.downcast_ref::<ChannelError>()) {
+                Some(overflow) => Error::Channel(overflow.clone()),
+                None => Error::Io(e),
+            })?;
+            self.apl
+                .process_message(message)
+                .await
+                .map_err(|e| Error::Protocol(e.to_string()))?;
+        }
         Ok(())
     }
 
     async fn read_firmware_crc(&mut self, address: u32, size: u32) -> Result<u32> {
+        self.request_firmware_crc(address, size).await?;
+
+        // Read CRC response
+        let mut crc = [0u8; 4];
+        self.read_response(&mut crc).await?;
+        Ok(u32::from_le_bytes(crc))
+    }
+
+    async fn request_firmware_crc(&mut self, address: u32, size: u32) -> Result<()> {
         self.lpl.send_request(
             &mut self.stream,
             apl::AplRequestType::ReadRequest,
             size_of::<u32>(),
-            0,
+            Duration::ZERO,
             Command::ReadProgramCrc as usize,
             address as usize,
             size as usize,
         ).await?;
 
-        // Read CRC response
-        let mut crc = [0u8; 4];
-        self.stream.read_exact(&mut crc).await?;
-        Ok(u32::from_le_bytes(crc))
+        Ok(())
+    }
+
+    async fn read_firmware_sha256(&mut self, address: u32, size: u32) -> Result<[u8; 32]> {
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::ReadRequest,
+            32,
+            Duration::ZERO,
+            Command::ReadProgramSha256 as usize,
+            address as usize,
+            size as usize,
+        ).await?;
+
+        let mut digest = [0u8; 32];
+        self.read_response(&mut digest).await?;
+        Ok(digest)
+    }
+
+    async fn read_memory(&mut self, address: u32, len: usize) -> Result<Vec<u8>> {
+        self.lpl.send_request(
+            &mut self.stream,
+            apl::AplRequestType::ReadRequest,
+            len,
+            Duration::ZERO,
+            Command::ReadProgramMemory as usize,
+            address as usize,
+            len,
+        ).await?;
+
+        let mut data = vec![0u8; len];
+        self.read_response(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Reads a raw response of exactly `buf.len()` bytes, counting timeouts
+    /// and responses cut short by the link closing
+    async fn read_response(&mut self, buf: &mut [u8]) -> Result<()> {
+        match timeout(RESPONSE_TIMEOUT, self.stream.read_exact(buf)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    self.errors.record(
+                        ProtocolErrorKind::ShortResponse,
+                        format!("link closed during a {} byte response", buf.len()),
+                    );
+                }
+                Err(e.into())
+            }
+            Err(_) => {
+                self.errors.record(
+                    ProtocolErrorKind::Timeout,
+                    format!("no {} byte response within {:?}", buf.len(), RESPONSE_TIMEOUT),
+                );
+                Err(Error::Timeout)
+            }
+        }
+    }
+}
+
+/// Bytes written out of everything the session writes, across all regions
+struct Progress {
+    written: usize,
+    total: usize,
+}
+
+impl Progress {
+    fn advance(&mut self, bytes: usize) {
+        self.written += bytes;
+        info!(
+            "Progress: {}% ({} of {} bytes)",
+            self.written * 100 / self.total.max(1),
+            self.written,
+            self.total
+        );
+    }
+
+    /// Drops a region found already installed from the total
+    fn skip(&mut self, bytes: usize) {
+        self.total = self.total.saturating_sub(bytes);
     }
 }
 
@@ -232,54 +1318,172 @@ fn calculate_crc32(data: &[u8]) -> u32 {
     crc.checksum(data)
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
-    fn load_firmware(&self) -> Result<Vec<u8>> {
-        let filename = self.config.filename.as_ref()
-            .ok_or(Error::NoFirmwareFile)?;
-            
-        let ihex = ihex::Reader::new(filename)
-            .map_err(Error::HexFileError)?;
-            
-        let mut firmware = vec![0xFF; self.max_firmware_size()];
-        
-        for record in ihex {
-            let record = record.map_err(Error::HexFileError)?;
-            if let ihex::Record::Data { offset, value } = record {
-                firmware[offset..offset + value.len()]
-                    .copy_from_slice(&value);
+impl<T: DfuTransport> DfuStream<T> {
+    async fn load_firmware(&self) -> Result<LoadedFirmware> {
+        if let Some(data) = &self.config.firmware_bytes {
+            let firmware = self.parse_bytes(data.to_vec())?;
+            return self.check_loaded(firmware, "from memory").map(LoadedFirmware::Single);
+        }
+
+        if let Some(path) = &self.config.bundle {
+            let bundle = Bundle::open(path)?;
+            if bundle.images().is_empty() {
+                return Err(Error::NoFirmwareFile);
             }
+            let firmware = bundle.load(self.config.firmware_format, self.config.gap_filling as u8)?;
+            return self.check_loaded(firmware, path).map(LoadedFirmware::Single);
         }
-        
+
+        let Some(path) = &self.config.firmware_set else {
+            let filename = self.config.filename.as_ref()
+                .ok_or(Error::NoFirmwareFile)?;
+            if download::is_url(filename) {
+                let data = self.fetch_firmware(filename).await?;
+                let firmware = self.parse_bytes(compression::unpack(data, Path::new(filename))?)?;
+                return self.check_loaded(firmware, filename).map(LoadedFirmware::Single);
+            }
+            return self.load_image(Path::new(filename)).map(LoadedFirmware::Single);
+        };
+
+        // Every candidate is parsed up front, whichever device ends up answering
+        let set = FirmwareSet::open(path)?;
+        if set.rules().is_empty() {
+            return Err(Error::NoFirmwareFile);
+        }
+        let mut candidates = Vec::with_capacity(set.rules().len());
+        let mut total = 0;
+        for rule in set.rules() {
+            let image = self.load_image(&set.path(rule))?;
+            total += image.data_len();
+            self.budget.check_image(total)?;
+            candidates.push((rule.clone(), image));
+        }
+        Ok(LoadedFirmware::ByDevice(candidates))
+    }
+
+    /// Downloads `url`, going through the artifact cache when one is configured
+    async fn fetch_firmware(&self, url: &str) -> Result<Vec<u8>> {
+        let expected = self.config.firmware_sha256.as_deref();
+        let Some(dir) = &self.config.cache_dir else {
+            return download::download(url, expected).await;
+        };
+
+        // Only a pinned digest identifies the artifact without asking the server
+        let mut cache = ArtifactCache::open(dir)?;
+        if let Some(digest) = expected {
+            if let Some(data) = cache.get(digest)? {
+                info!("Using cached firmware {}", digest);
+                return Ok(data);
+            }
+        }
+        let data = download::download(url, expected).await?;
+        cache.insert(&data, Some(url))?;
+        Ok(data)
+    }
+
+    fn load_image(&self, path: &Path) -> Result<FirmwareImage> {
+        let firmware = FirmwareImage::load_target(
+            path,
+            self.config.firmware_format,
+            self.config.dfu_target,
+            self.config.gap_filling as u8,
+        )?;
+        self.check_loaded(firmware, &path.display().to_string())
+    }
+
+    fn parse_bytes(&self, data: Vec<u8>) -> Result<FirmwareImage> {
+        FirmwareImage::from_bytes(
+            data,
+            self.config.firmware_format,
+            self.config.dfu_target,
+            self.config.gap_filling as u8,
+        )
+    }
+
+    fn check_loaded(&self, firmware: FirmwareImage, source: &str) -> Result<FirmwareImage> {
+        // Only the explicit override is known before the device reports its memory map
+        if let Some(max) = self.config.max_firmware_size {
+            if firmware.data_len() > max {
+                return Err(Error::FirmwareTooLarge { size: firmware.data_len(), max });
+            }
+        }
+
+        self.budget.check_image(firmware.data_len())?;
+        info!(
+            "Loaded firmware image {}: {} bytes in {} segment(s)",
+            source,
+            firmware.data_len(),
+            firmware.segments().len()
+        );
         Ok(firmware)
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> DfuStream<T> {
-    fn max_firmware_size(&self) -> usize {
-        // Default to 1MB if not specified in config
-        1024 * 1024
+impl<T: DfuTransport> DfuStream<T> {
+    /// Largest write the config, the bootloader and its quirks all allow
+    fn max_block_size(&self, info: &InfoBlockV2) -> usize {
+        let max = self.config.block_size.min(info.max_block_size as usize);
+        match self.quirks.max_frame_size {
+            Some(frame_size) => max.min(frame_size),
+            None => max,
+        }
+    }
+
+    fn max_firmware_size(&self, info: &InfoBlockV2) -> usize {
+        // Explicit override wins over the size reported in the memory map
+        self.config.max_firmware_size
+            .unwrap_or(info.memmap.firmware_size as usize)
+    }
+
+    /// Applies the trim/pad options to a placed image
+    fn shape_firmware(&self, firmware: FirmwareImage, info: &InfoBlockV2) -> FirmwareImage {
+        let fill = self.config.gap_filling as u8;
+        let len = firmware.len();
+        let firmware = if self.config.pad_to_crc_window {
+            let window_end = info.memmap.firmware_address + info.memmap.firmware_size;
+            firmware.pad_to(window_end, fill)
+        } else if self.config.trim_fill {
+            firmware.trim_end(fill)
+        } else {
+            firmware
+        };
+
+        if firmware.len() != len {
+            info!("Firmware image resized from {} to {} bytes", len, firmware.len());
+        }
+        firmware
     }
 
-    fn validate_firmware(&self, firmware: &[u8], info: &InfoBlockV2) -> Result<()> {
-        // Check firmware size
-        if firmware.len() > info.memmap.firmware_size as usize {
-            return Err(Error::FirmwareTooLarge);
+    fn validate_firmware(&self, firmware: &FirmwareImage, info: &InfoBlockV2) -> Result<()> {
+        if let Some(header) = firmware.container() {
+            header.check(info)?;
+            info!("Firmware container version {} matches device", header.version);
+        }
+
+        // Gaps between segments aren't transferred, so only occupied bytes count
+        let max = self.max_firmware_size(info);
+        if firmware.data_len() > max {
+            return Err(Error::FirmwareTooLarge { size: firmware.data_len(), max });
         }
 
-        // Verify device ID if needed
-        if info.version >= 0x30 && !self.config.overwrite {
+        // Images are only bound to one device on request
+        if self.config.require_uid && info.version >= 0x30 {
             self.verify_device_id(firmware, &info.device.uid)?;
         }
 
         Ok(())
     }
 
-    fn verify_device_id(&self, firmware: &[u8], device_uid: &[u8]) -> Result<()> {
+    fn verify_device_id(&self, firmware: &FirmwareImage, device_uid: &[u8]) -> Result<()> {
         // Search for device UID in firmware
-        if firmware.windows(device_uid.len()).any(|window| window == device_uid) {
+        let found = firmware
+            .segments()
+            .iter()
+            .any(|segment| segment.data.windows(device_uid.len()).any(|window| window == device_uid));
+        if found {
             Ok(())
         } else {
-            Err(Error::InvalidDeviceId)
+            Err(Error::InvalidDeviceId { uid: device_uid.to_vec() })
         }
     }
 }
@@ -0,0 +1,100 @@
+use std::io::Write;
+use std::time::Duration;
+use log::info;
+use serde::Deserialize;
+use tokio::time::sleep;
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::entry::EntryStrategy;
+use super::DfuStream;
+
+const DEFAULT_OFF_TIME_MS: u64 = 2000;
+
+const HID_RELAY_VID: u16 = 0x16c0;
+const HID_RELAY_PID: u16 = 0x05df;
+
+/// Power switch controlling the device supply
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerSwitch {
+    /// LCUS-style relay board on a serial port
+    SerialRelay { port: String, channel: u8 },
+    /// DCT-Tech USB HID relay, optionally selected by its serial string
+    HidRelay { serial: Option<String>, channel: u8 },
+    /// Networked PDU switched with plain HTTP requests
+    Http { on_url: String, off_url: String },
+}
+
+impl PowerSwitch {
+    async fn set(&self, on: bool) -> Result<()> {
+        match self {
+            Self::SerialRelay { port, channel } => {
+                let state = on as u8;
+                let frame = [0xA0, *channel, state, 0xA0u8.wrapping_add(*channel).wrapping_add(state)];
+                let mut port = serialport::new(port, 9600)
+                    .open()
+                    .map_err(|e| Error::EntryFailed(e.to_string()))?;
+                port.write_all(&frame)?;
+                Ok(())
+            }
+            Self::HidRelay { serial, channel } => {
+                let api = hidapi::HidApi::new()
+                    .map_err(|e| Error::EntryFailed(e.to_string()))?;
+                let device = match serial {
+                    Some(serial) => api.open_serial(HID_RELAY_VID, HID_RELAY_PID, serial),
+                    None => api.open(HID_RELAY_VID, HID_RELAY_PID),
+                }
+                .map_err(|e| Error::EntryFailed(e.to_string()))?;
+
+                let command = if on { 0xFF } else { 0xFD };
+                device.send_feature_report(&[0x00, command, *channel, 0, 0, 0, 0, 0, 0])
+                    .map_err(|e| Error::EntryFailed(e.to_string()))
+            }
+            Self::Http { on_url, off_url } => {
+                let url = if on { on_url } else { off_url };
+                reqwest::get(url)
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| Error::EntryFailed(e.to_string()))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Cold-boots the device into its bootloader by cycling its power
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PowerCycleEntry {
+    pub switch: PowerSwitch,
+    #[serde(default = "default_off_time")]
+    pub off_time_ms: u64,
+}
+
+fn default_off_time() -> u64 {
+    DEFAULT_OFF_TIME_MS
+}
+
+impl PowerCycleEntry {
+    pub fn new(switch: PowerSwitch) -> Self {
+        Self {
+            switch,
+            off_time_ms: DEFAULT_OFF_TIME_MS,
+        }
+    }
+}
+
+impl EntryStrategy for PowerCycleEntry {
+    fn name(&self) -> &'static str {
+        "power cycle"
+    }
+
+    async fn enter<T: DfuTransport>(&self, dfu: &mut DfuStream<T>) -> Result<()> {
+        info!("Power cycling device ({} ms off)", self.off_time_ms);
+        self.switch.set(false).await?;
+        sleep(Duration::from_millis(self.off_time_ms)).await;
+        self.switch.set(true).await?;
+        sleep(dfu.config.entry_timing.startup()).await;
+        Ok(())
+    }
+}
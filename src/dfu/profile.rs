@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::protocols::apl::AckPolicy;
+use crate::protocols::lpl::Framing;
+use super::banner::BannerParser;
+use super::entry::{EntryMethod, EntryTiming, SyncPreamble};
+use super::image::FirmwareFormat;
+use super::quirks::Quirks;
+use super::region::UpdateOrdering;
+use super::types::{Baud, DfuConfig, UpdateMode};
+use super::verify::VerifyMethod;
+
+pub const PROFILE_ENV_VAR: &str = "FWUPD_CONFIG";
+pub const DEFAULT_PROFILE_FILE: &str = "fwupd.toml";
+
+/// A single named profile; unset fields are inherited from the parent profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub inherits: Option<String>,
+    pub uri: Option<String>,
+    pub firmware: Option<String>,
+    pub firmware_sha256: Option<String>,
+    pub firmware_set: Option<String>,
+    pub cache_dir: Option<String>,
+    pub bundle: Option<String>,
+    pub base_address: Option<u32>,
+    pub firmware_format: Option<FirmwareFormat>,
+    pub dfu_target: Option<usize>,
+    pub block_size: Option<usize>,
+    pub max_firmware_size: Option<usize>,
+    pub memory_limit: Option<usize>,
+    pub channel_capacity: Option<usize>,
+    pub get_info: Option<bool>,
+    pub update: Option<bool>,
+    pub overwrite: Option<bool>,
+    pub verify: Option<bool>,
+    pub verifier: Option<VerifyMethod>,
+    pub manifest_key: Option<String>,
+    pub pipelined_verify: Option<bool>,
+    pub update_ordering: Option<UpdateOrdering>,
+    pub quit: Option<bool>,
+    pub commit: Option<bool>,
+    pub strict: Option<bool>,
+    pub diagnostics: Option<bool>,
+    pub dev_netid: Option<usize>,
+    pub bus_addressing: Option<bool>,
+    pub framing: Option<Framing>,
+    pub dev_speed: Option<Baud>,
+    pub upd_speed: Option<Baud>,
+    pub lnk_speed: Option<Baud>,
+    pub baud_sweep: Option<Vec<Baud>>,
+    pub reconnect_attempts: Option<usize>,
+    pub reconnect_backoff_ms: Option<u64>,
+    pub upd_mode: Option<UpdateMode>,
+    pub entry: Option<EntryMethod>,
+    pub entry_timing: Option<EntryTiming>,
+    pub sync_preamble: Option<SyncPreamble>,
+    pub console_port: Option<String>,
+    pub banner: Option<BannerParser>,
+    pub quirk_database: Option<String>,
+    pub quirks: Option<Quirks>,
+    pub ack_policy: Option<AckPolicy>,
+    pub session_file: Option<String>,
+    pub keep_open: Option<bool>,
+    pub request_journal: Option<String>,
+    pub registry_file: Option<String>,
+    pub backup_file: Option<String>,
+    pub archive_dir: Option<String>,
+    pub require_uid: Option<bool>,
+    pub conformance_scratch: Option<u32>,
+    pub wear_limit: Option<u64>,
+    pub gap_filling: Option<usize>,
+    pub trim_fill: Option<bool>,
+    pub pad_to_crc_window: Option<bool>,
+}
+
+impl Profile {
+    fn apply(&self, mut config: DfuConfig) -> DfuConfig {
+        if let Some(uri) = &self.uri {
+            config.uri = uri.clone();
+        }
+        if let Some(firmware) = &self.firmware {
+            config.filename = Some(firmware.clone());
+        }
+        if let Some(digest) = &self.firmware_sha256 {
+            config.firmware_sha256 = Some(digest.clone());
+        }
+        if let Some(path) = &self.firmware_set {
+            config.firmware_set = Some(path.clone());
+        }
+        if let Some(dir) = &self.cache_dir {
+            config.cache_dir = Some(dir.clone());
+        }
+        if let Some(path) = &self.bundle {
+            config.bundle = Some(path.clone());
+        }
+        if let Some(address) = self.base_address {
+            config.base_address = Some(address);
+        }
+        if let Some(format) = self.firmware_format {
+            config.firmware_format = format;
+        }
+        if let Some(target) = self.dfu_target {
+            config.dfu_target = target;
+        }
+        if let Some(block_size) = self.block_size {
+            config.block_size = block_size;
+        }
+        if let Some(size) = self.max_firmware_size {
+            config.max_firmware_size = Some(size);
+        }
+        if let Some(limit) = self.memory_limit {
+            config.memory_limit = Some(limit);
+        }
+        if let Some(capacity) = self.channel_capacity {
+            config.channel_capacity = Some(capacity);
+        }
+        if let Some(get_info) = self.get_info {
+            config.get_info = get_info;
+        }
+        if let Some(update) = self.update {
+            config.update = update;
+        }
+        if let Some(overwrite) = self.overwrite {
+            config.overwrite = overwrite;
+        }
+        if let Some(verify) = self.verify {
+            config.verify = verify;
+        }
+        if let Some(verifier) = &self.verifier {
+            config.verifier = verifier.clone();
+        }
+        if let Some(path) = &self.manifest_key {
+            config.manifest_key = Some(path.clone());
+        }
+        if let Some(pipelined) = self.pipelined_verify {
+            config.pipelined_verify = pipelined;
+        }
+        if let Some(ordering) = self.update_ordering {
+            config.update_ordering = ordering;
+        }
+        if let Some(quit) = self.quit {
+            config.quit = quit;
+        }
+        if let Some(commit) = self.commit {
+            config.commit = commit;
+        }
+        if let Some(strict) = self.strict {
+            config.strict = strict;
+        }
+        if let Some(diagnostics) = self.diagnostics {
+            config.diagnostics = diagnostics;
+        }
+        if let Some(netid) = self.dev_netid {
+            config.dev_netid = netid;
+        }
+        if let Some(addressing) = self.bus_addressing {
+            config.bus_addressing = addressing;
+        }
+        if let Some(framing) = self.framing {
+            config.framing = framing;
+        }
+        if let Some(speed) = self.dev_speed {
+            config.dev_speed = speed;
+        }
+        if let Some(speed) = self.upd_speed {
+            config.upd_speed = speed;
+        }
+        if let Some(speed) = self.lnk_speed {
+            config.lnk_speed = speed;
+        }
+        if let Some(speeds) = &self.baud_sweep {
+            config.baud_sweep = speeds.clone();
+        }
+        if let Some(attempts) = self.reconnect_attempts {
+            config.reconnect_attempts = attempts;
+        }
+        if let Some(ms) = self.reconnect_backoff_ms {
+            config.reconnect_backoff = Duration::from_millis(ms);
+        }
+        if let Some(mode) = self.upd_mode {
+            config.upd_mode = mode;
+        }
+        if let Some(entry) = &self.entry {
+            config.entry = entry.clone();
+        }
+        if let Some(timing) = self.entry_timing {
+            config.entry_timing = timing;
+        }
+        if let Some(preamble) = &self.sync_preamble {
+            config.sync_preamble = Some(preamble.clone());
+        }
+        if let Some(port) = &self.console_port {
+            config.console_port = Some(port.clone());
+        }
+        if let Some(parser) = &self.banner {
+            config.banner = Some(parser.clone());
+        }
+        if let Some(path) = &self.quirk_database {
+            config.quirk_database = Some(path.clone());
+        }
+        if let Some(quirks) = self.quirks {
+            config.quirks = quirks;
+        }
+        if let Some(policy) = self.ack_policy {
+            config.ack_policy = policy;
+        }
+        if let Some(path) = &self.session_file {
+            config.session_file = Some(path.clone());
+        }
+        if let Some(keep_open) = self.keep_open {
+            config.keep_open = keep_open;
+        }
+        if let Some(path) = &self.request_journal {
+            config.request_journal = Some(path.clone());
+        }
+        if let Some(path) = &self.registry_file {
+            config.registry_file = Some(path.clone());
+        }
+        if let Some(path) = &self.backup_file {
+            config.backup_file = Some(path.clone());
+        }
+        if let Some(dir) = &self.archive_dir {
+            config.archive_dir = Some(dir.clone());
+        }
+        if let Some(require) = self.require_uid {
+            config.require_uid = require;
+        }
+        if let Some(address) = self.conformance_scratch {
+            config.conformance_scratch = Some(address);
+        }
+        if let Some(limit) = self.wear_limit {
+            config.wear_limit = limit;
+        }
+        if let Some(fill) = self.gap_filling {
+            config.gap_filling = fill;
+        }
+        if let Some(trim) = self.trim_fill {
+            config.trim_fill = trim;
+        }
+        if let Some(pad) = self.pad_to_crc_window {
+            config.pad_to_crc_window = pad;
+        }
+        config
+    }
+}
+
+/// Set of named profiles loaded from one TOML file
+///
+/// ```toml
+/// [base]
+/// uri = "serial:///dev/ttyUSB0"
+/// upd_speed = 115200
+///
+/// [factory]
+/// inherits = "base"
+/// overwrite = true
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProfileSet {
+    profiles: HashMap<String, Profile>,
+}
+
+impl ProfileSet {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+            .map_err(|e| Error::Configuration(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let profiles = toml::from_str(content)
+            .map_err(|e| Error::Configuration(e.to_string()))?;
+        Ok(Self { profiles })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    /// Builds a config from the named profile and all of its ancestors
+    pub fn resolve(&self, name: &str) -> Result<DfuConfig> {
+        let mut chain = Vec::new();
+        let mut current = Some(name);
+
+        while let Some(name) = current {
+            if chain.iter().any(|(n, _)| *n == name) {
+                return Err(Error::Configuration(format!(
+                    "Profile inheritance cycle at '{}'", name
+                )));
+            }
+
+            let profile = self.profiles.get(name).ok_or_else(|| {
+                Error::Configuration(format!("Unknown profile '{}'", name))
+            })?;
+
+            chain.push((name, profile));
+            current = profile.inherits.as_deref();
+        }
+
+        // Apply from the root ancestor down so children override parents
+        Ok(chain
+            .iter()
+            .rev()
+            .fold(DfuConfig::default(), |config, (_, profile)| profile.apply(config)))
+    }
+}
+
+impl DfuConfig {
+    /// Resolves a named profile from `$FWUPD_CONFIG` or `./fwupd.toml`
+    pub fn profile(name: &str) -> Result<Self> {
+        let path = std::env::var(PROFILE_ENV_VAR)
+            .unwrap_or_else(|_| DEFAULT_PROFILE_FILE.to_string());
+        ProfileSet::from_file(path)?.resolve(name)
+    }
+
+    pub fn profile_from_file(path: impl AsRef<Path>, name: &str) -> Result<Self> {
+        ProfileSet::from_file(path)?.resolve(name)
+    }
+}
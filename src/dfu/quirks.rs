@@ -0,0 +1,103 @@
+use std::path::Path;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// Workarounds for bootloader behaviour that deviates from the protocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    /// Extra wait after every erase command
+    pub erase_delay_ms: u32,
+    /// Device ACKs block N with block number N + 1
+    pub off_by_one_ack: bool,
+    /// Largest data block the device accepts in one frame
+    pub max_frame_size: Option<usize>,
+}
+
+impl Quirks {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Combines two quirk sets, keeping the more conservative value of each field
+    pub fn merge(self, other: Quirks) -> Quirks {
+        Quirks {
+            erase_delay_ms: self.erase_delay_ms.max(other.erase_delay_ms),
+            off_by_one_ack: self.off_by_one_ack || other.off_by_one_ack,
+            max_frame_size: match (self.max_frame_size, other.max_frame_size) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct QuirkEntry {
+    pub device_id: u16,
+    #[serde(default)]
+    pub min_version: u8,
+    #[serde(default = "max_version")]
+    pub max_version: u8,
+    #[serde(flatten)]
+    pub quirks: Quirks,
+}
+
+fn max_version() -> u8 {
+    u8::MAX
+}
+
+impl QuirkEntry {
+    pub fn matches(&self, device_id: u16, version: u8) -> bool {
+        self.device_id == device_id
+            && version >= self.min_version
+            && version <= self.max_version
+    }
+}
+
+// Known devices; extended as field reports come in
+const BUILTIN_QUIRKS: &[QuirkEntry] = &[];
+
+/// Quirk table keyed by device id and bootloader version
+///
+/// ```toml
+/// [[device]]
+/// device_id = 0x0413
+/// max_version = 0x2f
+/// erase_delay_ms = 50
+/// max_frame_size = 256
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuirkDatabase {
+    #[serde(rename = "device", default)]
+    entries: Vec<QuirkEntry>,
+}
+
+impl QuirkDatabase {
+    pub fn builtin() -> Self {
+        Self {
+            entries: BUILTIN_QUIRKS.to_vec(),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    pub fn add(&mut self, entry: QuirkEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn extend(&mut self, other: QuirkDatabase) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn lookup(&self, device_id: u16, version: u8) -> Quirks {
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches(device_id, version))
+            .fold(Quirks::default(), |quirks, entry| quirks.merge(entry.quirks))
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::time::Duration;
+use log::{info, warn};
+use tokio::time::sleep;
+
+use crate::error::{Error, Result};
+use crate::transport::{connect, DfuTransport};
+use super::report::UpdateReport;
+use super::types::DfuConfig;
+use super::DfuStream;
+
+/// Longest wait between two reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Runs the update over a link opened from `config.uri`, re-opening it when
+/// it is lost mid-transfer (a USB adapter re-enumerating, a TCP connection
+/// dropped). Each retry re-detects the bootloader and continues at the
+/// block that failed, waiting `reconnect_backoff`, then twice as long, up to
+/// `reconnect_attempts` times.
+pub async fn update_with_reconnect(config: DfuConfig) -> Result<UpdateReport> {
+    reconnecting(config, |config| async move { connect(&config).await }).await
+}
+
+/// [`update_with_reconnect`] over links opened by `open`
+async fn reconnecting<T, F, Fut>(mut config: DfuConfig, mut open: F) -> Result<UpdateReport>
+where
+    T: DfuTransport,
+    F: FnMut(DfuConfig) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = config.reconnect_backoff;
+    let mut attempt = 0;
+    loop {
+        let result = match open(config.clone()).await {
+            Ok(stream) => match DfuStream::new(stream, config.clone()) {
+                Ok(mut dfu) => dfu.update().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+
+        let error = match result {
+            Err(Error::LinkLost { token, source }) => {
+                config.resume_token = Some(*token);
+                source
+            }
+            // Reopening may fail while the device is still coming back
+            Err(e) if attempt > 0 && e.is_link_loss() => Box::new(e),
+            other => return other,
+        };
+        if attempt >= config.reconnect_attempts {
+            warn!("Giving up on {} after {} reconnection attempts", config.uri, attempt);
+            return Err(*error);
+        }
+        attempt += 1;
+        warn!(
+            "Link to {} lost ({}), reconnecting in {:?} ({}/{})",
+            config.uri, error, backoff, attempt, config.reconnect_attempts
+        );
+        match &config.cancellation {
+            Some(cancellation) => tokio::select! {
+                _ = sleep(backoff) => {}
+                _ = cancellation.cancelled() => return Err(Error::Cancelled),
+            },
+            None => sleep(backoff).await,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        if let Some(token) = &config.resume_token {
+            info!("Resuming at {:#010x}", token.next_address());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex as StdMutex;
+    use tokio::sync::Mutex;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::dfu::{FirmwareFormat, SimFault, SimModel, SimulatedDevice};
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_after_doubling_backoff() {
+        // Dropped on the write request of the first two sessions
+        let model = SimModel {
+            faults: vec![SimFault::Disconnect { after: 4 }, SimFault::Disconnect { after: 10 }],
+            ..SimModel::default()
+        };
+        let device = Arc::new(Mutex::new(SimulatedDevice::new(model)));
+        let opened = Arc::new(StdMutex::new(Vec::new()));
+
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let config = DfuConfig::new()
+            .with_uri("sim")
+            .with_firmware_bytes(data.clone())
+            .with_firmware_format(FirmwareFormat::Binary)
+            .with_reconnect(3, Duration::from_millis(500))
+            .update();
+
+        let report = reconnecting(config, |_| {
+            let device = device.clone();
+            opened.lock().unwrap().push(Instant::now());
+            async move {
+                let (host, link) = tokio::io::duplex(64 * 1024);
+                tokio::spawn(async move { device.lock().await.serve(link).await });
+                Ok(host)
+            }
+        })
+        .await
+        .expect("update completes after reconnecting");
+
+        let waits: Vec<Duration> = opened.lock().unwrap().windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert_eq!(waits, [Duration::from_millis(500), Duration::from_millis(1000)]);
+        assert!(!report.regions.is_empty());
+
+        let device = device.lock().await;
+        let offset = (device.model().memory.firmware_address - device.model().memory.flash_address) as usize;
+        assert_eq!(&device.flash()[offset..offset + data.len()], &data[..]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::ops::Range;
+use serde::Deserialize;
+use crate::error::{Error, Result};
+use super::image::FirmwareImage;
+use super::types::{DeviceMemoryMap, InfoBlockV2};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Firmware,
+    Metadata,
+}
+
+/// Order in which the regions of an image are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateOrdering {
+    /// Lowest address first
+    #[default]
+    Address,
+    /// The metadata region is erased before anything else is written and
+    /// only rewritten once every other region verified, so an interrupted
+    /// update leaves the device without a valid image, in its bootloader
+    MetadataLast,
+}
+
+/// Programmable area of the device memory map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryRegion {
+    pub kind: RegionKind,
+    pub address: u32,
+    pub size: u32,
+    pub erase_size: u32,
+    pub write_size: u16,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u32 {
+        // Maps are range-checked when read, but regions can be built by hand
+        self.address.saturating_add(self.size)
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.address && address < self.end()
+    }
+
+    /// Largest block not exceeding `max` that is a multiple of the flash write size
+    pub fn block_size(&self, max: usize) -> usize {
+        let max = max.min(self.size as usize);
+        let write_size = self.write_size.max(1) as usize;
+        (max / write_size).max(1) * write_size
+    }
+
+    /// Expands `[address, address + len)` to whole erase sectors
+    pub fn erase_range(&self, address: u32, len: u32) -> (u32, u32) {
+        if self.erase_size == 0 {
+            return (address, len);
+        }
+        let offset = address - self.address;
+        let start = offset - offset % self.erase_size;
+        let end = (offset + len).div_ceil(self.erase_size) * self.erase_size;
+        (self.address + start, end.min(self.size) - start)
+    }
+}
+
+impl DeviceMemoryMap {
+    /// Rejects a map whose areas or sector table run past the end of the
+    /// 32-bit address space
+    pub fn check_ranges(&self) -> Result<()> {
+        let areas = [
+            ("Flash", self.flash_address, self.flash_size),
+            ("Firmware region", self.firmware_address, self.firmware_size),
+            ("Metadata region", self.metadata_address, self.metadata_size),
+        ];
+        for (name, address, size) in areas {
+            if address.checked_add(size).is_none() {
+                return Err(Error::Protocol(format!(
+                    "{} at {:#010x} with size {:#x} exceeds the address space", name, address, size
+                )));
+            }
+        }
+
+        let mut start = self.flash_address;
+        for (i, region) in self.regions.iter().enumerate() {
+            let (count, size) = (region.count, region.size);
+            start = count
+                .checked_mul(size)
+                .and_then(|len| start.checked_add(len))
+                .ok_or_else(|| Error::Protocol(format!(
+                    "Flash region {} ({} sectors of {:#x}) exceeds the address space", i, count, size
+                )))?;
+        }
+        Ok(())
+    }
+
+    /// Size of the flash sector containing `address`, from the region table
+    pub fn sector_size_at(&self, address: u32) -> u32 {
+        let mut start = self.flash_address;
+        for region in self.regions.iter() {
+            let (count, size) = (region.count, region.size);
+            let Some(end) = count.checked_mul(size).and_then(|len| start.checked_add(len)) else {
+                break;
+            };
+            if address >= start && address < end {
+                return size;
+            }
+            start = end;
+        }
+        0
+    }
+
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        let mut regions = vec![MemoryRegion {
+            kind: RegionKind::Firmware,
+            address: self.firmware_address,
+            size: self.firmware_size,
+            erase_size: self.sector_size_at(self.firmware_address),
+            write_size: self.flash_write_blocksize,
+        }];
+
+        if self.metadata_size > 0 {
+            regions.push(MemoryRegion {
+                kind: RegionKind::Metadata,
+                address: self.metadata_address,
+                size: self.metadata_size,
+                erase_size: self.sector_size_at(self.metadata_address),
+                write_size: self.flash_write_blocksize,
+            });
+        }
+
+        regions
+    }
+}
+
+/// Part of the firmware image that belongs to a single memory region
+#[derive(Debug, Clone)]
+pub struct RegionImage {
+    pub region: MemoryRegion,
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl RegionImage {
+    pub fn range(&self) -> Range<u32> {
+        self.address..self.address + self.data.len() as u32
+    }
+}
+
+/// Number of image bytes that fall outside every region
+pub fn bytes_outside_regions(image: &FirmwareImage, info: &InfoBlockV2) -> u32 {
+    let regions = info.memmap.memory_regions();
+    image
+        .segments()
+        .iter()
+        .flat_map(|segment| segment.range())
+        .filter(|address| !regions.iter().any(|r| r.contains(*address)))
+        .count() as u32
+}
+
+/// Splits an image across the device memory regions, only covering the
+/// addresses its segments occupy.
+///
+/// Segments sharing an erase sector are joined (the gap written as `fill`)
+/// so erasing one part can't wipe another. Bytes equal to `fill` outside
+/// every region are dropped; any other byte outside the memory map is an error.
+pub fn split_image(
+    image: &FirmwareImage,
+    info: &InfoBlockV2,
+    fill: u8,
+) -> Result<Vec<RegionImage>> {
+    let regions = info.memmap.memory_regions();
+
+    for segment in image.segments() {
+        let fits = u32::try_from(segment.data.len())
+            .ok()
+            .and_then(|len| segment.address.checked_add(len))
+            .is_some();
+        if !fits {
+            return Err(Error::OutsideMemoryMap(segment.address));
+        }
+        for (i, byte) in segment.data.iter().enumerate() {
+            let address = segment.address + i as u32;
+            if *byte != fill && !regions.iter().any(|r| r.contains(address)) {
+                return Err(Error::OutsideMemoryMap(address));
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    for region in regions {
+        let mut region_parts: Vec<RegionImage> = Vec::new();
+        for segment in image.segments() {
+            let start = region.address.max(segment.address);
+            let end = region.end().min(segment.end());
+            if start >= end {
+                continue;
+            }
+
+            let offset = (start - segment.address) as usize;
+            let data = &segment.data[offset..offset + (end - start) as usize];
+            // Skip parts the image only touches with padding
+            if data.iter().all(|b| *b == fill) {
+                continue;
+            }
+
+            if let Some(last) = region_parts.last_mut() {
+                let (erase_address, erase_size) = region.erase_range(last.address, last.data.len() as u32);
+                if start < erase_address + erase_size {
+                    last.data.resize((start - last.address) as usize, fill);
+                    last.data.extend_from_slice(data);
+                    continue;
+                }
+            }
+            region_parts.push(RegionImage {
+                region,
+                address: start,
+                data: data.to_vec(),
+            });
+        }
+        parts.extend(region_parts);
+    }
+
+    Ok(parts)
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use super::info::DeviceInfo;
+use super::report::RegionReport;
+use super::signing::to_hex;
+
+/// Default number of erases per region after which a warning is raised
+pub const DEFAULT_WEAR_LIMIT: u64 = 10_000;
+
+/// Erase/write counts of one region, accumulated across sessions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionWear {
+    pub erases: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub device_id: u16,
+    /// Keyed by region start address in hex
+    #[serde(default)]
+    pub regions: BTreeMap<String, RegionWear>,
+}
+
+/// Host-side record of every device seen, keyed by hex UID and stored as TOML
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRegistry {
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceRecord>,
+}
+
+impl DeviceRegistry {
+    /// Loads the registry, starting empty if the file does not exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let content = toml::to_string(self).map_err(|e| Error::Configuration(e.to_string()))?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    pub fn device(&self, uid: &[u8]) -> Option<&DeviceRecord> {
+        self.devices.get(&to_hex(uid))
+    }
+
+    pub fn device_mut(&mut self, device: &DeviceInfo) -> &mut DeviceRecord {
+        let record = self.devices.entry(to_hex(&device.uid)).or_default();
+        record.device_id = device.device_id;
+        record
+    }
+}
+
+impl DeviceRecord {
+    pub fn region(&self, address: u32) -> RegionWear {
+        self.regions.get(&region_key(address)).copied().unwrap_or_default()
+    }
+
+    /// Adds one session's erase/write of a region and returns the new totals
+    pub fn record(&mut self, region: &RegionReport) -> RegionWear {
+        let wear = self.regions.entry(region_key(region.address)).or_default();
+        if region.erased > 0 {
+            wear.erases += 1;
+        }
+        if !region.skipped {
+            wear.writes += 1;
+        }
+        *wear
+    }
+}
+
+fn region_key(address: u32) -> String {
+    format!("{:08x}", address)
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::protocols::stats::ProtocolErrorKind;
+
+use super::capabilities::Capabilities;
+use super::idempotency::Replay;
+use super::info::DeviceInfo;
+use super::quirks::Quirks;
+use super::region::RegionKind;
+use super::types::Command;
+use super::verify::VerifyMethod;
+
+/// Summary of a completed update session
+#[derive(Debug, Clone, Default)]
+pub struct UpdateReport {
+    pub device: Option<DeviceInfo>,
+    pub hardware_warnings: Vec<String>,
+    pub regions: Vec<RegionReport>,
+    pub timings: PhaseTimings,
+    /// Measured time from starting bootloader entry until it answered
+    pub entry_time: Option<Duration>,
+    pub console: Option<String>,
+    pub quirks: Quirks,
+    pub unsupported: Vec<UnsupportedCommand>,
+    pub capabilities: Option<Capabilities>,
+    /// Recoverable link and protocol errors by kind
+    pub protocol_errors: BTreeMap<ProtocolErrorKind, u64>,
+    /// Non-fatal conditions; the update succeeded, but not cleanly
+    pub warnings: Vec<Warning>,
+    /// Set when the update ran under an idempotency key
+    pub replay: Option<Replay>,
+}
+
+/// What [`ensure_firmware`](crate::ensure_firmware) did to the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnsureOutcome {
+    /// The device already held the image; nothing was written
+    AlreadyCurrent,
+    /// The image was written and verified
+    Updated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// Padding bytes outside every writable region that were not written
+    DataDropped { bytes: u32 },
+    QuirksApplied(Quirks),
+    /// Device info fields this host doesn't understand and ignored
+    UnsupportedInfo(Vec<String>),
+    /// A banner parser is configured but the bootloader printed no version
+    VersionMissing,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::DataDropped { bytes } => {
+                write!(f, "{} padding byte(s) outside writable regions dropped", bytes)
+            }
+            Warning::QuirksApplied(quirks) => write!(f, "device quirks applied: {:?}", quirks),
+            Warning::UnsupportedInfo(fields) => {
+                write!(f, "unsupported device info ignored: {}", fields.join(", "))
+            }
+            Warning::VersionMissing => write!(f, "bootloader banner carries no version string"),
+        }
+    }
+}
+
+/// How the session degraded when an optional command was unavailable
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fallback {
+    EraseSkipped,
+    VerifyWith(VerifyMethod),
+    DiagnosticsSkipped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedCommand {
+    pub command: Command,
+    pub fallback: Fallback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Entry,
+    Info,
+    Erase,
+    /// Firmware read out to a file before anything is written
+    Backup,
+    Write,
+    Verify,
+    /// Bootloader validates the metadata and marks the image bootable
+    Commit,
+    Exit,
+}
+
+/// Time spent in each update phase; repeated phases (e.g. per region) accumulate
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimings {
+    entries: Vec<(Phase, Duration)>,
+}
+
+impl PhaseTimings {
+    pub fn add(&mut self, phase: Phase, elapsed: Duration) {
+        match self.entries.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.entries.push((phase, elapsed)),
+        }
+    }
+
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        self.entries
+            .iter()
+            .find(|(p, _)| *p == phase)
+            .map(|(_, elapsed)| *elapsed)
+    }
+
+    pub fn total(&self) -> Duration {
+        self.entries.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    /// Phases in the order they were first entered
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        self.entries.iter().copied()
+    }
+}
+
+/// Outcome of programming a single memory region
+#[derive(Debug, Clone)]
+pub struct RegionReport {
+    pub kind: RegionKind,
+    pub address: u32,
+    pub size: u32,
+    pub block_size: usize,
+    pub erased: u32,
+    pub crc: u32,
+    pub skipped: bool,
+    pub verified: bool,
+}
+
+impl UpdateReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_marginal(&self) -> bool {
+        !self.hardware_warnings.is_empty()
+    }
+
+    /// Every region was found already installed on the device
+    pub fn is_up_to_date(&self) -> bool {
+        !self.regions.is_empty() && self.regions.iter().all(|region| region.skipped)
+    }
+
+    /// Succeeded, but with something automation may want to look at
+    pub fn has_warnings(&self) -> bool {
+        !self.warnings.is_empty() || self.is_marginal()
+    }
+
+    pub(super) fn warn(&mut self, warning: Warning) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::error::{Error, Result};
+use super::image::FirmwareImage;
+use super::session::SessionState;
+use super::signing::{from_hex, to_hex};
+
+/// Opaque state of a suspended transfer; pass it to `DfuConfig::with_resume_token`
+/// in any process to continue where the transfer stopped
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    session: SessionState,
+    image_crc: u32,
+    image_len: usize,
+    /// First device address not yet written
+    next_address: u32,
+}
+
+impl ResumeToken {
+    pub(super) fn new(session: SessionState, image: &FirmwareImage, next_address: u32) -> Self {
+        Self {
+            session,
+            image_crc: image.crc32(),
+            image_len: image.len(),
+            next_address,
+        }
+    }
+
+    pub(super) fn session(&self) -> &SessionState {
+        &self.session
+    }
+
+    /// First device address not yet written
+    pub fn next_address(&self) -> u32 {
+        self.next_address
+    }
+
+    pub(super) fn matches_image(&self, image: &FirmwareImage) -> bool {
+        self.image_len == image.len() && self.image_crc == image.crc32()
+    }
+
+    /// Serializes the token into a string safe to store or pass between workers
+    pub fn encode(&self) -> String {
+        let content = toml::to_string(self).expect("resume token is always serializable");
+        to_hex(content.as_bytes())
+    }
+
+    pub fn decode(token: &str) -> Result<Self> {
+        let content = String::from_utf8(from_hex(token.trim())?)
+            .map_err(|_| Error::Configuration("Malformed resume token".into()))?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum TransferState {
+    Idle,
+    Running,
+    Suspended(ResumeToken),
+    Finished,
+}
+
+/// Controls an update running on another task
+#[derive(Debug, Clone)]
+pub struct UpdateHandle {
+    suspend: Arc<AtomicBool>,
+    state: watch::Receiver<TransferState>,
+}
+
+impl UpdateHandle {
+    pub(super) fn new(suspend: Arc<AtomicBool>, state: watch::Receiver<TransferState>) -> Self {
+        Self { suspend, state }
+    }
+
+    /// Asks the transfer to stop at the next block boundary without waiting
+    pub(super) fn request_suspend(&self) {
+        self.suspend.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops the transfer at the next block boundary, leaving the device in the
+    /// bootloader, and returns the token needed to resume it
+    pub async fn suspend(&self) -> Result<ResumeToken> {
+        self.request_suspend();
+
+        let mut state = self.state.clone();
+        let state = state
+            .wait_for(|state| matches!(state, TransferState::Suspended(_) | TransferState::Finished))
+            .await
+            .map_err(|_| Error::Configuration("Update was dropped before it could be suspended".into()))?;
+        match &*state {
+            TransferState::Suspended(token) => Ok(token.clone()),
+            _ => Err(Error::Configuration("Update finished before it could be suspended".into())),
+        }
+    }
+}
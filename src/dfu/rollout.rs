@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use log::info;
+
+use crate::error::Result;
+use super::image::{FirmwareFormat, FirmwareImage, DEFAULT_FILL};
+use super::info::DeviceInfo;
+use super::scan::{Inventory, InventoryEntry};
+use super::selection::FirmwareSet;
+
+/// Assumed transfer rate when none is given, roughly a 115200 baud link
+pub const DEFAULT_THROUGHPUT: u32 = 11_520;
+/// Bootloader entry, erase and verification time added to every device
+pub const DEFAULT_DEVICE_OVERHEAD: Duration = Duration::from_secs(10);
+
+/// One device that needs new firmware
+#[derive(Debug, Clone)]
+pub struct PlannedUpdate {
+    pub uri: String,
+    pub device: DeviceInfo,
+    pub file: PathBuf,
+    /// Firmware CRC the device reported during the scan
+    pub current_crc: u32,
+    /// Firmware CRC the device will report once updated
+    pub target_crc: u32,
+    /// Bytes to transfer
+    pub size: usize,
+    pub estimate: Duration,
+}
+
+/// Updates sharing one bus, in the order they are started; the estimate
+/// assumes they run one at a time
+#[derive(Debug, Clone)]
+pub struct BusPlan {
+    pub bus: String,
+    pub updates: Vec<PlannedUpdate>,
+    pub estimate: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The device already runs the image selected for it
+    UpToDate,
+    /// No rule in the firmware set covers the device id and revision
+    NoMatchingFirmware,
+    /// Left out by a tag filter
+    Filtered,
+}
+
+/// What a rollout will do. Buses are independent and may run in parallel.
+#[derive(Debug, Clone, Default)]
+pub struct RolloutPlan {
+    pub buses: Vec<BusPlan>,
+    pub skipped: Vec<(String, SkipReason)>,
+}
+
+impl RolloutPlan {
+    pub fn updates(&self) -> impl Iterator<Item = &PlannedUpdate> {
+        self.buses.iter().flat_map(|bus| bus.updates.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buses.iter().map(|bus| bus.updates.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wall-clock estimate with every bus running in parallel
+    pub fn estimate(&self) -> Duration {
+        self.buses.iter().map(|bus| bus.estimate).max().unwrap_or_default()
+    }
+}
+
+/// Compares an inventory scan against a firmware set and plans the updates
+/// that bring every device onto its selected image
+pub struct RolloutPlanner<'a> {
+    set: &'a FirmwareSet,
+    format: FirmwareFormat,
+    fill: u8,
+    /// Bytes per second
+    throughput: u32,
+    overhead: Duration,
+}
+
+impl<'a> RolloutPlanner<'a> {
+    pub fn new(set: &'a FirmwareSet) -> Self {
+        Self {
+            set,
+            format: FirmwareFormat::Auto,
+            fill: DEFAULT_FILL,
+            throughput: DEFAULT_THROUGHPUT,
+            overhead: DEFAULT_DEVICE_OVERHEAD,
+        }
+    }
+
+    pub fn with_firmware_format(mut self, format: FirmwareFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_gap_filling(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    pub fn with_throughput(mut self, bytes_per_second: u32) -> Self {
+        self.throughput = bytes_per_second.max(1);
+        self
+    }
+
+    pub fn with_device_overhead(mut self, overhead: Duration) -> Self {
+        self.overhead = overhead;
+        self
+    }
+
+    /// Fails only if a selected image can't be loaded; unreachable devices
+    /// in the inventory are left out of the plan
+    pub fn plan(&self, inventory: &Inventory) -> Result<RolloutPlan> {
+        let mut images: HashMap<PathBuf, FirmwareImage> = HashMap::new();
+        let mut plan = RolloutPlan::default();
+
+        for entry in &inventory.devices {
+            let device = &entry.device;
+            let Some(rule) = self.set.select(device.device_id, device.device_rev) else {
+                plan.skipped.push((entry.uri.clone(), SkipReason::NoMatchingFirmware));
+                continue;
+            };
+
+            let file = self.set.path(rule);
+            if !images.contains_key(&file) {
+                let image = FirmwareImage::load(&file, self.format, self.fill)?;
+                images.insert(file.clone(), image);
+            }
+            let image = placed(&images[&file], entry);
+
+            let target_crc = image.window_crc(
+                entry.firmware_region.clone(),
+                entry.metadata_region.clone(),
+                self.fill,
+            );
+            if target_crc == entry.firmware_crc {
+                plan.skipped.push((entry.uri.clone(), SkipReason::UpToDate));
+                continue;
+            }
+
+            let size = image.data_len();
+            let update = PlannedUpdate {
+                uri: entry.uri.clone(),
+                device: device.clone(),
+                file,
+                current_crc: entry.firmware_crc,
+                target_crc,
+                size,
+                estimate: self.overhead + Duration::from_secs_f64(size as f64 / self.throughput as f64),
+            };
+
+            let bus = bus_of(&entry.uri);
+            match plan.buses.iter_mut().find(|plan| plan.bus == bus) {
+                Some(plan) => {
+                    plan.estimate += update.estimate;
+                    plan.updates.push(update);
+                }
+                None => plan.buses.push(BusPlan {
+                    bus: bus.to_string(),
+                    estimate: update.estimate,
+                    updates: vec![update],
+                }),
+            }
+        }
+
+        info!(
+            "Rollout plan: {} update(s) on {} bus(es), {} skipped, about {:?}",
+            plan.len(),
+            plan.buses.len(),
+            plan.skipped.len(),
+            plan.estimate()
+        );
+        Ok(plan)
+    }
+}
+
+/// Unplaced images land at the start of the device's firmware region, as in an update
+fn placed(image: &FirmwareImage, entry: &InventoryEntry) -> FirmwareImage {
+    if image.is_placed() {
+        image.clone()
+    } else {
+        image.clone().with_base(entry.firmware_region.start)
+    }
+}
+
+/// Devices addressed through the same port or gateway share a bus; only the
+/// query (e.g. a network id) tells them apart
+pub(super) fn bus_of(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or(uri)
+}
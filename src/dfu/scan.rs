@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::ops::Range;
+use log::{info, warn};
+
+use crate::error::{Error, Result};
+use crate::transport::DfuTransport;
+use super::info::DeviceInfo;
+use super::report::UpdateReport;
+use super::types::UpdateMode;
+use super::DfuStream;
+
+/// What a device reported during a scan
+#[derive(Debug, Clone)]
+pub struct InventoryEntry {
+    pub uri: String,
+    /// UID, device id and revision, bootloader version
+    pub device: DeviceInfo,
+    /// Device CRC over the whole firmware region
+    pub firmware_crc: u32,
+    pub firmware_region: Range<u32>,
+    /// Left out of the firmware CRC where it overlaps the firmware region
+    pub metadata_region: Range<u32>,
+}
+
+/// Result of a read-only scan over a set of devices
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub devices: Vec<InventoryEntry>,
+    /// URIs that couldn't be opened or didn't answer, with the reason
+    pub unreachable: Vec<(String, Error)>,
+}
+
+impl Inventory {
+    pub fn find(&self, uid: &[u8; 16]) -> Option<&InventoryEntry> {
+        self.devices.iter().find(|entry| &entry.device.uid == uid)
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Reads the device's identity and firmware CRC without writing anything
+    pub async fn inventory(&mut self) -> Result<InventoryEntry> {
+        let mut report = UpdateReport::new();
+        if self.config.upd_mode != UpdateMode::None {
+            self.enter_bootloader(&mut report).await?;
+        }
+
+        let info = self.prepare_session(&mut report).await?;
+        let (address, size) = (info.memmap.firmware_address, info.memmap.firmware_size);
+        let (md_address, md_size) = (info.memmap.metadata_address, info.memmap.metadata_size);
+        let firmware_crc = self.read_firmware_crc(address, size).await?;
+        self.leave_bootloader(&mut report, false).await?;
+
+        Ok(InventoryEntry {
+            uri: self.config.uri.clone(),
+            device: DeviceInfo::from(&info),
+            firmware_crc,
+            firmware_region: address..address + size,
+            metadata_region: md_address..md_address + md_size,
+        })
+    }
+}
+
+/// Visits every URI in turn, opening a session with `open` and reading its
+/// inventory. A device that fails is recorded as unreachable and the scan
+/// carries on.
+pub async fn scan<T, F, Fut>(uris: &[String], mut open: F) -> Inventory
+where
+    T: DfuTransport,
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<DfuStream<T>>>,
+{
+    let mut inventory = Inventory::default();
+    for uri in uris {
+        let entry = match open(uri.clone()).await {
+            Ok(mut dfu) => dfu.inventory().await,
+            Err(e) => Err(e),
+        };
+
+        match entry {
+            Ok(entry) => {
+                info!(
+                    "{}: device {:#06x} rev {}, bootloader {:#04x}, firmware CRC {:#010x}",
+                    uri,
+                    entry.device.device_id,
+                    entry.device.device_rev,
+                    entry.device.bootloader_version,
+                    entry.firmware_crc
+                );
+                inventory.devices.push(entry);
+            }
+            Err(e) => {
+                warn!("{}: {}", uri, e);
+                inventory.unreachable.push((uri.clone(), e));
+            }
+        }
+    }
+    inventory
+}
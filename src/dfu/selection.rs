@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use log::info;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use super::image::FirmwareImage;
+use super::info::DeviceInfo;
+
+/// Mapping file looked up when a firmware set is given as a directory
+pub const FIRMWARE_SET_MANIFEST: &str = "firmware.toml";
+
+/// Selects the image built for a device id and revision range
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FirmwareRule {
+    pub device_id: u16,
+    #[serde(default)]
+    pub min_rev: u16,
+    #[serde(default = "max_rev")]
+    pub max_rev: u16,
+    /// Image path, relative to the mapping file
+    pub file: String,
+}
+
+fn max_rev() -> u16 {
+    u16::MAX
+}
+
+impl FirmwareRule {
+    pub fn matches(&self, device_id: u16, rev: u16) -> bool {
+        self.device_id == device_id && rev >= self.min_rev && rev <= self.max_rev
+    }
+}
+
+#[derive(Deserialize)]
+struct FirmwareSetFile {
+    #[serde(default)]
+    firmware: Vec<FirmwareRule>,
+}
+
+/// Firmware images for several hardware variants; the first matching rule wins
+///
+/// ```toml
+/// [[firmware]]
+/// device_id = 0x1234
+/// max_rev = 1
+/// file = "board-a.hex"
+///
+/// [[firmware]]
+/// device_id = 0x1234
+/// min_rev = 2
+/// file = "board-b.hex"
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareSet {
+    rules: Vec<FirmwareRule>,
+    base_dir: PathBuf,
+}
+
+impl FirmwareSet {
+    /// Opens a mapping file, or the `firmware.toml` inside a directory
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            Self::from_file(path.join(FIRMWARE_SET_MANIFEST))
+        } else {
+            Self::from_file(path)
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let file: FirmwareSetFile = toml::from_str(&content)
+            .map_err(|e| Error::Configuration(e.to_string()))?;
+
+        Ok(Self {
+            rules: file.firmware,
+            base_dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        })
+    }
+
+    pub fn rules(&self) -> &[FirmwareRule] {
+        &self.rules
+    }
+
+    pub fn select(&self, device_id: u16, rev: u16) -> Option<&FirmwareRule> {
+        self.rules.iter().find(|rule| rule.matches(device_id, rev))
+    }
+
+    pub fn path(&self, rule: &FirmwareRule) -> PathBuf {
+        self.base_dir.join(&rule.file)
+    }
+}
+
+/// Images parsed before bootloader entry, pending the device's identity
+pub(super) enum LoadedFirmware {
+    Single(FirmwareImage),
+    ByDevice(Vec<(FirmwareRule, FirmwareImage)>),
+}
+
+impl LoadedFirmware {
+    pub(super) fn select(self, device: &DeviceInfo) -> Result<FirmwareImage> {
+        match self {
+            LoadedFirmware::Single(image) => Ok(image),
+            LoadedFirmware::ByDevice(candidates) => {
+                let (rule, image) = candidates
+                    .into_iter()
+                    .find(|(rule, _)| rule.matches(device.device_id, device.device_rev))
+                    .ok_or(Error::NoMatchingFirmware {
+                        device_id: device.device_id,
+                        device_rev: device.device_rev,
+                    })?;
+                info!(
+                    "Selected {} for device {:#06x} rev {}",
+                    rule.file, device.device_id, device.device_rev
+                );
+                Ok(image)
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use log::{info, warn};
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use crate::error::Result;
+use super::signing::to_hex;
+use super::types::DfuConfig;
+use super::DfuStream;
+
+/// Accepts devices dialling in from behind NAT and runs the update flow on
+/// each connection as it arrives. Devices are told apart by the UID in
+/// their bootloader info, so `config` should pick images with a firmware set
+/// when more than one kind connects. Runs until the listener fails.
+pub async fn serve(addr: impl ToSocketAddrs, config: DfuConfig) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Waiting for devices on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        // Each connection is a session of its own, locked by the peer address
+        let config = DfuConfig { uri: format!("tcp://{}", peer), ..config.clone() };
+        tokio::spawn(async move {
+            info!("Device connected from {}", peer);
+            let result = match DfuStream::new(stream, config) {
+                Ok(mut dfu) => dfu.update().await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(report) => match &report.device {
+                    Some(device) => info!("Device {} at {} done", to_hex(&device.uid), peer),
+                    None => info!("Session with {} done", peer),
+                },
+                Err(e) => warn!("Session with {} failed: {}", peer, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use super::info::DeviceInfo;
+use super::types::Baud;
+
+/// Sessions older than this are assumed to have timed out in the bootloader
+pub const SESSION_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// "Device is in the bootloader at this URI/baud" record handed between processes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    pub uri: String,
+    pub speed: Baud,
+    pub device_id: u16,
+    pub bootloader_version: u8,
+    pub uid: [u8; 16],
+    /// Seconds since the Unix epoch when the bootloader was last known active
+    pub timestamp: u64,
+}
+
+impl SessionState {
+    pub fn new(uri: &str, speed: Baud, device: &DeviceInfo) -> Self {
+        Self {
+            uri: uri.to_string(),
+            speed,
+            device_id: device.device_id,
+            bootloader_version: device.bootloader_version,
+            uid: device.uid,
+            timestamp: unix_now(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let content = toml::to_string(self).map_err(|e| Error::Configuration(e.to_string()))?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.timestamp))
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.age() > SESSION_MAX_AGE
+    }
+
+    /// Checks that a resumed session still talks to the same device
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.device_id == device.device_id && self.uid == device.uid
+    }
+}
+
+pub(super) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
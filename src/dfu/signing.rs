@@ -0,0 +1,92 @@
+use std::path::Path;
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use super::image::FirmwareImage;
+use super::verify::{Manifest, ManifestEntry};
+
+pub use ed25519_dalek::SigningKey;
+
+/// Produces a signed manifest for `image`, in the format the `Manifest` verifier reads
+pub fn sign(image: &FirmwareImage, key: &SigningKey) -> Manifest {
+    let mut sha256 = Sha256::new();
+    image.for_each_chunk(|chunk| sha256.update(chunk));
+    let mut manifest = Manifest {
+        regions: vec![ManifestEntry {
+            address: image.base(),
+            size: image.len() as u32,
+            crc32: image.crc32(),
+            sha256: Some(to_hex(&sha256.finalize())),
+        }],
+        signature: None,
+    };
+    let signature = key.sign(&manifest.signed_bytes());
+    manifest.signature = Some(to_hex(&signature.to_bytes()));
+    manifest
+}
+
+impl Manifest {
+    /// Canonical encoding of the region entries covered by the signature
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.regions.len() * 44);
+        for entry in &self.regions {
+            bytes.extend_from_slice(&entry.address.to_le_bytes());
+            bytes.extend_from_slice(&entry.size.to_le_bytes());
+            bytes.extend_from_slice(&entry.crc32.to_le_bytes());
+            let digest = entry.sha256.as_deref().map(from_hex).transpose();
+            match digest {
+                Ok(Some(digest)) if digest.len() == 32 => bytes.extend_from_slice(&digest),
+                _ => bytes.extend_from_slice(&[0; 32]),
+            }
+        }
+        bytes
+    }
+
+    pub fn verify_signature(&self, key: &VerifyingKey) -> Result<()> {
+        let signature = self.signature.as_deref().ok_or(Error::SignatureInvalid)?;
+        let signature = from_hex(signature)?
+            .try_into()
+            .map(|bytes: [u8; 64]| Signature::from_bytes(&bytes))
+            .map_err(|_| Error::SignatureInvalid)?;
+
+        key.verify_strict(&self.signed_bytes(), &signature)
+            .map_err(|_| Error::SignatureInvalid)
+    }
+}
+
+/// Loads an Ed25519 private key stored as 64 hex characters
+pub fn load_signing_key(path: impl AsRef<Path>) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&read_key(path)?))
+}
+
+/// Loads an Ed25519 public key stored as 64 hex characters
+pub fn load_verifying_key(path: impl AsRef<Path>) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&read_key(path)?)
+        .map_err(|e| Error::Configuration(format!("Invalid public key: {}", e)))
+}
+
+fn read_key(path: impl AsRef<Path>) -> Result<[u8; 32]> {
+    let content = std::fs::read_to_string(path)?;
+    from_hex(content.trim())?
+        .try_into()
+        .map_err(|_| Error::Configuration("Key must be 32 bytes".to_string()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(Error::Configuration(format!("Invalid hex string: {}", s)));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| Error::Configuration(format!("Invalid hex string: {}", s)))
+        })
+        .collect()
+}
@@ -0,0 +1,642 @@
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use bytes::{BufMut, BytesMut};
+// CRC-16/IBM-3740 is the catalog name of CRC-16/CCITT-FALSE
+use crc::{Crc, CRC_16_IBM_3740};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+
+use crate::error::{Error, Result};
+use crate::protocols::apl::{
+    AckPolicy, AplAckPacket, AplDataPacket, AplHeader, AplRequestPacket, AplRequestPacket64, AplRequestType,
+};
+use crate::protocols::lpl::{encode_frame, Framing, RESPONSE_FLAG};
+use super::capabilities::CAPABILITIES_BLOCK_SIZE;
+use super::image::FirmwareImage;
+use super::info::{DIAGNOSTICS_BLOCK_SIZE, PROTOCOL_FLAG_ADDR64, PROTOCOL_FLAG_CAPABILITIES};
+use super::types::{Command, InfoBlockV2};
+
+const READ_CHUNK: usize = 1024;
+/// Frames longer than this are line noise, not requests
+const MAX_FRAME_LEN: usize = 4096;
+const ERASED: u8 = 0xFF;
+
+/// Flash layout of a simulated device
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimMemoryMap {
+    pub flash_address: u32,
+    pub flash_size: u32,
+    pub metadata_address: u32,
+    pub metadata_size: u32,
+    pub firmware_address: u32,
+    pub firmware_size: u32,
+    pub write_block_size: u16,
+    /// Sector groups from the start of flash as (count, size), at most five
+    pub sectors: Vec<(u32, u32)>,
+}
+
+impl Default for SimMemoryMap {
+    fn default() -> Self {
+        Self {
+            flash_address: 0x0800_0000,
+            flash_size: 0x4_0000,
+            metadata_address: 0x0800_3000,
+            metadata_size: 0x1000,
+            firmware_address: 0x0800_4000,
+            firmware_size: 0x3_C000,
+            write_block_size: 256,
+            sectors: vec![(4, 0x1000), (15, 0x4000)],
+        }
+    }
+}
+
+impl SimMemoryMap {
+    fn flash_range(&self) -> Range<u32> {
+        self.flash_address..self.flash_address + self.flash_size
+    }
+
+    fn metadata_range(&self) -> Range<u32> {
+        self.metadata_address..self.metadata_address + self.metadata_size
+    }
+
+    /// Sectors overlapping `range`
+    fn sectors_in(&self, range: Range<u32>) -> u32 {
+        let mut start = self.flash_address;
+        let mut touched = 0;
+        for &(count, size) in &self.sectors {
+            for _ in 0..count {
+                if start < range.end && start + size > range.start {
+                    touched += 1;
+                }
+                start += size;
+            }
+        }
+        touched
+    }
+}
+
+/// Time the simulated bootloader takes over each operation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimDelays {
+    pub response_ms: u64,
+    pub erase_sector_ms: u64,
+    pub write_block_ms: u64,
+}
+
+/// Misbehaviour the simulated device can be told to show
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SimFault {
+    /// Stops answering after this many requests, until the link is re-opened
+    Hang { after: u32 },
+    /// Drops the link once, after this many requests
+    Disconnect { after: u32 },
+    /// Leaves every n-th request unanswered
+    DropEvery { n: u32 },
+    /// Reports CRCs with the low bit flipped
+    WrongCrc,
+    /// Answers `CommitImage` with this status
+    RejectCommit { status: u8 },
+    /// Acknowledges block N as N + 1, like some early bootloaders
+    OffByOneAck,
+}
+
+impl FromStr for SimFault {
+    type Err = Error;
+
+    /// `hang:N`, `disconnect:N`, `drop-every:N`, `wrong-crc`, `reject-commit:STATUS`
+    /// or `off-by-one-ack`
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None),
+        };
+        let number = || -> Result<u32> {
+            value
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Error::Configuration(format!("Fault {} needs a number", name)))
+        };
+        match name {
+            "hang" => Ok(Self::Hang { after: number()? }),
+            "disconnect" => Ok(Self::Disconnect { after: number()? }),
+            "drop-every" => Ok(Self::DropEvery { n: number()?.max(1) }),
+            "wrong-crc" => Ok(Self::WrongCrc),
+            "off-by-one-ack" => Ok(Self::OffByOneAck),
+            "reject-commit" => {
+                let status = u8::try_from(number()?)
+                    .map_err(|_| Error::Configuration("Commit status must fit a byte".into()))?;
+                Ok(Self::RejectCommit { status })
+            }
+            _ => Err(Error::Configuration(format!("Unknown fault: {}", s))),
+        }
+    }
+}
+
+impl TryFrom<String> for SimFault {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// The bootloader a [`SimulatedDevice`] pretends to be, loadable from TOML:
+///
+/// ```toml
+/// device_id = 0x1234
+/// banner = "BL v2.1 (sim)"
+/// faults = ["wrong-crc", "disconnect:40"]
+///
+/// [memory]
+/// flash_size = 0x80000
+/// sectors = [[4, 0x4000], [7, 0x10000]]
+///
+/// [delays]
+/// erase_sector_ms = 20
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimModel {
+    pub bootloader_version: u8,
+    pub max_block_size: u16,
+    pub device_id: u16,
+    pub device_rev: u16,
+    pub uid: [u8; 16],
+    pub addr64: bool,
+    /// Answer `ReadCapabilities` rather than leave the host to infer them
+    pub capabilities: bool,
+    /// Printed on every new connection, before the binary protocol
+    pub banner: Option<String>,
+    /// Node address on a multi-drop bus; frames for other nodes are ignored
+    pub netid: Option<u8>,
+    pub framing: Framing,
+    /// How data blocks are acknowledged; the window is also reported as
+    /// the device's concurrent block limit
+    pub ack_policy: AckPolicy,
+    pub memory: SimMemoryMap,
+    pub delays: SimDelays,
+    pub faults: Vec<SimFault>,
+}
+
+impl Default for SimModel {
+    fn default() -> Self {
+        Self {
+            bootloader_version: 0x30,
+            max_block_size: 1024,
+            device_id: 0x5100,
+            device_rev: 1,
+            uid: *b"fwupd-simulator\0",
+            addr64: false,
+            capabilities: true,
+            banner: None,
+            netid: None,
+            framing: Framing::default(),
+            ack_policy: AckPolicy::default(),
+            memory: SimMemoryMap::default(),
+            delays: SimDelays::default(),
+            faults: Vec::new(),
+        }
+    }
+}
+
+impl SimModel {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    /// The info block as the bootloader sends it, laid out like [`InfoBlockV2`]
+    fn info_block(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.addr64 {
+            flags |= PROTOCOL_FLAG_ADDR64;
+        }
+        if self.capabilities {
+            flags |= PROTOCOL_FLAG_CAPABILITIES;
+        }
+        let memory = &self.memory;
+
+        let mut block = BytesMut::with_capacity(size_of::<InfoBlockV2>());
+        block.put_u8(self.bootloader_version);
+        block.put_u16_le(self.max_block_size);
+        block.put_u16_le(self.device_id);
+        block.put_u16_le(self.device_rev);
+        block.put_slice(&self.uid);
+        block.put_u8(flags);
+        block.put_bytes(0, 17);
+        for value in [
+            memory.metadata_address,
+            memory.metadata_size,
+            memory.firmware_address,
+            memory.firmware_size,
+            memory.flash_address,
+            memory.flash_size,
+        ] {
+            block.put_u32_le(value);
+        }
+        block.put_u16_le(memory.write_block_size);
+        for i in 0..5 {
+            let (count, size) = memory.sectors.get(i).copied().unwrap_or_default();
+            block.put_u32_le(count);
+            block.put_u32_le(size);
+        }
+        block.to_vec()
+    }
+
+    fn capability_block(&self) -> Vec<u8> {
+        let bitmap = Command::ALL.iter().fold(0u32, |bitmap, command| bitmap | 1 << *command as u8);
+        let mut sizes: Vec<u32> = self.memory.sectors.iter().map(|&(_, size)| size).collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+        sizes.truncate(6);
+
+        let mut block = BytesMut::with_capacity(CAPABILITIES_BLOCK_SIZE);
+        block.put_u32_le(bitmap);
+        block.put_u16_le(self.ack_policy.window());
+        // CRC32 and SHA-256
+        block.put_u8(0x03);
+        block.put_u8(sizes.len() as u8);
+        for size in sizes {
+            block.put_u32_le(size);
+        }
+        block.resize(CAPABILITIES_BLOCK_SIZE, 0);
+        block.to_vec()
+    }
+}
+
+/// What a request left the session to do next
+enum Flow {
+    Continue,
+    Quit,
+    Disconnect,
+}
+
+/// A bootloader in software, answering the host's requests on any stream
+/// (TCP, a PTY, or an in-memory [`tokio::io::duplex`] pipe). Flash contents
+/// survive across [`serve`](Self::serve) calls, like a device across
+/// reconnections.
+///
+/// Block data follows a `WriteProgramMemory` request as APL data packets,
+/// acknowledged as the model's [`AckPolicy`] says. Writes can only clear bits, so a region written
+/// without an erase reads back corrupted, as it would on real flash.
+pub struct SimulatedDevice {
+    model: SimModel,
+    flash: Vec<u8>,
+    faults: Vec<SimFault>,
+    erase_count: u32,
+    write_count: u32,
+    requests: u32,
+    /// Next address and bytes left of the write request being served
+    pending_write: Option<(u64, u64)>,
+    block_number: u16,
+    /// Blocks written since the last ACK
+    unacked: u16,
+    hung: bool,
+}
+
+impl SimulatedDevice {
+    pub fn new(model: SimModel) -> Self {
+        let flash = vec![ERASED; model.memory.flash_size as usize];
+        let faults = model.faults.clone();
+        Self {
+            model,
+            flash,
+            faults,
+            erase_count: 0,
+            write_count: 0,
+            requests: 0,
+            pending_write: None,
+            block_number: 0,
+            unacked: 0,
+            hung: false,
+        }
+    }
+
+    /// Starts with `image` already in flash, as if flashed at the factory
+    pub fn with_image(mut self, image: &FirmwareImage) -> Result<Self> {
+        let range = self.offsets(image.base() as u64, image.len() as u64).ok_or_else(|| {
+            Error::Configuration(format!("Image at {:#010x} lies outside the simulated flash", image.base()))
+        })?;
+        self.flash[range].copy_from_slice(&image.to_bytes());
+        Ok(self)
+    }
+
+    pub fn model(&self) -> &SimModel {
+        &self.model
+    }
+
+    /// Current flash contents, from `flash_address`
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Answers requests on `stream` until the host quits or closes it
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&mut self, mut stream: S) -> Result<()> {
+        self.pending_write = None;
+        self.hung = false;
+        if let Some(banner) = &self.model.banner {
+            stream.write_all(format!("{}\r\n", banner).as_bytes()).await?;
+        }
+
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                debug!("Host closed the link");
+                return Ok(());
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+
+            while let Some(frame) = self.next_frame(&mut buffer) {
+                match self.handle_frame(&frame, &mut stream).await? {
+                    Flow::Continue => {}
+                    Flow::Quit => {
+                        info!("Host quit the bootloader");
+                        return Ok(());
+                    }
+                    Flow::Disconnect => {
+                        warn!("Dropping the link after {} requests", self.requests);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the next complete frame body out of `buffer`, skipping idle
+    /// bytes and noise before its SYN
+    fn next_frame(&self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+        let Framing { syn, delimiter } = self.model.framing;
+        let start = match buffer.iter().position(|b| *b == syn) {
+            Some(start) => start,
+            None => {
+                buffer.clear();
+                return None;
+            }
+        };
+        buffer.drain(..start);
+        let end = buffer[1..].iter().position(|b| *b == delimiter)? + 1;
+        let frame = buffer[1..end].to_vec();
+        buffer.drain(..=end);
+        if frame.len() > MAX_FRAME_LEN {
+            debug!("Dropping {} byte frame", frame.len());
+            return None;
+        }
+        Some(frame)
+    }
+
+    /// Checks a frame's COBS, CRC and node address; `None` if it must be ignored
+    fn decode_frame(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let payload: Vec<u8> = frame.iter().map(|b| b ^ self.model.framing.delimiter).collect();
+        let mut decoded = vec![0; payload.len()];
+        let Ok(len) = cobs::decode(&payload, &mut decoded) else {
+            debug!("Dropping frame with invalid COBS encoding");
+            return None;
+        };
+        if len < 2 {
+            return None;
+        }
+        decoded.truncate(len);
+
+        let crc_bytes = decoded.split_off(len - 2);
+        let received = u16::from_le_bytes([crc_bytes[0], crc_bytes[1]]);
+        if Crc::<u16>::new(&CRC_16_IBM_3740).checksum(&decoded) != received {
+            debug!("Dropping frame with bad CRC");
+            return None;
+        }
+
+        match self.model.netid {
+            Some(netid) if decoded.first() == Some(&netid) => Some(decoded.split_off(1)),
+            Some(_) => None,
+            None => Some(decoded),
+        }
+    }
+
+    async fn handle_frame<S: AsyncWrite + Unpin>(&mut self, frame: &[u8], stream: &mut S) -> Result<Flow> {
+        let Some(packet) = self.decode_frame(frame) else {
+            return Ok(Flow::Continue);
+        };
+        let Some(&type_id) = packet.first() else {
+            return Ok(Flow::Continue);
+        };
+        let Ok(packet_type) = AplRequestType::try_from(type_id) else {
+            warn!("Ignoring packet of unknown type {:#04x}", type_id);
+            return Ok(Flow::Continue);
+        };
+
+        if packet_type == AplRequestType::Data {
+            return self.handle_data(&packet, stream).await;
+        }
+        let request = match packet_type {
+            AplRequestType::ReadRequest | AplRequestType::WriteRequest => {
+                AplRequestPacket::from_bytes(&packet).map(|p| (p.command, p.offset as u64, p.length as u64))
+            }
+            AplRequestType::ReadRequest64 | AplRequestType::WriteRequest64 => {
+                AplRequestPacket64::from_bytes(&packet).map(|p| (p.command, p.offset, p.length))
+            }
+            other => {
+                warn!("Ignoring unexpected {:?} packet from the host", other);
+                return Ok(Flow::Continue);
+            }
+        };
+        let Ok((command, offset, length)) = request else {
+            warn!("Ignoring truncated request");
+            return Ok(Flow::Continue);
+        };
+
+        self.requests += 1;
+        if let Some(flow) = self.inject_fault() {
+            return Ok(flow);
+        }
+        if self.pending_write.take().is_some() {
+            warn!("Write request at block {} received no data", self.block_number);
+        }
+        let Ok(command) = Command::try_from(command) else {
+            warn!("Ignoring unknown command {}", command);
+            return Ok(Flow::Continue);
+        };
+        debug!("{:?} at {:#010x}, {} bytes", command, offset, length);
+
+        sleep(Duration::from_millis(self.model.delays.response_ms)).await;
+        let response = match command {
+            Command::ReadBootloaderInfo => self.model.info_block(),
+            Command::ReadCapabilities => self.model.capability_block(),
+            Command::ReadProgramMemory => self.read(offset, length),
+            Command::ReadProgramCrc => {
+                let crc = crc32fast::hash(&self.crc_window(offset, length));
+                let crc = if self.faults.contains(&SimFault::WrongCrc) { crc ^ 1 } else { crc };
+                crc.to_le_bytes().to_vec()
+            }
+            Command::ReadProgramSha256 => Sha256::digest(self.read(offset, length)).to_vec(),
+            Command::ReadDiagnostics => self.diagnostics(),
+            Command::EraseMemory => {
+                self.erase(offset, length).await;
+                Vec::new()
+            }
+            Command::WriteProgramMemory => {
+                self.pending_write = Some((offset, length));
+                self.block_number = 0;
+                self.unacked = 0;
+                self.write_count += 1;
+                Vec::new()
+            }
+            Command::CommitImage => {
+                let status = self.faults.iter().find_map(|fault| match fault {
+                    SimFault::RejectCommit { status } => Some(*status),
+                    _ => None,
+                });
+                vec![status.unwrap_or(0)]
+            }
+            Command::BootloaderQuit => return Ok(Flow::Quit),
+        };
+        stream.write_all(&response).await?;
+        Ok(Flow::Continue)
+    }
+
+    /// Applies the configured faults to the request just counted; `Some`
+    /// when it must go unanswered
+    fn inject_fault(&mut self) -> Option<Flow> {
+        if self.hung {
+            return Some(Flow::Continue);
+        }
+        let requests = self.requests;
+        for i in 0..self.faults.len() {
+            match self.faults[i] {
+                SimFault::Hang { after } if requests > after => {
+                    warn!("Hanging after {} requests", after);
+                    self.faults.remove(i);
+                    self.hung = true;
+                    return Some(Flow::Continue);
+                }
+                SimFault::Disconnect { after } if requests > after => {
+                    self.faults.remove(i);
+                    return Some(Flow::Disconnect);
+                }
+                SimFault::DropEvery { n } if requests.is_multiple_of(n) => {
+                    debug!("Dropping request {}", requests);
+                    return Some(Flow::Continue);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    async fn handle_data<S: AsyncWrite + Unpin>(&mut self, packet: &[u8], stream: &mut S) -> Result<Flow> {
+        let Some((address, remaining)) = self.pending_write else {
+            warn!("Ignoring data packet outside a write request");
+            return Ok(Flow::Continue);
+        };
+        let Ok(data) = AplDataPacket::from_bytes(packet) else {
+            return Ok(Flow::Continue);
+        };
+        if data.block_number != self.block_number {
+            warn!("Expected block {}, got {}", self.block_number, data.block_number);
+            return Ok(Flow::Continue);
+        }
+
+        let len = (data.data.len() as u64).min(remaining);
+        match self.offsets(address, len) {
+            // Programming can only clear bits
+            Some(range) => self.flash[range]
+                .iter_mut()
+                .zip(&data.data)
+                .for_each(|(cell, byte)| *cell &= byte),
+            None => warn!("Write at {:#010x} lies outside flash", address),
+        }
+        sleep(Duration::from_millis(self.model.delays.write_block_ms)).await;
+
+        self.pending_write = (remaining > len).then(|| (address + len, remaining - len));
+        let block_number = self.block_number;
+        self.block_number = self.block_number.wrapping_add(1);
+        self.unacked += 1;
+
+        // ACKs are cumulative, one per window under a coalescing policy
+        let policy = self.model.ack_policy;
+        let last = self.pending_write.is_none();
+        if self.unacked < policy.window() && !(last && policy.acks_partial_window()) {
+            return Ok(Flow::Continue);
+        }
+        self.unacked = 0;
+        let off_by_one = self.faults.contains(&SimFault::OffByOneAck);
+        let ack = AplAckPacket {
+            header: AplHeader { type_id: AplRequestType::Ack as u8 },
+            block_number: block_number.wrapping_add(off_by_one as u16),
+        };
+        let netid = self.model.netid.map(|netid| netid | RESPONSE_FLAG);
+        let mut frame = BytesMut::new();
+        encode_frame(self.model.framing, netid, &ack.to_bytes(), &mut frame);
+        stream.write_all(&frame).await?;
+        Ok(Flow::Continue)
+    }
+
+    /// Index range into `flash` for a device address range, if inside it
+    fn offsets(&self, address: u64, len: u64) -> Option<Range<usize>> {
+        let flash = self.model.memory.flash_range();
+        let end = address.checked_add(len)?;
+        if address < flash.start as u64 || end > flash.end as u64 {
+            return None;
+        }
+        let start = (address - flash.start as u64) as usize;
+        Some(start..start + len as usize)
+    }
+
+    /// Bytes at `address`, erased flash outside the simulated range
+    fn read(&self, address: u64, len: u64) -> Vec<u8> {
+        (address..address + len)
+            .map(|address| match self.offsets(address, 1) {
+                Some(range) => self.flash[range.start],
+                None => ERASED,
+            })
+            .collect()
+    }
+
+    /// The range the bootloader checksums: its own metadata is left out of
+    /// any larger range overlapping it
+    fn crc_window(&self, address: u64, len: u64) -> Vec<u8> {
+        let metadata = self.model.memory.metadata_range();
+        let (md_start, md_end) = (metadata.start as u64, metadata.end as u64);
+        let start = md_start.max(address);
+        let end = md_end.min(address + len);
+        let inside = address >= md_start && address + len <= md_end;
+        if start >= end || inside {
+            return self.read(address, len);
+        }
+        let mut window = self.read(address, start - address);
+        window.extend(self.read(end, address + len - end));
+        window
+    }
+
+    async fn erase(&mut self, address: u64, len: u64) {
+        match self.offsets(address, len) {
+            Some(range) => self.flash[range].fill(ERASED),
+            None => {
+                warn!("Erase at {:#010x} lies outside flash", address);
+                return;
+            }
+        }
+        self.erase_count += 1;
+        let sectors = self.model.memory.sectors_in(address as u32..(address + len) as u32);
+        sleep(Duration::from_millis(self.model.delays.erase_sector_ms * sectors as u64)).await;
+    }
+
+    fn diagnostics(&self) -> Vec<u8> {
+        let mut block = BytesMut::with_capacity(DIAGNOSTICS_BLOCK_SIZE);
+        // 3.3 V, 25.0 C, reset by power-on
+        block.put_u16_le(3300);
+        block.put_i16_le(250);
+        block.put_u8(1);
+        block.put_bytes(0, 3);
+        block.put_u32_le(self.erase_count);
+        block.put_u32_le(self.write_count);
+        block.to_vec()
+    }
+}
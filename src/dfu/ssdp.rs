@@ -0,0 +1,89 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use log::{debug, info};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+use crate::error::{Error, Result};
+use super::discovery::UriCandidate;
+
+/// SSDP search target networked bootloaders and gateways answer
+pub const DFU_SEARCH_TARGET: &str = "urn:dfu-lib-rs:service:dfu:1";
+const SSDP_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+/// URI schemes a `LOCATION` header may name
+const DEVICE_SCHEMES: [&str; 3] = ["tcp", "udp", "quic"];
+
+/// Multicasts an SSDP `M-SEARCH` for [`DFU_SEARCH_TARGET`] and collects
+/// the answers for `window`. Each answer's `LOCATION` header holds the
+/// device URI (`tcp://10.0.0.7:5000`); `USN` is taken as its serial number.
+pub async fn ssdp_uri_candidates(window: Duration) -> Result<Vec<UriCandidate>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| Error::Connection(format!("SSDP: {}", e)))?;
+    let mx = window.as_secs().clamp(1, 5);
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+        SSDP_GROUP, mx, DFU_SEARCH_TARGET
+    );
+    socket.send_to(search.as_bytes(), SSDP_GROUP).await?;
+
+    let deadline = Instant::now() + window;
+    let mut candidates: Vec<UriCandidate> = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Ok(Ok((len, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Some(candidate) = parse_response(&String::from_utf8_lossy(&buf[..len])) else {
+            debug!("SSDP: ignoring answer from {}", from);
+            continue;
+        };
+        if candidates.iter().any(|known| known.uri == candidate.uri) {
+            continue;
+        }
+        debug!("SSDP: {} answered with {}", from, candidate.uri);
+        candidates.push(candidate);
+    }
+    info!("SSDP: {} device(s) answering {}", candidates.len(), DFU_SEARCH_TARGET);
+    Ok(candidates)
+}
+
+/// Picks the device URI out of an `HTTP/1.1 200 OK` search answer
+fn parse_response(response: &str) -> Option<UriCandidate> {
+    let mut lines = response.lines();
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    let (mut location, mut target, mut usn, mut server) = (None, None, None, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match name.trim().to_ascii_uppercase().as_str() {
+            "LOCATION" => location = Some(value),
+            "ST" => target = Some(value),
+            "USN" => usn = Some(value),
+            "SERVER" => server = Some(value),
+            _ => {}
+        }
+    }
+    if target.as_deref() != Some(DFU_SEARCH_TARGET) {
+        return None;
+    }
+    let uri = location?;
+    let (scheme, _) = uri.split_once("://")?;
+    if !DEVICE_SCHEMES.contains(&scheme) {
+        return None;
+    }
+
+    // uuid:<id>::<search target>
+    let serial_number = usn.map(|usn| {
+        let id = usn.split("::").next().unwrap_or(&usn);
+        id.strip_prefix("uuid:").unwrap_or(id).to_string()
+    });
+    Some(UriCandidate {
+        uri,
+        description: format!("Network device {}", server.as_deref().unwrap_or("(SSDP)")),
+        vid: None,
+        pid: None,
+        serial_number,
+    })
+}
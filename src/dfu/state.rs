@@ -0,0 +1,37 @@
+use super::report::Phase;
+
+/// What an update is doing right now; watch it through `DfuStream::state` to
+/// tell a slow erase or verify from a stalled transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpdateState {
+    #[default]
+    Idle,
+    EnteringBootloader,
+    /// Reading device info and capabilities
+    Negotiating,
+    Erasing,
+    /// Index of the block being written within the current region
+    Writing { block: usize },
+    Verifying,
+    Exiting,
+}
+
+/// How far the transfer has come; watch it through `DfuStream::progress` to
+/// draw a progress bar
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateProgress {
+    /// `None` until the first region is erased or written
+    pub phase: Option<Phase>,
+    /// Index of the last block written within the current region
+    pub block: usize,
+    /// Bytes written so far, across all regions
+    pub written: usize,
+    /// Bytes the session writes in total, less regions found up to date
+    pub total: usize,
+}
+
+impl UpdateProgress {
+    pub fn percent(&self) -> u8 {
+        (self.written * 100 / self.total.max(1)).min(100) as u8
+    }
+}
@@ -0,0 +1,276 @@
+use log::info;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::time::Instant;
+
+use crate::error::{Checksum, Error, Result};
+use crate::transport::DfuTransport;
+use super::image::FirmwareFormat;
+use super::info::DeviceInfo;
+use super::region::MemoryRegion;
+use super::report::{Phase, RegionReport, UpdateReport};
+use super::types::{Command, UpdateMode};
+use super::DfuStream;
+
+/// Gathers streamed bytes into device blocks, writing each one as soon as it is full
+struct BlockWriter {
+    region: MemoryRegion,
+    base: u32,
+    block_size: usize,
+    /// Gap filling between Intel HEX records
+    #[cfg_attr(not(feature = "ihex"), allow(dead_code))]
+    fill: u8,
+    block: Vec<u8>,
+    /// Bytes already sent to the device
+    written: u32,
+    crc: crc32fast::Hasher,
+}
+
+impl BlockWriter {
+    fn new(region: MemoryRegion, base: u32, block_size: usize, fill: u8) -> Self {
+        Self {
+            region,
+            base,
+            block_size,
+            fill,
+            block: Vec::with_capacity(block_size),
+            written: 0,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Address the next pushed byte lands at
+    fn position(&self) -> u32 {
+        self.base + self.written + self.block.len() as u32
+    }
+
+    async fn push<T: DfuTransport>(
+        &mut self,
+        dfu: &mut DfuStream<T>,
+        data: &[u8],
+    ) -> Result<()> {
+        let end = self.position() as u64 + data.len() as u64;
+        if end > self.region.end() as u64 {
+            return Err(Error::FirmwareTooLarge {
+                size: (end - self.base as u64) as usize,
+                max: (self.region.end() - self.base) as usize,
+            });
+        }
+
+        for chunk in data.chunks(self.block_size) {
+            let room = self.block_size - self.block.len();
+            let (head, rest) = chunk.split_at(room.min(chunk.len()));
+            self.block.extend_from_slice(head);
+            if self.block.len() == self.block_size {
+                self.write(dfu).await?;
+            }
+            self.block.extend_from_slice(rest);
+        }
+        Ok(())
+    }
+
+    /// Pads with fill bytes up to `address`; records may only move forward
+    #[cfg(feature = "ihex")]
+    async fn seek<T: DfuTransport>(
+        &mut self,
+        dfu: &mut DfuStream<T>,
+        address: u32,
+    ) -> Result<()> {
+        let position = self.position();
+        if address < position {
+            return Err(Error::UnorderedRecord(address));
+        }
+        let gap = vec![self.fill; (address - position) as usize];
+        self.push(dfu, &gap).await
+    }
+
+    async fn write<T: DfuTransport>(&mut self, dfu: &mut DfuStream<T>) -> Result<()> {
+        let address = self.base + self.written;
+        let index = (self.written as usize) / self.block_size;
+        // The image length is unknown up front, so every block is a write request of its own
+        dfu.begin_write(address, self.block.len(), self.block_size)
+            .await
+            .map_err(|e| e.at_block(Phase::Write, index, address))?;
+        let written = async {
+            dfu.write_block(&self.block).await?;
+            dfu.end_write().await
+        };
+        written.await.map_err(|e| e.at_block(Phase::Write, index, address))?;
+
+        self.crc.update(&self.block);
+        self.written += self.block.len() as u32;
+        self.block.clear();
+        info!("Written {} bytes", self.written);
+        Ok(())
+    }
+
+    /// Writes the final partial block; returns the image length and CRC32
+    async fn finish<T: DfuTransport>(
+        mut self,
+        dfu: &mut DfuStream<T>,
+    ) -> Result<(u32, u32)> {
+        if !self.block.is_empty() {
+            self.write(dfu).await?;
+        }
+        Ok((self.written, self.crc.finalize()))
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    /// Flashes an image read from `reader` (stdin, a socket, ...) block by
+    /// block, without holding the whole image in memory.
+    ///
+    /// Only formats that can be parsed front to back are supported: raw
+    /// binaries and Intel HEX with ascending records. The image is written
+    /// from `base_address`, or the start of the firmware region, and its
+    /// device CRC is checked when verification is enabled.
+    pub async fn update_from_reader<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<UpdateReport> {
+        info!("Starting streamed firmware update");
+        let mut report = UpdateReport::new();
+        let mut reader = BufReader::new(reader);
+
+        let format = match self.config.firmware_format {
+            FirmwareFormat::Auto if reader.fill_buf().await?.first() == Some(&b':') => {
+                FirmwareFormat::IntelHex
+            }
+            FirmwareFormat::Auto | FirmwareFormat::Binary => FirmwareFormat::Binary,
+            FirmwareFormat::IntelHex => FirmwareFormat::IntelHex,
+            other => {
+                return Err(Error::Configuration(format!("{:?} images can't be streamed", other)));
+            }
+        };
+
+        if self.config.upd_mode != UpdateMode::None {
+            self.enter_bootloader(&mut report).await?;
+        }
+
+        let started = Instant::now();
+        let info = self.prepare_session(&mut report).await?;
+        report.device = Some(DeviceInfo::from(&info));
+        report.timings.add(Phase::Info, started.elapsed());
+
+        // The UID search needs the whole image before anything is written
+        if self.config.require_uid && info.version >= 0x30 {
+            return Err(Error::Configuration(
+                "Streamed updates can't check the device ID up front".into()
+            ));
+        }
+
+        let base = self.config.base_address.unwrap_or(info.memmap.firmware_address);
+        let region = info.memmap
+            .memory_regions()
+            .into_iter()
+            .find(|region| region.contains(base))
+            .ok_or(Error::OutsideMemoryMap(base))?;
+        let block_size = region.block_size(self.max_block_size(&info));
+
+        // The image length is unknown up front, so erase to the end of the region
+        let mut erased = 0;
+        if self.commands.contains(Command::EraseMemory) {
+            let started = Instant::now();
+            let (address, size) = region.erase_range(base, region.end() - base);
+            self.erase_memory(address, size)
+                .await
+                .map_err(|e| e.at_block(Phase::Erase, 0, address))?;
+            erased = size;
+            report.timings.add(Phase::Erase, started.elapsed());
+        }
+
+        let started = Instant::now();
+        let mut writer = BlockWriter::new(region, base, block_size, self.config.gap_filling as u8);
+        match format {
+            FirmwareFormat::IntelHex => self.stream_hex(&mut reader, &mut writer).await?,
+            _ => self.stream_binary(&mut reader, &mut writer).await?,
+        }
+        let (size, crc) = writer.finish(self).await?;
+        report.timings.add(Phase::Write, started.elapsed());
+        info!("Streamed {} bytes to {:#010x}", size, base);
+
+        let mut verified = false;
+        if self.config.verify {
+            let started = Instant::now();
+            let actual = self.read_firmware_crc(base, size).await?;
+            if actual != crc {
+                return Err(Error::VerificationFailed {
+                    range: base..base + size,
+                    expected: Checksum::Crc32(crc),
+                    actual: Checksum::Crc32(actual),
+                });
+            }
+            verified = true;
+            report.timings.add(Phase::Verify, started.elapsed());
+        }
+
+        report.regions.push(RegionReport {
+            kind: region.kind,
+            address: base,
+            size,
+            block_size,
+            erased,
+            crc,
+            skipped: false,
+            verified,
+        });
+
+        self.leave_bootloader(&mut report, false).await?;
+        self.collect_protocol_errors(&mut report);
+        info!("Streamed firmware update completed successfully");
+        Ok(report)
+    }
+
+    async fn stream_binary<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut BlockWriter,
+    ) -> Result<()> {
+        let mut buffer = vec![0u8; writer.block_size];
+        loop {
+            let len = reader.read(&mut buffer).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            writer.push(self, &buffer[..len]).await?;
+        }
+    }
+
+    /// Data records are absolute once an extended address record has been
+    /// seen and relative to the base before, as for [`FirmwareImage::from_hex_file`]
+    ///
+    /// [`FirmwareImage::from_hex_file`]: super::FirmwareImage::from_hex_file
+    #[cfg(feature = "ihex")]
+    async fn stream_hex<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut BlockWriter,
+    ) -> Result<()> {
+        let mut lines = reader.lines();
+        let mut upper: Option<u32> = None;
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match ihex::Record::from_record_string(line).map_err(Error::HexFileError)? {
+                ihex::Record::Data { offset, value } => {
+                    let address = upper.unwrap_or(writer.base) + offset as u32;
+                    writer.seek(self, address).await?;
+                    writer.push(self, &value).await?;
+                }
+                ihex::Record::ExtendedLinearAddress(high) => upper = Some((high as u32) << 16),
+                ihex::Record::ExtendedSegmentAddress(segment) => upper = Some((segment as u32) << 4),
+                ihex::Record::EndOfFile => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "ihex"))]
+    async fn stream_hex<R: AsyncBufRead + Unpin>(
+        &mut self,
+        _reader: &mut R,
+        _writer: &mut BlockWriter,
+    ) -> Result<()> {
+        Err(Error::Configuration("Intel HEX support needs the `ihex` feature".into()))
+    }
+}
@@ -0,0 +1,115 @@
+use std::io::{Error, ErrorKind};
+
+use super::types::Command;
+use super::verify::VerifyMethod;
+
+/// First info block version whose bootloaders implement the optional commands
+const EXTENDED_COMMANDS_VERSION: u8 = 0x30;
+
+impl Command {
+    pub const ALL: [Command; 10] = [
+        Command::ReadBootloaderInfo,
+        Command::ReadProgramMemory,
+        Command::ReadProgramSha256,
+        Command::ReadProgramCrc,
+        Command::EraseMemory,
+        Command::BootloaderQuit,
+        Command::WriteProgramMemory,
+        Command::ReadDiagnostics,
+        Command::ReadCapabilities,
+        Command::CommitImage,
+    ];
+
+    /// Commands every bootloader must implement
+    pub fn is_mandatory(self) -> bool {
+        match self {
+            Command::ReadBootloaderInfo
+            | Command::ReadProgramCrc
+            | Command::BootloaderQuit
+            | Command::WriteProgramMemory => true,
+            Command::ReadProgramMemory
+            | Command::ReadProgramSha256
+            | Command::EraseMemory
+            | Command::ReadDiagnostics
+            | Command::ReadCapabilities
+            | Command::CommitImage => false,
+        }
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Command::ALL
+            .into_iter()
+            .find(|command| *command as u8 == value)
+            .ok_or_else(|| Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown command: {}", value)
+            ))
+    }
+}
+
+/// Set of commands a bootloader is known to implement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSet(u32);
+
+impl CommandSet {
+    pub fn mandatory() -> Self {
+        Command::ALL
+            .into_iter()
+            .filter(|command| command.is_mandatory())
+            .fold(Self(0), Self::with)
+    }
+
+    pub fn all() -> Self {
+        Command::ALL.into_iter().fold(Self(0), Self::with)
+    }
+
+    /// Commands implied by the info block version when the device can't be asked
+    pub fn infer(version: u8) -> Self {
+        if version >= EXTENDED_COMMANDS_VERSION {
+            // Capability reporting is advertised by a protocol flag and commit
+            // support by the capability report, not by the version
+            Self::all()
+                .without(Command::ReadCapabilities)
+                .without(Command::CommitImage)
+        } else {
+            Self::mandatory()
+        }
+    }
+
+    pub fn with(self, command: Command) -> Self {
+        Self(self.0 | 1 << command as u8)
+    }
+
+    pub fn without(self, command: Command) -> Self {
+        Self(self.0 & !(1 << command as u8))
+    }
+
+    pub fn contains(&self, command: Command) -> bool {
+        self.0 & (1 << command as u8) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Command> + '_ {
+        Command::ALL.into_iter().filter(|command| self.contains(*command))
+    }
+}
+
+impl Default for CommandSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl VerifyMethod {
+    /// Optional command the method depends on, if any
+    pub fn required_command(&self) -> Option<Command> {
+        match self {
+            VerifyMethod::DeviceCrc | VerifyMethod::Manifest(_) => None,
+            VerifyMethod::Readback => Some(Command::ReadProgramMemory),
+            VerifyMethod::Sha256 => Some(Command::ReadProgramSha256),
+        }
+    }
+}
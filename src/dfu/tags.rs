@@ -0,0 +1,245 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use super::info::DeviceInfo;
+use super::rollout::{bus_of, RolloutPlan, SkipReason};
+use super::scan::{Inventory, InventoryEntry};
+use super::signing::to_hex;
+
+/// Attaches `name` to every device matching all of the given criteria.
+/// Patterns may use `*` as a wildcard.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagRule {
+    pub name: String,
+    /// Pattern over the UID in lowercase hex, e.g. "0a1b*"
+    pub uid: Option<String>,
+    pub device_id: Option<u16>,
+    pub min_rev: Option<u16>,
+    pub max_rev: Option<u16>,
+    /// Pattern over the bus (the URI without its query)
+    pub bus: Option<String>,
+    /// Pattern over the full URI, e.g. a site's gateway addresses
+    pub uri: Option<String>,
+}
+
+impl TagRule {
+    pub fn matches(&self, uri: &str, device: &DeviceInfo) -> bool {
+        self.uid.as_deref().is_none_or(|pattern| glob(pattern, &to_hex(&device.uid)))
+            && self.device_id.is_none_or(|id| id == device.device_id)
+            && self.min_rev.is_none_or(|rev| device.device_rev >= rev)
+            && self.max_rev.is_none_or(|rev| device.device_rev <= rev)
+            && self.bus.as_deref().is_none_or(|pattern| glob(pattern, bus_of(uri)))
+            && self.uri.as_deref().is_none_or(|pattern| glob(pattern, uri))
+    }
+}
+
+#[derive(Deserialize)]
+struct TagFile {
+    #[serde(default)]
+    tag: Vec<TagRule>,
+}
+
+/// Rules naming groups of devices
+///
+/// ```toml
+/// [[tag]]
+/// name = "rev-c"
+/// device_id = 0x1234
+/// min_rev = 3
+/// max_rev = 3
+///
+/// [[tag]]
+/// name = "bus-2"
+/// bus = "serial:///dev/ttyUSB2"
+///
+/// [[tag]]
+/// name = "site-berlin"
+/// uri = "tcp://10.1.*"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagRules {
+    rules: Vec<TagRule>,
+}
+
+impl TagRules {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let file: TagFile = toml::from_str(&content)
+            .map_err(|e| Error::Configuration(e.to_string()))?;
+        Ok(Self { rules: file.tag })
+    }
+
+    pub fn with_rule(mut self, rule: TagRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[TagRule] {
+        &self.rules
+    }
+
+    pub fn tags(&self, uri: &str, device: &DeviceInfo) -> BTreeSet<String> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(uri, device))
+            .map(|rule| rule.name.clone())
+            .collect()
+    }
+}
+
+/// Boolean expression over tags, e.g. `rev-c and bus-2 and not (lab or spare)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+impl TagExpr {
+    /// `not` binds tightest, then `and`, then `or`; parentheses group
+    pub fn parse(expr: &str) -> Result<Self> {
+        let tokens = tokenize(expr);
+        let mut parser = Parser { tokens: &tokens, pos: 0, expr };
+        let parsed = parser.or()?;
+        if parser.pos != tokens.len() {
+            return Err(parser.error());
+        }
+        Ok(parsed)
+    }
+
+    pub fn matches(&self, tags: &BTreeSet<String>) -> bool {
+        match self {
+            TagExpr::Tag(tag) => tags.contains(tag),
+            TagExpr::Not(inner) => !inner.matches(tags),
+            TagExpr::And(a, b) => a.matches(tags) && b.matches(tags),
+            TagExpr::Or(a, b) => a.matches(tags) || b.matches(tags),
+        }
+    }
+}
+
+impl Inventory {
+    /// Devices whose tags satisfy `expr`
+    pub fn select<'a>(&'a self, rules: &TagRules, expr: &TagExpr) -> Vec<&'a InventoryEntry> {
+        self.devices
+            .iter()
+            .filter(|entry| expr.matches(&rules.tags(&entry.uri, &entry.device)))
+            .collect()
+    }
+}
+
+impl RolloutPlan {
+    /// Drops every update whose device doesn't satisfy `expr`
+    pub fn retain_tagged(&mut self, rules: &TagRules, expr: &TagExpr) {
+        for bus in &mut self.buses {
+            let (keep, drop): (Vec<_>, Vec<_>) = bus
+                .updates
+                .drain(..)
+                .partition(|update| expr.matches(&rules.tags(&update.uri, &update.device)));
+            self.skipped.extend(drop.into_iter().map(|update| (update.uri, SkipReason::Filtered)));
+            bus.estimate = keep.iter().map(|update| update.estimate).sum();
+            bus.updates = keep;
+        }
+        self.buses.retain(|bus| !bus.updates.is_empty());
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in expr.char_indices() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if let Some(s) = start.take() {
+                tokens.push(&expr[s..i]);
+            }
+            if !c.is_whitespace() {
+                tokens.push(&expr[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&expr[s..]);
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+    expr: &'a str,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> Error {
+        Error::Configuration(format!("Invalid tag expression: {}", self.expr))
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.tokens.get(self.pos) == Some(&token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<TagExpr> {
+        let mut expr = self.and()?;
+        while self.eat("or") {
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<TagExpr> {
+        let mut expr = self.not()?;
+        while self.eat("and") {
+            expr = TagExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<TagExpr> {
+        if self.eat("not") {
+            return Ok(TagExpr::Not(Box::new(self.not()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(self.error());
+            }
+            return Ok(expr);
+        }
+        match self.tokens.get(self.pos) {
+            Some(&token) if !matches!(token, "and" | "or" | ")") => {
+                self.pos += 1;
+                Ok(TagExpr::Tag(token.to_string()))
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of characters
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::{Error, Result};
+use crate::protocols::apl::AckPolicy;
+use crate::protocols::lpl::Framing;
+use crate::transport::{DegradedLink, LinkConditions};
+use super::image::FirmwareFormat;
+use super::simulator::{SimModel, SimulatedDevice};
+use super::types::DfuConfig;
+use super::DfuStream;
+
+const BLOCK_SIZES: [usize; 3] = [256, 512, 1024];
+const ACK_POLICIES: [AckPolicy; 4] = [
+    AckPolicy::EveryBlock,
+    AckPolicy::EveryN(4),
+    AckPolicy::EndOfWindow(4),
+    AckPolicy::EndOfWindow(16),
+];
+/// Node address used for the multi-drop cases
+const BENCH_NETID: u8 = 0x12;
+
+/// One transfer configuration to measure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferCase {
+    pub block_size: usize,
+    pub ack_policy: AckPolicy,
+    pub framing: Framing,
+    /// Node address prefixed to every frame, as on an RS-485 bus
+    pub netid: Option<u8>,
+}
+
+impl TransferCase {
+    pub fn new(block_size: usize, ack_policy: AckPolicy) -> Self {
+        Self { block_size, ack_policy, framing: Framing::DEFAULT, netid: None }
+    }
+
+    /// Every block size and ACK policy with the default framing, then the
+    /// framing options at the largest block size
+    pub fn sweep() -> Vec<Self> {
+        let mut cases: Vec<Self> = BLOCK_SIZES
+            .into_iter()
+            .flat_map(|block_size| ACK_POLICIES.map(|policy| Self::new(block_size, policy)))
+            .collect();
+        let block_size = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
+        for policy in [AckPolicy::EveryBlock, AckPolicy::EndOfWindow(16)] {
+            cases.push(Self { netid: Some(BENCH_NETID), ..Self::new(block_size, policy) });
+            cases.push(Self { framing: Framing { syn: 0x7E, delimiter: 0x7E }, ..Self::new(block_size, policy) });
+        }
+        cases
+    }
+}
+
+impl fmt::Display for TransferCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} byte blocks, {:?}", self.block_size, self.ack_policy)?;
+        if self.framing != Framing::DEFAULT {
+            write!(f, ", SYN {:#04x} delimiter {:#04x}", self.framing.syn, self.framing.delimiter)?;
+        }
+        if let Some(netid) = self.netid {
+            write!(f, ", node {:#04x}", netid)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long a full update took for one [`TransferCase`]
+#[derive(Debug, Clone)]
+pub struct TransferSample {
+    pub case: TransferCase,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl TransferSample {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Updates a fresh [`SimulatedDevice`] built from `model` with `image`,
+/// over an in-memory link degraded by `conditions`, and times the whole
+/// session: requests, data frames and ACKs go through the same code as
+/// against hardware. The device is set up to agree with `case` on ACK
+/// policy, framing and node address.
+pub async fn measure_transfer(
+    model: &SimModel,
+    conditions: LinkConditions,
+    case: TransferCase,
+    image: &[u8],
+) -> Result<TransferSample> {
+    let model = SimModel {
+        ack_policy: case.ack_policy,
+        framing: case.framing,
+        netid: case.netid,
+        ..model.clone()
+    };
+    let mut config = DfuConfig::new()
+        .with_uri("sim://throughput")
+        .with_firmware_bytes(image.to_vec())
+        .with_firmware_format(FirmwareFormat::Binary)
+        .with_block_size(case.block_size)
+        .with_ack_policy(case.ack_policy)
+        .with_framing(case.framing)
+        .update();
+    if let Some(netid) = case.netid {
+        config = config.with_network_id(netid as usize);
+    }
+
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model);
+    let server = tokio::spawn(async move { sim.serve(device).await });
+
+    let started = Instant::now();
+    let mut dfu = DfuStream::new(DegradedLink::new(host, conditions), config)?;
+    let result = dfu.update().await;
+    let elapsed = started.elapsed();
+    drop(dfu);
+    let served = server.await.map_err(|e| Error::Connection(format!("Simulated device failed: {}", e)))?;
+    result?;
+    served?;
+
+    Ok(TransferSample { case, bytes: image.len(), elapsed })
+}
@@ -1,17 +1,34 @@
-use bytes::BytesMut;
+use std::fmt;
+use std::time::Duration;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Clone, Copy)]
+use crate::protocols::apl::AckPolicy;
+use crate::protocols::lpl::Framing;
+use super::banner::BannerParser;
+#[cfg(feature = "fault-injection")]
+use super::fault::AbortPoint;
+use super::entry::{EntryMethod, EntryTiming, SyncPreamble};
+use super::image::FirmwareFormat;
+use super::info::DiagnosticLimits;
+use super::quirks::Quirks;
+use super::resume::ResumeToken;
+use super::region::UpdateOrdering;
+use super::verify::VerifyMethod;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     ReadBootloaderInfo = 0,
+    ReadProgramMemory = 1,
+    ReadProgramSha256 = 2,
     ReadProgramCrc = 3,
+    EraseMemory = 4,
     BootloaderQuit = 5,
     WriteProgramMemory = 6,
-}
-
-#[repr(C, packed)]
-pub struct FirmwareMetadata {
-    pub image_size: u32,
-    pub image_crc: u32,
+    ReadDiagnostics = 7,
+    ReadCapabilities = 8,
+    CommitImage = 9,
 }
 
 #[repr(C, packed)]
@@ -48,24 +65,112 @@ pub struct InfoBlockV2 {
     pub memmap: DeviceMemoryMap,
 }
 
+#[derive(Clone)]
 pub struct DfuConfig {
     pub uri: String,
     pub filename: Option<String>,
+    /// Image held in memory, used instead of `filename`
+    pub firmware_bytes: Option<Bytes>,
+    /// Expected SHA-256 (hex) of a firmware downloaded by URL
+    pub firmware_sha256: Option<String>,
+    pub firmware_set: Option<String>,
+    /// Directory of downloaded artifacts, keyed by SHA-256
+    pub cache_dir: Option<String>,
+    pub bundle: Option<String>,
+    pub base_address: Option<u32>,
+    pub firmware_format: FirmwareFormat,
+    pub dfu_target: usize,
     pub block_size: usize,
+    pub max_firmware_size: Option<usize>,
+    pub memory_limit: Option<usize>,
+    pub channel_capacity: Option<usize>,
     pub get_info: bool,
     pub update: bool,
     pub overwrite: bool,
     pub verify: bool,
+    pub verifier: VerifyMethod,
+    pub pipelined_verify: bool,
+    pub update_ordering: UpdateOrdering,
+    pub manifest_key: Option<String>,
     pub quit: bool,
+    pub commit: bool,
+    pub strict: bool,
+    pub diagnostics: bool,
+    pub diagnostic_limits: DiagnosticLimits,
     pub dev_netid: usize,
-    pub dev_speed: usize,
-    pub upd_speed: usize,
-    pub lnk_speed: usize,
+    /// Prefix frames with `dev_netid` to share an RS-485 bus with other devices
+    pub bus_addressing: bool,
+    /// SYN byte and delimiter of LPL frames, for bootloaders not using 0x55/0x00
+    pub framing: Framing,
+    pub dev_speed: Baud,
+    pub upd_speed: Baud,
+    pub lnk_speed: Baud,
+    /// Speeds tried in turn when entry fails at `lnk_speed`
+    pub baud_sweep: Vec<Baud>,
+    /// Times a lost link is re-opened before the update gives up
+    pub reconnect_attempts: usize,
+    /// Wait before the first reconnection, doubled after each failed one
+    pub reconnect_backoff: Duration,
     pub upd_mode: UpdateMode,
+    pub entry: EntryMethod,
+    pub entry_timing: EntryTiming,
+    pub sync_preamble: Option<SyncPreamble>,
+    pub console_port: Option<String>,
+    pub banner: Option<BannerParser>,
+    pub quirk_database: Option<String>,
+    pub quirks: Quirks,
+    pub ack_policy: AckPolicy,
+    pub session_file: Option<String>,
+    pub keep_open: bool,
+    pub resume_token: Option<ResumeToken>,
+    /// Caller-chosen key making retries of the same update safe
+    pub idempotency_key: Option<String>,
+    pub request_journal: Option<String>,
+    pub registry_file: Option<String>,
+    pub backup_file: Option<String>,
+    /// Directory keeping each device's last-known-good image, for rollback
+    pub archive_dir: Option<String>,
+    /// Refuses to touch any device but the one with this UID
+    pub expected_uid: Option<[u8; 16]>,
+    /// Refuses images that don't embed the device UID (bootloader 0x30 and later)
+    pub require_uid: bool,
+    /// Flash the conformance checker may erase and write a few blocks at
+    pub conformance_scratch: Option<u32>,
+    /// Stops the update at the next block boundary once cancelled
+    pub cancellation: Option<CancellationToken>,
+    pub wear_limit: u64,
     pub gap_filling: usize,
+    pub trim_fill: bool,
+    pub pad_to_crc_window: bool,
+    #[cfg(feature = "fault-injection")]
+    pub abort_at: Option<AbortPoint>,
+}
+
+/// Serial line speed in bits per second; a plain integer in profiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Baud(pub u32);
+
+impl Baud {
+    /// Rates most devices run at, the usual defaults first
+    pub const COMMON: [Baud; 8] = [
+        Baud(115200), Baud(9600), Baud(57600), Baud(38400),
+        Baud(19200), Baud(230400), Baud(460800), Baud(921600),
+    ];
+
+    pub const fn bps(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Baud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} baud", self.0)
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum UpdateMode {
     None = 0,
     Direct = 1,
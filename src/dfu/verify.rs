@@ -0,0 +1,255 @@
+use std::future::Future;
+use std::path::Path;
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256 as Sha256Hasher};
+
+use crate::error::{Checksum, Error, Result};
+use crate::transport::DfuTransport;
+use super::{calculate_crc32, DfuStream};
+use super::region::RegionImage;
+use super::signing::{from_hex, load_verifying_key};
+
+/// Verification strategy selected on `DfuConfig`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMethod {
+    /// Compare the bootloader's CRC32 of each region with the host CRC32
+    #[serde(rename = "crc")]
+    DeviceCrc,
+    /// Read every region back and compare byte by byte
+    Readback,
+    /// Compare a device-computed SHA-256 with the host digest
+    Sha256,
+    /// Compare device CRCs with the values recorded in a release manifest
+    Manifest(String),
+}
+
+pub trait Verifier {
+    fn name(&self) -> &'static str;
+
+    fn verify<T: DfuTransport>(
+        &self,
+        dfu: &mut DfuStream<T>,
+        part: &RegionImage,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+pub struct DeviceCrc;
+
+impl Verifier for DeviceCrc {
+    fn name(&self) -> &'static str {
+        "device CRC"
+    }
+
+    async fn verify<T: DfuTransport>(
+        &self,
+        dfu: &mut DfuStream<T>,
+        part: &RegionImage,
+    ) -> Result<()> {
+        let expected = calculate_crc32(&part.data);
+        let actual = dfu.read_firmware_crc(part.address, part.data.len() as u32).await?;
+
+        if expected != actual {
+            return Err(Error::VerificationFailed {
+                range: part.range(),
+                expected: Checksum::Crc32(expected),
+                actual: Checksum::Crc32(actual),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Readback;
+
+impl Verifier for Readback {
+    fn name(&self) -> &'static str {
+        "readback"
+    }
+
+    async fn verify<T: DfuTransport>(
+        &self,
+        dfu: &mut DfuStream<T>,
+        part: &RegionImage,
+    ) -> Result<()> {
+        let block_size = part.region.block_size(dfu.config.block_size);
+
+        for (i, chunk) in part.data.chunks(block_size).enumerate() {
+            let address = part.address + (i * block_size) as u32;
+            let readback = dfu.read_memory(address, chunk.len()).await?;
+
+            if let Some(pos) = chunk.iter().zip(&readback).position(|(a, b)| a != b) {
+                let mismatch = address + pos as u32;
+                return Err(Error::VerificationFailed {
+                    range: mismatch..mismatch + 1,
+                    expected: Checksum::Byte(chunk[pos]),
+                    actual: Checksum::Byte(readback[pos]),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Sha256;
+
+impl Verifier for Sha256 {
+    fn name(&self) -> &'static str {
+        "SHA-256"
+    }
+
+    async fn verify<T: DfuTransport>(
+        &self,
+        dfu: &mut DfuStream<T>,
+        part: &RegionImage,
+    ) -> Result<()> {
+        let expected: [u8; 32] = Sha256Hasher::digest(&part.data).into();
+        let actual = dfu.read_firmware_sha256(part.address, part.data.len() as u32).await?;
+
+        if expected != actual {
+            return Err(Error::VerificationFailed {
+                range: part.range(),
+                expected: Checksum::Sha256(expected),
+                actual: Checksum::Sha256(actual),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub address: u32,
+    pub size: u32,
+    pub crc32: u32,
+    /// Hex-encoded SHA-256 of the region contents
+    pub sha256: Option<String>,
+}
+
+/// Release manifest listing the expected CRC32 of every region, optionally signed
+///
+/// ```toml
+/// [[regions]]
+/// address = 0x08004000
+/// size = 122880
+/// crc32 = 0x1c291ca3
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub regions: Vec<ManifestEntry>,
+    /// Hex-encoded Ed25519 signature over [`Manifest::signed_bytes`]
+    pub signature: Option<String>,
+}
+
+impl Manifest {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| Error::Configuration(e.to_string()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let content = toml::to_string(self).map_err(|e| Error::Configuration(e.to_string()))?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+impl Verifier for Manifest {
+    fn name(&self) -> &'static str {
+        "manifest"
+    }
+
+    async fn verify<T: DfuTransport>(
+        &self,
+        dfu: &mut DfuStream<T>,
+        part: &RegionImage,
+    ) -> Result<()> {
+        let size = part.data.len() as u32;
+        let entry = self.regions
+            .iter()
+            .find(|e| e.address == part.address && e.size == size)
+            .ok_or_else(|| Error::Configuration(format!(
+                "Manifest has no entry for {:#010x}+{:#x}", part.address, size
+            )))?;
+
+        // The artifact itself must match the manifest, not just the device
+        let image_crc = calculate_crc32(&part.data);
+        if image_crc != entry.crc32 {
+            return Err(Error::ManifestMismatch {
+                range: part.range(),
+                expected: Checksum::Crc32(entry.crc32),
+                actual: Checksum::Crc32(image_crc),
+            });
+        }
+        if let Some(digest) = &entry.sha256 {
+            let expected: [u8; 32] = from_hex(digest)?
+                .try_into()
+                .map_err(|_| Error::Configuration(format!("Invalid manifest SHA-256: {}", digest)))?;
+            let actual: [u8; 32] = Sha256Hasher::digest(&part.data).into();
+            if actual != expected {
+                return Err(Error::ManifestMismatch {
+                    range: part.range(),
+                    expected: Checksum::Sha256(expected),
+                    actual: Checksum::Sha256(actual),
+                });
+            }
+        }
+
+        let actual = dfu.read_firmware_crc(part.address, size).await?;
+        if actual != entry.crc32 {
+            return Err(Error::VerificationFailed {
+                range: part.range(),
+                expected: Checksum::Crc32(entry.crc32),
+                actual: Checksum::Crc32(actual),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`VerifyMethod`] ready for every region of an update, its manifest
+/// read and signature-checked once
+pub(super) enum PreparedVerifier {
+    DeviceCrc,
+    Readback,
+    Sha256,
+    Manifest(Manifest),
+}
+
+impl VerifyMethod {
+    pub(super) fn prepare(&self, manifest_key: Option<&str>) -> Result<PreparedVerifier> {
+        Ok(match self {
+            VerifyMethod::DeviceCrc => PreparedVerifier::DeviceCrc,
+            VerifyMethod::Readback => PreparedVerifier::Readback,
+            VerifyMethod::Sha256 => PreparedVerifier::Sha256,
+            VerifyMethod::Manifest(path) => {
+                let manifest = Manifest::from_file(path)?;
+                if let Some(key) = manifest_key {
+                    manifest.verify_signature(&load_verifying_key(key)?)?;
+                }
+                PreparedVerifier::Manifest(manifest)
+            }
+        })
+    }
+}
+
+impl<T: DfuTransport> DfuStream<T> {
+    pub(super) async fn run_verifier(&mut self, verifier: &PreparedVerifier, part: &RegionImage) -> Result<()> {
+        match verifier {
+            PreparedVerifier::DeviceCrc => self.verify_with(&DeviceCrc, part).await,
+            PreparedVerifier::Readback => self.verify_with(&Readback, part).await,
+            PreparedVerifier::Sha256 => self.verify_with(&Sha256, part).await,
+            PreparedVerifier::Manifest(manifest) => self.verify_with(manifest, part).await,
+        }
+    }
+
+    async fn verify_with<V: Verifier>(&mut self, verifier: &V, part: &RegionImage) -> Result<()> {
+        info!("Verifying {:?} region using {}", part.region.kind, verifier.name());
+        verifier.verify(self, part).await
+    }
+}
@@ -1,5 +1,10 @@
+use std::fmt;
+use std::ops::Range;
 use thiserror::Error;
 
+use crate::dfu::{AbortPoint, Command, Phase, ResumeToken, UriCandidate};
+use crate::protocols::channel::ChannelError;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Protocol error: {0}")]
@@ -17,29 +22,175 @@ pub enum Error {
     #[error("Connection error: {0}")]
     Connection(String),
 
+    #[error("Another session is using {uri}")]
+    DeviceBusyLocked { uri: String },
+
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
     #[error("No firmware file specified")]
     NoFirmwareFile,
 
+    #[error("No firmware in the set matches device {device_id:#06x} rev {device_rev}")]
+    NoMatchingFirmware { device_id: u16, device_rev: u16 },
+
+    #[error("S-record error on line {line}: {reason}")]
+    SrecError { line: usize, reason: &'static str },
+
+    #[error("ELF file error: {0}")]
+    ElfError(&'static str),
+
+    #[error("Download failed: {0}")]
+    Download(String),
+
+    #[error("Downloaded {url} has {actual}, expected {expected}")]
+    DownloadMismatch { url: String, expected: Checksum, actual: Checksum },
+
+    #[error("Decompression failed: {0}")]
+    Decompression(String),
+
+    #[error("DFU file error: {0}")]
+    DfuFileError(&'static str),
+
+    #[error("DFU file has no target {index} ({available} available)")]
+    NoDfuTarget { index: usize, available: usize },
+
+    #[error("Firmware container error: {0}")]
+    ContainerError(String),
+
+    #[error("Firmware container doesn't fit this device: {0}")]
+    ContainerMismatch(String),
+
+    #[cfg(feature = "ihex")]
     #[error("Hex file error: {0}")]
-    HexFileError(#[from] ihex::Error),
+    HexFileError(#[from] ihex::ReaderError),
+
+    #[error("Streamed firmware records must ascend, got one at {0:#010x}")]
+    UnorderedRecord(u32),
+
+    #[error("Firmware too large for device: {size} bytes, maximum is {max} bytes")]
+    FirmwareTooLarge { size: usize, max: usize },
+
+    #[error("Session memory limit exceeded: {needed} bytes needed, {available} available")]
+    MemoryLimit { needed: usize, available: usize },
+
+    #[error("Image data at {0:#010x} is outside the device memory map")]
+    OutsideMemoryMap(u32),
+
+    #[error("Firmware does not contain the device UID {uid:02x?}")]
+    InvalidDeviceId { uid: Vec<u8> },
+
+    #[error(
+        "Verification failed at {:#010x}..{:#010x}: expected {expected}, got {actual}",
+        .range.start, .range.end
+    )]
+    VerificationFailed { range: Range<u32>, expected: Checksum, actual: Checksum },
+
+    #[error(
+        "Firmware image does not match manifest at {:#010x}..{:#010x}: expected {expected}, got {actual}",
+        .range.start, .range.end
+    )]
+    ManifestMismatch { range: Range<u32>, expected: Checksum, actual: Checksum },
+
+    #[error("{phase:?} failed at block {block} ({address:#010x}): {source}")]
+    BlockFailed { phase: Phase, block: usize, address: u32, source: Box<Error> },
 
-    #[error("Firmware too large for device")]
-    FirmwareTooLarge,
+    #[error("Response too short: expected {expected} bytes, got {actual}")]
+    TruncatedResponse { expected: usize, actual: usize },
 
-    #[error("Invalid device ID")]
-    InvalidDeviceId,
+    #[error("Bootloader rejected commit of {size} byte image with CRC {crc:#010x} (status {status:#04x})")]
+    CommitFailed { status: u8, size: u32, crc: u32 },
 
-    #[error("Verification failed")]
-    VerificationFailed,
+    #[error("Bootloader doesn't support {0:?}")]
+    CommandUnsupported(Command),
+
+    #[error("Post-update health check failed: {0}")]
+    HealthCheckFailed(String),
+
+    #[error("Manifest signature missing or invalid")]
+    SignatureInvalid,
+
+    #[error("No matching device found")]
+    DeviceNotFound,
+
+    #[error("{} devices match, pick one of: {}", .candidates.len(),
+        .candidates.iter().map(|c| c.uri.as_str()).collect::<Vec<_>>().join(", "))]
+    AmbiguousDevice { candidates: Vec<UriCandidate> },
 
     #[error("Bootloader not detected")]
     BootloaderNotDetected,
 
+    #[error("No clean bootloader response after {attempts} sync attempt(s)")]
+    LinkNotSynchronized { attempts: usize },
+
+    #[error("Unsupported device info: {}", .fields.join(", "))]
+    UnsupportedInfo { fields: Vec<String> },
+
+    #[error("Device {uid:02x?} is not the one expected")]
+    UnexpectedDevice { uid: Vec<u8> },
+
+    #[error("Resumed session belongs to a different device")]
+    SessionMismatch,
+
+    #[error("Update suspended before {:#010x}", .0.next_address())]
+    Suspended(Box<ResumeToken>),
+
+    #[error("Link lost before {:#010x}: {source}", .token.next_address())]
+    LinkLost { token: Box<ResumeToken>, source: Box<Error> },
+
+    #[error("Session aborted at {0:?} by fault injection")]
+    Aborted(AbortPoint),
+
+    #[error("Update cancelled")]
+    Cancelled,
+
+    #[error("Bootloader entry failed: {0}")]
+    EntryFailed(String),
+
     #[error("Invalid configuration: {0}")]
     Configuration(String),
 }
 
+impl Error {
+    /// Attaches the phase and block position at which a transfer failed
+    pub(crate) fn at_block(self, phase: Phase, block: usize, address: u32) -> Self {
+        Error::BlockFailed { phase, block, address, source: Box::new(self) }
+    }
+
+    /// The transport went away or stopped answering, rather than the device
+    /// refusing something
+    pub fn is_link_loss(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Connection(_) | Error::Timeout => true,
+            Error::BlockFailed { source, .. } => source.is_link_loss(),
+            _ => false,
+        }
+    }
+}
+
+/// Value compared during verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Crc32(u32),
+    Sha256([u8; 32]),
+    /// Single byte compared during readback
+    Byte(u8),
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Crc32(crc) => write!(f, "CRC32 {:#010x}", crc),
+            Checksum::Sha256(digest) => {
+                write!(f, "SHA-256 ")?;
+                digest.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            Checksum::Byte(byte) => write!(f, "byte {:#04x}", byte),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
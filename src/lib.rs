@@ -4,96 +4,302 @@
 //! using either serial or network connections.
 //! 
 //! # Features
-//! - Serial and TCP connection support
-//! - Intel HEX firmware file parsing
-//! - Automatic bootloader mode handling
-//! - CRC-based verification
-//! - Progress reporting
+//! - Serial and TCP connection support, opened from the URI with `connect`, with baud
+//!   switching and DTR/RTS control through the `DfuTransport` trait
+//! - Serial-to-TCP relay (`bridge`), so a device on one machine can be updated from another
+//! - Listen mode (`serve`) for devices behind NAT that dial out to the update host
+//! - RS-485 multi-drop buses, each frame addressed to one node by its network id
+//! - Per-profile SYN byte and frame delimiter for legacy bootloaders (e.g. 0x7E framing);
+//!   the datagram transports (UDP, MQTT, CAN, I2C, SPI) still expect the default framing
+//! - QUIC for lossy WAN links (`quic` feature)
+//! - LPL frames over UDP datagrams with per-request retransmit (`udp://host:port`, `udp` feature)
+//! - Local daemons behind Unix domain sockets (`unix:///run/dfu.sock`, `unix-socket` feature)
+//! - ISO-TP framing over Linux socketcan for devices on a CAN bus (`can` feature)
+//! - Co-processors on a Linux I2C bus, polled and tolerant of clock stretching (`i2c:///dev/i2c-1?addr=0x42`, `i2c` feature)
+//! - SPI bootloaders through spidev, with chip select and inter-transfer delays configurable (`spi` feature)
+//! - Vendor-class and CDC USB devices over their bulk endpoints (`usb://vid:pid`, `usb` feature)
+//! - Networked serial servers speaking RFC 2217, baud changes included (`telnet://host:port`, `rfc2217` feature)
+//! - Serial ports on remote gateways tunnelled over SSH (`ssh://host/dev/ttyUSB0`, `ssh` feature)
+//! - Cellular fleets reached through an MQTT broker on per-device request/response topics (`mqtt` feature)
+//! - Serial port discovery (`discover_serial`), optionally probing each port for its bootloader
+//! - Networked devices found by mDNS/DNS-SD (`_dfu._tcp`, `mdns` feature) or SSDP (`ssdp` feature)
+//!   instead of static address lists, optionally probed for their device info (`discover_network`)
+//! - Intel HEX (`ihex` feature), Motorola S-record, ELF, DfuSe and raw binary firmware
+//!   images, optionally in a container naming the target device and minimum bootloader
+//! - gzip, xz and zip compressed firmware files (`compression` feature)
+//! - Firmware downloads by URL with SHA-256 checks (`http` feature)
+//! - Automatic bootloader mode handling (reboot command, DTR/RTS reset sequences, GPIO,
+//!   hooks), with version and build parsed from entry banners
+//! - CRC-based verification and Ed25519-signed release manifests
+//! - Firmware backup to Intel HEX or raw binary before overwriting, and Intel HEX
+//!   dumps of any region or address range that flash back losslessly
+//! - Archive of the image each update replaced, per device UID, for `rollback`
+//! - Multi-image bundles (application, configuration, second bank) in one session
+//! - Reconnection with exponential backoff when the link drops mid-transfer, resuming at the
+//!   block that failed (`update_with_reconnect`)
+//! - Cancellation between blocks through a `CancellationToken` (`with_cancellation`),
+//!   leaving the device in its bootloader
+//! - Idempotency keys, so orchestration retries never flash a device twice
+//! - Progress reporting through a watch channel (`DfuStream::progress`): phase, block,
+//!   bytes written and percentage, for progress bars
+//! - A simulated bootloader (`SimulatedDevice`) with configurable memory map, delays and
+//!   fault modes, served over TCP or a PTY by the `fwupd-sim` binary (`sim` feature)
+//! - Simulated link degradation (clean, noisy RS-485, lossy radio, satellite) around
+//!   in-memory streams, for evaluating protocol changes under realistic conditions
+//! - Throughput measurements of whole updates against the simulator (`measure_transfer`)
+//!   across block sizes, ACK policies and framing, also run by `fwupd-sim --bench`
+//! - `ensure_firmware`: compare-and-flash in one call, skipping devices already up to date
+//! - Protocol conformance checks (`check_conformance`) flagging where a bootloader or the
+//!   simulator deviates from the documented protocol, as a report for its developers
+//! - Bootloader diagnostics (supply voltage, temperature, reset cause, flash wear)
+//! - Deliberate aborts mid-write, before commit or during verify, to prove on real
+//!   hardware that interrupted updates stay recoverable (`fault-injection` feature)
 //! 
+//! # Cargo features
+//! The protocol core, image handling for the dependency-free formats and the
+//! orchestration layers always build. Every transport and parser pulling in
+//! extra crates is a feature of its own; the default is `serial`, `tcp` and
+//! `ihex`. Build with `default-features = false` to embed just the core and
+//! bring a stream of your own. `transports` and `formats` enable them all.
+//!
+//! # Testing
+//! All delays and response timeouts run on tokio's clock, so tests using
+//! `#[tokio::test(start_paused = true)]` advance through them instantly.
+//!
 //! # Protocol Stack
 //! - Application Protocol Layer (APL)
 //! - Link Protocol Layer (LPL)
+//!
+//! # Wire format
+//! Every multi-byte field is little-endian. An APL packet starts with its
+//! type byte ([`AplRequestType`]); requests then carry block size, timeout
+//! in ms, command, offset and length, the offsets and lengths 64 bits wide
+//! once the device announces support. [`encode_request`] and the packet
+//! types' `to_bytes` produce them; [`encode_frame`] wraps one in an LPL frame:
+//! SYN, COBS over the optional node address, the packet and its
+//! CRC16-CCITT-FALSE, then the delimiter. This encoding is stable.
+//! `golden/packets.toml` in the crate holds reference encodings for other
+//! implementations to check against byte for byte.
 //! 
 //! # Examples
 //! 
 //! ## Serial Device Update
-//! ```rust
-//! use fwupd::{DfuConfig, UpdateMode};
+//! ```no_run
+//! use fwupd_lib_rs::{Baud, DfuConfig, UpdateMode};
 //! 
 //! #[tokio::main]
-//! async fn main() -> fwupd::Result<()> {
+//! async fn main() -> fwupd_lib_rs::Result<()> {
 //!     // Direct connection to device
 //!     let config = DfuConfig::new()
 //!         .with_uri("serial:///dev/ttyUSB0")
 //!         .with_firmware("firmware.hex")
 //!         .with_update_mode(UpdateMode::Direct)
-//!         .with_device_speed(9600)
-//!         .with_update_speed(115200)
+//!         .with_device_speed(Baud(9600))
+//!         .with_update_speed(Baud(115200))
 //!         .update()
 //!         .verify();
 //!
-//!     let stream = tokio_serial::SerialStream::open(&config.uri)?;
-//!     fwupd::update_firmware(stream, config).await
+//!     let stream = fwupd_lib_rs::connect(&config).await?;
+//!     let report = fwupd_lib_rs::update_firmware(stream, config).await?;
+//!     if report.is_marginal() {
+//!         log::warn!("Device updated but hardware looks marginal");
+//!     }
+//!     Ok(())
 //! }
 //! ```
 //!
 //! ## Network Device Update
-//! ```rust
+//! ```no_run
+//! use fwupd_lib_rs::DfuConfig;
+//!
 //! #[tokio::main]
-//! async fn main() -> fwupd::Result<()> {
+//! async fn main() -> fwupd_lib_rs::Result<()> {
 //!     let config = DfuConfig::new()
 //!         .with_uri("tcp://192.168.1.100:5000")
 //!         .with_firmware("firmware.hex")
 //!         .update()
 //!         .verify();
 //!
-//!     let stream = tokio::net::TcpStream::connect(&config.uri).await?;
-//!     fwupd::update_firmware(stream, config).await
+//!     let stream = fwupd_lib_rs::connect(&config).await?;
+//!     fwupd_lib_rs::update_firmware(stream, config).await?;
+//!     Ok(())
 //! }
 //! ```
 //!
 //! ## Reading Device Info
-//! ```rust
+//! ```no_run
+//! use fwupd_lib_rs::DfuConfig;
+//!
 //! #[tokio::main]
-//! async fn main() -> fwupd::Result<()> {
+//! async fn main() -> fwupd_lib_rs::Result<()> {
 //!     let config = DfuConfig::new()
 //!         .with_uri("serial:///dev/ttyUSB0")
 //!         .get_info();
 //!
-//!     let stream = tokio_serial::SerialStream::open(&config.uri)?;
-//!     fwupd::read_device_info(stream).await
+//!     let stream = fwupd_lib_rs::connect(&config).await?;
+//!     let info = fwupd_lib_rs::read_device_info(stream).await?;
+//!     println!("Device {:#06x} rev {}, bootloader {:#04x}", info.device_id, info.device_rev, info.bootloader_version);
+//!     for region in &info.memory_map {
+//!         println!("{:?} at {:#010x}, {} bytes", region.kind, region.address, region.size);
+//!     }
+//!     Ok(())
 //! }
 //! ```
 
 mod dfu;
 mod error;
 mod protocols;
+mod transport;
 
-pub use dfu::{DfuStream, DfuConfig, UpdateMode, Command};
-pub use error::{Error, Result};
-
-use tokio::io::{AsyncRead, AsyncWrite};
+pub use dfu::{
+    DfuStream, DfuConfig, UpdateMode, Baud, Command, UpdateReport, EnsureOutcome,
+    DeviceInfo, Capabilities, HashAlgorithm, Diagnostics, DiagnosticLimits, ResetCause,
+    Profile, ProfileSet, MemoryRegion, RegionKind, DumpRange, RegionReport, UpdateOrdering, Warning,
+    MemoryBudget, Phase, PhaseTimings, FirmwareImage, FirmwareFormat, ImageInspection, Segment, VerifyMethod, Verifier,
+    DfuFile, DfuSuffix, DfuTarget, FirmwareContainer, ContainerHeader, SessionLock,
+    EntryMethod, EntryStrategy, EntryTiming, SyncPreamble, ResetSequence, LineStep, GpioEntry, HookEntry, ConsoleCapture, ConsoleTap,
+    Quirks, QuirkEntry, QuirkDatabase, CommandSet, Fallback, UnsupportedCommand,
+    UriCandidate, PortFilter, DiscoveredDevice, serial_uri_candidates, discover_serial, discover_network, discover, complete_uri, find_device,
+    Shell, completion_script, uri_completions, COMPLETE_URI_FLAG,
+    DeviceRegistry, DeviceRecord, RegionWear, Inventory, InventoryEntry, scan,
+    RolloutPlanner, RolloutPlan, BusPlan, PlannedUpdate, SkipReason,
+    TagRule, TagRules, TagExpr,
+    Scheduler, SchedulerLimits, ConcurrencyLimit, FleetReport, RolloutPolicy, FleetControl, FleetState, default_health_check,
+    FirmwareSet, FirmwareRule, Bundle, BundleImage, ArtifactCache, CacheEntry, FirmwareArchive, ArchivedImage,
+    SessionState, ResumeToken, UpdateHandle, UpdateState, UpdateProgress, update_with_reconnect, Replay, AbortPoint, BannerParser, BootloaderBanner,
+    Manifest, ManifestEntry, SigningKey, sign, load_signing_key, load_verifying_key,
+    SimulatedDevice, SimModel, SimMemoryMap, SimDelays, SimFault, TransferCase, TransferSample, measure_transfer,
+    ConformanceReport, ConformanceCheck, CheckOutcome,
+};
+#[cfg(feature = "power-switch")]
+pub use dfu::{PowerCycleEntry, PowerSwitch};
+#[cfg(feature = "tcp")]
+pub use dfu::serve;
+#[cfg(feature = "mdns")]
+pub use dfu::{network_uri_candidates, DFU_SERVICE_TYPE};
+#[cfg(feature = "ssdp")]
+pub use dfu::{ssdp_uri_candidates, DFU_SEARCH_TARGET};
+#[cfg(feature = "tcp")]
+pub use transport::bridge;
+#[cfg(feature = "can")]
+pub use transport::CanStream;
+#[cfg(feature = "i2c")]
+pub use transport::I2cStream;
+#[cfg(feature = "mqtt")]
+pub use transport::MqttStream;
+#[cfg(feature = "rfc2217")]
+pub use transport::Rfc2217Stream;
+#[cfg(feature = "usb")]
+pub use transport::{UsbEndpoints, UsbStream};
+#[cfg(feature = "quic")]
+pub use transport::{QuicOptions, QuicStream};
+#[cfg(feature = "spi")]
+pub use transport::{ChipSelect, SpiStream};
+#[cfg(feature = "ssh")]
+pub use transport::{SshStream, SshTarget};
+#[cfg(feature = "udp")]
+pub use transport::UdpStream;
+pub use transport::{connect, open_from_uri, DfuTransport, Transport, DegradedLink, LinkConditions};
+pub use error::{Checksum, Error, Result};
+pub use protocols::apl::{
+    encode_request, AckPolicy, AddressWidth, AplAckPacket, AplDataPacket, AplErrorPacket, AplHeader,
+    AplMessage, AplRequestPacket, AplRequestPacket64, AplRequestType,
+};
+pub use protocols::channel::{ChannelConfig, ChannelError};
+pub use protocols::lpl::{encode_frame, Framing};
+pub use protocols::stats::{ErrorStats, ProtocolErrorKind};
 
 /// Performs firmware update on a device
-pub async fn update_firmware<T>(stream: T, config: DfuConfig) -> Result<()> 
+pub async fn update_firmware<T>(stream: T, config: DfuConfig) -> Result<UpdateReport> 
 where 
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: DfuTransport,
 {
     let mut dfu = DfuStream::new(stream, config)?;
     dfu.update().await
 }
 
-/// Reads device information including bootloader version and device ID
-pub async fn read_device_info<T>(stream: T) -> Result<()> 
+/// Compares the device against the firmware and only updates and verifies
+/// it if the CRCs differ; returns which of the two happened
+pub async fn ensure_firmware<T>(stream: T, config: DfuConfig) -> Result<(EnsureOutcome, UpdateReport)>
+where
+    T: DfuTransport,
+{
+    let config = DfuConfig { overwrite: false, ..config }.update().verify();
+    let mut dfu = DfuStream::new(stream, config)?;
+    let report = dfu.update().await?;
+    let outcome = if report.is_up_to_date() {
+        EnsureOutcome::AlreadyCurrent
+    } else {
+        EnsureOutcome::Updated
+    };
+    Ok((outcome, report))
+}
+
+/// Restores the image archived for `uid` before its last update; `config`
+/// names the archive directory and the connection
+pub async fn rollback<T>(stream: T, config: DfuConfig, uid: &[u8]) -> Result<UpdateReport>
+where
+    T: DfuTransport,
+{
+    let mut dfu = DfuStream::new(stream, config)?;
+    dfu.rollback(uid).await
+}
+
+/// Reads device information: bootloader version, device ID, revision and
+/// UID, memory map and maximum block size
+pub async fn read_device_info<T>(stream: T) -> Result<DeviceInfo>
 where 
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: DfuTransport,
 {
     let config = DfuConfig::new()
         .with_uri("stream")
         .get_info();
 
     let mut dfu = DfuStream::new(stream, config)?;
-    dfu.update().await
+    let report = dfu.update().await?;
+    Ok(report.device.expect("get_info reads the device info"))
+}
+
+/// Exercises the bootloader's read-only commands, and writes at the
+/// configured scratch address, reporting every deviation from the
+/// documented protocol
+pub async fn check_conformance<T>(stream: T, config: DfuConfig) -> Result<ConformanceReport>
+where
+    T: DfuTransport,
+{
+    let mut dfu = DfuStream::new(stream, config)?;
+    dfu.check_conformance().await
+}
+
+/// Reads bootloader diagnostics (supply voltage, temperature, reset cause, flash wear)
+pub async fn read_device_diagnostics<T>(stream: T) -> Result<Diagnostics>
+where
+    T: DfuTransport,
+{
+    let config = DfuConfig::new()
+        .with_uri("stream");
+
+    let mut dfu = DfuStream::new(stream, config)?;
+    dfu.read_diagnostics().await
+}
+
+/// Reads the bootloader's supported commands, block buffering, hashes and erase sizes
+pub async fn read_device_capabilities<T>(stream: T) -> Result<Capabilities>
+where
+    T: DfuTransport,
+{
+    let config = DfuConfig::new()
+        .with_uri("stream");
+
+    let mut dfu = DfuStream::new(stream, config)?;
+    dfu.capabilities().await
+}
+
+/// Parses a firmware file (format detected from its contents) and describes
+/// its address ranges, gaps, entry point and CRC32, without a device
+pub fn inspect_firmware(path: impl AsRef<std::path::Path>) -> Result<ImageInspection> {
+    let image = FirmwareImage::load(path, FirmwareFormat::Auto, dfu::DEFAULT_FILL)?;
+    Ok(image.inspect())
 }
 
 /// Creates a new DFU configuration with default settings
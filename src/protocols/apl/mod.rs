@@ -1,103 +1,129 @@
-use tokio::sync::mpsc;
-use tokio_stream::{Stream, StreamExt};
-use bytes::{Buf, BufMut, BytesMut};
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use bytes::BytesMut;
 use std::io::{Error, ErrorKind};
+use std::time::Duration;
 
 mod types;
 mod packet;
 
-pub use self::types::{AplMessage, AplRequestType};
-pub use self::packet::{AplHeader, AplDataPacket, AplAckPacket, AplErrorPacket, AplRequestPacket};
+use crate::protocols::stats::{ErrorStats, ProtocolErrorKind};
 
-const APL_MAX_PACKET_SIZE: usize = 1024;
+pub use self::types::{AckPolicy, AddressWidth, AplMessage, AplRequestType};
+pub use self::packet::{AplHeader, AplDataPacket, AplAckPacket, AplErrorPacket, AplRequestPacket, AplRequestPacket64};
 
+/// Block numbering and ACK accounting for data transfers to the device
 pub struct AplStream {
-    rx: mpsc::Receiver<AplMessage>,
-    tx: mpsc::Sender<AplMessage>,
     block_number: u16,
     retries: usize,
     max_retries: usize,
-    max_reconnects: usize,
+    ack_offset: u16,
+    ack_policy: AckPolicy,
+    unacked: u16,
+    errors: ErrorStats,
+}
+
+impl Default for AplStream {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AplStream {
-    pub fn new(buffer: usize) -> (Self, mpsc::Sender<AplMessage>) {
-        let (tx1, rx1) = mpsc::channel(buffer);
-        let (tx2, rx2) = mpsc::channel(buffer);
-        
-        (Self {
-            rx: rx1,
-            tx: tx2,
+    pub fn new() -> Self {
+        Self {
             block_number: 0,
             retries: 0,
             max_retries: 3,
-            max_reconnects: 3,
-        }, tx1)
+            ack_offset: 0,
+            ack_policy: AckPolicy::default(),
+            unacked: 0,
+            errors: ErrorStats::new(),
+        }
     }
 
-    pub fn create_request(
-        &mut self,
-        request_type: AplRequestType,
-        block_size: usize,
-        timeout: usize,
-        command: u8,
-        offset: usize,
-        size: usize,
-    ) -> Result<BytesMut, Error> {
-        let mut packet = BytesMut::with_capacity(APL_MAX_PACKET_SIZE);
-        
-        packet.put_u8(request_type as u8);
-        packet.put_u16_le(block_size as u16);
-        packet.put_u16_le(timeout as u16);
-        packet.put_u8(command);
-        packet.put_u32_le(offset as u32);
-        packet.put_u32_le(size as u32);
-        
-        Ok(packet)
+    /// Accept ACKs numbered `offset` ahead of the block they acknowledge
+    pub fn set_ack_offset(&mut self, offset: u16) {
+        self.ack_offset = offset;
     }
 
-    async fn handle_data(&mut self, msg: AplMessage) -> Result<(), Error> {
-        if msg.block_number != self.block_number {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid block number"));
-        }
+    pub fn set_ack_policy(&mut self, policy: AckPolicy) {
+        self.ack_policy = policy;
+    }
 
-        let response = AplMessage {
-            packet_type: AplRequestType::Ack,
-            block_number: self.block_number,
-            data: Vec::new(),
-        };
-        
-        self.tx.send(response).await?;
-        self.block_number += 1;
+    /// Records a transmitted block; returns true once the sender must wait for an ACK
+    pub fn block_sent(&mut self) -> bool {
+        self.unacked = self.unacked.saturating_add(1);
+        self.ack_due()
+    }
+
+    pub fn ack_due(&self) -> bool {
+        self.unacked >= self.ack_policy.window()
+    }
+
+    /// Blocks sent but not yet covered by an ACK (e.g. a final partial window)
+    pub fn unacked(&self) -> u16 {
+        self.unacked
+    }
+
+    /// Blocks of the current transfer the device has acknowledged
+    pub fn acknowledged(&self) -> u16 {
+        self.block_number
+    }
+
+    /// True when the blocks left at the end of a transfer still get an ACK
+    pub fn final_ack_due(&self) -> bool {
+        self.unacked > 0 && self.ack_policy.acks_partial_window()
+    }
+
+    /// Numbers blocks from zero again, as the device does for every write request
+    pub fn begin_transfer(&mut self) {
+        self.block_number = 0;
+        self.unacked = 0;
         self.retries = 0;
-        
-        Ok(())
+    }
+
+    /// Encodes the next data packet of the current transfer
+    pub fn create_data(&self, data: &[u8]) -> BytesMut {
+        AplDataPacket {
+            header: AplHeader { type_id: AplRequestType::Data as u8 },
+            block_number: self.block_number.wrapping_add(self.unacked),
+            data: data.to_vec(),
+        }.to_bytes()
+    }
+
+    /// Error packets received from the device so far
+    pub fn errors(&self) -> &ErrorStats {
+        &self.errors
     }
 
     async fn handle_ack(&mut self, msg: AplMessage) -> Result<(), Error> {
-        if msg.block_number != self.block_number {
+        // ACKs are cumulative: one for block N covers every block up to N
+        let expected = self.block_number.wrapping_add(self.ack_offset);
+        let ahead = msg.block_number.wrapping_sub(expected);
+        if ahead >= self.ack_policy.window() {
             return Err(Error::new(ErrorKind::InvalidData, "Invalid ACK block number"));
         }
 
-        self.block_number += 1;
+        self.block_number = self.block_number.wrapping_add(ahead + 1);
+        self.unacked = self.unacked.saturating_sub(ahead + 1);
         self.retries = 0;
         Ok(())
     }
 
     async fn handle_error(&mut self, msg: AplMessage) -> Result<(), Error> {
+        self.errors.record(
+            ProtocolErrorKind::DeviceError,
+            format!("block {}: {:?}", msg.block_number, msg.data),
+        );
         if self.retries >= self.max_retries {
-            return Err(Error::new(ErrorKind::Other, "Max retries exceeded"));
+            return Err(Error::other("Max retries exceeded"));
         }
         
         self.retries += 1;
-        Err(Error::new(ErrorKind::Other, format!("Protocol error: {:?}", msg.data)))
+        Err(Error::other(format!("Protocol error: {:?}", msg.data)))
     }
 
     pub async fn process_message(&mut self, msg: AplMessage) -> Result<(), Error> {
         match msg.packet_type {
-            AplRequestType::Data => self.handle_data(msg).await,
             AplRequestType::Ack => self.handle_ack(msg).await,
             AplRequestType::Error => self.handle_error(msg).await,
             _ => Err(Error::new(ErrorKind::InvalidData, "Unsupported message type")),
@@ -105,10 +131,44 @@ impl AplStream {
     }
 }
 
-impl Stream for AplStream {
-    type Item = Result<AplMessage, Error>;
+/// Encodes a request packet, using the 64-bit layout when negotiated
+pub fn encode_request(
+    width: AddressWidth,
+    request_type: AplRequestType,
+    block_size: usize,
+    timeout: Duration,
+    command: u8,
+    offset: usize,
+    size: usize,
+) -> crate::error::Result<BytesMut> {
+    let block_size = u16::try_from(block_size).map_err(|_| {
+        crate::error::Error::Configuration(format!("Block size {} exceeds 65535 bytes", block_size))
+    })?;
+    let timeout = u16::try_from(timeout.as_millis()).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "Timeout exceeds 65535 ms")
+    })?;
+    let packet = match width {
+        AddressWidth::Bits32 => AplRequestPacket {
+            header: AplHeader { type_id: request_type as u8 },
+            block_size,
+            timeout,
+            command,
+            offset: u32::try_from(offset).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "Offset exceeds 32-bit address range")
+            })?,
+            length: u32::try_from(size).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "Length exceeds 32-bit address range")
+            })?,
+        }.to_bytes(),
+        AddressWidth::Bits64 => AplRequestPacket64 {
+            header: AplHeader { type_id: request_type.wide() as u8 },
+            block_size,
+            timeout,
+            command,
+            offset: offset as u64,
+            length: size as u64,
+        }.to_bytes(),
+    };
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.rx.poll_recv(cx).map(|opt| opt.map(Ok))
-    }
+    Ok(packet)
 }
@@ -1,125 +1,322 @@
-#[repr(C, packed)]
-pub struct AplHeader {
-    pub type_id: u8,  // 3 bits type, 5 bits id
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::{Error, ErrorKind};
+
+/// Fixed-size field encoded little-endian, independent of host byte order and alignment
+pub trait WireField: Sized {
+    const SIZE: usize;
+
+    fn put(&self, buf: &mut BytesMut);
+    fn get(buf: &mut &[u8]) -> Self;
 }
 
-#[repr(C, packed)]
-pub struct AplDataPacket {
-    pub header: AplHeader,
-    pub block_number: u16,
-    // Variable length data follows
+/// Variable-length trailer that consumes the rest of the packet
+pub trait WireTail: Sized {
+    fn len(&self) -> usize;
+    fn put(&self, buf: &mut BytesMut);
+    fn from_rest(rest: &[u8]) -> Self;
 }
 
-#[repr(C, packed)]
-pub struct AplAckPacket {
-    pub header: AplHeader,
-    pub block_number: u16,
+macro_rules! wire_int {
+    ($($ty:ty => $put:ident, $get:ident;)*) => {
+        $(
+            impl WireField for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn put(&self, buf: &mut BytesMut) {
+                    buf.$put(*self);
+                }
+
+                fn get(buf: &mut &[u8]) -> Self {
+                    buf.$get()
+                }
+            }
+        )*
+    };
 }
 
-#[repr(C, packed)]
-pub struct AplErrorPacket {
-    pub header: AplHeader,
-    pub block_number: u16,
-    pub error_code: u8,
-    // Variable length error message follows
+wire_int! {
+    u8 => put_u8, get_u8;
+    u16 => put_u16_le, get_u16_le;
+    u32 => put_u32_le, get_u32_le;
+    u64 => put_u64_le, get_u64_le;
 }
 
-#[repr(C, packed)]
-pub struct AplRequestPacket {
-    pub header: AplHeader,
-    pub block_size: u16,
-    pub timeout: u16,
-    pub command: u8,
-    pub offset: u32,
-    pub length: u32,
+impl WireTail for Vec<u8> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn put(&self, buf: &mut BytesMut) {
+        buf.extend_from_slice(self);
+    }
+
+    fn from_rest(rest: &[u8]) -> Self {
+        rest.to_vec()
+    }
 }
 
-impl AplDataPacket {
-    pub fn to_bytes(&self) -> BytesMut {
-        let mut buf = BytesMut::with_capacity(3 + self.data.len());
-        buf.put_u8(self.header.type_id);
-        buf.put_u16_le(self.block_number);
-        buf.extend_from_slice(&self.data);
-        buf
+impl WireTail for String {
+    fn len(&self) -> usize {
+        self.len()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < 3 {
-            return Err(Error::new(ErrorKind::InvalidData, "Packet too short"));
-        }
-        Ok(Self {
-            header: AplHeader { type_id: bytes[0] },
-            block_number: u16::from_le_bytes([bytes[1], bytes[2]]),
-            data: bytes[3..].to_vec(),
-        })
+    fn put(&self, buf: &mut BytesMut) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn from_rest(rest: &[u8]) -> Self {
+        String::from_utf8_lossy(rest).into_owned()
     }
 }
 
-impl AplAckPacket {
-    pub fn to_bytes(&self) -> BytesMut {
-        let mut buf = BytesMut::with_capacity(3);
-        buf.put_u8(self.header.type_id);
-        buf.put_u16_le(self.block_number);
-        buf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AplHeader {
+    pub type_id: u8,  // 3 bits type, 5 bits id
+}
+
+impl WireField for AplHeader {
+    const SIZE: usize = 1;
+
+    fn put(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.type_id);
+    }
+
+    fn get(buf: &mut &[u8]) -> Self {
+        Self { type_id: buf.get_u8() }
     }
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < 3 {
-            return Err(Error::new(ErrorKind::InvalidData, "Packet too short"));
+/// Declares a packet whose encoder and decoder are generated from one field
+/// list, so the two can't drift apart.
+macro_rules! wire_packet {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $(pub $field:ident: $ty:ty,)*
+            $(..$tail:ident: $tail_ty:ty,)?
         }
-        Ok(Self {
-            header: AplHeader { type_id: bytes[0] },
-            block_number: u16::from_le_bytes([bytes[1], bytes[2]]),
-        })
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name {
+            $(pub $field: $ty,)*
+            $(pub $tail: $tail_ty,)?
+        }
+
+        impl $name {
+            /// Size of the fixed part of the packet
+            pub const FIXED_LEN: usize = 0 $(+ <$ty as WireField>::SIZE)*;
+
+            pub fn encoded_len(&self) -> usize {
+                Self::FIXED_LEN $(+ WireTail::len(&self.$tail))?
+            }
+
+            pub fn to_bytes(&self) -> BytesMut {
+                let mut buf = BytesMut::with_capacity(self.encoded_len());
+                $(WireField::put(&self.$field, &mut buf);)*
+                $(WireTail::put(&self.$tail, &mut buf);)?
+                buf
+            }
+
+            pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+                if bytes.len() < Self::FIXED_LEN {
+                    return Err(Error::new(ErrorKind::InvalidData, "Packet too short"));
+                }
+                let mut cursor = bytes;
+                $(let $field = <$ty as WireField>::get(&mut cursor);)*
+                $(let $tail = <$tail_ty as WireTail>::from_rest(cursor);)?
+                Ok(Self {
+                    $($field,)*
+                    $($tail,)?
+                })
+            }
+        }
+    };
+}
+
+wire_packet! {
+    /// Type 3: one block of a transfer, the data filling the rest of the packet
+    pub struct AplDataPacket {
+        pub header: AplHeader,
+        pub block_number: u16,
+        ..data: Vec<u8>,
+    }
+}
+
+wire_packet! {
+    /// Type 4: acknowledges every block up to `block_number`
+    pub struct AplAckPacket {
+        pub header: AplHeader,
+        pub block_number: u16,
     }
 }
 
-impl AplErrorPacket {
-    pub fn to_bytes(&self) -> BytesMut {
-        let mut buf = BytesMut::with_capacity(4 + self.error_message.len());
-        buf.put_u8(self.header.type_id);
-        buf.put_u16_le(self.block_number);
-        buf.put_u8(self.error_code);
-        buf.extend_from_slice(self.error_message.as_bytes());
-        buf
+wire_packet! {
+    /// Type 5: a failed block, with a UTF-8 message filling the rest of the packet
+    pub struct AplErrorPacket {
+        pub header: AplHeader,
+        pub block_number: u16,
+        pub error_code: u8,
+        ..error_message: String,
     }
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < 4 {
-            return Err(Error::new(ErrorKind::InvalidData, "Packet too short"));
-        }
-        Ok(Self {
-            header: AplHeader { type_id: bytes[0] },
-            block_number: u16::from_le_bytes([bytes[1], bytes[2]]),
-            error_code: bytes[3],
-            error_message: String::from_utf8_lossy(&bytes[4..]).to_string(),
-        })
+wire_packet! {
+    /// Types 1 and 2: read or write request with 32-bit offset and length
+    pub struct AplRequestPacket {
+        pub header: AplHeader,
+        pub block_size: u16,
+        pub timeout: u16,
+        pub command: u8,
+        pub offset: u32,
+        pub length: u32,
     }
 }
 
-impl AplRequestPacket {
-    pub fn to_bytes(&self) -> BytesMut {
-        let mut buf = BytesMut::with_capacity(12);
-        buf.put_u8(self.header.type_id);
-        buf.put_u16_le(self.block_size);
-        buf.put_u16_le(self.timeout);
-        buf.put_u8(self.command);
-        buf.put_u32_le(self.offset);
-        buf.put_u32_le(self.length);
-        buf
-    }
-
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < 12 {
-            return Err(Error::new(ErrorKind::InvalidData, "Packet too short"));
+wire_packet! {
+    /// Types 6 and 7: read or write request with 64-bit offset and length
+    pub struct AplRequestPacket64 {
+        pub header: AplHeader,
+        pub block_size: u16,
+        pub timeout: u16,
+        pub command: u8,
+        pub offset: u64,
+        pub length: u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(type_id: u8) -> AplHeader {
+        AplHeader { type_id }
+    }
+
+    /// Every prefix shorter than the fixed part must fail rather than read past the end
+    fn assert_rejects_short<P>(encoded: &[u8], fixed_len: usize, decode: impl Fn(&[u8]) -> Result<P, Error>) {
+        for len in 0..fixed_len {
+            let err = decode(&encoded[..len]).err().expect("truncated packet decoded");
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn data_packet_round_trips() {
+        for data in [vec![], vec![0xA5], (0..=255).collect()] {
+            let packet = AplDataPacket { header: header(3), block_number: 0xBEEF, data };
+            let bytes = packet.to_bytes();
+            assert_eq!(bytes.len(), packet.encoded_len());
+            assert_eq!(&bytes[..3], &[3, 0xEF, 0xBE]);
+            assert_eq!(AplDataPacket::from_bytes(&bytes).unwrap(), packet);
         }
-        Ok(Self {
-            header: AplHeader { type_id: bytes[0] },
-            block_size: u16::from_le_bytes([bytes[1], bytes[2]]),
-            timeout: u16::from_le_bytes([bytes[3], bytes[4]]),
-            command: bytes[5],
-            offset: u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
-            length: u32::from_le_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]),
-        })
+    }
+
+    #[test]
+    fn ack_packet_round_trips() {
+        let packet = AplAckPacket { header: header(4), block_number: 0x0102 };
+        let bytes = packet.to_bytes();
+        assert_eq!(&bytes[..], &[4, 0x02, 0x01]);
+        assert_eq!(AplAckPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn error_packet_round_trips() {
+        let packet = AplErrorPacket {
+            header: header(5),
+            block_number: 7,
+            error_code: 2,
+            error_message: "flash busy".into(),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), AplErrorPacket::FIXED_LEN + 10);
+        assert_eq!(AplErrorPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn request_packet_round_trips() {
+        let packet = AplRequestPacket {
+            header: header(2),
+            block_size: 1024,
+            timeout: 500,
+            command: 6,
+            offset: 0x0800_4000,
+            length: 0x0001_E000,
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), AplRequestPacket::FIXED_LEN);
+        assert_eq!(&bytes[6..10], &[0x00, 0x40, 0x00, 0x08]);
+        assert_eq!(AplRequestPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn request64_packet_round_trips() {
+        let packet = AplRequestPacket64 {
+            header: header(7),
+            block_size: 256,
+            timeout: 0,
+            command: 1,
+            offset: 0x0000_0001_0000_0000,
+            length: u64::MAX,
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), AplRequestPacket64::FIXED_LEN);
+        assert_eq!(AplRequestPacket64::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn short_packets_are_rejected() {
+        let data = AplDataPacket { header: header(3), block_number: 1, data: vec![1, 2] }.to_bytes();
+        assert_rejects_short(&data, AplDataPacket::FIXED_LEN, AplDataPacket::from_bytes);
+
+        let ack = AplAckPacket { header: header(4), block_number: 1 }.to_bytes();
+        assert_rejects_short(&ack, AplAckPacket::FIXED_LEN, AplAckPacket::from_bytes);
+
+        let error = AplErrorPacket {
+            header: header(5),
+            block_number: 1,
+            error_code: 1,
+            error_message: String::new(),
+        }.to_bytes();
+        assert_rejects_short(&error, AplErrorPacket::FIXED_LEN, AplErrorPacket::from_bytes);
+
+        let request64 = AplRequestPacket64 {
+            header: header(6),
+            block_size: 1,
+            timeout: 1,
+            command: 1,
+            offset: 1,
+            length: 1,
+        }.to_bytes();
+        assert_rejects_short(&request64, AplRequestPacket64::FIXED_LEN, AplRequestPacket64::from_bytes);
+    }
+
+    #[test]
+    fn request_packet_checks_its_full_length() {
+        // The old decoder accepted 12 bytes and then read the 14 the packet needs
+        assert_eq!(AplRequestPacket::FIXED_LEN, 14);
+        let request = AplRequestPacket {
+            header: header(1),
+            block_size: 1,
+            timeout: 1,
+            command: 1,
+            offset: 1,
+            length: 1,
+        }.to_bytes();
+        assert!(AplRequestPacket::from_bytes(&request[..12]).is_err());
+        assert!(AplRequestPacket::from_bytes(&request[..13]).is_err());
+        assert_rejects_short(&request, AplRequestPacket::FIXED_LEN, AplRequestPacket::from_bytes);
+    }
+
+    #[test]
+    fn tails_take_the_rest_of_the_packet() {
+        let bytes = [3, 0, 0];
+        let data = AplDataPacket::from_bytes(&bytes).unwrap();
+        assert!(data.data.is_empty());
+
+        let bytes = [5, 9, 0, 4, b'o', b'k'];
+        let error = AplErrorPacket::from_bytes(&bytes).unwrap();
+        assert_eq!((error.block_number, error.error_code), (9, 4));
+        assert_eq!(error.error_message, "ok");
     }
 }
@@ -1,4 +1,5 @@
 use bytes::BytesMut;
+use serde::Deserialize;
 use std::io::{Error, ErrorKind};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -9,12 +10,34 @@ pub enum AplRequestType {
     Data = 3,
     Ack = 4,
     Error = 5,
+    ReadRequest64 = 6,
+    WriteRequest64 = 7,
+    /// Diagnostic text the device may send at any time
+    Log = 8,
+    /// Progress or status notification the device may send at any time
+    Notify = 9,
+}
+
+impl AplRequestType {
+    /// Request type carrying 64-bit offset/length fields
+    pub fn wide(self) -> Self {
+        match self {
+            Self::ReadRequest => Self::ReadRequest64,
+            Self::WriteRequest => Self::WriteRequest64,
+            other => other,
+        }
+    }
+
+    /// Sent by the device on its own rather than in answer to a request
+    pub fn is_unsolicited(self) -> bool {
+        matches!(self, Self::Log | Self::Notify)
+    }
 }
 
 impl TryFrom<u8> for AplRequestType {
     type Error = Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> Result<Self, Error> {
         match value {
             0 => Ok(Self::None),
             1 => Ok(Self::ReadRequest),
@@ -22,6 +45,10 @@ impl TryFrom<u8> for AplRequestType {
             3 => Ok(Self::Data),
             4 => Ok(Self::Ack),
             5 => Ok(Self::Error),
+            6 => Ok(Self::ReadRequest64),
+            7 => Ok(Self::WriteRequest64),
+            8 => Ok(Self::Log),
+            9 => Ok(Self::Notify),
             _ => Err(Error::new(
                 ErrorKind::InvalidData,
                 format!("Invalid packet type: {}", value)
@@ -30,6 +57,52 @@ impl TryFrom<u8> for AplRequestType {
     }
 }
 
+/// Width of the offset/length fields in request packets, negotiated per device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressWidth {
+    #[default]
+    Bits32,
+    Bits64,
+}
+
+/// When the device is expected to acknowledge transferred blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckPolicy {
+    /// Every block is acknowledged individually
+    #[default]
+    EveryBlock,
+    /// One cumulative ACK after every N blocks; the blocks left over at the
+    /// end of a write request are never acknowledged
+    EveryN(u16),
+    /// One cumulative ACK per window of up to N blocks, the final partial
+    /// window of a write request included
+    EndOfWindow(u16),
+}
+
+impl AckPolicy {
+    /// Number of blocks that may be in flight without an ACK
+    pub fn window(&self) -> u16 {
+        match self {
+            AckPolicy::EveryBlock => 1,
+            AckPolicy::EveryN(n) | AckPolicy::EndOfWindow(n) => (*n).max(1),
+        }
+    }
+
+    /// Whether the device acknowledges a window cut short by the end of the transfer
+    pub fn acks_partial_window(&self) -> bool {
+        !matches!(self, AckPolicy::EveryN(_))
+    }
+
+    /// The same policy with at most `limit` blocks per ACK
+    pub fn limit_window(self, limit: u16) -> Self {
+        match self {
+            AckPolicy::EveryN(n) => AckPolicy::EveryN(n.min(limit)),
+            AckPolicy::EndOfWindow(n) => AckPolicy::EndOfWindow(n.min(limit)),
+            other => other,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct AplMessage {
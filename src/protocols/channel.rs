@@ -0,0 +1,60 @@
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+/// Queue depth used when nothing else is configured
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Sizing of the bounded queue carrying device notifications
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelConfig {
+    pub capacity: usize,
+}
+
+impl ChannelConfig {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1) }
+    }
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANNEL_CAPACITY)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ChannelError {
+    #[error("{channel} channel closed")]
+    Closed { channel: &'static str },
+
+    #[error("{channel} channel full ({capacity} messages)")]
+    Overflow { channel: &'static str, capacity: usize },
+}
+
+/// Sending half of a named queue that reports a full queue as a typed error
+#[derive(Debug, Clone)]
+pub struct BoundedSender<T> {
+    tx: mpsc::Sender<T>,
+    name: &'static str,
+    config: ChannelConfig,
+}
+
+impl<T> BoundedSender<T> {
+    /// Sends without waiting; on a full queue the message is dropped and
+    /// reported as an overflow
+    pub fn try_send(&self, message: T) -> Result<(), ChannelError> {
+        self.tx.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => ChannelError::Overflow {
+                channel: self.name,
+                capacity: self.config.capacity,
+            },
+            mpsc::error::TrySendError::Closed(_) => ChannelError::Closed { channel: self.name },
+        })
+    }
+}
+
+/// Creates a named bounded queue
+pub fn bounded<T>(name: &'static str, config: ChannelConfig) -> (BoundedSender<T>, mpsc::Receiver<T>) {
+    let (tx, rx) = mpsc::channel(config.capacity);
+    (BoundedSender { tx, name, config }, rx)
+}
@@ -1,130 +1,306 @@
 use tokio::sync::mpsc;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_stream::{Stream, StreamExt};
-use bytes::{Buf, BufMut, BytesMut};
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bytes::{BufMut, BytesMut};
 use std::io::{Error, ErrorKind};
-use crc::{Crc, CRC_16_CCITT_FALSE};
+use std::time::Duration;
+// CRC-16/IBM-3740 is the catalog name of CRC-16/CCITT-FALSE
+use crc::{Crc, CRC_16_IBM_3740};
+use log::trace;
 
 mod types;
-pub use self::types::{LplMessage, LplStream};
+pub use self::types::Framing;
 
-use crate::protocols::apl::{AplMessage, AplRequestType};
+use crate::protocols::apl::{self, AddressWidth, AplMessage, AplRequestType};
+use crate::protocols::channel::{bounded, BoundedSender, ChannelConfig, ChannelError};
+use crate::protocols::stats::{ErrorStats, ProtocolErrorKind};
 
-const SYN: u8 = 0x55;
 const LPL_MAX_BUFFER_SIZE: usize = 1024;
+/// Highest node address on a multi-drop bus
+pub const MAX_NETID: usize = 0x7F;
+/// Set in the address byte of frames sent by a device, so requests echoed
+/// back on a half-duplex bus are never taken for responses
+pub(crate) const RESPONSE_FLAG: u8 = 0x80;
 
+/// Frames requests and data for the transport and reads responses off it.
+/// Reading is driven by the caller waiting for a response; there is no
+/// background reader.
 pub struct LplStream {
-    rx: mpsc::Receiver<LplMessage>,
-    tx: mpsc::Sender<LplMessage>,
-    apl_tx: mpsc::Sender<AplMessage>,
+    notify_tx: BoundedSender<AplMessage>,
     tx_buffer: BytesMut,
     rx_buffer: BytesMut,
+    address_width: AddressWidth,
+    /// Node addressed on a multi-drop bus; `None` for point-to-point links
+    netid: Option<u8>,
+    framing: Framing,
+    errors: ErrorStats,
 }
 
 impl LplStream {
-    pub fn new(
-        buffer: usize,
-        apl_tx: mpsc::Sender<AplMessage>,
-    ) -> (Self, mpsc::Sender<LplMessage>) {
-        let (tx1, rx1) = mpsc::channel(buffer);
-        let (tx2, rx2) = mpsc::channel(buffer);
-        
+    /// Returns the stream and the queue receiving log and notification
+    /// messages the device sends on its own
+    pub fn new(channels: ChannelConfig) -> (Self, mpsc::Receiver<AplMessage>) {
+        let (notify_tx, notifications) = bounded("LPL notifications", channels);
+
         (Self {
-            rx: rx1,
-            tx: tx2,
-            apl_tx,
+            notify_tx,
             tx_buffer: BytesMut::with_capacity(LPL_MAX_BUFFER_SIZE),
             rx_buffer: BytesMut::with_capacity(LPL_MAX_BUFFER_SIZE),
-        }, tx1)
+            address_width: AddressWidth::default(),
+            netid: None,
+            framing: Framing::default(),
+            errors: ErrorStats::new(),
+        }, notifications)
+    }
+
+    pub fn set_address_width(&mut self, width: AddressWidth) {
+        self.address_width = width;
+    }
+
+    /// Prefixes every frame with the node address and drops frames from
+    /// other nodes; the address is covered by the frame CRC
+    pub fn set_netid(&mut self, netid: Option<u8>) {
+        self.netid = netid;
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
     }
 
+    /// Decode errors seen on the link so far
+    pub fn errors(&self) -> &ErrorStats {
+        &self.errors
+    }
+
+    /// Arguments follow the fields of the request packet
+    #[allow(clippy::too_many_arguments)]
     pub async fn send_request<T: AsyncWrite + Unpin>(
         &mut self,
         stream: &mut T,
         request_type: AplRequestType,
         block_size: usize,
-        timeout: usize,
+        timeout: Duration,
         command: usize,
         offset: usize,
         size: usize,
-    ) -> Result<(), Error> {
-        self.tx_buffer.clear();
-        self.tx_buffer.put_u8(SYN);
-
-        let mut packet = BytesMut::with_capacity(LPL_MAX_BUFFER_SIZE);
-        
+    ) -> crate::error::Result<()> {
         // Create APL request
-        let apl_request = AplMessage {
-            packet_type: request_type,
-            block_number: 0,
-            data: vec![],
-        };
-        
-        packet.extend_from_slice(&apl_request.to_bytes()?);
-
-        // Calculate CRC
-        let crc = Crc::<u16>::new(&CRC_16_CCITT_FALSE);
-        let mut digest = crc.digest();
-        digest.update(&packet);
-        let checksum = digest.finalize();
-        packet.put_u16_le(checksum);
+        let apl_request = apl::encode_request(
+            self.address_width,
+            request_type,
+            block_size,
+            timeout,
+            command as u8,
+            offset,
+            size,
+        )?;
 
-        // COBS encode
-        let mut encoded = vec![0; cobs::max_encoding_length(packet.len())];
-        let encoded_len = cobs::encode(&packet, &mut encoded);
-        
-        self.tx_buffer.extend_from_slice(&encoded[..encoded_len]);
-        self.tx_buffer.put_u8(0);
+        Ok(self.send_packet(stream, &apl_request).await?)
+    }
 
+    /// Frames an encoded APL packet (e.g. a data block) and writes it out
+    pub async fn send_packet<T: AsyncWrite + Unpin>(&mut self, stream: &mut T, packet: &[u8]) -> Result<(), Error> {
+        self.tx_buffer.clear();
+        encode_frame(self.framing, self.netid, packet, &mut self.tx_buffer);
         stream.write_all(&self.tx_buffer).await
     }
 
-    async fn decode_message(&self, msg: LplMessage) -> Result<AplMessage, Error> {
-        let mut decoded = vec![0; msg.payload.len()];
-        let decoded_len = cobs::decode(&msg.payload, &mut decoded)?;
-        
+    /// Reads frames off `stream` until one carries a response for this node.
+    /// Damaged frames are counted and skipped, so a caller waiting for an ACK
+    /// is bounded by its own timeout rather than the first bit error; log and
+    /// notification messages in between go to the notification queue. A full
+    /// queue fails the read with [`ChannelError::Overflow`]; the frames after
+    /// the dropped message are still on the link for the next read.
+    pub async fn read_message<T: AsyncRead + Unpin>(&mut self, stream: &mut T) -> Result<AplMessage, Error> {
+        loop {
+            self.read_frame(stream).await?;
+            let body = self.rx_buffer.split();
+            let Ok(Some(apl_msg)) = self.decode_message(&body).await else {
+                continue;
+            };
+            if apl_msg.packet_type.is_unsolicited() {
+                self.notify(apl_msg).map_err(Error::other)?;
+                continue;
+            }
+            return Ok(apl_msg);
+        }
+    }
+
+    /// Queues a message the device sent on its own. It is dropped rather
+    /// than stall the link; a full queue is counted and reported, a closed
+    /// one means nobody is listening.
+    fn notify(&mut self, msg: AplMessage) -> Result<(), ChannelError> {
+        match self.notify_tx.try_send(msg) {
+            Err(e @ ChannelError::Overflow { .. }) => {
+                self.errors.record(ProtocolErrorKind::NotificationDropped, &e);
+                Err(e)
+            }
+            Err(e @ ChannelError::Closed { .. }) => {
+                trace!("Dropping device notification: {}", e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Reads the next frame body, between SYN and delimiter, into `rx_buffer`
+    async fn read_frame<T: AsyncRead + Unpin>(&mut self, stream: &mut T) -> Result<(), Error> {
+        self.rx_buffer.clear();
+        while stream.read_u8().await? != self.framing.syn {}
+        loop {
+            let byte = stream.read_u8().await?;
+            if byte == self.framing.delimiter {
+                return Ok(());
+            }
+            if self.rx_buffer.len() == LPL_MAX_BUFFER_SIZE {
+                // Noise that never ends a frame; look for the next SYN
+                self.errors.record(ProtocolErrorKind::Framing, "frame exceeds buffer");
+                self.rx_buffer.clear();
+                while stream.read_u8().await? != self.framing.syn {}
+                continue;
+            }
+            self.rx_buffer.put_u8(byte);
+        }
+    }
+
+    /// `None` for intact frames addressed to or sent by another node
+    async fn decode_message(&mut self, body: &[u8]) -> Result<Option<AplMessage>, Error> {
+        let payload: Vec<u8> = body.iter().map(|b| b ^ self.framing.delimiter).collect();
+        let mut decoded = vec![0; payload.len()];
+        let decoded_len = match cobs::decode(&payload, &mut decoded) {
+            Ok(len) => len,
+            Err(()) => {
+                self.errors.record(ProtocolErrorKind::Framing, "invalid COBS encoding");
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid COBS encoding"));
+            }
+        };
+
         if decoded_len < 2 {
+            self.errors.record(ProtocolErrorKind::Truncated, format!("{} byte frame", decoded_len));
             return Err(Error::new(ErrorKind::InvalidData, "Packet too small"));
         }
 
         let (data, crc_bytes) = decoded.split_at(decoded_len - 2);
         let received_crc = u16::from_le_bytes([crc_bytes[0], crc_bytes[1]]);
 
-        let crc = Crc::<u16>::new(&CRC_16_CCITT_FALSE);
+        let crc = Crc::<u16>::new(&CRC_16_IBM_3740);
         let mut digest = crc.digest();
         digest.update(data);
         let calculated_crc = digest.finalize();
 
         if calculated_crc != received_crc {
+            self.errors.record(
+                ProtocolErrorKind::CrcMismatch,
+                format!("expected {:#06x}, got {:#06x}", calculated_crc, received_crc),
+            );
             return Err(Error::new(ErrorKind::InvalidData, "CRC mismatch"));
         }
 
-        AplMessage::from_bytes(data)
-    }
-
-    pub async fn run(&mut self) {
-        while let Some(msg) = self.rx.recv().await {
-            match self.decode_message(msg).await {
-                Ok(apl_msg) => {
-                    if let Err(e) = self.apl_tx.send(apl_msg).await {
-                        log::error!("Failed to forward message to APL: {}", e);
-                        break;
-                    }
+        let data = match self.netid {
+            Some(netid) => match data.split_first() {
+                Some((&address, rest)) if address == netid | RESPONSE_FLAG => rest,
+                // Traffic between other nodes on a shared bus is not an error
+                Some((&address, _)) => {
+                    trace!("Ignoring frame for node {:#04x}", address);
+                    return Ok(None);
                 }
-                Err(e) => {
-                    log::error!("Failed to decode message: {}", e);
+                None => {
+                    self.errors.record(ProtocolErrorKind::Truncated, "frame without address");
+                    return Err(Error::new(ErrorKind::InvalidData, "Missing node address"));
                 }
-            }
-        }
+            },
+            None => data,
+        };
+
+        AplMessage::from_bytes(data).map(Some).inspect_err(|e| {
+            self.errors.record(ProtocolErrorKind::InvalidPacket, e);
+        })
     }
 }
 
-impl Stream for LplStream {
-    type Item = Result<LplMessage, Error>;
+/// Appends one LPL frame carrying `packet` to `out`: SYN, then COBS over
+/// the node address (on a multi-drop bus), the packet and its CRC16, then
+/// the delimiter
+pub fn encode_frame(framing: Framing, netid: Option<u8>, packet: &[u8], out: &mut BytesMut) {
+    out.put_u8(framing.syn);
+
+    let mut framed = BytesMut::with_capacity(packet.len() + 3);
+    if let Some(netid) = netid {
+        framed.put_u8(netid);
+    }
+    framed.extend_from_slice(packet);
+
+    // Calculate CRC
+    let crc = Crc::<u16>::new(&CRC_16_IBM_3740);
+    let mut digest = crc.digest();
+    digest.update(&framed);
+    framed.put_u16_le(digest.finalize());
+
+    // COBS encode
+    let mut encoded = vec![0; cobs::max_encoding_length(framed.len())];
+    let encoded_len = cobs::encode(&framed, &mut encoded);
+    out.extend(encoded[..encoded_len].iter().map(|b| b ^ framing.delimiter));
+    out.put_u8(framing.delimiter);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(packet_type: AplRequestType, block_number: u16, data: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        let message = AplMessage::new(packet_type, block_number, data.to_vec());
+        encode_frame(Framing::DEFAULT, None, &message.to_bytes(), &mut out);
+        out
+    }
+
+    #[tokio::test]
+    async fn unsolicited_messages_are_routed_apart_from_responses() {
+        let (mut lpl, mut notifications) = LplStream::new(ChannelConfig::default());
+
+        let mut wire = BytesMut::new();
+        wire.extend_from_slice(&framed(AplRequestType::Log, 0, b"erasing"));
+        // Line noise and a damaged frame are skipped as well
+        wire.extend_from_slice(&[0x13, 0x37]);
+        let mut damaged = framed(AplRequestType::Ack, 9, &[]);
+        damaged[2] ^= 0x01;
+        wire.extend_from_slice(&damaged);
+        wire.extend_from_slice(&framed(AplRequestType::Notify, 0, &[50]));
+        wire.extend_from_slice(&framed(AplRequestType::Ack, 3, &[]));
+
+        let mut reader = &wire[..];
+        let response = lpl.read_message(&mut reader).await.unwrap();
+        assert_eq!((response.packet_type, response.block_number), (AplRequestType::Ack, 3));
+        assert!(reader.is_empty());
+
+        let log = notifications.try_recv().unwrap();
+        assert_eq!((log.packet_type, log.data.as_slice()), (AplRequestType::Log, &b"erasing"[..]));
+        let notify = notifications.try_recv().unwrap();
+        assert_eq!((notify.packet_type, notify.data.as_slice()), (AplRequestType::Notify, &[50][..]));
+        assert_eq!(lpl.errors().count(ProtocolErrorKind::CrcMismatch), 1);
+    }
+
+    #[tokio::test]
+    async fn full_notification_queue_is_reported() {
+        let (mut lpl, mut notifications) = LplStream::new(ChannelConfig::new(1));
+
+        let mut wire = BytesMut::new();
+        wire.extend_from_slice(&framed(AplRequestType::Log, 0, b"first"));
+        wire.extend_from_slice(&framed(AplRequestType::Log, 0, b"second"));
+        wire.extend_from_slice(&framed(AplRequestType::Ack, 1, &[]));
+
+        let mut reader = &wire[..];
+        let error = lpl.read_message(&mut reader).await.unwrap_err();
+        let overflow = error.get_ref().and_then(|e| e.downcast_ref::<ChannelError>());
+        assert_eq!(
+            overflow,
+            Some(&ChannelError::Overflow { channel: "LPL notifications", capacity: 1 })
+        );
+        assert_eq!(lpl.errors().count(ProtocolErrorKind::NotificationDropped), 1);
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.rx.poll_recv(cx).map(|opt| opt.map(Ok))
+        // The ACK behind the dropped message is still read
+        let response = lpl.read_message(&mut reader).await.unwrap();
+        assert_eq!((response.packet_type, response.block_number), (AplRequestType::Ack, 1));
+        assert_eq!(notifications.try_recv().unwrap().data, b"first");
+        assert!(notifications.try_recv().is_err());
     }
 }
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Minimum time between two log lines for the same kind of error
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Class of a recoverable protocol error, used to aggregate repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolErrorKind {
+    /// Frame failed COBS decoding
+    Framing,
+    /// Frame shorter than its header and checksum
+    Truncated,
+    CrcMismatch,
+    /// Frame decoded but carried an unknown packet type or layout
+    InvalidPacket,
+    /// Device answered with an APL error packet
+    DeviceError,
+    /// No response or ACK within the response timeout
+    Timeout,
+    /// Link closed partway through a raw response
+    ShortResponse,
+    /// Device notification dropped because its queue was full
+    NotificationDropped,
+}
+
+/// Per-kind error counters that log the first occurrence in full and then at
+/// most one summary line per interval; every error is still logged at trace level
+#[derive(Debug, Default)]
+pub struct ErrorStats {
+    counts: BTreeMap<ProtocolErrorKind, u64>,
+    last_logged: BTreeMap<ProtocolErrorKind, (Instant, u64)>,
+}
+
+impl ErrorStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, kind: ProtocolErrorKind, detail: impl Display) {
+        let count = self.counts.entry(kind).or_insert(0);
+        *count += 1;
+        let count = *count;
+
+        log::trace!("{:?}: {}", kind, detail);
+
+        let now = Instant::now();
+        match self.last_logged.get(&kind) {
+            Some((at, _)) if now.duration_since(*at) < LOG_INTERVAL => {}
+            Some((_, logged_count)) => {
+                let suppressed = count - logged_count - 1;
+                log::error!("{:?}: {} ({} similar errors suppressed)", kind, detail, suppressed);
+                self.last_logged.insert(kind, (now, count));
+            }
+            None => {
+                log::error!("{:?}: {}", kind, detail);
+                self.last_logged.insert(kind, (now, count));
+            }
+        }
+    }
+
+    pub fn count(&self, kind: ProtocolErrorKind) -> u64 {
+        self.counts.get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn counts(&self) -> &BTreeMap<ProtocolErrorKind, u64> {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
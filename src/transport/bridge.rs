@@ -0,0 +1,27 @@
+use log::{info, warn};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use crate::dfu::DfuConfig;
+use crate::error::Result;
+use super::connect::connect;
+
+/// Exposes the device at `config.uri` (usually a local serial port) over
+/// TCP, relaying raw LPL frames both ways, so a host elsewhere can update it
+/// through `tcp://this-host:port`. The port runs at the link speed; one
+/// client is served at a time and the device stays open between them.
+pub async fn bridge(config: &DfuConfig, addr: impl ToSocketAddrs) -> Result<()> {
+    let mut device = connect(config).await?;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Relaying {} on {}", config.uri, listener.local_addr()?);
+    loop {
+        let (mut client, peer) = listener.accept().await?;
+        client.set_nodelay(true)?;
+        info!("Bridge client {} connected", peer);
+        match copy_bidirectional(&mut client, &mut device).await {
+            Ok((up, down)) => info!("Bridge client {} left ({} bytes up, {} down)", peer, up, down),
+            // Either side may have gone; the next client finds out about the device
+            Err(e) => warn!("Bridge client {}: {}", peer, e),
+        }
+    }
+}
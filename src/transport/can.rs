@@ -0,0 +1,316 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use log::{debug, info, warn};
+use socketcan::tokio::CanSocket;
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId, Id, StandardId};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+/// Longest message an ISO-TP first frame can announce
+const MAX_MESSAGE: usize = 0xFFF;
+/// How long a sender waits for the receiver's flow control frame
+const FLOW_CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Closes every LPL frame
+const FRAME_DELIMITER: u8 = 0x00;
+
+const SINGLE_FRAME: u8 = 0x00;
+const FIRST_FRAME: u8 = 0x10;
+const CONSECUTIVE_FRAME: u8 = 0x20;
+const FLOW_CONTROL: u8 = 0x30;
+
+const FLOW_CONTINUE: u8 = 0;
+const FLOW_WAIT: u8 = 1;
+const FLOW_OVERFLOW: u8 = 2;
+
+/// LPL frames carried over a Linux socketcan interface
+/// (`can://can0?rx_id=0x7E8&tx_id=0x7E0`).
+///
+/// Each LPL frame is one ISO-TP message: up to 7 bytes fit a single CAN
+/// frame, longer ones are split into a first frame and consecutive frames
+/// paced by the receiver's flow control. Identifiers above 0x7FF are sent
+/// as extended (29-bit) identifiers.
+pub struct CanStream {
+    /// Bytes written but not yet handed over as a complete frame
+    tx: Vec<u8>,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<io::Result<Vec<u8>>>,
+    rx: BytesMut,
+}
+
+impl CanStream {
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix("can://")
+            .ok_or_else(|| Error::Connection(format!("Not a can:// URI: {}", uri)))?;
+        let (interface, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut rx_id = None;
+        let mut tx_id = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "rx_id" => rx_id = Some(parse_id(value)?),
+                "tx_id" => tx_id = Some(parse_id(value)?),
+                _ => return Err(Error::Connection(format!("Unknown CAN option: {}", key))),
+            }
+        }
+        let rx_id = rx_id.ok_or_else(|| Error::Connection(format!("{}: rx_id is required", uri)))?;
+        let tx_id = tx_id.ok_or_else(|| Error::Connection(format!("{}: tx_id is required", uri)))?;
+
+        let socket = CanSocket::open(interface)
+            .map_err(|e| Error::Connection(format!("{}: {}", interface, e)))?;
+        info!("CAN link on {} open (tx {:?}, rx {:?})", interface, tx_id, rx_id);
+
+        let (outgoing, requests) = mpsc::unbounded_channel();
+        let (responses, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(run_link(IsoTp { socket, tx_id, rx_id }, requests, responses));
+
+        Ok(Self { tx: Vec::new(), outgoing, incoming, rx: BytesMut::new() })
+    }
+}
+
+impl AsyncRead for CanStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.rx.is_empty() {
+            match this.incoming.poll_recv(cx) {
+                Poll::Ready(Some(Ok(message))) => this.rx.extend_from_slice(&message),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                // Link task gone: end of stream
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for CanStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        this.tx.extend_from_slice(buf);
+        while let Some(end) = this.tx.iter().position(|b| *b == FRAME_DELIMITER) {
+            let frame: Vec<u8> = this.tx.drain(..=end).collect();
+            if frame.len() > MAX_MESSAGE {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} byte frame exceeds the ISO-TP limit", frame.len()),
+                )));
+            }
+            this.outgoing
+                .send(frame)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "CAN link closed"))?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// `0x7E8` or `2024`; values above 0x7FF become extended identifiers
+fn parse_id(value: &str) -> Result<Id> {
+    let raw = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| Error::Connection(format!("Invalid CAN id: {}", value)))?;
+
+    let id = match StandardId::new(raw as u16).filter(|_| raw <= 0x7FF) {
+        Some(id) => Id::Standard(id),
+        None => Id::Extended(
+            ExtendedId::new(raw).ok_or_else(|| Error::Connection(format!("CAN id out of range: {}", value)))?,
+        ),
+    };
+    Ok(id)
+}
+
+/// Message being reassembled from consecutive frames
+struct Reassembly {
+    data: Vec<u8>,
+    len: usize,
+    next_seq: u8,
+}
+
+struct IsoTp {
+    socket: CanSocket,
+    tx_id: Id,
+    rx_id: Id,
+}
+
+impl IsoTp {
+    async fn write(&self, data: &[u8]) -> io::Result<()> {
+        let frame = CanFrame::new(self.tx_id, data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid CAN frame"))?;
+        self.socket.write_frame(frame).await
+    }
+
+    /// Next data frame from the device, other traffic on the bus is ignored
+    async fn read(&self) -> io::Result<Vec<u8>> {
+        loop {
+            let frame = self.socket.read_frame().await?;
+            if matches!(frame, CanFrame::Data(_)) && frame.id() == self.rx_id && !frame.data().is_empty() {
+                return Ok(frame.data().to_vec());
+            }
+        }
+    }
+
+    async fn send(&self, message: &[u8]) -> io::Result<()> {
+        if message.len() <= 7 {
+            let mut frame = vec![SINGLE_FRAME | message.len() as u8];
+            frame.extend_from_slice(message);
+            return self.write(&frame).await;
+        }
+
+        let mut first = vec![FIRST_FRAME | (message.len() >> 8) as u8, message.len() as u8];
+        first.extend_from_slice(&message[..6]);
+        self.write(&first).await?;
+
+        let mut seq = 1u8;
+        let mut chunks = message[6..].chunks(7).peekable();
+        while chunks.peek().is_some() {
+            let (block_size, separation) = self.flow_control().await?;
+            let mut sent = 0;
+            while let Some(chunk) = chunks.next() {
+                let mut frame = vec![CONSECUTIVE_FRAME | (seq & 0x0F)];
+                frame.extend_from_slice(chunk);
+                self.write(&frame).await?;
+                seq = seq.wrapping_add(1);
+                sent += 1;
+                if block_size != 0 && sent == block_size {
+                    break;
+                }
+                if chunks.peek().is_some() && !separation.is_zero() {
+                    sleep(separation).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for clearance to send; returns the block size and separation time
+    async fn flow_control(&self) -> io::Result<(u8, Duration)> {
+        loop {
+            let frame = timeout(FLOW_CONTROL_TIMEOUT, self.read())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No ISO-TP flow control"))??;
+            if frame[0] & 0xF0 != FLOW_CONTROL || frame.len() < 3 {
+                debug!("Ignoring CAN frame while waiting for flow control");
+                continue;
+            }
+            match frame[0] & 0x0F {
+                FLOW_CONTINUE => return Ok((frame[1], separation_time(frame[2]))),
+                FLOW_WAIT => continue,
+                FLOW_OVERFLOW => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Receiver buffer overflow"))
+                }
+                status => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid flow status {}", status),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Feeds one received frame; returns a message once it is complete
+    async fn receive(&self, frame: &[u8], pending: &mut Option<Reassembly>) -> io::Result<Option<Vec<u8>>> {
+        match frame[0] & 0xF0 {
+            SINGLE_FRAME => {
+                let len = (frame[0] & 0x0F) as usize;
+                Ok(frame.get(1..1 + len).map(<[u8]>::to_vec))
+            }
+            FIRST_FRAME if frame.len() >= 2 => {
+                let len = (((frame[0] & 0x0F) as usize) << 8) | frame[1] as usize;
+                let mut data = Vec::with_capacity(len);
+                data.extend_from_slice(&frame[2..]);
+                *pending = Some(Reassembly { data, len, next_seq: 1 });
+                // Clear to send everything, back to back
+                self.write(&[FLOW_CONTROL | FLOW_CONTINUE, 0, 0]).await?;
+                Ok(None)
+            }
+            CONSECUTIVE_FRAME => {
+                let Some(message) = pending.as_mut() else {
+                    return Ok(None);
+                };
+                if frame[0] & 0x0F != message.next_seq {
+                    warn!("ISO-TP sequence error, dropping the message");
+                    *pending = None;
+                    return Ok(None);
+                }
+                message.next_seq = (message.next_seq + 1) & 0x0F;
+                message.data.extend_from_slice(&frame[1..]);
+                if message.data.len() < message.len {
+                    return Ok(None);
+                }
+                // The last frame may carry padding
+                let len = message.len;
+                let mut data = pending.take().map(|message| message.data).unwrap_or_default();
+                data.truncate(len);
+                Ok(Some(data))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Owns the socket; sends queued frames and hands back reassembled ones
+async fn run_link(
+    link: IsoTp,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+    responses: mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+) {
+    let mut pending = None;
+    loop {
+        let result = tokio::select! {
+            request = requests.recv() => match request {
+                Some(message) => link.send(&message).await.map(|_| None),
+                None => break,
+            },
+            frame = link.read() => match frame {
+                Ok(frame) => link.receive(&frame, &mut pending).await,
+                Err(e) => Err(e),
+            },
+        };
+        let outcome = match result {
+            Ok(Some(message)) => responses.send(Ok(message)),
+            Ok(None) => Ok(()),
+            Err(e) => responses.send(Err(e)),
+        };
+        if outcome.is_err() {
+            break;
+        }
+    }
+}
+
+/// STmin: milliseconds up to 0x7F, 100-900 µs for 0xF1-0xF9
+fn separation_time(value: u8) -> Duration {
+    match value {
+        0x00..=0x7F => Duration::from_millis(value as u64),
+        0xF1..=0xF9 => Duration::from_micros((value - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+impl DfuTransport for CanStream {}
@@ -0,0 +1,227 @@
+// Without any transport feature the delegating bodies never touch their arguments
+#![cfg_attr(not(any(
+    feature = "serial", feature = "tcp", feature = "rfc2217", feature = "ssh", feature = "udp",
+    all(unix, feature = "unix-socket"), feature = "can", feature = "usb", feature = "i2c", feature = "spi",
+    feature = "quic", feature = "mqtt",
+)), allow(unused_variables))]
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "tcp")]
+use tokio::net::TcpStream;
+#[cfg(all(unix, feature = "unix-socket"))]
+use tokio::net::UnixStream;
+#[cfg(feature = "serial")]
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+#[cfg(feature = "serial")]
+use crate::dfu::serial_path;
+use crate::dfu::{Baud, DfuConfig};
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+#[cfg(feature = "can")]
+use super::can::CanStream;
+#[cfg(feature = "i2c")]
+use super::i2c::I2cStream;
+#[cfg(feature = "mqtt")]
+use super::mqtt::MqttStream;
+#[cfg(feature = "quic")]
+use super::quic::{QuicOptions, QuicStream};
+#[cfg(feature = "rfc2217")]
+use super::rfc2217::Rfc2217Stream;
+#[cfg(feature = "spi")]
+use super::spi::SpiStream;
+#[cfg(feature = "ssh")]
+use super::ssh::SshStream;
+#[cfg(feature = "udp")]
+use super::udp::UdpStream;
+#[cfg(feature = "usb")]
+use super::usb::UsbStream;
+
+/// Device stream opened from a URI by [`connect`]
+pub enum Transport {
+    #[cfg(feature = "serial")]
+    Serial(SerialStream),
+    #[cfg(feature = "tcp")]
+    Tcp(TcpStream),
+    #[cfg(feature = "rfc2217")]
+    Telnet(Rfc2217Stream),
+    #[cfg(feature = "ssh")]
+    Ssh(SshStream),
+    #[cfg(feature = "udp")]
+    Udp(UdpStream),
+    #[cfg(all(unix, feature = "unix-socket"))]
+    Unix(UnixStream),
+    #[cfg(feature = "can")]
+    Can(CanStream),
+    #[cfg(feature = "usb")]
+    Usb(UsbStream),
+    #[cfg(feature = "i2c")]
+    I2c(I2cStream),
+    #[cfg(feature = "spi")]
+    Spi(SpiStream),
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttStream),
+}
+
+/// Forwards a call to whichever stream the transport wraps. Matching on the
+/// place rather than the reference keeps the match exhaustive when no
+/// transport feature is enabled and the enum has no variants.
+macro_rules! delegate {
+    ($target:expr, $stream:ident => $call:expr) => {
+        match *$target {
+            #[cfg(feature = "serial")]
+            Transport::Serial(ref mut $stream) => $call,
+            #[cfg(feature = "tcp")]
+            Transport::Tcp(ref mut $stream) => $call,
+            #[cfg(feature = "rfc2217")]
+            Transport::Telnet(ref mut $stream) => $call,
+            #[cfg(feature = "ssh")]
+            Transport::Ssh(ref mut $stream) => $call,
+            #[cfg(feature = "udp")]
+            Transport::Udp(ref mut $stream) => $call,
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Transport::Unix(ref mut $stream) => $call,
+            #[cfg(feature = "can")]
+            Transport::Can(ref mut $stream) => $call,
+            #[cfg(feature = "usb")]
+            Transport::Usb(ref mut $stream) => $call,
+            #[cfg(feature = "i2c")]
+            Transport::I2c(ref mut $stream) => $call,
+            #[cfg(feature = "spi")]
+            Transport::Spi(ref mut $stream) => $call,
+            #[cfg(feature = "quic")]
+            Transport::Quic(ref mut $stream) => $call,
+            #[cfg(feature = "mqtt")]
+            Transport::Mqtt(ref mut $stream) => $call,
+        }
+    };
+}
+
+impl DfuTransport for Transport {
+    async fn set_baud(&mut self, baud: Baud) -> Result<()> {
+        delegate!(self, stream => stream.set_baud(baud).await)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        delegate!(self, stream => DfuTransport::flush(stream).await)
+    }
+
+    async fn reset_lines(&mut self, dtr: Option<bool>, rts: Option<bool>) -> Result<()> {
+        delegate!(self, stream => stream.reset_lines(dtr, rts).await)
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        delegate!(self.get_mut(), stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        delegate!(self.get_mut(), stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self.get_mut(), stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self.get_mut(), stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+/// Opens the transport named by `config.uri`; serial ports and SSH bridges
+/// start at the link speed
+pub async fn connect(config: &DfuConfig) -> Result<Transport> {
+    let uri = config.uri.as_str();
+    // Only the path-style schemes open the remainder directly
+    #[cfg_attr(
+        not(any(feature = "serial", feature = "tcp", all(unix, feature = "unix-socket"))),
+        allow(unused_variables)
+    )]
+    let (scheme, rest) = uri
+        .split_once("://")
+        .ok_or_else(|| Error::Connection(format!("Not a URI: {}", uri)))?;
+
+    info!("Connecting to {}", uri);
+    match scheme {
+        #[cfg(feature = "serial")]
+        "serial" => {
+            let path = serial_path(uri).unwrap_or(rest);
+            let stream = tokio_serial::new(path, config.lnk_speed.bps())
+                .open_native_async()
+                .map_err(|e| Error::Connection(format!("{}: {}", path, e)))?;
+            Ok(Transport::Serial(stream))
+        }
+        #[cfg(feature = "tcp")]
+        "tcp" => {
+            let stream = TcpStream::connect(rest).await?;
+            // Requests are small and latency-bound
+            stream.set_nodelay(true)?;
+            Ok(Transport::Tcp(stream))
+        }
+        #[cfg(feature = "rfc2217")]
+        "telnet" => Ok(Transport::Telnet(Rfc2217Stream::connect(uri, config.lnk_speed.bps()).await?)),
+        #[cfg(feature = "ssh")]
+        "ssh" => Ok(Transport::Ssh(SshStream::connect(uri, config.lnk_speed.bps()).await?)),
+        #[cfg(feature = "udp")]
+        "udp" => Ok(Transport::Udp(UdpStream::connect(uri).await?)),
+        // unix:///run/dfu.sock names an absolute path
+        #[cfg(all(unix, feature = "unix-socket"))]
+        "unix" => {
+            let stream = UnixStream::connect(rest)
+                .await
+                .map_err(|e| Error::Connection(format!("{}: {}", rest, e)))?;
+            Ok(Transport::Unix(stream))
+        }
+        #[cfg(feature = "can")]
+        "can" => Ok(Transport::Can(CanStream::connect(uri).await?)),
+        #[cfg(feature = "usb")]
+        "usb" => Ok(Transport::Usb(UsbStream::connect(uri)?)),
+        #[cfg(feature = "i2c")]
+        "i2c" => Ok(Transport::I2c(I2cStream::connect(uri)?)),
+        #[cfg(feature = "spi")]
+        "spi" => Ok(Transport::Spi(SpiStream::connect(uri)?)),
+        #[cfg(feature = "quic")]
+        "quic" => Ok(Transport::Quic(QuicStream::connect(uri, &QuicOptions::default()).await?)),
+        #[cfg(feature = "mqtt")]
+        "mqtt" => Ok(Transport::Mqtt(MqttStream::connect(uri).await?)),
+        _ => match scheme_feature(scheme) {
+            Some(feature) => Err(Error::Configuration(format!(
+                "{}:// URIs need the `{}` feature", scheme, feature
+            ))),
+            None => Err(Error::Connection(format!("Unsupported URI scheme: {}", scheme))),
+        },
+    }
+}
+
+/// Cargo feature providing each known scheme
+fn scheme_feature(scheme: &str) -> Option<&'static str> {
+    match scheme {
+        "serial" => Some("serial"),
+        "tcp" => Some("tcp"),
+        "telnet" => Some("rfc2217"),
+        "ssh" => Some("ssh"),
+        "udp" => Some("udp"),
+        "unix" => Some("unix-socket"),
+        "can" => Some("can"),
+        "usb" => Some("usb"),
+        "i2c" => Some("i2c"),
+        "spi" => Some("spi"),
+        "quic" => Some("quic"),
+        "mqtt" => Some("mqtt"),
+        _ => None,
+    }
+}
+
+/// Like [`connect`], with the default speeds
+pub async fn open_from_uri(uri: &str) -> Result<Transport> {
+    connect(&DfuConfig::new().with_uri(uri)).await
+}
@@ -0,0 +1,61 @@
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(feature = "serial")]
+use tokio_serial::{SerialPort, SerialStream};
+
+use crate::dfu::Baud;
+use crate::error::{Error, Result};
+
+/// Byte stream to a device plus the line control a bootloader session
+/// needs. Links without a line speed keep the defaults; implement this for
+/// a stream of your own with an empty `impl` block if that holds for it.
+pub trait DfuTransport: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Changes the line speed; links without one ignore it
+    fn set_baud(&mut self, _baud: Baud) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Waits until everything written has left for the device
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            AsyncWriteExt::flush(self).await?;
+            Ok(())
+        }
+    }
+
+    /// Drives DTR and RTS; `None` leaves a line as it is
+    fn reset_lines(&mut self, _dtr: Option<bool>, _rts: Option<bool>) -> impl Future<Output = Result<()>> + Send {
+        async { Err(Error::Configuration("Transport has no DTR/RTS lines".into())) }
+    }
+}
+
+#[cfg(feature = "serial")]
+impl DfuTransport for SerialStream {
+    async fn set_baud(&mut self, baud: Baud) -> Result<()> {
+        self.set_baud_rate(baud.bps())
+            .map_err(|e| Error::Connection(format!("Cannot set {}: {}", baud, e)))
+    }
+
+    async fn reset_lines(&mut self, dtr: Option<bool>, rts: Option<bool>) -> Result<()> {
+        if let Some(level) = dtr {
+            self.write_data_terminal_ready(level)
+                .map_err(|e| Error::Connection(format!("Cannot set DTR: {}", e)))?;
+        }
+        if let Some(level) = rts {
+            self.write_request_to_send(level)
+                .map_err(|e| Error::Connection(format!("Cannot set RTS: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+impl DfuTransport for TcpStream {}
+
+#[cfg(unix)]
+impl DfuTransport for UnixStream {}
+
+/// In-memory pipe, for simulated devices
+impl DfuTransport for DuplexStream {}
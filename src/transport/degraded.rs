@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+use crate::dfu::Baud;
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+const READ_CHUNK: usize = 1024;
+const DEFAULT_SEED: u64 = 0x5EED_D00D_CAFE_F00D;
+
+/// How a simulated link mistreats the bytes crossing it, in each direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConditions {
+    /// One-way delay added to every write and every read
+    pub latency: Duration,
+    /// Throughput limit; unlimited when unset
+    pub bytes_per_sec: Option<u32>,
+    /// Chance of each bit arriving flipped
+    pub bit_error_rate: f64,
+    /// Chance of each byte being lost
+    pub drop_rate: f64,
+}
+
+impl LinkConditions {
+    /// Passes bytes through untouched, as a baseline
+    pub const fn clean() -> Self {
+        Self { latency: Duration::ZERO, bytes_per_sec: None, bit_error_rate: 0.0, drop_rate: 0.0 }
+    }
+
+    /// Long RS-485 run at 115200 baud next to motors and relays
+    pub const fn noisy_rs485() -> Self {
+        Self {
+            latency: Duration::from_millis(2),
+            bytes_per_sec: Some(11_520),
+            bit_error_rate: 1e-5,
+            drop_rate: 1e-4,
+        }
+    }
+
+    /// Sub-GHz radio modem at the edge of its range
+    pub const fn lossy_radio() -> Self {
+        Self {
+            latency: Duration::from_millis(30),
+            bytes_per_sec: Some(2_400),
+            bit_error_rate: 1e-4,
+            drop_rate: 2e-3,
+        }
+    }
+
+    /// Geostationary satellite backhaul: clean but slow to answer
+    pub const fn satellite() -> Self {
+        Self {
+            latency: Duration::from_millis(300),
+            bytes_per_sec: Some(16_000),
+            bit_error_rate: 1e-7,
+            drop_rate: 0.0,
+        }
+    }
+
+    /// Time `len` bytes spend on the wire
+    fn delay(&self, len: usize) -> Duration {
+        let transfer = match self.bytes_per_sec {
+            Some(rate) => Duration::from_secs_f64(len as f64 / rate.max(1) as f64),
+            None => Duration::ZERO,
+        };
+        self.latency + transfer
+    }
+}
+
+impl FromStr for LinkConditions {
+    type Err = Error;
+
+    /// `clean`, `noisy-rs485`, `lossy-radio` or `satellite`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clean" => Ok(Self::clean()),
+            "noisy-rs485" => Ok(Self::noisy_rs485()),
+            "lossy-radio" => Ok(Self::lossy_radio()),
+            "satellite" => Ok(Self::satellite()),
+            _ => Err(Error::Configuration(format!("Unknown link conditions: {}", s))),
+        }
+    }
+}
+
+/// Wraps a stream, typically a [`tokio::io::DuplexStream`] to a simulated
+/// device, in [`LinkConditions`]. Errors come from a seeded generator, so a
+/// run is repeatable.
+pub struct DegradedLink<S> {
+    inner: S,
+    conditions: LinkConditions,
+    rng: u64,
+    /// Degraded bytes from the device, held back until `read_delay` elapses
+    rx: BytesMut,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    /// Degraded bytes on their way to the device
+    tx: BytesMut,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> DegradedLink<S> {
+    pub fn new(inner: S, conditions: LinkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            rng: DEFAULT_SEED,
+            rx: BytesMut::new(),
+            read_delay: None,
+            tx: BytesMut::new(),
+            write_delay: None,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        // xorshift never leaves zero
+        self.rng = seed.max(1);
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// xorshift64*, uniform in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn degrade(&mut self, data: &[u8], out: &mut BytesMut) {
+        let LinkConditions { bit_error_rate, drop_rate, .. } = self.conditions;
+        for &byte in data {
+            if drop_rate > 0.0 && self.random() < drop_rate {
+                continue;
+            }
+            let mut byte = byte;
+            if bit_error_rate > 0.0 {
+                for bit in 0..8 {
+                    if self.random() < bit_error_rate {
+                        byte ^= 1 << bit;
+                    }
+                }
+            }
+            out.extend_from_slice(&[byte]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DegradedLink<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.tx.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.tx))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.tx.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DegradedLink<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.rx.is_empty() {
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let mut data = BytesMut::new();
+            this.degrade(chunk.filled(), &mut data);
+            if !data.is_empty() {
+                this.read_delay = Some(Box::pin(sleep(this.conditions.delay(data.len()))));
+                this.rx = data;
+            }
+        }
+        if let Some(delay) = this.read_delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.read_delay = None;
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DegradedLink<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_write_pending(cx))?;
+        let delay = this.conditions.delay(buf.len());
+        let sleeping = this.write_delay.get_or_insert_with(|| Box::pin(sleep(delay)));
+        ready!(sleeping.as_mut().poll(cx));
+        this.write_delay = None;
+
+        let mut data = BytesMut::new();
+        this.degrade(buf, &mut data);
+        this.tx = data;
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: DfuTransport> DfuTransport for DegradedLink<S> {
+    async fn set_baud(&mut self, baud: Baud) -> Result<()> {
+        self.inner.set_baud(baud).await
+    }
+
+    async fn reset_lines(&mut self, dtr: Option<bool>, rts: Option<bool>) -> Result<()> {
+        self.inner.reset_lines(dtr, rts).await
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::LinuxI2CDevice;
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+use super::polled::{parse_number, PollSettings, PolledBus, PolledStream};
+
+/// SMBus block transfers carry at most 32 bytes
+const DEFAULT_CHUNK: usize = 32;
+const DEFAULT_POLL: Duration = Duration::from_millis(2);
+/// How long a target may stretch the clock or NAK while busy
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Co-processor on a Linux I2C bus
+/// (`i2c:///dev/i2c-1?addr=0x42&timeout_ms=500&chunk=32&poll_ms=2`).
+///
+/// I2C is host-clocked, so the device is polled for its answers; it sends
+/// 0xFF while it has nothing queued. A target busy writing flash may NAK or
+/// stretch the clock, which is retried until `timeout_ms` runs out.
+pub struct I2cStream {
+    link: PolledStream,
+}
+
+impl I2cStream {
+    pub fn connect(uri: &str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix("i2c://")
+            .ok_or_else(|| Error::Connection(format!("Not an i2c:// URI: {}", uri)))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut address = None;
+        let mut busy_timeout = DEFAULT_BUSY_TIMEOUT;
+        let mut settings = PollSettings { chunk: DEFAULT_CHUNK, interval: DEFAULT_POLL };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let number = parse_number(value)
+                .ok_or_else(|| Error::Connection(format!("Invalid I2C option value: {}", value)))?;
+            match key {
+                "addr" if number <= 0x7F => address = Some(number as u16),
+                "timeout_ms" => busy_timeout = Duration::from_millis(number),
+                "chunk" if number > 0 => settings.chunk = number as usize,
+                "poll_ms" => settings.interval = Duration::from_millis(number),
+                _ => return Err(Error::Connection(format!("Invalid I2C option: {}", pair))),
+            }
+        }
+        let address = address.ok_or_else(|| Error::Connection(format!("{}: addr is required", uri)))?;
+
+        let device = LinuxI2CDevice::new(path, address)
+            .map_err(|e| Error::Connection(format!("{}: {}", path, e)))?;
+        info!("I2C target {:#04x} on {} open", address, path);
+
+        let bus = I2cBus { device, busy_timeout };
+        Ok(Self { link: PolledStream::spawn(bus, settings) })
+    }
+}
+
+struct I2cBus {
+    device: LinuxI2CDevice,
+    busy_timeout: Duration,
+}
+
+impl I2cBus {
+    /// Retries a transfer while the target NAKs or stretches the clock past
+    /// the adapter's own timeout
+    fn retry(&mut self, mut transfer: impl FnMut(&mut LinuxI2CDevice) -> io::Result<()>) -> io::Result<()> {
+        let deadline = Instant::now() + self.busy_timeout;
+        loop {
+            match transfer(&mut self.device) {
+                Err(e) if is_busy(&e) && Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                result => return result,
+            }
+        }
+    }
+}
+
+impl PolledBus for I2cBus {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.retry(|device| device.write(data).map_err(io::Error::from))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.retry(|device| device.read(buf).map_err(io::Error::from))
+    }
+}
+
+/// NAK (ENXIO, EREMOTEIO), arbitration loss or timeout: the target is busy
+fn is_busy(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENXIO | libc::EREMOTEIO | libc::EAGAIN | libc::ETIMEDOUT)
+    )
+}
+
+impl AsyncRead for I2cStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for I2cStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.link).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for I2cStream {}
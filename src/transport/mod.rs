@@ -0,0 +1,52 @@
+//! Byte streams to devices beyond plain serial ports and TCP sockets
+
+#[cfg(feature = "tcp")]
+mod bridge;
+#[cfg(feature = "can")]
+mod can;
+mod connect;
+mod control;
+mod degraded;
+#[cfg(feature = "i2c")]
+mod i2c;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(any(feature = "i2c", feature = "spi"))]
+mod polled;
+#[cfg(feature = "quic")]
+mod quic;
+#[cfg(feature = "rfc2217")]
+mod rfc2217;
+#[cfg(feature = "spi")]
+mod spi;
+#[cfg(feature = "ssh")]
+mod ssh;
+#[cfg(feature = "udp")]
+mod udp;
+#[cfg(feature = "usb")]
+mod usb;
+
+#[cfg(feature = "tcp")]
+pub use bridge::*;
+#[cfg(feature = "can")]
+pub use can::*;
+pub use connect::*;
+pub use control::*;
+pub use degraded::*;
+#[cfg(feature = "i2c")]
+pub use i2c::*;
+#[cfg(feature = "mqtt")]
+pub use mqtt::*;
+
+#[cfg(feature = "quic")]
+pub use quic::*;
+#[cfg(feature = "rfc2217")]
+pub use rfc2217::*;
+#[cfg(feature = "spi")]
+pub use spi::*;
+#[cfg(feature = "ssh")]
+pub use ssh::*;
+#[cfg(feature = "udp")]
+pub use udp::*;
+#[cfg(feature = "usb")]
+pub use usb::*;
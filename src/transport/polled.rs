@@ -0,0 +1,152 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+/// Clocked in from a device with nothing queued, and clocked out to poll it
+pub(super) const IDLE_BYTE: u8 = 0xFF;
+/// Opens every LPL frame
+const SYN: u8 = 0x55;
+/// Closes every LPL frame
+const FRAME_DELIMITER: u8 = 0x00;
+
+/// A bus the host clocks, where the device can only answer when read
+pub(super) trait PolledBus: Send + 'static {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Clocks exactly `buf.len()` bytes in from the device
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PollSettings {
+    /// Largest single transfer, in both directions
+    pub chunk: usize,
+    /// Pause after a read that brought nothing
+    pub interval: Duration,
+}
+
+/// Drops the idle bytes a polled device sends between frames
+#[derive(Debug, Default)]
+struct IdleFilter {
+    in_frame: bool,
+}
+
+impl IdleFilter {
+    fn strip(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.in_frame {
+                self.in_frame = byte != FRAME_DELIMITER;
+            } else if byte == IDLE_BYTE {
+                continue;
+            } else {
+                self.in_frame = byte == SYN;
+            }
+            out.push(byte);
+        }
+        out
+    }
+}
+
+/// Byte stream over a [`PolledBus`], driven by a thread of its own since
+/// bus drivers block
+pub(super) struct PolledStream {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<io::Result<Vec<u8>>>,
+    rx: BytesMut,
+}
+
+impl PolledStream {
+    pub fn spawn(bus: impl PolledBus, settings: PollSettings) -> Self {
+        let (outgoing, requests) = mpsc::unbounded_channel();
+        let (responses, incoming) = mpsc::unbounded_channel();
+        thread::spawn(move || run_bus(bus, settings, requests, responses));
+        Self { outgoing, incoming, rx: BytesMut::new() }
+    }
+}
+
+impl AsyncRead for PolledStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.rx.is_empty() {
+            match this.incoming.poll_recv(cx) {
+                Poll::Ready(Some(Ok(data))) => this.rx.extend_from_slice(&data),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                // Bus thread gone: end of stream
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PolledStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.outgoing
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Bus thread stopped"))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writes whatever is queued, otherwise polls the device; stops on the
+/// first bus error or once the stream is dropped
+fn run_bus(
+    mut bus: impl PolledBus,
+    settings: PollSettings,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+    responses: mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+) {
+    let mut filter = IdleFilter::default();
+    let mut buf = vec![IDLE_BYTE; settings.chunk];
+    loop {
+        let result = match requests.try_recv() {
+            Ok(data) => data.chunks(settings.chunk).try_for_each(|chunk| bus.write(chunk)).map(|_| None),
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => bus.read(&mut buf).map(|_| Some(filter.strip(&buf))),
+        };
+        match result {
+            Ok(Some(data)) if data.is_empty() => thread::sleep(settings.interval),
+            Ok(Some(data)) => {
+                if responses.send(Ok(data)).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = responses.send(Err(e));
+                break;
+            }
+        }
+    }
+}
+
+/// `0x42` or `66`
+pub(super) fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,156 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use log::info;
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::RootCertStore;
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// TLS and liveness settings for [`QuicStream::connect`]
+#[derive(Debug, Clone)]
+pub struct QuicOptions {
+    /// Name checked against the server certificate; defaults to the URI host
+    pub server_name: Option<String>,
+    /// DER-encoded CA certificate; the platform trust store is used otherwise
+    pub ca_cert: Option<PathBuf>,
+    /// Ping interval keeping NAT bindings on cellular links open
+    pub keep_alive: Duration,
+    /// Give up on a link silent for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for QuicOptions {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            ca_cert: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl QuicOptions {
+    pub fn with_server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    pub fn with_ca_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let mut config = match &self.ca_cert {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(CertificateDer::from(std::fs::read(path)?))
+                    .map_err(|e| Error::Connection(format!("{}: {}", path.display(), e)))?;
+                ClientConfig::with_root_certificates(Arc::new(roots))
+                    .map_err(|e| Error::Connection(e.to_string()))?
+            }
+            None => ClientConfig::try_with_platform_verifier()
+                .map_err(|e| Error::Connection(e.to_string()))?,
+        };
+
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(self.keep_alive));
+        transport.max_idle_timeout(Some(
+            self.idle_timeout
+                .try_into()
+                .map_err(|_| Error::Connection("QUIC idle timeout out of range".into()))?,
+        ));
+        config.transport_config(Arc::new(transport));
+        Ok(config)
+    }
+}
+
+/// One bidirectional QUIC stream to a device gateway (`quic://host:port`).
+///
+/// QUIC retransmits, paces and encrypts over a single UDP port, which suits
+/// field devices on lossy cellular links better than raw TCP.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    connection: Connection,
+    _endpoint: Endpoint,
+}
+
+impl QuicStream {
+    pub async fn connect(uri: &str, options: &QuicOptions) -> Result<Self> {
+        let authority = uri
+            .strip_prefix("quic://")
+            .ok_or_else(|| Error::Connection(format!("Not a quic:// URI: {}", uri)))?;
+        let address = tokio::net::lookup_host(authority)
+            .await?
+            .next()
+            .ok_or_else(|| Error::Connection(format!("Cannot resolve {}", authority)))?;
+        let host = authority.rsplit_once(':').map_or(authority, |(host, _)| host);
+        let server_name = options.server_name.as_deref().unwrap_or(host);
+
+        let bind: SocketAddr = if address.is_ipv6() {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(options.client_config()?);
+
+        let connection = endpoint
+            .connect(address, server_name)
+            .map_err(|e| Error::Connection(e.to_string()))?
+            .await
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let (send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|e| Error::Connection(e.to_string()))?;
+
+        info!("QUIC connection to {} established", address);
+        Ok(Self { send, recv, connection, _endpoint: endpoint })
+    }
+
+    /// Current round-trip estimate, useful for sizing response timeouts
+    pub fn rtt(&self) -> Duration {
+        self.connection.rtt()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for QuicStream {}
@@ -0,0 +1,302 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+
+use crate::dfu::Baud;
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+/// How long the server gets to confirm a line setting
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_CHUNK: usize = 1024;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+/// Added to a subcommand in the server's answer
+const SERVER_OFFSET: u8 = 100;
+
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_ONE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Telnet {
+    Data,
+    Iac,
+    Negotiate(u8),
+    Sub,
+    SubIac,
+}
+
+/// Serial port shared by a networked serial server speaking RFC 2217
+/// (`telnet://host:port`), e.g. ser2net in `telnet` mode.
+///
+/// Data is escaped per telnet rules and the line is set up through the
+/// COM-PORT-OPTION, so [`set_baud_rate`](Self::set_baud_rate) changes the
+/// speed of the remote port just like it would a local one.
+pub struct Rfc2217Stream {
+    stream: TcpStream,
+    state: Telnet,
+    subnegotiation: Vec<u8>,
+    /// Payload bytes with telnet commands stripped
+    rx: BytesMut,
+    /// Escaped payload and negotiation replies waiting for the socket
+    tx: BytesMut,
+    /// Baud rate the server last confirmed
+    confirmed_baud: Option<u32>,
+    /// Server refused COM-PORT-OPTION; it is a plain telnet server
+    refused: bool,
+}
+
+impl Rfc2217Stream {
+    /// Connects and sets the remote port to `baud`, 8N1
+    pub async fn connect(uri: &str, baud: u32) -> Result<Self> {
+        let authority = uri
+            .strip_prefix("telnet://")
+            .ok_or_else(|| Error::Connection(format!("Not a telnet:// URI: {}", uri)))?;
+        let stream = TcpStream::connect(authority)
+            .await
+            .map_err(|e| Error::Connection(format!("{}: {}", authority, e)))?;
+        stream.set_nodelay(true)?;
+
+        let mut this = Self {
+            stream,
+            state: Telnet::Data,
+            subnegotiation: Vec::new(),
+            rx: BytesMut::new(),
+            tx: BytesMut::new(),
+            confirmed_baud: None,
+            refused: false,
+        };
+        for (command, option) in [
+            (WILL, COM_PORT_OPTION),
+            (WILL, BINARY),
+            (DO, BINARY),
+            (WILL, SUPPRESS_GO_AHEAD),
+            (DO, SUPPRESS_GO_AHEAD),
+        ] {
+            this.tx.extend_from_slice(&[IAC, command, option]);
+        }
+        this.com_port(SET_DATASIZE, &[8]);
+        this.com_port(SET_PARITY, &[PARITY_NONE]);
+        this.com_port(SET_STOPSIZE, &[STOPSIZE_ONE]);
+        this.set_baud_rate(baud).await?;
+        info!("RFC 2217 port at {} open ({} baud)", authority, baud);
+        Ok(this)
+    }
+
+    /// Changes the remote port's speed and waits for the server to confirm it
+    pub async fn set_baud_rate(&mut self, baud: u32) -> Result<()> {
+        if self.refused {
+            return Err(Error::Connection("Server does not support RFC 2217 port control".into()));
+        }
+        self.confirmed_baud = None;
+        self.com_port(SET_BAUDRATE, &baud.to_be_bytes());
+        self.write_pending().await?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut buf = [0u8; READ_CHUNK];
+        while self.confirmed_baud.is_none() && !self.refused {
+            match timeout_at(deadline, self.stream.read(&mut buf)).await {
+                Ok(Ok(0)) => return Err(Error::Connection("RFC 2217 server closed the connection".into())),
+                Ok(Ok(n)) => {
+                    self.feed(&buf[..n]);
+                    self.write_pending().await?;
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    // Some servers apply settings without answering
+                    warn!("RFC 2217 server did not confirm {} baud", baud);
+                    return Ok(());
+                }
+            }
+        }
+        match self.confirmed_baud {
+            Some(confirmed) if confirmed != baud => Err(Error::Connection(format!(
+                "RFC 2217 server set {} baud instead of {}", confirmed, baud
+            ))),
+            Some(_) => Ok(()),
+            None => Err(Error::Connection("Server does not support RFC 2217 port control".into())),
+        }
+    }
+
+    /// Queues a COM-PORT-OPTION subnegotiation
+    fn com_port(&mut self, subcommand: u8, value: &[u8]) {
+        self.tx.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, subcommand]);
+        escape_into(&mut self.tx, value);
+        self.tx.extend_from_slice(&[IAC, SE]);
+    }
+
+    async fn write_pending(&mut self) -> io::Result<()> {
+        self.stream.write_all(&self.tx).await?;
+        self.tx.clear();
+        Ok(())
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.tx.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.tx))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.tx.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Separates payload from telnet commands, answering negotiations
+    fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (Telnet::Data, IAC) => Telnet::Iac,
+                (Telnet::Data, _) => {
+                    self.rx.extend_from_slice(&[byte]);
+                    Telnet::Data
+                }
+                (Telnet::Iac, IAC) => {
+                    self.rx.extend_from_slice(&[IAC]);
+                    Telnet::Data
+                }
+                (Telnet::Iac, WILL | WONT | DO | DONT) => Telnet::Negotiate(byte),
+                (Telnet::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    Telnet::Sub
+                }
+                // NOP, GA and the like carry nothing for us
+                (Telnet::Iac, _) => Telnet::Data,
+                (Telnet::Negotiate(command), option) => {
+                    self.negotiate(command, option);
+                    Telnet::Data
+                }
+                (Telnet::Sub, IAC) => Telnet::SubIac,
+                (Telnet::Sub, _) => {
+                    self.subnegotiation.push(byte);
+                    Telnet::Sub
+                }
+                (Telnet::SubIac, IAC) => {
+                    self.subnegotiation.push(IAC);
+                    Telnet::Sub
+                }
+                (Telnet::SubIac, SE) => {
+                    self.handle_subnegotiation();
+                    Telnet::Data
+                }
+                (Telnet::SubIac, _) => Telnet::Data,
+            };
+        }
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8) {
+        let supported = matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION);
+        match command {
+            // Supported options were offered up front, so agreeing needs no reply
+            DO | WILL if supported => {}
+            DO => self.tx.extend_from_slice(&[IAC, WONT, option]),
+            WILL => self.tx.extend_from_slice(&[IAC, DONT, option]),
+            _ if option == COM_PORT_OPTION => {
+                warn!("Telnet server refused RFC 2217 port control");
+                self.refused = true;
+            }
+            _ => debug!("Telnet server disabled option {}", option),
+        }
+    }
+
+    fn handle_subnegotiation(&mut self) {
+        match self.subnegotiation.as_slice() {
+            [COM_PORT_OPTION, reply, a, b, c, d] if *reply == SET_BAUDRATE + SERVER_OFFSET => {
+                self.confirmed_baud = Some(u32::from_be_bytes([*a, *b, *c, *d]));
+            }
+            // Line and modem state notifications are not needed
+            [COM_PORT_OPTION, ..] => {}
+            other => debug!("Ignoring telnet subnegotiation {:?}", other),
+        }
+    }
+}
+
+/// Doubles IAC bytes so they are not taken for commands
+fn escape_into(out: &mut BytesMut, data: &[u8]) {
+    for chunk in data.split_inclusive(|b| *b == IAC) {
+        out.extend_from_slice(chunk);
+        if chunk.last() == Some(&IAC) {
+            out.extend_from_slice(&[IAC]);
+        }
+    }
+}
+
+impl AsyncRead for Rfc2217Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.rx.is_empty() {
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.feed(chunk.filled());
+            // Negotiation replies go out on the next write if the socket is busy
+            if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Rfc2217Stream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // Earlier data must leave first or escaping could reorder bytes
+        ready!(this.poll_write_pending(cx))?;
+        escape_into(&mut this.tx, buf);
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for Rfc2217Stream {
+    async fn set_baud(&mut self, baud: Baud) -> Result<()> {
+        self.set_baud_rate(baud.bps()).await
+    }
+}
@@ -0,0 +1,156 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use log::info;
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+use super::polled::{parse_number, PollSettings, PolledBus, PolledStream, IDLE_BYTE};
+
+const DEFAULT_SPEED_HZ: u32 = 1_000_000;
+const DEFAULT_CHUNK: usize = 64;
+const DEFAULT_POLL: Duration = Duration::from_millis(2);
+
+/// How the chip select line is driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChipSelect {
+    #[default]
+    ActiveLow,
+    ActiveHigh,
+    /// The target has no CS, or it is driven by other means
+    None,
+}
+
+/// Bootloader on a Linux spidev device
+/// (`spi:///dev/spidev0.0?speed=1000000&mode=0&cs=low&delay_us=10&gap_us=50`).
+///
+/// SPI is host-clocked, so the device is polled for its answers and must
+/// send 0xFF while it has nothing queued, including while it receives.
+/// `delay_us` holds CS after each transfer; `gap_us` spaces transfers out
+/// for targets that need time to refill their shift register.
+pub struct SpiStream {
+    link: PolledStream,
+}
+
+impl SpiStream {
+    pub fn connect(uri: &str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix("spi://")
+            .ok_or_else(|| Error::Connection(format!("Not an spi:// URI: {}", uri)))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut speed = DEFAULT_SPEED_HZ;
+        let mut mode = SpiModeFlags::SPI_MODE_0;
+        let mut chip_select = ChipSelect::default();
+        let mut delay_us = 0;
+        let mut gap = Duration::ZERO;
+        let mut settings = PollSettings { chunk: DEFAULT_CHUNK, interval: DEFAULT_POLL };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key == "cs" {
+                chip_select = match value {
+                    "low" => ChipSelect::ActiveLow,
+                    "high" => ChipSelect::ActiveHigh,
+                    "none" => ChipSelect::None,
+                    _ => return Err(Error::Connection(format!("Invalid SPI chip select: {}", value))),
+                };
+                continue;
+            }
+            let number = parse_number(value)
+                .ok_or_else(|| Error::Connection(format!("Invalid SPI option value: {}", value)))?;
+            match key {
+                "speed" => speed = number as u32,
+                "mode" => {
+                    mode = match number {
+                        0 => SpiModeFlags::SPI_MODE_0,
+                        1 => SpiModeFlags::SPI_MODE_1,
+                        2 => SpiModeFlags::SPI_MODE_2,
+                        3 => SpiModeFlags::SPI_MODE_3,
+                        _ => return Err(Error::Connection(format!("Invalid SPI mode: {}", value))),
+                    }
+                }
+                "delay_us" => delay_us = number as u16,
+                "gap_us" => gap = Duration::from_micros(number),
+                "chunk" if number > 0 => settings.chunk = number as usize,
+                "poll_ms" => settings.interval = Duration::from_millis(number),
+                _ => return Err(Error::Connection(format!("Invalid SPI option: {}", pair))),
+            }
+        }
+
+        match chip_select {
+            ChipSelect::ActiveLow => {}
+            ChipSelect::ActiveHigh => mode |= SpiModeFlags::SPI_CS_HIGH,
+            ChipSelect::None => mode |= SpiModeFlags::SPI_NO_CS,
+        }
+        let mut device = Spidev::open(path).map_err(|e| Error::Connection(format!("{}: {}", path, e)))?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(speed)
+            .mode(mode)
+            .build();
+        device
+            .configure(&options)
+            .map_err(|e| Error::Connection(format!("{}: {}", path, e)))?;
+        info!("SPI device {} open ({} Hz, {:?})", path, speed, chip_select);
+
+        let bus = SpiBus { device, delay_us, gap };
+        Ok(Self { link: PolledStream::spawn(bus, settings) })
+    }
+}
+
+struct SpiBus {
+    device: Spidev,
+    delay_us: u16,
+    gap: Duration,
+}
+
+impl SpiBus {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut transfer = SpidevTransfer::read_write(tx, rx);
+        transfer.delay_usecs = self.delay_us;
+        self.device.transfer(&mut transfer)?;
+        if !self.gap.is_zero() {
+            thread::sleep(self.gap);
+        }
+        Ok(())
+    }
+}
+
+impl PolledBus for SpiBus {
+    /// Whatever the target clocks out meanwhile is idle filler and dropped
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut discard = vec![0u8; data.len()];
+        self.transfer(data, &mut discard)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let idle = vec![IDLE_BYTE; buf.len()];
+        self.transfer(&idle, buf)
+    }
+}
+
+impl AsyncRead for SpiStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SpiStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.link).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.link).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for SpiStream {}
@@ -0,0 +1,135 @@
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+/// Remote command bridging stdin/stdout to the device; `{port}` and `{baud}`
+/// are substituted
+pub const DEFAULT_REMOTE_COMMAND: &str = "socat - {port},raw,echo=0,b{baud}";
+
+/// Parsed `ssh://[user@]host[:port]/dev/ttyX` URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshTarget {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Serial device on the gateway
+    pub device: String,
+}
+
+impl SshTarget {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let invalid = || Error::Connection(format!("Invalid ssh:// URI: {}", uri));
+        let rest = uri.strip_prefix("ssh://").ok_or_else(invalid)?;
+        let (authority, device) = rest.find('/').map(|i| rest.split_at(i)).ok_or_else(invalid)?;
+
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid())?)),
+            None => (host_port, None),
+        };
+        if host.is_empty() || device.len() < 2 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            device: device.to_string(),
+        })
+    }
+
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+}
+
+/// Device stream tunnelled through the system `ssh` client to a gateway
+/// that runs a bridge (socat by default) to its local serial port. Uses the
+/// caller's SSH keys and config, so nothing has to be deployed first.
+pub struct SshStream {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl SshStream {
+    pub async fn connect(uri: &str, baud: u32) -> Result<Self> {
+        Self::connect_with(uri, baud, DEFAULT_REMOTE_COMMAND).await
+    }
+
+    /// Like [`Self::connect`], running `remote_command` on the gateway instead of socat
+    pub async fn connect_with(uri: &str, baud: u32, remote_command: &str) -> Result<Self> {
+        let target = SshTarget::parse(uri)?;
+        let remote = remote_command
+            .replace("{port}", &target.device)
+            .replace("{baud}", &baud.to_string());
+
+        let mut command = Command::new("ssh");
+        // Batch mode: a password prompt would otherwise eat the binary stream
+        command.args(["-T", "-o", "BatchMode=yes"]);
+        if let Some(port) = target.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        command
+            .arg(target.destination())
+            .arg(&remote)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+
+        info!("Opening {} on {} via ssh", target.device, target.host);
+        let mut child = command
+            .spawn()
+            .map_err(|e| Error::Connection(format!("Failed to run ssh: {}", e)))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self { child, stdin, stdout })
+    }
+
+    /// Ends the tunnel and the remote bridge
+    pub async fn close(mut self) -> Result<()> {
+        self.child.kill().await?;
+        Ok(())
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for SshStream {}
@@ -0,0 +1,182 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use log::{debug, info};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, Sleep};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const DEFAULT_RETRANSMITS: u32 = 3;
+/// Largest datagram accepted from the gateway
+const MAX_DATAGRAM: usize = 2048;
+/// Closes every LPL frame
+const FRAME_DELIMITER: u8 = 0x00;
+
+/// LPL frames exchanged with a gateway as UDP datagrams (`udp://host:port`).
+///
+/// Each frame written goes out as exactly one datagram. A request that gets
+/// no answer within the retransmit timeout is sent again, so a lost datagram
+/// costs a fraction of the response timeout instead of failing the request.
+/// Bootloader commands are idempotent, so a duplicate is harmless.
+pub struct UdpStream {
+    socket: UdpSocket,
+    /// Bytes written but not yet sent as a complete frame
+    tx: Vec<u8>,
+    /// Complete frames waiting for the socket
+    pending: Vec<Vec<u8>>,
+    rx: BytesMut,
+    /// Last frame sent, kept until any datagram answers it
+    last_frame: Option<Vec<u8>>,
+    retransmit: Option<Pin<Box<Sleep>>>,
+    attempts: u32,
+    retransmit_timeout: Duration,
+    retransmits: u32,
+}
+
+impl UdpStream {
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let authority = uri
+            .strip_prefix("udp://")
+            .ok_or_else(|| Error::Connection(format!("Not a udp:// URI: {}", uri)))?;
+        let address = tokio::net::lookup_host(authority)
+            .await?
+            .next()
+            .ok_or_else(|| Error::Connection(format!("Cannot resolve {}", authority)))?;
+
+        let bind: SocketAddr = if address.is_ipv6() {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        // Connected, so datagrams from anyone else are dropped by the OS
+        socket.connect(address).await?;
+        info!("UDP link to {} open", address);
+
+        Ok(Self {
+            socket,
+            tx: Vec::new(),
+            pending: Vec::new(),
+            rx: BytesMut::new(),
+            last_frame: None,
+            retransmit: None,
+            attempts: 0,
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            retransmits: DEFAULT_RETRANSMITS,
+        })
+    }
+
+    /// Resends an unanswered request after `timeout`, at most `retransmits` times
+    pub fn with_retransmit(mut self, timeout: Duration, retransmits: u32) -> Self {
+        self.retransmit_timeout = timeout;
+        self.retransmits = retransmits;
+        self
+    }
+
+    /// Sends every queued frame, arming the retransmit timer for the last one
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(frame) = self.pending.first() {
+            ready!(self.socket.poll_send(cx, frame))?;
+            let frame = self.pending.remove(0);
+            self.last_frame = Some(frame);
+            self.attempts = 0;
+            self.retransmit = Some(Box::pin(sleep(self.retransmit_timeout)));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Resends the last frame each time the timer fires without an answer
+    fn poll_retransmit(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while let Some(timer) = self.retransmit.as_mut() {
+            if timer.as_mut().poll(cx).is_pending() {
+                return Ok(());
+            }
+            let Some(frame) = &self.last_frame else {
+                self.retransmit = None;
+                return Ok(());
+            };
+            if self.attempts >= self.retransmits {
+                // Give up; the response timeout above decides what happens next
+                self.retransmit = None;
+                return Ok(());
+            }
+            self.attempts += 1;
+            debug!("Retransmitting unanswered frame (attempt {})", self.attempts);
+            match self.socket.try_send(frame) {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+            self.retransmit = Some(Box::pin(sleep(self.retransmit_timeout)));
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.rx.is_empty() {
+            if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            let mut datagram = [0u8; MAX_DATAGRAM];
+            let mut datagram = ReadBuf::new(&mut datagram);
+            match this.socket.poll_recv(cx, &mut datagram) {
+                Poll::Ready(Ok(())) => {
+                    this.rx.extend_from_slice(datagram.filled());
+                    this.last_frame = None;
+                    this.retransmit = None;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    this.poll_retransmit(cx)?;
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        this.tx.extend_from_slice(buf);
+        while let Some(end) = this.tx.iter().position(|b| *b == FRAME_DELIMITER) {
+            let frame: Vec<u8> = this.tx.drain(..=end).collect();
+            this.pending.push(frame);
+        }
+        // Frames the socket can't take yet go out on the next write, flush or read
+        if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl DfuTransport for UdpStream {}
@@ -0,0 +1,179 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, BytesMut};
+use log::info;
+use nusb::transfer::{Completion, Direction, EndpointType, Queue, RequestBuffer};
+use nusb::Interface;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use super::control::DfuTransport;
+
+/// Bulk transfers kept in flight towards the device
+const MAX_PENDING_WRITES: usize = 4;
+const DEFAULT_READ_SIZE: usize = 512;
+
+/// Interface and endpoints to use; whatever is left unset is found from
+/// the descriptors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsbEndpoints {
+    pub interface: Option<u8>,
+    pub bulk_in: Option<u8>,
+    pub bulk_out: Option<u8>,
+}
+
+/// Bulk endpoints of a vendor-class or CDC device opened without a virtual
+/// COM port (`usb://1209:0001?interface=1&in=0x81&out=0x01&serial=A1B2`)
+pub struct UsbStream {
+    reader: Queue<RequestBuffer>,
+    writer: Queue<Vec<u8>>,
+    read_size: usize,
+    rx: BytesMut,
+    _interface: Interface,
+}
+
+impl UsbStream {
+    pub fn connect(uri: &str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix("usb://")
+            .ok_or_else(|| Error::Connection(format!("Not a usb:// URI: {}", uri)))?;
+        let (ids, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (vid, pid) = ids
+            .split_once(':')
+            .and_then(|(vid, pid)| Some((u16::from_str_radix(vid, 16).ok()?, u16::from_str_radix(pid, 16).ok()?)))
+            .ok_or_else(|| Error::Connection(format!("Expected usb://vid:pid, got {}", uri)))?;
+
+        let mut endpoints = UsbEndpoints::default();
+        let mut serial = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "interface" => endpoints.interface = Some(parse_number(value)?),
+                "in" => endpoints.bulk_in = Some(parse_number(value)?),
+                "out" => endpoints.bulk_out = Some(parse_number(value)?),
+                "serial" => serial = Some(value),
+                _ => return Err(Error::Connection(format!("Unknown USB option: {}", key))),
+            }
+        }
+
+        let device = nusb::list_devices()
+            .map_err(|e| Error::Connection(e.to_string()))?
+            .find(|device| {
+                device.vendor_id() == vid
+                    && device.product_id() == pid
+                    && serial.is_none_or(|serial| device.serial_number() == Some(serial))
+            })
+            .ok_or_else(|| Error::Connection(format!("No USB device {:04x}:{:04x}", vid, pid)))?;
+        Self::open(&device, endpoints)
+    }
+
+    pub fn open(device: &nusb::DeviceInfo, endpoints: UsbEndpoints) -> Result<Self> {
+        let handle = device.open().map_err(|e| Error::Connection(e.to_string()))?;
+        let configuration = handle
+            .active_configuration()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+
+        // The first interface offering bulk endpoints both ways, unless one was named
+        let mut selected = None;
+        for alt in configuration.interface_alt_settings() {
+            if endpoints.interface.is_some_and(|number| number != alt.interface_number()) {
+                continue;
+            }
+            let bulk = |direction: Direction| {
+                alt.endpoints()
+                    .find(|ep| ep.transfer_type() == EndpointType::Bulk && ep.direction() == direction)
+                    .map(|ep| (ep.address(), ep.max_packet_size()))
+            };
+            let (Some(bulk_in), Some(bulk_out)) = (bulk(Direction::In), bulk(Direction::Out)) else {
+                continue;
+            };
+            selected = Some((alt.interface_number(), bulk_in, bulk_out));
+            break;
+        }
+        let (number, (found_in, packet), (found_out, _)) = selected.ok_or_else(|| {
+            Error::Connection("No interface with bulk IN and OUT endpoints".into())
+        })?;
+        let bulk_in = endpoints.bulk_in.unwrap_or(found_in);
+        let bulk_out = endpoints.bulk_out.unwrap_or(found_out);
+
+        // Detaches a kernel driver such as cdc_acm first
+        let interface = handle
+            .detach_and_claim_interface(number)
+            .map_err(|e| Error::Connection(format!("Cannot claim interface {}: {}", number, e)))?;
+        info!(
+            "USB device {:04x}:{:04x} open (interface {}, in {:#04x}, out {:#04x})",
+            device.vendor_id(),
+            device.product_id(),
+            number,
+            bulk_in,
+            bulk_out
+        );
+
+        Ok(Self {
+            reader: interface.bulk_in_queue(bulk_in),
+            writer: interface.bulk_out_queue(bulk_out),
+            read_size: packet.max(DEFAULT_READ_SIZE),
+            rx: BytesMut::new(),
+            _interface: interface,
+        })
+    }
+}
+
+impl AsyncRead for UsbStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        // Zero-length packets complete a transfer without data; keep reading
+        while this.rx.is_empty() {
+            if this.reader.pending() == 0 {
+                this.reader.submit(RequestBuffer::new(this.read_size));
+            }
+            let Completion { data, status } = ready!(this.reader.poll_next(cx));
+            status.map_err(io::Error::other)?;
+            this.rx.extend_from_slice(&data);
+        }
+
+        let len = this.rx.len().min(buf.remaining());
+        buf.put_slice(&this.rx[..len]);
+        this.rx.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UsbStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.writer.pending() >= MAX_PENDING_WRITES {
+            ready!(this.writer.poll_next(cx)).status.map_err(io::Error::other)?;
+        }
+        this.writer.submit(buf.to_vec());
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.writer.pending() > 0 {
+            ready!(this.writer.poll_next(cx)).status.map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// `1`, `0x81`
+fn parse_number(value: &str) -> Result<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| Error::Connection(format!("Invalid USB option value: {}", value)))
+}
+
+impl DfuTransport for UsbStream {}
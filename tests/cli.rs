@@ -0,0 +1,51 @@
+//! The `fwupd` binary as a shell and a user run it.
+#![cfg(feature = "cli")]
+
+use std::process::Output;
+use fwupd_lib_rs::{completion_script, Shell};
+use tokio::process::Command;
+
+async fn fwupd(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fwupd")).args(args).output().await.expect("fwupd runs")
+}
+
+#[tokio::test]
+async fn prints_completion_scripts() {
+    for (name, shell) in [("bash", Shell::Bash), ("zsh", Shell::Zsh), ("fish", Shell::Fish)] {
+        let output = fwupd(&["completions", name]).await;
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), completion_script(shell, "fwupd"));
+    }
+    assert!(!fwupd(&["completions", "tcsh"]).await.status.success());
+}
+
+#[tokio::test]
+async fn answers_uri_completion_callbacks() {
+    let output = fwupd(&["--complete-uri", "bash", "serial:///dev/tty"]).await;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().all(|uri| uri.starts_with("serial:///dev/tty")), "{}", stdout);
+
+    // Bash passes no word at all when completing an empty one
+    assert!(fwupd(&["--complete-uri", "bash"]).await.status.success());
+}
+
+#[cfg(feature = "tcp")]
+#[tokio::test]
+async fn reads_device_info() {
+    use fwupd_lib_rs::{SimModel, SimulatedDevice};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = format!("tcp://{}", listener.local_addr().unwrap());
+    let model = SimModel::default();
+    let expected = format!("Device {:#06x} rev {}", model.device_id, model.device_rev);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        SimulatedDevice::new(model).serve(stream).await
+    });
+
+    let output = fwupd(&["--uri", &uri]).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with(&expected));
+}
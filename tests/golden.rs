@@ -0,0 +1,198 @@
+//! Golden vectors for the wire encoding, shipped in `golden/packets.toml` so
+//! device firmware and other hosts can check their encoders byte for byte.
+//!
+//! `FWUPD_RECORD_GOLDEN=1 cargo test --test golden` rewrites the file from
+//! the current encoders instead of comparing against it.
+
+use std::time::Duration;
+use bytes::BytesMut;
+use fwupd_lib_rs::{
+    encode_frame, encode_request, AddressWidth, AplAckPacket, AplDataPacket, AplErrorPacket, AplHeader,
+    AplRequestType, Command, Error, Framing,
+};
+use serde::Deserialize;
+
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/packets.toml");
+const HEADER: &str = "\
+# Golden vectors for the APL packet and LPL frame encoders, as hex.
+# Regenerate with `FWUPD_RECORD_GOLDEN=1 cargo test --test golden` and
+# review the diff: any change here breaks compatibility with deployed devices.
+";
+
+#[derive(Deserialize)]
+struct Golden {
+    vector: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+struct Vector {
+    name: String,
+    hex: String,
+}
+
+struct Case {
+    name: &'static str,
+    layer: &'static str,
+    description: &'static str,
+    bytes: Vec<u8>,
+}
+
+fn request(
+    width: AddressWidth,
+    request_type: AplRequestType,
+    block_size: usize,
+    timeout_ms: u64,
+    command: Command,
+    offset: usize,
+    size: usize,
+) -> Vec<u8> {
+    let timeout = Duration::from_millis(timeout_ms);
+    encode_request(width, request_type, block_size, timeout, command as u8, offset, size).unwrap().to_vec()
+}
+
+fn frame(framing: Framing, netid: Option<u8>, packet: &[u8]) -> Vec<u8> {
+    let mut out = BytesMut::new();
+    encode_frame(framing, netid, packet, &mut out);
+    out.to_vec()
+}
+
+fn cases() -> Vec<Case> {
+    use AddressWidth::{Bits32, Bits64};
+    use AplRequestType::{ReadRequest, WriteRequest};
+
+    let info = request(Bits32, ReadRequest, 107, 0, Command::ReadBootloaderInfo, 0, 107);
+    let data = AplDataPacket {
+        header: AplHeader { type_id: AplRequestType::Data as u8 },
+        block_number: 5,
+        data: vec![0, 1, 2, 3],
+    }.to_bytes().to_vec();
+    let ack = AplAckPacket {
+        header: AplHeader { type_id: AplRequestType::Ack as u8 },
+        block_number: 0x0102,
+    }.to_bytes().to_vec();
+    let error = AplErrorPacket {
+        header: AplHeader { type_id: AplRequestType::Error as u8 },
+        block_number: 7,
+        error_code: 2,
+        error_message: "CRC".into(),
+    }.to_bytes().to_vec();
+    let legacy = Framing { syn: 0x7E, delimiter: 0x7E };
+
+    vec![
+        Case {
+            name: "apl-read-bootloader-info",
+            layer: "apl",
+            description: "ReadRequest for the 107-byte info block (command 0)",
+            bytes: info.clone(),
+        },
+        Case {
+            name: "apl-write-program-memory",
+            layer: "apl",
+            description: "WriteRequest for a 256-byte block at 0x08004000 (command 6)",
+            bytes: request(Bits32, WriteRequest, 256, 0, Command::WriteProgramMemory, 0x0800_4000, 256),
+        },
+        Case {
+            name: "apl-read-program-crc",
+            layer: "apl",
+            description: "ReadRequest for the CRC32 of 0x3C000 bytes at 0x08004000 (command 3)",
+            bytes: request(Bits32, ReadRequest, 4, 0, Command::ReadProgramCrc, 0x0800_4000, 0x3_C000),
+        },
+        Case {
+            name: "apl-erase-memory-timeout",
+            layer: "apl",
+            description: "WriteRequest erasing 0x4000 bytes at 0x08008000 with a 500 ms timeout (command 4)",
+            bytes: request(Bits32, WriteRequest, 0, 500, Command::EraseMemory, 0x0800_8000, 0x4000),
+        },
+        Case {
+            name: "apl-erase-memory-64",
+            layer: "apl",
+            description: "64-bit WriteRequest erasing 0x10000 bytes at 0x100000000 (command 4)",
+            bytes: request(Bits64, WriteRequest, 0, 0, Command::EraseMemory, 0x1_0000_0000, 0x1_0000),
+        },
+        Case {
+            name: "apl-read-memory-64",
+            layer: "apl",
+            description: "64-bit ReadRequest for 256 bytes at 0x100000000 (command 1)",
+            bytes: request(Bits64, ReadRequest, 256, 0, Command::ReadProgramMemory, 0x1_0000_0000, 256),
+        },
+        Case {
+            name: "apl-data",
+            layer: "apl",
+            description: "Data packet for block 5 carrying 00 01 02 03",
+            bytes: data.clone(),
+        },
+        Case {
+            name: "apl-ack",
+            layer: "apl",
+            description: "ACK for block 0x0102",
+            bytes: ack,
+        },
+        Case {
+            name: "apl-error",
+            layer: "apl",
+            description: "Error packet for block 7, code 2, message \"CRC\"",
+            bytes: error,
+        },
+        Case {
+            name: "lpl-frame",
+            layer: "lpl",
+            description: "apl-read-bootloader-info framed point-to-point with SYN 0x55 and delimiter 0x00",
+            bytes: frame(Framing::DEFAULT, None, &info),
+        },
+        Case {
+            name: "lpl-frame-netid",
+            layer: "lpl",
+            description: "apl-read-bootloader-info framed for node 0x12 on a multi-drop bus",
+            bytes: frame(Framing::DEFAULT, Some(0x12), &info),
+        },
+        Case {
+            name: "lpl-frame-0x7e",
+            layer: "lpl",
+            description: "apl-read-bootloader-info framed with SYN and delimiter 0x7E",
+            bytes: frame(legacy, None, &info),
+        },
+        Case {
+            name: "lpl-frame-data",
+            layer: "lpl",
+            description: "apl-data framed point-to-point, its zero bytes COBS-encoded",
+            bytes: frame(Framing::DEFAULT, None, &data),
+        },
+    ]
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn record(cases: &[Case]) {
+    let mut content = String::from(HEADER);
+    for case in cases {
+        content.push_str(&format!(
+            "\n[[vector]]\nname = {:?}\nlayer = {:?}\ndescription = {:?}\nhex = {:?}\n",
+            case.name, case.layer, case.description, to_hex(&case.bytes)
+        ));
+    }
+    std::fs::write(GOLDEN, content).unwrap();
+}
+
+#[test]
+fn encoders_match_golden_vectors() {
+    let cases = cases();
+    if std::env::var_os("FWUPD_RECORD_GOLDEN").is_some() {
+        record(&cases);
+        return;
+    }
+
+    let golden: Golden = toml::from_str(&std::fs::read_to_string(GOLDEN).unwrap()).unwrap();
+    assert_eq!(golden.vector.len(), cases.len(), "vector count changed; record and review");
+    for (vector, case) in golden.vector.iter().zip(&cases) {
+        assert_eq!(vector.name, case.name);
+        assert_eq!(vector.hex, to_hex(&case.bytes), "{} no longer encodes as recorded", case.name);
+    }
+}
+
+#[test]
+fn block_size_beyond_the_request_field_is_rejected() {
+    let encoded = encode_request(AddressWidth::Bits32, AplRequestType::WriteRequest, 0x10000, Duration::ZERO, 0, 0, 0);
+    assert!(matches!(encoded, Err(Error::Configuration(_))), "{:?}", encoded);
+}
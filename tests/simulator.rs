@@ -0,0 +1,374 @@
+//! Full updates against the simulated bootloader over an in-memory link,
+//! exercising the real request, data and ACK frames.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use fwupd_lib_rs::{
+    check_conformance, measure_transfer, read_device_info, AckPolicy, BannerParser, Baud, CheckOutcome,
+    ConformanceReport, DfuConfig, DfuStream, DfuTransport, EntryMethod, Error, FirmwareFormat, FirmwareImage,
+    LinkConditions, ProtocolErrorKind, RegionKind, Result, SimFault, SimModel, SimulatedDevice, TransferCase, UpdateMode,
+    UpdateOrdering, UpdateReport, Warning,
+};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::time::Instant;
+
+/// Image spanning several blocks, the last one partial
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+/// Runs `config` against a fresh simulated device; returns the flash afterwards
+async fn run(model: SimModel, config: DfuConfig) -> (Result<UpdateReport>, Vec<u8>) {
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model);
+    let server = tokio::spawn(async move {
+        sim.serve(device).await.expect("simulator");
+        sim
+    });
+
+    let mut dfu = DfuStream::new(host, config).expect("valid config");
+    let result = dfu.update().await;
+    drop(dfu);
+    let sim = server.await.expect("simulator task");
+    (result, sim.flash().to_vec())
+}
+
+fn firmware_offset(model: &SimModel) -> usize {
+    (model.memory.firmware_address - model.memory.flash_address) as usize
+}
+
+#[tokio::test]
+async fn update_writes_image_into_flash() {
+    let model = SimModel::default();
+    let data = image(5000);
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(data.clone())
+        .with_firmware_format(FirmwareFormat::Binary)
+        .with_block_size(1024)
+        .update()
+        .verify();
+
+    let (result, flash) = run(model.clone(), config).await;
+    let report = result.expect("update succeeds");
+
+    let offset = firmware_offset(&model);
+    assert_eq!(&flash[offset..offset + data.len()], &data[..]);
+    let region = &report.regions[0];
+    assert!(!region.skipped);
+    assert!(region.verified);
+    assert_eq!(region.size, data.len() as u32);
+    assert!(report.protocol_errors.is_empty());
+}
+
+/// Writes five blocks, the last window partial, with host and device
+/// agreeing on `policy`
+async fn update_with_ack_policy(policy: AckPolicy) {
+    let model = SimModel { ack_policy: policy, ..SimModel::default() };
+    let data = image(4500);
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(data.clone())
+        .with_firmware_format(FirmwareFormat::Binary)
+        .with_block_size(1024)
+        .with_ack_policy(policy)
+        .update()
+        .verify();
+
+    let (result, flash) = run(model.clone(), config).await;
+    let report = result.expect("update succeeds");
+
+    let offset = firmware_offset(&model);
+    assert_eq!(&flash[offset..offset + data.len()], &data[..]);
+    assert!(report.regions[0].verified);
+    assert!(report.protocol_errors.is_empty());
+}
+
+#[tokio::test]
+async fn update_with_ack_every_n_blocks() {
+    // The fifth block is never acknowledged; a stray ACK would be read as the CRC
+    update_with_ack_policy(AckPolicy::EveryN(2)).await;
+}
+
+#[tokio::test]
+async fn update_with_ack_at_end_of_window() {
+    update_with_ack_policy(AckPolicy::EndOfWindow(2)).await;
+}
+
+#[tokio::test]
+async fn update_with_ack_window_larger_than_transfer() {
+    update_with_ack_policy(AckPolicy::EndOfWindow(8)).await;
+}
+
+#[tokio::test]
+async fn metadata_last_needs_verification() {
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(image(100))
+        .with_update_ordering(UpdateOrdering::MetadataLast)
+        .update();
+    let (host, _device) = tokio::io::duplex(1024);
+    assert!(matches!(DfuStream::new(host, config), Err(Error::Configuration(_))));
+}
+
+#[tokio::test]
+async fn update_skips_image_already_in_flash() {
+    let model = SimModel::default();
+    let data = image(3000);
+    let preloaded = FirmwareImage::new(model.memory.firmware_address, data.clone());
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(data)
+        .with_firmware_format(FirmwareFormat::Binary)
+        .update();
+
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model).with_image(&preloaded).expect("image fits");
+    let server = tokio::spawn(async move {
+        sim.serve(device).await.expect("simulator");
+    });
+    let mut dfu = DfuStream::new(host, config).expect("valid config");
+    let report = dfu.update().await.expect("update succeeds");
+    drop(dfu);
+    server.await.expect("simulator task");
+
+    assert!(report.regions[0].skipped);
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_device_times_out() {
+    let model = SimModel { faults: vec![SimFault::Hang { after: 0 }], ..SimModel::default() };
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model);
+    let server = tokio::spawn(async move { sim.serve(device).await });
+
+    let started = Instant::now();
+    let result = read_device_info(host).await;
+    assert!(matches!(result, Err(Error::Timeout)), "{:?}", result.err());
+    // The whole response timeout elapses, on the paused clock
+    assert_eq!(started.elapsed(), Duration::from_secs(2));
+    server.abort();
+}
+
+/// Runs an update against a device with `fault`; returns the error counts
+async fn failed_update(fault: SimFault) -> std::collections::BTreeMap<ProtocolErrorKind, u64> {
+    let model = SimModel { faults: vec![fault], ..SimModel::default() };
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model);
+    let server = tokio::spawn(async move { sim.serve(device).await });
+
+    let config = DfuConfig::new().with_uri("sim").with_firmware_bytes(image(3000)).update();
+    let mut dfu = DfuStream::new(host, config).expect("valid config");
+    assert!(dfu.update().await.is_err());
+    server.abort();
+    dfu.protocol_errors()
+}
+
+#[tokio::test(start_paused = true)]
+async fn response_timeouts_are_counted() {
+    let errors = failed_update(SimFault::Hang { after: 0 }).await;
+    assert_eq!(errors.get(&ProtocolErrorKind::Timeout), Some(&1));
+}
+
+#[tokio::test]
+async fn responses_cut_short_are_counted() {
+    let errors = failed_update(SimFault::Disconnect { after: 0 }).await;
+    assert_eq!(errors.get(&ProtocolErrorKind::ShortResponse), Some(&1));
+}
+
+/// Checks a simulated device, letting the checker write at the start of
+/// the firmware region when `scratch` is set
+async fn conformance(model: SimModel, scratch: bool) -> ConformanceReport {
+    let mut config = DfuConfig::new().with_uri("sim").with_ack_policy(model.ack_policy);
+    if scratch {
+        config = config.with_conformance_scratch(model.memory.firmware_address);
+    }
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model);
+    let server = tokio::spawn(async move { sim.serve(device).await });
+    let report = check_conformance(host, config).await.expect("conformance run");
+    server.abort();
+    report
+}
+
+fn outcome<'a>(report: &'a ConformanceReport, name: &str) -> &'a CheckOutcome {
+    &report.checks.iter().find(|check| check.name == name).expect("check ran").outcome
+}
+
+#[tokio::test]
+async fn acknowledgement_is_skipped_without_scratch_area() {
+    let report = conformance(SimModel::default(), false).await;
+    assert!(matches!(outcome(&report, "block acknowledgement"), CheckOutcome::Skipped(_)));
+    assert!(matches!(outcome(&report, "frame size"), CheckOutcome::Skipped(_)));
+}
+
+#[tokio::test]
+async fn scratch_write_checks_acknowledgement() {
+    let model = SimModel { ack_policy: AckPolicy::EndOfWindow(2), ..SimModel::default() };
+    let report = conformance(model, true).await;
+    assert!(report.is_conformant(), "{}", report);
+    assert_eq!(outcome(&report, "scratch write"), &CheckOutcome::Passed);
+    assert_eq!(outcome(&report, "block acknowledgement"), &CheckOutcome::Passed);
+}
+
+#[tokio::test]
+async fn off_by_one_acks_are_deviations() {
+    let model = SimModel { faults: vec![SimFault::OffByOneAck], ..SimModel::default() };
+    let report = conformance(model, true).await;
+    assert!(matches!(outcome(&report, "block acknowledgement"), CheckOutcome::Deviation(_)), "{}", report);
+}
+
+#[tokio::test]
+async fn every_benchmarked_transfer_completes() {
+    let model = SimModel::default();
+    let data = image(5000);
+    for case in TransferCase::sweep() {
+        let sample = measure_transfer(&model, LinkConditions::clean(), case, &data).await;
+        assert_eq!(sample.expect("transfer succeeds").bytes, data.len(), "{}", case);
+    }
+}
+
+/// Metadata and firmware regions both written and verified in `ordering`,
+/// with the CRC of each region optionally checked while the next one is
+/// written
+async fn update_two_regions(ordering: UpdateOrdering, pipelined: bool) {
+    let model = SimModel::default();
+    let metadata_len = (model.memory.firmware_address - model.memory.metadata_address) as usize;
+    let data = image(metadata_len + 3000);
+    let mut config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(data.clone())
+        .with_firmware_format(FirmwareFormat::Binary)
+        .with_base_address(model.memory.metadata_address)
+        .with_block_size(1024)
+        .with_update_ordering(ordering)
+        .update()
+        .verify();
+    if pipelined {
+        config = config.pipeline_verify();
+    }
+
+    let (result, flash) = run(model.clone(), config).await;
+    let report = result.expect("update succeeds");
+
+    let offset = (model.memory.metadata_address - model.memory.flash_address) as usize;
+    assert_eq!(&flash[offset..offset + data.len()], &data[..]);
+    // Metadata sits below the firmware
+    let metadata = (RegionKind::Metadata, model.memory.metadata_address);
+    let firmware = (RegionKind::Firmware, model.memory.firmware_address);
+    let order: Vec<_> = report.regions.iter().map(|region| (region.kind, region.address)).collect();
+    match ordering {
+        UpdateOrdering::Address => assert_eq!(order, [metadata, firmware]),
+        UpdateOrdering::MetadataLast => assert_eq!(order, [firmware, metadata]),
+    }
+    assert!(report.regions.iter().all(|region| region.verified && !region.skipped));
+}
+
+#[tokio::test]
+async fn update_verifies_every_region() {
+    update_two_regions(UpdateOrdering::Address, false).await;
+}
+
+#[tokio::test]
+async fn pipelined_verify_spans_regions() {
+    update_two_regions(UpdateOrdering::Address, true).await;
+}
+
+#[tokio::test]
+async fn metadata_last_writes_firmware_first() {
+    update_two_regions(UpdateOrdering::MetadataLast, false).await;
+    update_two_regions(UpdateOrdering::MetadataLast, true).await;
+}
+
+/// In-memory link that records every line speed the host sets
+struct SpeedLog {
+    inner: DuplexStream,
+    speeds: Arc<Mutex<Vec<Baud>>>,
+}
+
+impl AsyncRead for SpeedLog {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SpeedLog {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl DfuTransport for SpeedLog {
+    async fn set_baud(&mut self, baud: Baud) -> Result<()> {
+        self.speeds.lock().unwrap().push(baud);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn update_runs_at_update_speed() {
+    let model = SimModel::default();
+    let data = image(3000);
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_firmware_bytes(data.clone())
+        .with_firmware_format(FirmwareFormat::Binary)
+        .with_update_mode(UpdateMode::Direct)
+        .with_entry(EntryMethod::AlreadyInBootloader)
+        .with_link_speed(Baud(9600))
+        .with_update_speed(Baud(115200))
+        .update();
+
+    let (host, device) = tokio::io::duplex(64 * 1024);
+    let mut sim = SimulatedDevice::new(model.clone());
+    let server = tokio::spawn(async move {
+        sim.serve(device).await.expect("simulator");
+        sim
+    });
+
+    let speeds = Arc::new(Mutex::new(Vec::new()));
+    let link = SpeedLog { inner: host, speeds: speeds.clone() };
+    let mut dfu = DfuStream::new(link, config).expect("valid config");
+    dfu.update().await.expect("update succeeds");
+    drop(dfu);
+    let sim = server.await.expect("simulator task");
+
+    let offset = firmware_offset(&model);
+    assert_eq!(&sim.flash()[offset..offset + data.len()], &data[..]);
+    // Entry at the link speed, the transfer at the update speed, then back
+    assert_eq!(*speeds.lock().unwrap(), [Baud(9600), Baud(115200), Baud(9600)]);
+}
+
+/// Enters a device printing `banner` and reads its info
+async fn entered_with_banner(banner: &str) -> UpdateReport {
+    let model = SimModel { banner: Some(banner.into()), ..SimModel::default() };
+    let config = DfuConfig::new()
+        .with_uri("sim")
+        .with_update_mode(UpdateMode::Direct)
+        .with_entry(EntryMethod::AlreadyInBootloader)
+        .with_banner(BannerParser::from_format("BL v{version} ({build})").unwrap())
+        .get_info();
+
+    let (result, _) = run(model, config).await;
+    result.expect("info is read")
+}
+
+#[tokio::test]
+async fn missing_version_string_is_a_warning() {
+    let report = entered_with_banner("BL v2.1 (sim)").await;
+    assert_eq!(report.device.unwrap().banner.unwrap().version.as_deref(), Some("2.1"));
+    assert!(!report.warnings.contains(&Warning::VersionMissing));
+
+    let report = entered_with_banner("Bootloader ready").await;
+    assert_eq!(report.warnings, [Warning::VersionMissing]);
+}